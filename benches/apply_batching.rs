@@ -0,0 +1,82 @@
+//! Compares the apply throughput of a storage catching up, entry by entry and in runs of
+//! `apply_batch_size`.
+//!
+//! Run with `cargo bench --bench apply_batching`.
+
+use std::time::Instant;
+
+use actix::prelude::*;
+use actix_raft::messages::{EntryNormal, EntryPayload};
+
+use raftor::config::ConfigSchema;
+use raftor::hash_ring::Ring;
+use raftor::raft::state_machine::DefaultStateMachine;
+use raftor::raft::storage::{ApplyObserved, Entry, MemoryStorage, MemoryStorageData};
+use raftor::server::Server;
+
+const ENTRIES: u64 = 100_000;
+/// Entries per message, as a follower receives them while catching up
+const PER_MESSAGE: u64 = 1_000;
+/// Every this many entries adds a node, which rebalances the ring
+const ADD_EVERY: u64 = 100;
+
+fn config(apply_batch_size: Option<usize>) -> ConfigSchema {
+    let mut toml = "discovery_host = \"127.0.0.1:8080\"\njoin_strategy = \"static\"\nnodes = []\n".to_owned();
+    if let Some(size) = apply_batch_size {
+        toml.push_str(&format!("apply_batch_size = {}\n", size));
+    }
+    let mut config = config::Config::default();
+    config.merge(config::File::from_str(&toml, config::FileFormat::Toml)).unwrap();
+    config.try_into().unwrap()
+}
+
+fn entry(index: u64) -> Entry {
+    let data = if index % ADD_EVERY == 0 {
+        MemoryStorageData::Add(index)
+    } else {
+        MemoryStorageData::Incr(format!("counter-{}", index % 16), 1)
+    };
+    Entry {
+        index,
+        term: 1,
+        payload: EntryPayload::Normal(EntryNormal { data }),
+    }
+}
+
+fn bench(label: &str, apply_batch_size: Option<usize>) {
+    let mut sys = System::new("bench");
+    let config = config(apply_batch_size);
+    let dir = tempfile::tempdir_in("/tmp").unwrap();
+    let snapshot_dir = dir.path().to_string_lossy().to_string();
+    let ring = Ring::new(config.ring_replicas as isize);
+    // rebalances go to a server which never runs
+    let server = Context::<Server>::new().address();
+    let storage = MemoryStorage::create(move |_| {
+        MemoryStorage::new(0, vec![], snapshot_dir, ring, server, Box::new(DefaultStateMachine), &config)
+    });
+
+    let started = Instant::now();
+    let mut from = 1;
+    while from <= ENTRIES {
+        let entries = (from..from + PER_MESSAGE).map(entry).collect();
+        sys.block_on(storage.send(ApplyObserved(entries))).unwrap().unwrap();
+        from += PER_MESSAGE;
+    }
+    let elapsed = started.elapsed();
+
+    println!(
+        "  {:<16} {:>10.0} entries/s  ({:?} for {} entries)",
+        label,
+        ENTRIES as f64 / elapsed.as_secs_f64(),
+        elapsed,
+        ENTRIES
+    );
+}
+
+fn main() {
+    println!("apply ({} entries per message, a node added every {})", PER_MESSAGE, ADD_EVERY);
+    bench("entry by entry", None);
+    for &size in &[16, 128, 1024] {
+        bench(&format!("runs of {}", size), Some(size));
+    }
+}
@@ -0,0 +1,119 @@
+//! Measures how long a lagging follower takes to catch up depending on the entries sent per
+//! append entries RPC, see `max_entries_per_append`.
+//!
+//! Every batch is framed and decoded the way peers exchange RPCs, then appended and applied by the
+//! follower's storage. The network's round trip is not part of the measurement, the time spent
+//! waiting on it is estimated from the number of RPCs for a few round trip times.
+//!
+//! Run with `cargo bench --bench catch_up`.
+
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_raft::messages::{AppendEntriesRequest, EntryNormal, EntryPayload};
+use bytes::BytesMut;
+use tokio::codec::{Decoder, Encoder};
+
+use raftor::config::ConfigSchema;
+use raftor::hash_ring::Ring;
+use raftor::network::{ClientNodeCodec, NodeCodec, NodeRequest, MAX_FRAME_SIZE};
+use raftor::raft::state_machine::DefaultStateMachine;
+use raftor::raft::storage::{ApplyObserved, Entry, MemoryStorage, MemoryStorageData};
+use raftor::server::Server;
+
+/// Entries the follower is behind
+const BEHIND: u64 = 20_000;
+/// Round trip times the waiting on the network is estimated for
+const RTTS: [Duration; 2] = [Duration::from_micros(200), Duration::from_millis(2)];
+
+fn config() -> ConfigSchema {
+    let toml = "discovery_host = \"127.0.0.1:8080\"\njoin_strategy = \"static\"\nnodes = []\n";
+    let mut config = config::Config::default();
+    config.merge(config::File::from_str(toml, config::FileFormat::Toml)).unwrap();
+    config.try_into().unwrap()
+}
+
+fn entry(index: u64) -> Entry {
+    Entry {
+        index,
+        term: 1,
+        payload: EntryPayload::Normal(EntryNormal {
+            data: MemoryStorageData::Incr(format!("counter-{}", index % 16), 1),
+        }),
+    }
+}
+
+/// Frame a batch as the leader sends it and decode it as the follower receives it
+fn transfer(batch: Vec<Entry>, prev_log_index: u64) -> (Vec<Entry>, usize) {
+    let rpc = AppendEntriesRequest {
+        target: 2,
+        term: 1,
+        leader_id: 1,
+        prev_log_index,
+        prev_log_term: 1,
+        entries: batch,
+        leader_commit: prev_log_index,
+    };
+    let body = serde_json::to_string(&rpc).unwrap();
+    let mut frame = BytesMut::new();
+    ClientNodeCodec
+        .encode(NodeRequest::Message(prev_log_index, "AppendEntriesRequest".to_owned(), body), &mut frame)
+        .unwrap();
+    // without the length prefix
+    let size = frame.len() - 2;
+    assert!(size <= MAX_FRAME_SIZE, "a batch of {} entries exceeds the frame size", rpc.entries.len());
+
+    match NodeCodec.decode(&mut frame).unwrap() {
+        Some(NodeRequest::Message(_, _, body)) => {
+            let rpc: AppendEntriesRequest<MemoryStorageData> = serde_json::from_str(&body).unwrap();
+            (rpc.entries, size)
+        }
+        _ => panic!("the frame didn't decode to the message"),
+    }
+}
+
+fn bench(max_entries_per_append: u64) {
+    let mut sys = System::new("bench");
+    let config = config();
+    let dir = tempfile::tempdir_in("/tmp").unwrap();
+    let snapshot_dir = dir.path().to_string_lossy().to_string();
+    let ring = Ring::new(config.ring_replicas as isize);
+    let server = Context::<Server>::new().address();
+    let follower = MemoryStorage::create(move |_| {
+        MemoryStorage::new(1, vec![], snapshot_dir, ring, server, Box::new(DefaultStateMachine), &config)
+    });
+
+    let mut rpcs: u32 = 0;
+    let mut largest_frame = 0;
+    let mut from = 1;
+    let started = Instant::now();
+    while from <= BEHIND {
+        let until = (from + max_entries_per_append).min(BEHIND + 1);
+        let (entries, size) = transfer((from..until).map(entry).collect(), from - 1);
+        sys.block_on(follower.send(ApplyObserved(entries))).unwrap().unwrap();
+        rpcs += 1;
+        largest_frame = largest_frame.max(size);
+        from = until;
+    }
+    let elapsed = started.elapsed();
+
+    let estimates: Vec<String> = RTTS
+        .iter()
+        .map(|rtt| format!("{:?} at {:?} rtt", elapsed + *rtt * rpcs, rtt))
+        .collect();
+    println!(
+        "  {:>4} per rpc  {:>6} rpcs  largest frame {:>6} bytes  local {:?}  {}",
+        max_entries_per_append,
+        rpcs,
+        largest_frame,
+        elapsed,
+        estimates.join("  ")
+    );
+}
+
+fn main() {
+    println!("catching up {} entries", BEHIND);
+    for &max_entries_per_append in &[1, 8, 64, 256] {
+        bench(max_entries_per_append);
+    }
+}
@@ -0,0 +1,72 @@
+//! Compares the snapshot entry encodings on large entries.
+//!
+//! Run with `cargo bench --bench entry_encoding`.
+
+use std::time::{Duration, Instant};
+
+use actix_raft::messages::{EntryNormal, EntryPayload};
+
+use raftor::config::EntryEncoding;
+use raftor::raft::storage::{decode_entries, encode_entries, Entry, MemoryStorageData, MAX_COUNTER_KEY_LEN, MAX_RAW_ENTRY_LEN};
+
+const ENTRIES: u64 = 1_000;
+const ROUNDS: u32 = 20;
+
+fn entries(data: impl Fn(u64) -> MemoryStorageData) -> Vec<Entry> {
+    (1..=ENTRIES)
+        .map(|index| Entry {
+            index,
+            term: 1,
+            payload: EntryPayload::Normal(EntryNormal { data: data(index) }),
+        })
+        .collect()
+}
+
+fn per_round(elapsed: Duration) -> f64 {
+    elapsed.as_micros() as f64 / ROUNDS as f64
+}
+
+fn bench(name: &str, entries: &[Entry]) {
+    println!("{} ({} entries)", name, entries.len());
+    for &(label, encoding) in &[
+        ("messagepack", EntryEncoding::MessagePack),
+        ("json", EntryEncoding::Json),
+        ("bincode", EntryEncoding::Bincode),
+    ] {
+        let mut size = 0;
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            size = encode_entries(encoding, entries).unwrap().len();
+        }
+        let encode = started.elapsed();
+
+        let data = encode_entries(encoding, entries).unwrap();
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            assert_eq!(decode_entries(encoding, &data).unwrap().len(), entries.len());
+        }
+        let decode = started.elapsed();
+
+        println!(
+            "  {:<12} {:>10} bytes  encode {:>10.0}us  decode {:>10.0}us",
+            label,
+            size,
+            per_round(encode),
+            per_round(decode)
+        );
+    }
+}
+
+fn main() {
+    bench(
+        "raw",
+        &entries(|index| MemoryStorageData::Raw((0..MAX_RAW_ENTRY_LEN).map(|i| (i as u64 + index) as u8).collect())),
+    );
+    bench(
+        "counters",
+        &entries(|index| {
+            let key: String = format!("{:0>width$}", index, width = MAX_COUNTER_KEY_LEN);
+            MemoryStorageData::SetExpiring(key, index as i64, u64::max_value())
+        }),
+    );
+}
@@ -0,0 +1,14 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio::codec::Decoder;
+
+use raftor::network::{decode_remote, NodeCodec};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_remote(data);
+
+    // the same bytes as a stream of length prefixed frames
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_)) = NodeCodec.decode(&mut buf) {}
+});
@@ -1,6 +1,12 @@
+use actix_raft::NodeId;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Clone, Debug, PartialEq)]
+use crate::hash_ring::DEFAULT_REPLICAS;
+use crate::raft::storage::{MEMBERSHIP_AUDIT_SIZE, RECENT_ENTRIES_SIZE};
+use crate::utils::generate_node_id;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum NetworkType {
     Cluster,
     App,
@@ -11,6 +17,46 @@ pub struct NodeInfo {
     pub cluster_addr: String,
     pub app_addr: String,
     pub public_addr: String,
+    /// Explicit id, derived from `cluster_addr` when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<NodeId>,
+    /// Serves reads only, never takes part in the membership
+    #[serde(default)]
+    pub readonly: bool,
+    /// Free-form tags like region, rack or capacity, see `MAX_METADATA_ENTRIES`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Most metadata entries of a node
+pub const MAX_METADATA_ENTRIES: usize = 16;
+
+/// Longest metadata key or value, in bytes
+pub const MAX_METADATA_LEN: usize = 128;
+
+impl NodeInfo {
+    pub fn node_id(&self) -> NodeId {
+        self.id.unwrap_or_else(|| generate_node_id(self.cluster_addr.as_str()))
+    }
+
+    /// Fails when the metadata exceeds its bounds, it is sent in every handshake
+    pub fn check_metadata(&self) -> Result<(), String> {
+        if self.metadata.len() > MAX_METADATA_ENTRIES {
+            return Err(format!(
+                "node {} has {} metadata entries, at most {} are allowed",
+                self.cluster_addr,
+                self.metadata.len(),
+                MAX_METADATA_ENTRIES
+            ));
+        }
+        match self.metadata.iter().find(|(key, value)| key.len() > MAX_METADATA_LEN || value.len() > MAX_METADATA_LEN) {
+            Some((key, _)) => Err(format!(
+                "metadata {} of node {} exceeds {} bytes",
+                key, self.cluster_addr, MAX_METADATA_LEN
+            )),
+            None => Ok(()),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -19,11 +65,534 @@ pub enum JoinStrategy {
     Dynamic,
 }
 
+/// How many voters have to apply a write before it is acknowledged.
+///
+/// `All` only holds the response back, the entry still commits with a majority. While a voter is
+/// unreachable every write is acknowledged after the timeout, naming the voters which lag behind
+/// in its response. A bootstrap membership with a read-only node, which never applies as a voter,
+/// is refused.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitQuorum {
+    Majority,
+    All,
+}
+
+impl Default for CommitQuorum {
+    fn default() -> Self {
+        CommitQuorum::Majority
+    }
+}
+
+impl CommitQuorum {
+    /// Number of voters required to acknowledge a write
+    pub fn required(&self, voters: usize) -> usize {
+        match self {
+            CommitQuorum::Majority => voters / 2 + 1,
+            CommitQuorum::All => voters,
+        }
+    }
+
+    /// Number of voters that may fail while writes are still acknowledged
+    pub fn tolerated_failures(&self, voters: usize) -> usize {
+        voters.saturating_sub(self.required(voters))
+    }
+}
+
+/// Which snapshot files are kept on disk after a new snapshot has been written.
+///
+/// The current snapshot is never removed. Unset limits keep every snapshot.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SnapshotRetention {
+    /// Keep at most this many snapshots
+    pub keep_last: Option<usize>,
+    /// Remove snapshots older than this many seconds
+    pub max_age_secs: Option<u64>,
+}
+
+impl SnapshotRetention {
+    pub fn is_enabled(&self) -> bool {
+        self.keep_last.is_some() || self.max_age_secs.is_some()
+    }
+}
+
+/// What the state machine does when applying a committed entry fails.
+///
+/// `Halt` stops applying any further entries and marks the node unhealthy, which
+/// preserves safety. `SkipAndLog` drops the failed entry and carries on, it should only
+/// be used for errors known to be benign since committed entries are lost.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplyErrorPolicy {
+    Halt,
+    SkipAndLog,
+}
+
+impl Default for ApplyErrorPolicy {
+    fn default() -> Self {
+        ApplyErrorPolicy::Halt
+    }
+}
+
+/// What a follower does when the leader overwrites an entry it already applied, which raft
+/// never does unless a log diverged, e.g. after a bug.
+///
+/// Both report the index and the terms of the two entries. `Overwrite` then takes the
+/// leader's entry as raft would, `Halt` refuses it and stops the state machine for inspection.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DivergencePolicy {
+    Overwrite,
+    Halt,
+}
+
+impl Default for DivergencePolicy {
+    fn default() -> Self {
+        DivergencePolicy::Overwrite
+    }
+}
+
+/// When a membership entry proposed through a `ClientRequest` counts as done.
+///
+/// `Applied` waits until the entry is applied on the leader, so the new member is on the
+/// ring once the request resolves. `Committed` resolves as soon as a quorum stored it.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipResponseMode {
+    Committed,
+    Applied,
+}
+
+impl Default for MembershipResponseMode {
+    fn default() -> Self {
+        MembershipResponseMode::Applied
+    }
+}
+
+/// What a node bootstrapping a cluster does when initializing its raft fails, e.g. because
+/// the storage holds a different config.
+///
+/// `MarkFailed` leaves the raft uninitialized and reports the node unhealthy, `Retry` tries
+/// again after `max_raft_init_timeout`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InitFailurePolicy {
+    MarkFailed,
+    Retry,
+}
+
+impl Default for InitFailurePolicy {
+    fn default() -> Self {
+        InitFailurePolicy::MarkFailed
+    }
+}
+
+/// Backoff of the initial connect to a peer.
+///
+/// Without `max_retries` a peer is dialed until it comes up.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConnectBackoff {
+    pub base_ms: u64,
+    pub max_ms: u64,
+    pub max_retries: Option<u32>,
+}
+
+impl Default for ConnectBackoff {
+    fn default() -> Self {
+        ConnectBackoff {
+            base_ms: 200,
+            max_ms: 10000,
+            max_retries: None,
+        }
+    }
+}
+
+/// A snapshot file copied from the leader out-of-band, see `LoadSnapshotFromFile`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SeedSnapshot {
+    pub path: String,
+    /// Index and term of the last entry of the snapshot, as the leader reports them
+    pub index: u64,
+    pub term: u64,
+}
+
+/// Options of the sockets of the cluster and application networks.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm so small messages like heartbeats aren't held back
+    pub nodelay: bool,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    /// Let the listener bind while connections of a previous run linger in `TIME_WAIT`
+    pub reuse_addr: bool,
+    /// Pending connections the OS queues for the listener
+    pub accept_backlog: i32,
+    /// Most connections accepted at once, further ones are refused
+    pub max_connections: usize,
+    /// Most connections accepted at once from the same IP address
+    pub max_connections_per_ip: usize,
+    /// Close a cluster connection nothing was read from for this many milliseconds, 0 never does.
+    /// Peers ping every second, so this catches a peer stuck mid-frame.
+    pub read_timeout_ms: u64,
+    /// Close a connection whose writes stay blocked for this many milliseconds, 0 never does
+    pub write_timeout_ms: u64,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            // as the listeners bound by tokio on unix
+            reuse_addr: true,
+            accept_backlog: 1024,
+            max_connections: 1024,
+            max_connections_per_ip: 64,
+            read_timeout_ms: 30000,
+            write_timeout_ms: 30000,
+        }
+    }
+}
+
+/// Encoding of state machine entries in snapshots.
+///
+/// Snapshots are sent between nodes as is, every node of a cluster has to use the same encoding.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryEncoding {
+    MessagePack,
+    Json,
+    /// Most compact for large `Raw` entries, see `benches/entry_encoding.rs`
+    Bincode,
+}
+
+impl Default for EntryEncoding {
+    fn default() -> Self {
+        EntryEncoding::MessagePack
+    }
+}
+
+/// When the files the storage persists, snapshots and log exports, are synced to disk.
+///
+/// `Always` syncs a file before its write is reported done. `Batched` syncs it in the background,
+/// a crash right after the write may lose it. `Never` leaves it to the OS.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotSync {
+    Always,
+    Batched,
+    Never,
+}
+
+impl Default for SnapshotSync {
+    fn default() -> Self {
+        SnapshotSync::Always
+    }
+}
+
+/// What a node does with a remote message from a peer which fails to deserialize.
+///
+/// Every failure is counted per peer. `CloseConnection` forces a broken peer to reconnect and
+/// handshake again, `DropAndIncrementMetric` drops the message without logging it.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeserializeFailurePolicy {
+    DropAndLog,
+    CloseConnection,
+    DropAndIncrementMetric,
+}
+
+impl Default for DeserializeFailurePolicy {
+    fn default() -> Self {
+        DeserializeFailurePolicy::CloseConnection
+    }
+}
+
+/// Where a node joining an existing cluster gets the log it starts from.
+///
+/// `Follower` pulls the applied entries from the given member before the raft starts, so the
+/// leader only replicates what was committed since. The follower has to be within
+/// `promote_max_lag` entries of the most up to date peer, the leader is used otherwise.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotSource {
+    Leader,
+    Follower(NodeId),
+}
+
+impl Default for SnapshotSource {
+    fn default() -> Self {
+        SnapshotSource::Leader
+    }
+}
+
 pub type NodeList = Vec<NodeInfo>;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ConfigSchema {
+    /// Longest wait (in seconds) for the configured peers to connect at startup
+    #[serde(default = "default_startup_timeout")]
+    pub max_discovery_timeout: u64,
+    /// Longest wait (in seconds) for the peers to start their raft before initializing the cluster
+    #[serde(default = "default_startup_timeout")]
+    pub max_raft_init_timeout: u64,
+    /// Refuse to peer with nodes configured with other `nodes`, which could bootstrap a separate
+    /// cluster
+    #[serde(default = "default_true")]
+    pub verify_initial_members: bool,
+    /// Random extra wait (up to this many milliseconds) before initializing the cluster, so the
+    /// bootstrapping nodes don't all start the first election at once
+    #[serde(default = "default_election_jitter_ms")]
+    pub election_jitter_ms: u64,
     pub discovery_host: String,
+    /// Address the cluster network binds, the `CLUSTER_ADDRESS` argument by default
+    pub listen_addr: Option<String>,
+    /// Address peers reach the cluster network at, `listen_addr` by default
+    pub advertise_addr: Option<String>,
     pub join_strategy: JoinStrategy,
     pub nodes: NodeList,
+    #[serde(default)]
+    pub commit_quorum: CommitQuorum,
+    #[serde(default)]
+    pub snapshot_retention: SnapshotRetention,
+    #[serde(default)]
+    pub apply_error_policy: ApplyErrorPolicy,
+    #[serde(default)]
+    pub init_failure_policy: InitFailurePolicy,
+    #[serde(default)]
+    pub divergence_policy: DivergencePolicy,
+    #[serde(default)]
+    pub membership_response_mode: MembershipResponseMode,
+    /// Reject normal priority client requests while this many are in flight
+    pub max_inflight_requests: Option<usize>,
+    /// Number of independent raft groups run by every node
+    #[serde(default = "default_raft_groups")]
+    pub raft_groups: u64,
+    #[serde(default)]
+    pub connect_backoff: ConnectBackoff,
+    #[serde(default)]
+    pub socket: SocketOptions,
+    /// Bootstrap the storage of the default group from a log export
+    pub import_log: Option<String>,
+    /// Seed the empty storage of the default group with a snapshot of the leader, unless there is
+    /// a log export to import
+    pub seed_snapshot: Option<SeedSnapshot>,
+    /// Export the log of the default group to `import_log` on `Shutdown`, for the next start
+    #[serde(default)]
+    pub snapshot_on_shutdown: bool,
+    /// Warn about client requests taking longer than this, 0 disables it
+    #[serde(default = "default_slow_proposal_ms")]
+    pub slow_proposal_ms: u64,
+    /// Follow the log of every group without ever joining the membership
+    #[serde(default)]
+    pub observer: bool,
+    /// Run as an observer which relays writes to a read-write node
+    #[serde(default)]
+    pub readonly: bool,
+    /// Metadata of the local node, merged with the one of its `nodes` entry
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// Metadata key naming the rack of a node, the replicas of a key are spread across racks
+    #[serde(default = "default_placement_key")]
+    pub placement_key: String,
+    /// Connections opened to every peer, all but the first carry bulk traffic like snapshots
+    #[serde(default = "default_peer_connections")]
+    pub peer_connections: usize,
+    #[serde(default)]
+    pub entry_encoding: EntryEncoding,
+    #[serde(default)]
+    pub snapshot_sync: SnapshotSync,
+    #[serde(default)]
+    pub deserialize_failure_policy: DeserializeFailurePolicy,
+    /// Bandwidth cap of the snapshots sent to each follower, unlimited by default
+    pub snapshot_bytes_per_sec: Option<u64>,
+    /// Apply replicated entries in runs of up to this many, rebalancing the ring once per run,
+    /// see `benches/apply_batching.rs`
+    pub apply_batch_size: Option<usize>,
+    /// Most entries the leader sends per append entries RPC, the network splits a batch which
+    /// doesn't fit a frame
+    pub max_entries_per_append: Option<u64>,
+    /// Most entries a node may be behind to be promoted with `PromoteToVoter`
+    #[serde(default = "default_promote_max_lag")]
+    pub promote_max_lag: u64,
+    #[serde(default)]
+    pub snapshot_source: SnapshotSource,
+    /// Entries a follower may be behind the leader before `GetFollowerProgress` reports it at risk
+    #[serde(default = "default_at_risk_lag")]
+    pub at_risk_lag: u64,
+    /// Fail a client request after retrying it this many times, unbounded by default
+    pub max_client_retries: Option<u32>,
+    /// Fail a request the state machine rejected once an `ApplicationErrorHandler` retried it this
+    /// many times
+    #[serde(default = "default_max_application_retries")]
+    pub max_application_retries: u32,
+    /// Forward client requests to random peers while no leader is known, through at most this
+    /// many of them, 0 waits for a leader
+    #[serde(default)]
+    pub blind_forward_hops: u8,
+    /// Forward client requests to the leader, false fails them with `NotLeader` naming the leader
+    /// so the caller connects to it directly
+    #[serde(default = "default_true")]
+    pub forward_client_requests: bool,
+    /// Answer the writes of the `server::http` routes reaching a follower with a `307` to the
+    /// leader, instead of forwarding them
+    #[serde(default)]
+    pub redirect_to_leader: bool,
+    /// Refuse snapshots larger than this many bytes, their entries are held in memory
+    pub max_snapshot_bytes: Option<u64>,
+    /// Reject writes on the leader while fewer voters are healthy, unset accepts them regardless
+    pub min_voters_for_writes: Option<usize>,
+    /// Hold up to this many client requests while no leader is known, unset sends them right away
+    pub queue_until_leader: Option<usize>,
+    /// Warn when a group has more elections than this within a minute, 0 disables it
+    #[serde(default = "default_max_elections_per_minute")]
+    pub max_elections_per_minute: u32,
+    /// Report a membership change stuck in joint consensus for longer than this many
+    /// milliseconds and try to recover it, 0 disables it
+    #[serde(default = "default_joint_consensus_timeout_ms")]
+    pub joint_consensus_timeout_ms: u64,
+    /// Stop forwarding to a leader after this many failed forwards in a row, 0 disables it
+    #[serde(default = "default_leader_breaker_threshold")]
+    pub leader_breaker_threshold: u32,
+    /// How long a suspect leader is avoided at most, it is tried again afterwards
+    #[serde(default = "default_leader_breaker_timeout_ms")]
+    pub leader_breaker_timeout_ms: u64,
+    /// Add the node to the ring membership once the cluster it bootstrapped is initialized
+    #[serde(default = "default_auto_self_add")]
+    pub auto_self_add: bool,
+    /// Bootstrap a new cluster when no peer responds at startup, instead of waiting to be added
+    #[serde(default)]
+    pub auto_bootstrap: bool,
+    /// Virtual nodes per node on the ring until a `RingReplicas` entry replaces it
+    #[serde(default = "default_ring_replicas")]
+    pub ring_replicas: u32,
+    /// Warn about state machine applies taking longer than this, 0 disables it
+    #[serde(default = "default_slow_apply_ms")]
+    pub slow_apply_ms: u64,
+    /// Longest freeze (in seconds) of `FreezeWrites`, writes are accepted again afterwards
+    #[serde(default = "default_max_freeze_secs")]
+    pub max_freeze_secs: u64,
+    /// Delegate read leases of this many milliseconds to followers, unset disables them
+    pub read_lease_ms: Option<u64>,
+    /// Followers the leader delegates read leases to, every voter when empty
+    #[serde(default)]
+    pub read_lease_holders: Vec<NodeId>,
+    /// Log a summary of every raft RPC sent and received, for debugging replication
+    #[serde(default)]
+    pub rpc_tap: bool,
+    /// How often (in milliseconds) the leader checks for expired counters, 0 never expires them
+    #[serde(default = "default_expiry_interval_ms")]
+    pub expiry_interval_ms: u64,
+    /// Membership changes kept for `GetMembershipAudit`, 0 keeps none
+    #[serde(default = "default_membership_audit_size")]
+    pub membership_audit_size: usize,
+    /// Applied entries kept for `GetRecentEntries`, 0 keeps none
+    #[serde(default = "default_recent_entries_size")]
+    pub recent_entries_size: usize,
+}
+
+impl ConfigSchema {
+    /// Ids of the configured nodes, fails if two nodes end up with the same id or a node's
+    /// metadata exceeds its bounds
+    pub fn node_ids(&self) -> Result<HashMap<NodeId, NodeInfo>, String> {
+        let mut ids: HashMap<NodeId, NodeInfo> = HashMap::new();
+        for node in self.nodes.iter() {
+            node.check_metadata()?;
+            let id = node.node_id();
+            if let Some(other) = ids.get(&id) {
+                if other.cluster_addr != node.cluster_addr {
+                    return Err(format!("nodes {} and {} share the id {}", other.cluster_addr, node.cluster_addr, id));
+                }
+            }
+            ids.insert(id, node.clone());
+        }
+        Ok(ids)
+    }
+}
+
+fn default_startup_timeout() -> u64 {
+    5
+}
+
+fn default_raft_groups() -> u64 {
+    1
+}
+
+fn default_slow_proposal_ms() -> u64 {
+    1000
+}
+
+fn default_peer_connections() -> usize {
+    1
+}
+
+fn default_promote_max_lag() -> u64 {
+    100
+}
+
+fn default_at_risk_lag() -> u64 {
+    5000
+}
+
+fn default_max_application_retries() -> u32 {
+    3
+}
+
+fn default_max_elections_per_minute() -> u32 {
+    5
+}
+
+fn default_joint_consensus_timeout_ms() -> u64 {
+    60000
+}
+
+fn default_placement_key() -> String {
+    "rack".to_owned()
+}
+
+fn default_leader_breaker_threshold() -> u32 {
+    3
+}
+
+fn default_leader_breaker_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_auto_self_add() -> bool {
+    true
+}
+
+fn default_ring_replicas() -> u32 {
+    DEFAULT_REPLICAS as u32
+}
+
+fn default_membership_audit_size() -> usize {
+    MEMBERSHIP_AUDIT_SIZE
+}
+
+fn default_recent_entries_size() -> usize {
+    RECENT_ENTRIES_SIZE
+}
+
+fn default_slow_apply_ms() -> u64 {
+    100
+}
+
+fn default_max_freeze_secs() -> u64 {
+    3600
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_election_jitter_ms() -> u64 {
+    1000
+}
+
+fn default_expiry_interval_ms() -> u64 {
+    1000
 }
@@ -1,4 +1,5 @@
 use hash_ring::HashRing;
+use std::collections::{BTreeSet, HashSet};
 use std::sync::{Arc, RwLock};
 
 use actix_raft::NodeId;
@@ -6,8 +7,116 @@ use actix_raft::NodeId;
 pub type RingType = Arc<RwLock<HashRing<NodeId>>>;
 pub struct Ring;
 
+/// Virtual nodes per node on the ring
+pub const DEFAULT_REPLICAS: isize = 10;
+
+/// Most ring lookups made to find the replicas of a key
+const MAX_REPLICA_PROBES: usize = 64;
+
+/// Keys looked up to tell which nodes a ring holds
+const PLACEMENT_PROBES: usize = 4096;
+
 impl Ring {
     pub fn new(replicas: isize) -> RingType {
         Arc::new(RwLock::new(HashRing::new(Vec::new(), replicas)))
     }
+
+    /// Replace every node of the ring with `nodes`
+    pub fn reset(ring: &RingType, nodes: Vec<NodeId>, replicas: isize) {
+        *ring.write().unwrap() = HashRing::new(nodes, replicas);
+    }
+
+    /// Owner of each of a fixed set of probe keys. The ring can't list its nodes, two rings with
+    /// the same placements route keys the same way, and a node owning none of the probes holds a
+    /// negligible share of the keys.
+    pub fn placements(ring: &RingType) -> Vec<Option<NodeId>> {
+        let ring = ring.read().unwrap();
+        (0..PLACEMENT_PROBES).map(|probe| ring.get_node(format!("placement#{}", probe)).cloned()).collect()
+    }
+
+    /// The nodes owning some of the probe keys of `placements`
+    pub fn nodes(ring: &RingType) -> BTreeSet<NodeId> {
+        Ring::placements(ring).into_iter().filter_map(|node| node).collect()
+    }
+
+    /// Up to `n` distinct nodes to place the replicas of `key` on, its owner first. The ring is
+    /// probed with keys derived from `key`, so the replicas move as little as the owner does when
+    /// the membership changes. Nodes in a rack not picked yet are preferred, nodes sharing a rack
+    /// only fill in once the distinct racks ran out. A node without a rack is a rack of its own.
+    pub fn replica_nodes<F>(ring: &RingType, key: &str, n: usize, rack_of: F) -> Vec<NodeId>
+    where
+        F: Fn(NodeId) -> Option<String>,
+    {
+        let ring = ring.read().unwrap();
+        let mut picked: Vec<NodeId> = Vec::new();
+        let mut same_rack: Vec<NodeId> = Vec::new();
+        let mut racks = HashSet::new();
+
+        for probe in 0..MAX_REPLICA_PROBES {
+            if picked.len() >= n {
+                break;
+            }
+            let probe_key = if probe == 0 { key.to_owned() } else { format!("{}#{}", key, probe) };
+            let node = match ring.get_node(probe_key) {
+                Some(node) => *node,
+                None => break,
+            };
+            if picked.contains(&node) || same_rack.contains(&node) {
+                continue;
+            }
+            match rack_of(node) {
+                Some(rack) if !racks.insert(rack) => same_rack.push(node),
+                _ => picked.push(node),
+            }
+        }
+
+        let missing = n.saturating_sub(picked.len());
+        picked.extend(same_rack.into_iter().take(missing));
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(nodes: Vec<NodeId>) -> RingType {
+        let ring = Ring::new(DEFAULT_REPLICAS);
+        Ring::reset(&ring, nodes, DEFAULT_REPLICAS);
+        ring
+    }
+
+    fn rack(id: NodeId) -> Option<String> {
+        Some(format!("rack-{}", (id - 1) / 2))
+    }
+
+    #[test]
+    fn the_replicas_of_a_key_start_with_its_owner_and_spread_across_racks() {
+        let ring = ring(vec![1, 2, 3, 4, 5, 6]);
+        for key in &["alpha", "beta", "gamma", "delta"] {
+            let replicas = Ring::replica_nodes(&ring, key, 3, rack);
+            assert_eq!(replicas.len(), 3);
+            assert_eq!(Some(replicas[0]), ring.read().unwrap().get_node(key.to_string()).cloned());
+            let racks: HashSet<_> = replicas.iter().map(|id| rack(*id)).collect();
+            assert_eq!(racks.len(), 3, "{} placed on {:?}", key, replicas);
+        }
+    }
+
+    #[test]
+    fn nodes_sharing_a_rack_fill_in_once_the_racks_ran_out() {
+        let ring = ring(vec![1, 2, 3, 4]);
+        let replicas = Ring::replica_nodes(&ring, "alpha", 3, rack);
+        let distinct: HashSet<_> = replicas.iter().cloned().collect();
+        assert_eq!((replicas.len(), distinct.len()), (3, 3));
+        // both racks are used before one is used twice
+        let racks: HashSet<_> = replicas[..2].iter().map(|id| rack(*id)).collect();
+        assert_eq!(racks.len(), 2);
+    }
+
+    #[test]
+    fn a_ring_smaller_than_the_replicas_asked_for_places_them_on_every_node() {
+        // nodes without a rack are racks of their own
+        assert_eq!(Ring::replica_nodes(&ring(vec![1, 2, 3]), "alpha", 5, |_| None).len(), 3);
+        assert!(Ring::replica_nodes(&ring(vec![]), "alpha", 3, |_| None).is_empty());
+    }
 }
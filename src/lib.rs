@@ -3,9 +3,13 @@ extern crate log;
 pub mod config;
 pub mod data;
 pub mod hash_ring;
+pub mod logger;
 pub mod network;
 pub mod raft;
 pub mod raftor;
 pub mod server;
 pub mod session;
 pub mod utils;
+
+#[cfg(test)]
+mod test_utils;
@@ -0,0 +1,78 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Module of the log targets of the crate, the level set at runtime only applies to them
+const TARGET: &str = "raftor";
+
+/// No level was set at runtime, `RUST_LOG` decides
+const UNSET: usize = usize::max_value();
+
+static LEVEL: AtomicUsize = AtomicUsize::new(UNSET);
+
+/// Logs through `env_logger`, configured by `RUST_LOG` as before, except that the level of the
+/// raftor targets can be replaced at runtime with `SetLogLevel`
+struct Logger {
+    /// configured by `RUST_LOG` only, decides while no level is set
+    env: env_logger::Logger,
+    /// writes the records, it lets every record of the raftor targets through
+    output: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match level() {
+            Some(level) if is_raftor(metadata.target()) => metadata.level() <= level,
+            _ => self.env.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.output.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
+
+fn is_raftor(target: &str) -> bool {
+    target == TARGET || target.starts_with("raftor::")
+}
+
+/// Level of the raftor targets set at runtime, if any
+pub fn level() -> Option<LevelFilter> {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Some(LevelFilter::Off),
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        5 => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/// Set the level of the raftor targets, `None` goes back to the one of `RUST_LOG`. Other targets
+/// keep logging as configured.
+pub fn set_level(level: Option<LevelFilter>) {
+    LEVEL.store(level.map(|level| level as usize).unwrap_or(UNSET), Ordering::Relaxed);
+    // records above the global max level never reach the logger
+    let configured = env_logger::Builder::from_default_env().build().filter();
+    log::set_max_level(level.map(|level| level.max(configured)).unwrap_or(configured));
+}
+
+/// Install the logger, in place of `env_logger::init()`
+pub fn init() {
+    let env = env_logger::Builder::from_default_env().build();
+    let output = env_logger::Builder::from_default_env()
+        .filter_module(TARGET, LevelFilter::Trace)
+        .build();
+
+    let max_level = env.filter();
+    match log::set_boxed_logger(Box::new(Logger { env, output })) {
+        Ok(()) => log::set_max_level(max_level),
+        Err(err) => eprintln!("Logger already installed: {}", err),
+    }
+}
@@ -6,17 +6,22 @@ use actix_web::{
 };
 use actix_web_actors::ws;
 use futures::Future;
+use serde::Deserialize;
 use std::env;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use actix_raft::NodeId;
+use tokio::util::FutureExt;
 
 
 use raftor::{
-    network::{GetNode, GetNodes, GetClusterState, Network},
-    raftor::Raftor,
-    server::{self, Server},
+    network::{GetNode, GetNodes, GetClusterStatus, GetTopology, Network, SetMaintenance},
+    raftor::{GetLogLevel, JoinCluster, Raftor, SetLogLevel, Shutdown, WaitReady},
+    server::{self, http as raft_http, Server},
     session::Session,
-    raft::{RaftClient, ChangeRaftClusterConfig},
+    raft::{RaftClient, GetHealth},
+    utils::PrometheusMetrics,
 };
 
 fn index_route(
@@ -40,7 +45,7 @@ fn join_cluster_route(
 ) ->  HttpResponse {
 
     println!("got join request with id {:#?}", node_id);
-    srv.raft.do_send(ChangeRaftClusterConfig(vec![*node_id], vec![]));
+    srv.raftor.do_send(JoinCluster(*node_id));
     HttpResponse::Ok().json(()) // <- send json response
 }
 
@@ -75,12 +80,108 @@ fn state_route(
     _stream: web::Payload,
     srv: web::Data<Arc<ServerData>>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    srv.net
-        .send(GetClusterState)
+    srv.cluster_net
+        .send(GetClusterStatus)
         .map_err(Error::from)
         .and_then(|res| Ok(HttpResponse::Ok().json(res)))
 }
 
+#[derive(Deserialize)]
+struct TopologyQuery {
+    /// `dot` for Graphviz, JSON otherwise
+    format: Option<String>,
+}
+
+fn topology_route(
+    query: web::Query<TopologyQuery>,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let dot = query.format.as_ref().map(|format| format == "dot").unwrap_or(false);
+
+    srv.cluster_net
+        .send(GetTopology)
+        .map_err(Error::from)
+        .and_then(move |res| match res {
+            Ok(topology) if dot => Ok(HttpResponse::Ok().content_type("text/vnd.graphviz").body(topology.to_dot())),
+            Ok(topology) => Ok(HttpResponse::Ok().json(topology)),
+            Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+        })
+}
+
+fn maintenance_route(
+    maintenance: web::Json<bool>,
+    _req: HttpRequest,
+    _stream: web::Payload,
+    srv: web::Data<Arc<ServerData>>,
+) -> HttpResponse {
+    srv.raftor.do_send(SetMaintenance(*maintenance));
+    HttpResponse::Ok().json(()) // <- send json response
+}
+
+fn get_log_level_route(srv: web::Data<Arc<ServerData>>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raftor
+        .send(GetLogLevel)
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(level) => HttpResponse::Ok().json(level.map(|level| level.to_string())),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        })
+}
+
+fn set_log_level_route(
+    level: web::Json<Option<String>>,
+    srv: web::Data<Arc<ServerData>>,
+) -> HttpResponse {
+    let level = match level.into_inner() {
+        Some(level) => match log::LevelFilter::from_str(&level) {
+            Ok(level) => Some(level),
+            Err(_) => return HttpResponse::BadRequest().body(format!("unknown log level {}", level)),
+        },
+        None => None,
+    };
+    srv.raftor.do_send(SetLogLevel(level));
+    HttpResponse::Ok().json(()) // <- send json response
+}
+
+fn health_route(
+    _req: HttpRequest,
+    _stream: web::Payload,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(GetHealth)
+        .map_err(Error::from)
+        .and_then(|res| match res {
+            Ok(ref health) if health.healthy => Ok(HttpResponse::Ok().json(res)),
+            _ => Ok(HttpResponse::ServiceUnavailable().json(res)),
+        })
+}
+
+fn ready_route(
+    _req: HttpRequest,
+    _stream: web::Payload,
+    srv: web::Data<Arc<ServerData>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raftor
+        .send(WaitReady)
+        .timeout(Duration::from_secs(1))
+        .then(|res| match res {
+            Ok(Ok(_)) => Ok(HttpResponse::Ok().json(true)),
+            _ => Ok(HttpResponse::ServiceUnavailable().json(false)),
+        })
+}
+
+fn shutdown_route(srv: web::Data<Arc<ServerData>>) -> HttpResponse {
+    srv.raftor.do_send(Shutdown);
+    HttpResponse::Ok().json(()) // <- send json response
+}
+
+fn metrics_route(srv: web::Data<Arc<ServerData>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(srv.metrics.render())
+}
+
 fn room_route(
     req: HttpRequest,
     _stream: web::Payload,
@@ -107,27 +208,39 @@ fn ws_route(
 struct ServerData {
     server: Addr<Server>,
     net: Addr<Network>,
+    cluster_net: Addr<Network>,
     raft: Addr<RaftClient>,
+    raftor: Addr<Raftor>,
+    metrics: Arc<PrometheusMetrics>,
 }
 
 fn main() {
-    env_logger::init();
+    raftor::logger::init();
 
     let sys = System::new("raftor");
 
     let args: Vec<String> = env::args().collect();
     let public_address = args[3].as_str();
 
-    let raftor = Raftor::new();
+    let metrics = Arc::new(PrometheusMetrics::new());
+    let raftor = Raftor::with_metrics(metrics.clone());
+
+    let server = raftor.server.clone();
+    let net = raftor.app_net.clone();
+    let cluster_net = raftor.cluster_net.clone();
+    let raft = raftor.raft.clone();
+
+    let raft_http = raftor.http();
 
     let state = Arc::new(ServerData {
-        server: raftor.server.clone(),
-        net: raftor.app_net.clone(),
-        raft: raftor.raft.clone(),
+        server: server,
+        net: net,
+        cluster_net: cluster_net,
+        raft: raft,
+        raftor: raftor.start(),
+        metrics: metrics,
     });
 
-    let _ = raftor.start();
-
     HttpServer::new(move || {
         App::new()
             .wrap(
@@ -139,6 +252,8 @@ fn main() {
             )
             .wrap(Logger::default())
             .data(state.clone())
+            .data(raft_http.clone())
+            .configure(raft_http::routes)
             .service(web::resource("/").route(web::get().to(|| {
                 HttpResponse::Found()
                     .header("LOCATION", "/static/index.html")
@@ -147,7 +262,18 @@ fn main() {
             .service(web::resource("/node/{uid}").to_async(index_route))
             .service(web::resource("/cluster/nodes").to_async(nodes_route))
             .service(web::resource("/cluster/state").to_async(state_route))
+            .service(web::resource("/cluster/health").to_async(health_route))
+            .service(web::resource("/cluster/topology").to_async(topology_route))
+            .service(web::resource("/cluster/ready").to_async(ready_route))
+            .service(web::resource("/metrics").route(web::get().to(metrics_route)))
+            .service(web::resource("/cluster/shutdown").route(web::post().to(shutdown_route)))
             .service(web::resource("/cluster/join").route(web::put().to_async(join_cluster_route)))
+            .service(web::resource("/cluster/maintenance").route(web::put().to(maintenance_route)))
+            .service(
+                web::resource("/cluster/log_level")
+                    .route(web::get().to_async(get_log_level_route))
+                    .route(web::put().to(set_log_level_route)),
+            )
             .service(web::resource("/room/{room_id}").to_async(room_route))
             .service(web::resource("/members/{room_id}").to_async(members_route))
             .service(web::resource("/ws/{uid}").to_async(ws_route))
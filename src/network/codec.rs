@@ -1,27 +1,229 @@
 use actix_raft::NodeId;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use serde::{Deserialize, Serialize};
 use serde_json as json;
+use std::fmt;
+use std::io;
 use tokio::codec::{Decoder, Encoder};
 
 use crate::config::NodeInfo;
+use crate::utils::is_host_port;
+
+/// Frames are prefixed with their length as a u16
+pub const MAX_FRAME_SIZE: usize = u16::max_value() as usize;
+
+/// Bytes `value` takes in the payload of a frame, its json is escaped once more in there
+pub fn framed_len<T: Serialize>(value: &T) -> usize {
+    match json::to_string(value).and_then(|inner| json::to_string(&inner)) {
+        // without the quotes around the nested json
+        Ok(outer) => outer.len() - 2,
+        Err(_) => MAX_FRAME_SIZE,
+    }
+}
+
+/// Bytes a `NodeResponse::Result` frame takes besides its payload
+pub fn result_frame_overhead() -> usize {
+    json::to_string(&NodeResponse::Result(u64::max_value(), String::new())).map(|frame| frame.len()).unwrap_or(MAX_FRAME_SIZE)
+}
+
+/// Bytes a `NodeRequest::Message` frame of `type_id` takes besides its payload
+pub fn message_frame_overhead(type_id: &str) -> usize {
+    json::to_string(&NodeRequest::Message(u64::max_value(), type_id.to_owned(), String::new()))
+        .map(|frame| frame.len())
+        .unwrap_or(MAX_FRAME_SIZE)
+}
+
+/// A frame longer than its u16 length prefix can express is refused rather than truncated
+fn check_frame_size(len: usize) -> Result<(), io::Error> {
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes exceeds the {} bytes a frame holds", len, MAX_FRAME_SIZE),
+        ));
+    }
+    Ok(())
+}
+
+/// Version of the peer protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest peer protocol version this build still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// What a node supports, exchanged when a peer joins. Lists are in order of preference.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub codecs: Vec<String>,
+    pub compression: Vec<String>,
+    pub tls: bool,
+    /// the configured nodes this node bootstraps a cluster with, unset when not verified
+    #[serde(default)]
+    pub initial_members: Option<InitialMembers>,
+}
+
+/// Configured initial member set, two nodes bootstrapping with different sets could form
+/// separate clusters
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InitialMembers {
+    pub digest: String,
+    pub members: Vec<NodeId>,
+}
+
+impl InitialMembers {
+    pub fn new(members: Vec<NodeId>) -> Self {
+        let mut members = members;
+        members.sort();
+        members.dedup();
+
+        let mut hasher = Sha256::new();
+        for id in members.iter() {
+            hasher.input(&id.to_be_bytes());
+        }
+        InitialMembers {
+            digest: hasher.result_str(),
+            members: members,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Capabilities of this build
+    pub fn local() -> Self {
+        Capabilities {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            codecs: vec!["json".to_owned()],
+            compression: vec![],
+            tls: false,
+            initial_members: None,
+        }
+    }
+
+    /// Capabilities of a build predating their exchange, which joins with a plain `Join`
+    pub fn legacy() -> Self {
+        Capabilities {
+            protocol_version: 1,
+            min_protocol_version: 1,
+            codecs: vec!["json".to_owned()],
+            compression: vec![],
+            tls: false,
+            initial_members: None,
+        }
+    }
+
+    /// Announce the initial member set, peers announcing another one are rejected
+    pub fn with_initial_members(mut self, initial_members: Option<InitialMembers>) -> Self {
+        self.initial_members = initial_members;
+        self
+    }
+
+    /// The best options supported by both sides, fails when the protocol versions don't overlap
+    pub fn negotiate(&self, peer: &Capabilities) -> Result<Capabilities, String> {
+        let version = self.protocol_version.min(peer.protocol_version);
+        if version < self.min_protocol_version || version < peer.min_protocol_version {
+            return Err(format!(
+                "protocol version {} (min {}) is incompatible with {} (min {})",
+                peer.protocol_version, peer.min_protocol_version, self.protocol_version, self.min_protocol_version
+            ));
+        }
+
+        let common = |ours: &[String], theirs: &[String]| -> Vec<String> {
+            ours.iter().filter(|o| theirs.contains(o)).take(1).cloned().collect()
+        };
+        let codecs = common(&self.codecs, &peer.codecs);
+        if codecs.is_empty() {
+            return Err(format!("no common codec in {:?} and {:?}", self.codecs, peer.codecs));
+        }
+
+        if let (Some(ours), Some(theirs)) = (&self.initial_members, &peer.initial_members) {
+            if ours.digest != theirs.digest {
+                return Err(format!(
+                    "configured initial members {:?} differ from {:?}, refusing to peer",
+                    theirs.members, ours.members
+                ));
+            }
+        }
+
+        Ok(Capabilities {
+            protocol_version: version,
+            min_protocol_version: version,
+            codecs: codecs,
+            compression: common(&self.compression, &peer.compression),
+            tls: self.tls && peer.tls,
+            initial_members: self.initial_members.clone(),
+        })
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum NodeRequest {
     Ping,
+    /// join of a build predating the capabilities, taken as `Capabilities::legacy()`
     Join(NodeId, NodeInfo),
     /// Message(msg_id, type_id, payload)
     Message(u64, String, String),
     /// Dispatch(type_id, payload)
     Dispatch(String, String),
+    /// join announcing the capabilities of the node
+    JoinWithCapabilities(NodeId, NodeInfo, Capabilities),
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub enum NodeResponse {
     Ping,
+    /// answer to a plain `Join`, older builds decode no other
     Joined,
     /// Result(msg_id, payload)
     Result(u64, String),
+    /// answer to a `JoinWithCapabilities`, the negotiated capabilities
+    JoinedWithCapabilities(Capabilities),
+    /// the peer refused a `JoinWithCapabilities`, e.g. an incompatible protocol version
+    Rejected(String),
+}
+
+/// Peer input which failed to decode
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkError {
+    /// the payload isn't a request
+    Malformed(String),
+    /// a join announcing an address which doesn't parse
+    InvalidAddress(String),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::Malformed(err) => write!(f, "malformed request: {}", err),
+            NetworkError::InvalidAddress(addr) => write!(f, "invalid address {:?}", addr),
+        }
+    }
+}
+
+impl From<NetworkError> for io::Error {
+    fn from(err: NetworkError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+    }
+}
+
+/// Decode the payload of a frame sent by a peer, without its length prefix. Fails on any input
+/// the session couldn't act on instead of panicking, `fuzz/fuzz_targets/decode_remote.rs` drives
+/// it with arbitrary bytes.
+pub fn decode_remote(bytes: &[u8]) -> Result<NodeRequest, NetworkError> {
+    let req = json::from_slice::<NodeRequest>(bytes).map_err(|err| NetworkError::Malformed(err.to_string()))?;
+
+    // the node dialing a peer back resolves the addresses it announced, ips or dns names
+    if let NodeRequest::Join(_, ref info) | NodeRequest::JoinWithCapabilities(_, ref info, _) = req {
+        for addr in &[&info.cluster_addr, &info.app_addr] {
+            if !is_host_port(addr) {
+                return Err(NetworkError::InvalidAddress(addr.to_string()));
+            }
+        }
+    }
+    Ok(req)
 }
 
 pub struct NodeCodec;
@@ -42,7 +244,7 @@ impl Decoder for NodeCodec {
         if src.len() >= size + 2 {
             src.split_to(2);
             let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<NodeRequest>(&buf)?))
+            Ok(Some(decode_remote(&buf)?))
         } else {
             Ok(None)
         }
@@ -56,6 +258,7 @@ impl Encoder for NodeCodec {
     fn encode(&mut self, msg: NodeResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let msg = json::to_string(&msg).unwrap();
         let msg_ref: &[u8] = msg.as_ref();
+        check_frame_size(msg_ref.len())?;
 
         dst.reserve(msg_ref.len() + 2);
         dst.put_u16_be(msg_ref.len() as u16);
@@ -97,6 +300,7 @@ impl Encoder for ClientNodeCodec {
     fn encode(&mut self, msg: NodeRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let msg = json::to_string(&msg).unwrap();
         let msg_ref: &[u8] = msg.as_ref();
+        check_frame_size(msg_ref.len())?;
 
         dst.reserve(msg_ref.len() + 2);
         dst.put_u16_be(msg_ref.len() as u16);
@@ -4,10 +4,11 @@ mod node;
 mod recipient;
 pub mod remote;
 mod session;
+pub mod tap;
 
-pub use self::codec::{ClientNodeCodec, NodeCodec, NodeRequest, NodeResponse};
+pub use self::codec::{decode_remote, framed_len, message_frame_overhead, result_frame_overhead, Capabilities, InitialMembers, ClientNodeCodec, NetworkError, NodeCodec, NodeRequest, NodeResponse, MAX_FRAME_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 pub use self::network::{
-    DiscoverNodes, DistributeMessage, GetCurrentLeader, GetNode, GetNodeAddr, GetNodeById, Network, PeerConnected, DistributeAndWait, NodeDisconnect, RestoreNode, GetNodes, GetClusterState, SetClusterState, NetworkState, Handshake,
+    DeserializeFailed, DiscoverNodes, DistributeMessage, GetDeserializeFailures, ConfigState, ElectionResult, ElectionState, ElectionStats, GetElectionStats, ClearElectionStats, GetThroughputStats, ThroughputStats, THROUGHPUT_WINDOW, GetConfigState, GetStuckChange, StuckChange, GetCurrentLeader, GetElectionState, GetGroupSample, GroupSample, GetGroupLeader, GetGroupLeaderAvoiding, GetGroupLeaderOrPeer, GetGroupMetrics, GroupMetrics, RouteRpc, GetNode, GetNodeAddr, GetReplicaNodes, GetNodeById, GetHttpAddr, Network, PeerConnected, DistributeAndWait, NodeDisconnect, NodeStopped, SessionClosed, RestoreNode, GetNodes, GetClusterState, SetClusterState, ClusterStatus, GetClusterStatus, SetMaintenance, NetworkState, Handshake, FollowerProgress, FollowerProgressed, GetFollowerProgress, GetLeadershipConfirmed, LeaderAcked, GetPeerCapabilities, GetPeerLinks, GetTopology, Link, LinkState, Topology, TopologyNode, TopologyRole, GetVoterHealth, VoterHealth, PeerAddressChanged, UpdatePeerAddress, AwaitApplied, GetLastApplied, QuorumEvent, SubscribeQuorum, Undeliverable, SubscribeUndeliverable,
 };
 pub use self::node::Node;
 pub use self::recipient::{HandlerRegistry, Provider, RemoteMessageHandler};
@@ -1,29 +1,51 @@
 use actix::prelude::*;
 use actix_web::client::Client;
-use actix_raft::{NodeId, RaftMetrics};
-use log::debug;
+use actix_raft::{messages::MembershipConfig, metrics::State, NodeId, RaftMetrics};
+use log::{debug, error, info, warn};
 use serde::{de::DeserializeOwned, Serialize, Deserialize};
-use std::collections::{BTreeMap, HashMap};
+use net2::TcpBuilder;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use tokio::codec::FramedRead;
 use tokio::io::AsyncRead;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::timer::Delay;
+use tokio::reactor::Handle;
+use tokio::util::FutureExt;
 
 use crate::network::{
     remote::{RemoteMessage, SendRemoteMessage, DispatchMessage},
-    HandlerRegistry, Node, NodeCodec, NodeSession,
+    node::Retire,
+    Capabilities, HandlerRegistry, InitialMembers, Node, NodeCodec, NodeSession,
 };
 
-use crate::config::{ConfigSchema, NodeInfo, NetworkType};
-use crate::hash_ring::RingType;
+use crate::config::{CommitQuorum, ConfigSchema, ConnectBackoff, DeserializeFailurePolicy, NodeInfo, NetworkType, SocketOptions};
+use crate::hash_ring::{Ring, RingType};
 use crate::raft::{
+    storage::MembershipApplied,
+    GroupId,
     RaftClient,
     RemoveNode,
+    DEFAULT_GROUP,
 };
 use crate::server;
-use crate::utils::generate_node_id;
+use crate::utils::{jitter, peer_stream, resolve_addr, set_socket_options, NoopMetrics, SharedClock, SharedMetrics, SystemClock};
+
+/// How often voters are polled while waiting for an entry to be applied
+const APPLIED_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often reachable voters are compared against the quorum
+const QUORUM_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Window over which elections are counted
+const ELECTION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Window over which the commit rate is measured
+pub const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+/// How long a quorum change has to hold before it is reported
+const QUORUM_DEBOUNCE: Duration = Duration::from_secs(3);
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum NetworkState {
@@ -44,13 +66,64 @@ pub struct Network {
     nodes_info: HashMap<NodeId, NodeInfo>,
     _server: Option<Addr<server::Server>>,
     state: NetworkState,
-    metrics: Option<RaftMetrics>,
+    metrics: HashMap<GroupId, RaftMetrics>,
     _sessions: BTreeMap<NodeId, Addr<NodeSession>>,
     ring: RingType,
     raft: Addr<RaftClient>,
     registry: Arc<RwLock<HandlerRegistry>>,
     info: NodeInfo,
     join_mode: bool,
+    auto_bootstrap: bool,
+    socket_options: SocketOptions,
+    /// configured initial member set exchanged in the handshakes, unset when not verified
+    initial_members: Option<InitialMembers>,
+    /// accepted connections still open, per peer IP
+    sessions: HashMap<IpAddr, usize>,
+    connect_backoff: ConnectBackoff,
+    /// Connections per peer, the ones past the first are kept in `bulk_nodes`
+    peer_connections: usize,
+    bulk_nodes: BTreeMap<NodeId, Vec<Addr<Node>>>,
+    bulk_next: usize,
+    quorum_subscribers: Vec<Recipient<QuorumEvent>>,
+    undeliverable_subscribers: Vec<Recipient<Undeliverable>>,
+    has_quorum: bool,
+    /// when the quorum started to differ from `has_quorum`
+    quorum_changed_at: Option<Instant>,
+    /// set once the discovery host answered, or failed to
+    discovered: bool,
+    discovery_timeout: Duration,
+    clock: SharedClock,
+    metrics_sink: SharedMetrics,
+    deserialize_failure_policy: DeserializeFailurePolicy,
+    deserialize_failures: HashMap<NodeId, u64>,
+    /// last time the metrics of a group named another node as leader
+    leader_seen_at: HashMap<GroupId, Instant>,
+    last_election: HashMap<GroupId, ElectionResult>,
+    /// term changes seen per group, within `ELECTION_WINDOW`
+    elections: HashMap<GroupId, VecDeque<Instant>>,
+    elections_total: HashMap<GroupId, u64>,
+    /// applied index reported per group, within `THROUGHPUT_WINDOW`
+    applied_samples: HashMap<GroupId, VecDeque<(Instant, u64)>>,
+    max_elections_per_minute: u32,
+    /// since when each group is in joint consensus
+    joint_since: HashMap<GroupId, Instant>,
+    /// (match index, when it last advanced) of every non-voter the local leader syncs, before a
+    /// change enters joint consensus
+    non_voter_syncs: HashMap<GroupId, HashMap<NodeId, (u64, Instant)>>,
+    joint_consensus_timeout: Option<Duration>,
+    /// metadata key naming the rack of a node
+    placement_key: String,
+    /// last time a connection to a peer was established
+    connected_at: HashMap<NodeId, Instant>,
+    /// the node doesn't lead nor campaign while under maintenance
+    maintenance: bool,
+    /// capabilities negotiated with every peer which joined
+    peer_capabilities: HashMap<NodeId, Capabilities>,
+    /// (match, next) index of every follower, as last seen by the local leader of a group
+    follower_progress: HashMap<GroupId, HashMap<NodeId, (u64, u64)>>,
+    /// (term, sent) of the latest RPC every follower answered in the term of the local leader
+    leader_acks: HashMap<GroupId, HashMap<NodeId, (u64, Instant)>>,
+    at_risk_lag: u64,
 }
 
 impl Network {
@@ -67,46 +140,134 @@ impl Network {
             nodes_info: HashMap::new(),
             _server: None,
             state: NetworkState::Initialized,
-            metrics: None,
+            metrics: HashMap::new(),
             _sessions: BTreeMap::new(),
             ring: ring,
             raft: raft,
             registry: registry,
             info: info,
             join_mode: false,
+            auto_bootstrap: false,
+            socket_options: SocketOptions::default(),
+            initial_members: None,
+            sessions: HashMap::new(),
+            connect_backoff: ConnectBackoff::default(),
+            peer_connections: 1,
+            bulk_nodes: BTreeMap::new(),
+            bulk_next: 0,
+            quorum_subscribers: Vec::new(),
+            undeliverable_subscribers: Vec::new(),
+            has_quorum: true,
+            quorum_changed_at: None,
+            discovered: false,
+            discovery_timeout: Duration::from_secs(5),
+            clock: Arc::new(SystemClock),
+            metrics_sink: Arc::new(NoopMetrics),
+            deserialize_failure_policy: DeserializeFailurePolicy::default(),
+            deserialize_failures: HashMap::new(),
+            leader_seen_at: HashMap::new(),
+            last_election: HashMap::new(),
+            elections: HashMap::new(),
+            applied_samples: HashMap::new(),
+            elections_total: HashMap::new(),
+            max_elections_per_minute: 0,
+            joint_since: HashMap::new(),
+            non_voter_syncs: HashMap::new(),
+            joint_consensus_timeout: None,
+            placement_key: "rack".to_owned(),
+            connected_at: HashMap::new(),
+            maintenance: false,
+            peer_capabilities: HashMap::new(),
+            follower_progress: HashMap::new(),
+            leader_acks: HashMap::new(),
+            at_risk_lag: 0,
         }
     }
 
     pub fn configure(&mut self, config: ConfigSchema) {
-        let nodes = config.nodes;
-
-        for node in nodes.iter() {
-            let id = generate_node_id(node.cluster_addr.as_str());
-            self.nodes_info.insert(id, node.clone());
+        self.connect_backoff = config.connect_backoff;
+        self.peer_connections = config.peer_connections.max(1);
+        self.discovery_timeout = Duration::from_secs(config.max_discovery_timeout);
+        self.deserialize_failure_policy = config.deserialize_failure_policy;
+        self.auto_bootstrap = config.auto_bootstrap;
+        self.socket_options = config.socket;
+        self.max_elections_per_minute = config.max_elections_per_minute;
+        if config.joint_consensus_timeout_ms > 0 {
+            self.joint_consensus_timeout = Some(Duration::from_millis(config.joint_consensus_timeout_ms));
         }
+        self.at_risk_lag = config.at_risk_lag;
+        self.placement_key = config.placement_key.clone();
+        // collisions are refused at startup
+        if let Ok(nodes) = config.node_ids() {
+            self.nodes_info.extend(nodes);
+        }
+    }
+
+    /// Refuse to peer with nodes configured with another initial member set
+    pub fn set_initial_members(&mut self, members: Vec<NodeId>) {
+        self.initial_members = Some(InitialMembers::new(members));
     }
 
     /// register a new node to the network
     pub fn register_node(&mut self, id: NodeId, info: &NodeInfo, addr: Addr<Self>) {
         let info = info.clone();
 
-        let network_address = self.address.as_ref().unwrap().clone();
         let local_id = self.id;
         let net_type = self.net_type.clone();
-        let peer_addr = match self.net_type {
-            NetworkType::App => info.app_addr.clone(),
-            NetworkType::Cluster => info.cluster_addr.clone(),
+        let (peer_addr, advertised) = match self.net_type {
+            NetworkType::App => (info.app_addr.clone(), &self.info.app_addr),
+            NetworkType::Cluster => (info.cluster_addr.clone(), &self.info.cluster_addr),
         };
 
-        if peer_addr == *network_address {
+        // the local node is known by the address it advertises, not the one it listens on
+        if peer_addr == *advertised {
             return ();
         }
 
         self.restore_node(id); // restore node if needed
 
         if !self.nodes.contains_key(&id) {
-            let node = Node::new(id, local_id, peer_addr, addr, net_type, self.info.clone()).start();
+            let node = Node::new(id, local_id, peer_addr.clone(), addr.clone(), net_type.clone(), self.info.clone())
+                .with_backoff(&self.connect_backoff)
+                .with_clock(self.clock.clone())
+                .with_socket_options(&self.socket_options)
+                .with_initial_members(self.initial_members.clone())
+                .start();
             self.nodes.insert(id, node);
+
+            let bulk = (1..self.peer_connections)
+                .map(|_| {
+                    Node::new(id, local_id, peer_addr.clone(), addr.clone(), net_type.clone(), self.info.clone())
+                        .with_backoff(&self.connect_backoff)
+                        .with_clock(self.clock.clone())
+                        .with_socket_options(&self.socket_options)
+                        .bulk()
+                        .start()
+                })
+                .collect();
+            self.bulk_nodes.insert(id, bulk);
+        }
+    }
+
+    /// get a connection to a node for bulk traffic, falling back to its main connection
+    pub fn get_bulk_node(&mut self, id: NodeId) -> Option<&Addr<Node>> {
+        let next = self.bulk_next;
+        self.bulk_next = self.bulk_next.wrapping_add(1);
+
+        // stopped connections are dropped lazily
+        let connected = match self.bulk_nodes.get_mut(&id) {
+            Some(pool) => {
+                pool.retain(|node| node.connected());
+                !pool.is_empty()
+            }
+            None => false,
+        };
+
+        if connected {
+            let pool = &self.bulk_nodes[&id];
+            pool.get(next % pool.len())
+        } else {
+            self.nodes.get(&id)
         }
     }
 
@@ -119,6 +280,29 @@ impl Network {
         self.address = Some(address.to_owned());
     }
 
+    /// Drive the heartbeats and reconnects of peer connections, and the waits for voters to
+    /// apply an entry, from another clock. Share it with the raft clients.
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+    }
+
+    /// Report elections, undecodable and lost messages and open sessions to `metrics`
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.metrics_sink = metrics;
+    }
+
+    fn net_label(&self) -> &'static str {
+        match self.net_type {
+            NetworkType::Cluster => "cluster",
+            NetworkType::App => "app",
+        }
+    }
+
+    fn report_sessions(&self) {
+        let open: usize = self.sessions.values().sum();
+        self.metrics_sink.gauge("raftor_peer_sessions", &[("network", self.net_label())], open as f64);
+    }
+
     /// Isolate the network of the specified node.
     pub fn isolate_node(&mut self, id: NodeId) {
         if let Some((_idx, _)) = self.isolated_nodes.iter().enumerate().find(|(_, e)| *e == &id) {
@@ -129,6 +313,25 @@ impl Network {
         // self.isolated_nodes.push(id);
     }
 
+    /// the address of a peer on this network
+    fn peer_addr<'a>(&self, info: &'a NodeInfo) -> &'a String {
+        match self.net_type {
+            NetworkType::App => &info.app_addr,
+            NetworkType::Cluster => &info.cluster_addr,
+        }
+    }
+
+    /// close the connections to a peer, without deregistering the ones replacing them
+    fn retire_node(&mut self, id: NodeId) {
+        if let Some(node) = self.nodes.remove(&id) {
+            node.do_send(Retire);
+        }
+        for node in self.bulk_nodes.remove(&id).unwrap_or_default() {
+            node.do_send(Retire);
+        }
+        self.nodes_connected.retain(|n| *n != id);
+    }
+
     /// Restore the network of the specified node.
     pub fn restore_node(&mut self, id: NodeId) {
         if let Some((idx, _)) = self.isolated_nodes.iter().enumerate().find(|(_, e)| *e == &id) {
@@ -148,7 +351,9 @@ impl Handler<NodeDisconnect> for Network {
         let id = msg.0;
         self.isolated_nodes.push(id);
         self.nodes_info.remove(&id);
+        self.peer_capabilities.remove(&id);
         self.nodes.remove(&id);
+        self.bulk_nodes.remove(&id);
 
         if self.net_type != NetworkType::Cluster {
             return ();
@@ -161,7 +366,7 @@ impl Handler<NodeDisconnect> for Network {
                 let leader = res.unwrap();
 
                 if leader == id {
-                    fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(1)))
+                    fut::wrap_future::<_, Self>(act.clock.delay(Duration::from_secs(1)))
                         .map_err(|_, _, _| ())
                         .and_then(|_, _act, ctx| {
                             ctx.notify(msg);
@@ -184,15 +389,210 @@ impl Handler<NodeDisconnect> for Network {
     }
 }
 
+/// Sent by a `Node` actor once it stopped so its stale address is dropped, unless a newer
+/// connection to the same node replaced it
+#[derive(Message)]
+pub struct NodeStopped(pub NodeId, pub Addr<Node>);
+
+impl Handler<NodeStopped> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: NodeStopped, _ctx: &mut Context<Self>) {
+        let id = msg.0;
+        if self.nodes.get(&id) != Some(&msg.1) {
+            debug!("Stopped node {} was already replaced.", id);
+            return;
+        }
+        debug!("Deregistering stopped node {}.", id);
+        self.nodes.remove(&id);
+        self.bulk_nodes.remove(&id);
+        self.nodes_connected.retain(|n| *n != id);
+    }
+}
+
+/// Sent by a session for every message from a peer which failed to deserialize
+#[derive(Message)]
+pub struct DeserializeFailed(pub Option<NodeId>);
+
+impl Handler<DeserializeFailed> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeserializeFailed, _ctx: &mut Context<Self>) {
+        self.metrics_sink.counter("raftor_deserialize_failures_total", &[("network", self.net_label())], 1);
+        match msg.0 {
+            Some(id) => *self.deserialize_failures.entry(id).or_insert(0) += 1,
+            None => debug!("Undecodable message from a peer before its handshake"),
+        }
+    }
+}
+
+/// Number of messages from every peer which failed to deserialize
+pub struct GetDeserializeFailures;
+
+impl Message for GetDeserializeFailures {
+    type Result = Result<HashMap<NodeId, u64>, ()>;
+}
+
+impl Handler<GetDeserializeFailures> for Network {
+    type Result = Result<HashMap<NodeId, u64>, ()>;
+
+    fn handle(&mut self, _: GetDeserializeFailures, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.deserialize_failures.clone())
+    }
+}
+
 #[derive(Message)]
-pub struct Handshake(pub NodeId, pub NodeInfo);
+pub struct Handshake(pub NodeId, pub NodeInfo, pub Capabilities);
 
 impl Handler<Handshake> for Network {
     type Result = ();
 
     fn handle(&mut self, msg: Handshake, ctx: &mut Context<Self>) {
-        self.nodes_info.insert(msg.0, msg.1.clone());
-        self.register_node(msg.0, &msg.1, ctx.address().clone());
+        // a peer redeployed under the same id announces its new address
+        let moved = self.nodes_info.get(&msg.0)
+            .map(|known| self.peer_addr(known) != self.peer_addr(&msg.1))
+            .unwrap_or(false);
+        // an id derived from another address than the known node's is a hash collision
+        if moved && msg.1.id.is_none() {
+            error!(
+                "Refusing node {}, its id {} collides with the one of node {}",
+                msg.1.cluster_addr, msg.0, self.nodes_info[&msg.0].cluster_addr
+            );
+            return;
+        }
+        if moved {
+            info!("Node {} moved to {}", msg.0, self.peer_addr(&msg.1));
+            self.retire_node(msg.0);
+        }
+
+        let mut info = msg.1;
+        if let Err(err) = info.check_metadata() {
+            warn!("Dropping the metadata of node {}, {}", msg.0, err);
+            info.metadata.clear();
+        }
+        self.nodes_info.insert(msg.0, info.clone());
+        self.peer_capabilities.insert(msg.0, msg.2);
+        self.register_node(msg.0, &info, ctx.address().clone());
+    }
+}
+
+impl Handler<MembershipApplied> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: MembershipApplied, ctx: &mut Context<Self>) {
+        match msg {
+            MembershipApplied::Added(id) => {
+                if id == self.id || self.nodes.contains_key(&id) {
+                    return ();
+                }
+                // a peer which never dialed us is only known once it handshakes
+                if let Some(info) = self.nodes_info.get(&id).cloned() {
+                    info!("Node {} joined, connecting", id);
+                    self.register_node(id, &info, ctx.address());
+                }
+            }
+            MembershipApplied::Removed(id) => {
+                if id != self.id && self.nodes.contains_key(&id) {
+                    info!("Node {} left, disconnecting", id);
+                    self.retire_node(id);
+                }
+            }
+        }
+    }
+}
+
+/// Capabilities negotiated with every peer which joined
+pub struct GetPeerCapabilities;
+
+impl Message for GetPeerCapabilities {
+    type Result = Result<HashMap<NodeId, Capabilities>, ()>;
+}
+
+impl Handler<GetPeerCapabilities> for Network {
+    type Result = Result<HashMap<NodeId, Capabilities>, ()>;
+
+    fn handle(&mut self, _: GetPeerCapabilities, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.peer_capabilities.clone())
+    }
+}
+
+/// Point the connections to a peer at a new address, its raft membership is unchanged. The
+/// connected peers are told with `PeerAddressChanged`.
+pub struct UpdatePeerAddress(pub NodeId, pub SocketAddr);
+
+impl Message for UpdatePeerAddress {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<UpdatePeerAddress> for Network {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, msg: UpdatePeerAddress, ctx: &mut Context<Self>) -> Self::Result {
+        let (id, addr) = (msg.0, msg.1.to_string());
+        self.update_peer_address(id, addr.clone(), ctx)?;
+
+        let gossip = PeerAddressChanged {
+            id,
+            addr,
+            net_type: self.net_type.clone(),
+        };
+        for (peer, node) in self.nodes.iter() {
+            if *peer != id {
+                node.do_send(SendRemoteMessage(gossip.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The address of a peer changed on the network of the sender, gossiped by `UpdatePeerAddress`.
+/// The receiver updates its own connections and doesn't pass it on.
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+pub struct PeerAddressChanged {
+    pub id: NodeId,
+    pub addr: String,
+    pub net_type: NetworkType,
+}
+
+impl Handler<PeerAddressChanged> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerAddressChanged, ctx: &mut Context<Self>) {
+        if msg.net_type != self.net_type {
+            return;
+        }
+        let moved = self.nodes_info.get(&msg.id).map(|info| self.peer_addr(info) != &msg.addr);
+        if moved == Some(true) {
+            let _ = self.update_peer_address(msg.id, msg.addr, ctx);
+        }
+    }
+}
+
+impl Network {
+    fn update_peer_address(&mut self, id: NodeId, addr: String, ctx: &mut Context<Self>) -> Result<(), ()> {
+        if id == self.id {
+            warn!("Refusing to update the address of the local node");
+            return Err(());
+        }
+
+        let mut info = match self.nodes_info.get(&id) {
+            Some(info) => info.clone(),
+            None => {
+                warn!("Can't update the address of unknown node {}", id);
+                return Err(());
+            }
+        };
+        match self.net_type {
+            NetworkType::App => info.app_addr = addr.clone(),
+            NetworkType::Cluster => info.cluster_addr = addr.clone(),
+        }
+
+        info!("Updating the address of node {} to {}", id, addr);
+        self.retire_node(id);
+        self.nodes_info.insert(id, info.clone());
+        self.register_node(id, &info, ctx.address());
+
+        Ok(())
     }
 }
 
@@ -225,6 +625,52 @@ impl Handler<GetClusterState> for Network {
 #[derive(Message)]
 pub struct SetClusterState(pub NetworkState);
 
+/// State of the network as reported by the status endpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterStatus {
+    pub state: NetworkState,
+    pub maintenance: bool,
+    /// The node serves reads only and relays writes
+    pub readonly: bool,
+}
+
+pub struct GetClusterStatus;
+
+impl Message for GetClusterStatus {
+    type Result = Result<ClusterStatus, ()>;
+}
+
+impl Handler<GetClusterStatus> for Network {
+    type Result = Result<ClusterStatus, ()>;
+
+    fn handle(&mut self, _: GetClusterStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(ClusterStatus {
+            state: self.state.clone(),
+            maintenance: self.maintenance,
+            readonly: self.info.readonly,
+        })
+    }
+}
+
+/// Exclude the node from leadership before touching it, or include it back.
+///
+/// Only meaningful on the cluster network, sent through the `Raftor` which also pauses the
+/// election timer of every group with `PauseElections`. Meanwhile a leader's RPCs are dropped so
+/// that it can't hold on to its followers until its raft restarted as a follower.
+#[derive(Message)]
+pub struct SetMaintenance(pub bool);
+
+impl Handler<SetMaintenance> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetMaintenance, _ctx: &mut Context<Self>) {
+        if self.maintenance != msg.0 {
+            info!("Node {} maintenance {}", self.id, if msg.0 { "started" } else { "ended" });
+        }
+        self.maintenance = msg.0;
+    }
+}
+
 impl Handler<SetClusterState> for Network {
     type Result = ();
 
@@ -248,6 +694,30 @@ impl Handler<GetNodes> for Network {
     }
 }
 
+/// HTTP address of a node, the public address it serves its routes at
+pub struct GetHttpAddr(pub NodeId);
+
+impl Message for GetHttpAddr {
+    type Result = Result<String, ()>;
+}
+
+impl Handler<GetHttpAddr> for Network {
+    type Result = Result<String, ()>;
+
+    fn handle(&mut self, msg: GetHttpAddr, _ctx: &mut Context<Self>) -> Self::Result {
+        self.http_addr(msg.0).ok_or(())
+    }
+}
+
+impl Network {
+    pub fn http_addr(&self, id: NodeId) -> Option<String> {
+        if id == self.id {
+            return Some(self.info.public_addr.clone());
+        }
+        self.nodes_info.get(&id).map(|info| info.public_addr.clone())
+    }
+}
+
 pub struct DiscoverNodes;
 
 impl Message for DiscoverNodes {
@@ -257,15 +727,77 @@ impl Message for DiscoverNodes {
 impl Handler<DiscoverNodes> for Network {
     type Result = ResponseActFuture<Self, (Vec<NodeId>, bool), ()>;
 
-    fn handle(&mut self, _: DiscoverNodes, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, _: DiscoverNodes, ctx: &mut Context<Self>) -> Self::Result {
+        let deadline = self.clock.now() + self.discovery_timeout;
+
+        Box::new(
+            fut::wrap_future::<_, Self>(ctx.address().send(AwaitDiscovery(deadline)))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _act, _ctx| fut::result(res)),
+        )
+    }
+}
+
+/// Resolves with the connected nodes once every known node connected, or at `deadline`
+struct AwaitDiscovery(Instant);
+
+impl Message for AwaitDiscovery {
+    type Result = Result<(Vec<NodeId>, bool), ()>;
+}
+
+impl Handler<AwaitDiscovery> for Network {
+    type Result = ResponseActFuture<Self, (Vec<NodeId>, bool), ()>;
+
+    fn handle(&mut self, msg: AwaitDiscovery, _: &mut Context<Self>) -> Self::Result {
+        let deadline = msg.0;
+        let connected = self.discovered
+            && self.nodes_info.keys().all(|id| self.nodes_connected.contains(id));
+
+        if connected || self.clock.now() >= deadline {
+            debug!("Discovered nodes {:?}, all connected: {}", self.nodes_connected, connected);
+            return Box::new(fut::ok((self.nodes_connected.clone(), self.join_mode())));
+        }
+
         Box::new(
-            fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(5)))
+            fut::wrap_future::<_, Self>(self.clock.delay(APPLIED_POLL_INTERVAL))
                 .map_err(|_, _, _| ())
-                .and_then(|_, act: &mut Network, _| fut::result(Ok((act.nodes_connected.clone(), act.join_mode)))),
+                .and_then(move |_, _act, ctx: &mut Context<Self>| {
+                    fut::wrap_future::<_, Self>(ctx.address().send(AwaitDiscovery(deadline)))
+                        .map_err(|_, _, _| ())
+                        .and_then(|res, _act, _ctx| fut::result(res))
+                }),
         )
     }
 }
 
+impl Network {
+    /// Decide whether to join an existing cluster or to bootstrap one, once discovery is over.
+    ///
+    /// The node joins when the discovery host reports a cluster, and bootstraps with the peers
+    /// that connected otherwise. A node no peer responded to only bootstraps alone when it is
+    /// the discovery host or `auto_bootstrap` is set, a node that merely failed to reach the
+    /// others would start a cluster of its own.
+    fn join_mode(&self) -> bool {
+        if self.join_mode {
+            return true;
+        }
+
+        let peers = self.nodes_info.keys().filter(|id| **id != self.id).count();
+        let alone = self.nodes_connected.iter().all(|id| *id == self.id);
+        if peers == 0 || !alone {
+            return false;
+        }
+
+        if self.auto_bootstrap || self.info.public_addr == self.discovery_host {
+            println!("No peer responded, bootstrapping a new cluster");
+            false
+        } else {
+            println!("No peer responded, waiting to be added to an existing cluster");
+            true
+        }
+    }
+}
+
 impl Actor for Network {
     type Context = Context<Self>;
 
@@ -281,6 +813,10 @@ impl Actor for Network {
         self.listen(ctx);
         self.nodes_connected.push(self.id);
 
+        if self.net_type == NetworkType::Cluster {
+            ctx.run_interval(QUORUM_CHECK_INTERVAL, |act, _ctx| act.check_quorum());
+        }
+
         let client = Client::default();
 
         fut::wrap_future::<_, Self>(client.get(cluster_state_route).send())
@@ -330,6 +866,10 @@ impl Actor for Network {
 
                 fut::ok(())
             })
+            .then(|_, act, _ctx| {
+                act.discovered = true;
+                fut::ok(())
+            })
             .spawn(ctx);
     }
 }
@@ -339,8 +879,14 @@ struct NodeConnect(TcpStream);
 
 impl Network {
     fn listen(&mut self, ctx: &mut Context<Self>) {
-        let server_addr = self.address.as_ref().unwrap().as_str().parse().unwrap();
-        let listener = TcpListener::bind(&server_addr).unwrap();
+        let server_addr = resolve_addr(self.address.as_ref().unwrap()).unwrap();
+        let builder = if server_addr.is_ipv4() { TcpBuilder::new_v4() } else { TcpBuilder::new_v6() }.unwrap();
+        let listener = builder
+            .reuse_address(self.socket_options.reuse_addr)
+            .and_then(|builder| builder.bind(&server_addr))
+            .and_then(|builder| builder.listen(self.socket_options.accept_backlog))
+            .unwrap();
+        let listener = TcpListener::from_std(listener, &Handle::default()).unwrap();
 
         ctx.add_message_stream(listener.incoming().map_err(|_| ()).map(NodeConnect));
     }
@@ -350,23 +896,75 @@ impl Handler<NodeConnect> for Network {
     type Result = ();
 
     fn handle(&mut self, msg: NodeConnect, ctx: &mut Context<Self>) {
+        let ip = match msg.0.peer_addr() {
+            Ok(peer) => peer.ip(),
+            Err(err) => {
+                warn!("Refusing a connection without a peer address: {}", err);
+                return ();
+            }
+        };
+        // dropping the stream closes the connection
+        let open: usize = self.sessions.values().sum();
+        if open >= self.socket_options.max_connections {
+            warn!("Refusing a connection from {}, {} connections are open", ip, open);
+            return ();
+        }
+        let from_ip = self.sessions.get(&ip).cloned().unwrap_or(0);
+        if from_ip >= self.socket_options.max_connections_per_ip {
+            warn!("Refusing a connection from {}, it has {} connections open", ip, from_ip);
+            return ();
+        }
+        *self.sessions.entry(ip).or_insert(0) += 1;
+        self.report_sessions();
+
+        set_socket_options(&msg.0, &self.socket_options);
         let addr = ctx.address();
         let registry = self.registry.clone();
         let net_type = self.net_type.clone();
+        let clock = self.clock.clone();
+        let policy = self.deserialize_failure_policy;
+        let initial_members = self.initial_members.clone();
+        let stream = peer_stream(msg.0, &self.socket_options, self.net_type == NetworkType::Cluster);
 
         NodeSession::create(move |ctx| {
-            let (r, w) = msg.0.split();
+            let (r, w) = stream.split();
             NodeSession::add_stream(FramedRead::new(r, NodeCodec), ctx);
             NodeSession::new(
                 actix::io::FramedWrite::new(w, NodeCodec, ctx),
                 addr,
                 registry,
-                net_type
+                net_type,
+                clock,
+                policy,
             )
+            .with_peer_ip(ip)
+            .with_initial_members(initial_members)
         });
     }
 }
 
+/// Sent by a session accepted from `ip` once its connection closed
+#[derive(Message)]
+pub struct SessionClosed(pub IpAddr);
+
+impl Handler<SessionClosed> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: SessionClosed, _ctx: &mut Context<Self>) {
+        let open = match self.sessions.get_mut(&msg.0) {
+            Some(open) => {
+                *open = open.saturating_sub(1);
+                *open
+            }
+            None => return (),
+        };
+        if open == 0 {
+            self.sessions.remove(&msg.0);
+        }
+        self.report_sessions();
+    }
+}
+
 pub struct GetNodeAddr(pub String);
 
 impl Message for GetNodeAddr {
@@ -489,6 +1087,9 @@ impl Handler<GetNode> for Network {
             public_addr: "".to_owned(),
             app_addr: "".to_owned(),
             cluster_addr: "".to_owned(),
+            id: None,
+            readonly: false,
+            metadata: Default::default(),
         };
 
         let node = self.nodes_info.get(node_id).unwrap_or(&default);
@@ -496,6 +1097,37 @@ impl Handler<GetNode> for Network {
     }
 }
 
+/// Nodes to place `n` replicas of `key` on, the owner first and spread across racks when the
+/// nodes have enough distinct ones, see `Ring::replica_nodes`
+pub struct GetReplicaNodes {
+    pub key: String,
+    pub n: usize,
+}
+
+impl Message for GetReplicaNodes {
+    type Result = Result<Vec<NodeId>, ()>;
+}
+
+impl Handler<GetReplicaNodes> for Network {
+    type Result = Result<Vec<NodeId>, ()>;
+
+    fn handle(&mut self, msg: GetReplicaNodes, _ctx: &mut Context<Self>) -> Self::Result {
+        let nodes = Ring::replica_nodes(&self.ring, &msg.key, msg.n, |id| self.rack_of(id));
+        if nodes.is_empty() {
+            return Err(());
+        }
+        Ok(nodes)
+    }
+}
+
+impl Network {
+    /// Rack of a node according to its metadata, unknown until it handshaked
+    fn rack_of(&self, id: NodeId) -> Option<String> {
+        let info = if id == self.id { Some(&self.info) } else { self.nodes_info.get(&id) };
+        info.and_then(|info| info.metadata.get(&self.placement_key)).cloned()
+    }
+}
+
 #[derive(Message)]
 pub struct PeerConnected(pub NodeId);
 
@@ -504,9 +1136,111 @@ impl Handler<PeerConnected> for Network {
 
     fn handle(&mut self, msg: PeerConnected, _ctx: &mut Context<Self>) {
         self.nodes_connected.push(msg.0);
+        self.connected_at.insert(msg.0, self.clock.now());
+    }
+}
+
+/// Raised when the default group can no longer, or again, reach a majority of its voters
+#[derive(Message, Clone, Debug)]
+pub enum QuorumEvent {
+    Lost { reachable: usize, required: usize },
+    Regained { reachable: usize, required: usize },
+}
+
+/// Subscribe to `QuorumEvent`s
+#[derive(Message)]
+pub struct SubscribeQuorum(pub Recipient<QuorumEvent>);
+
+impl Handler<SubscribeQuorum> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeQuorum, _ctx: &mut Context<Self>) {
+        self.quorum_subscribers.push(msg.0);
+    }
+}
+
+/// A remote message which couldn't be delivered to a peer. Raft RPCs are dropped instead and
+/// client proposals go to the dead letter recipient of the raft client.
+#[derive(Message, Clone, Debug)]
+pub struct Undeliverable {
+    pub peer: NodeId,
+    /// wire type id of the message, with the group it was addressed to
+    pub type_id: String,
+    /// the message as JSON
+    pub body: String,
+    /// the connection was lost while waiting for the response, the peer may have handled it
+    pub maybe_delivered: bool,
+}
+
+/// Subscribe to `Undeliverable` messages, to retry or alert on them
+#[derive(Message)]
+pub struct SubscribeUndeliverable(pub Recipient<Undeliverable>);
+
+impl Handler<SubscribeUndeliverable> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeUndeliverable, _ctx: &mut Context<Self>) {
+        self.undeliverable_subscribers.push(msg.0);
+    }
+}
+
+impl Handler<Undeliverable> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: Undeliverable, _ctx: &mut Context<Self>) {
+        self.metrics_sink.counter("raftor_undeliverable_total", &[("network", self.net_label()), ("type", &msg.type_id)], 1);
+        self.undeliverable_subscribers
+            .retain(|subscriber| subscriber.do_send(msg.clone()).is_ok());
+        if self.undeliverable_subscribers.is_empty() {
+            warn!("Lost {} to node {} (maybe delivered: {})", msg.type_id, msg.peer, msg.maybe_delivered);
+        }
+    }
+}
+
+impl Network {
+    /// Compare reachable voters against the majority, a change is only reported once it held for
+    /// `QUORUM_DEBOUNCE` so a flapping peer doesn't flood subscribers.
+    fn check_quorum(&mut self) {
+        let voters = match self.metrics.get(&DEFAULT_GROUP) {
+            Some(metrics) => &metrics.membership_config.members,
+            None => return (),
+        };
+
+        let reachable = voters
+            .iter()
+            .filter(|id| self.nodes_connected.contains(id) && !self.isolated_nodes.contains(id))
+            .count();
+        let required = CommitQuorum::Majority.required(voters.len());
+        let has_quorum = reachable >= required;
+
+        if has_quorum == self.has_quorum {
+            self.quorum_changed_at = None;
+            return ();
+        }
+
+        let now = self.clock.now();
+        let changed_at = *self.quorum_changed_at.get_or_insert(now);
+        if now.duration_since(changed_at) < QUORUM_DEBOUNCE {
+            return ();
+        }
+
+        self.has_quorum = has_quorum;
+        self.quorum_changed_at = None;
+
+        let event = if has_quorum {
+            info!("Quorum regained, {} of {} required voters reachable", reachable, required);
+            QuorumEvent::Regained { reachable, required }
+        } else {
+            error!("Quorum lost, {} of {} required voters reachable", reachable, required);
+            QuorumEvent::Lost { reachable, required }
+        };
+
+        self.quorum_subscribers
+            .retain(|subscriber| subscriber.do_send(event.clone()).is_ok());
     }
 }
 
+/// Leader of the default raft group
 pub struct GetCurrentLeader;
 
 impl Message for GetCurrentLeader {
@@ -516,48 +1250,1157 @@ impl Message for GetCurrentLeader {
 impl Handler<GetCurrentLeader> for Network {
     type Result = ResponseActFuture<Self, NodeId, ()>;
 
-    fn handle(&mut self, msg: GetCurrentLeader, _ctx: &mut Context<Self>) -> Self::Result {
-        if let Some(ref mut metrics) = self.metrics {
-            if let Some(leader) = metrics.current_leader {
-                Box::new(fut::result(Ok(leader)))
-            } else {
-                Box::new(
-                    fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(1)))
-                        .map_err(|_, _, _| ())
-                        .and_then(|_, _, ctx| {
-                            fut::wrap_future::<_, Self>(ctx.address().send(msg))
-                                .map_err(|_, _, _| ())
-                                .and_then(|res, _, _| fut::result(res))
-                        })
-                )
-            }
-        } else {
-            Box::new(
-                    fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(1)))
-                        .map_err(|_, _, _| ())
-                        .and_then(|_, _, ctx| {
-                            fut::wrap_future::<_, Self>(ctx.address().send(msg))
-                                .map_err(|_, _, _| ())
-                                .and_then(|res, _, _| fut::result(res))
-                        })
-                )
-        }
+    fn handle(&mut self, _: GetCurrentLeader, ctx: &mut Context<Self>) -> Self::Result {
+        <Self as Handler<GetGroupLeader>>::handle(self, GetGroupLeader(DEFAULT_GROUP), ctx)
     }
 }
 
+/// Leader of a raft group, resolves once a leader is known
+pub struct GetGroupLeader(pub GroupId);
 
-//////////////////////////////////////////////////////////////////////////////
-// RaftMetrics ///////////////////////////////////////////////////////////////
+impl Message for GetGroupLeader {
+    type Result = Result<NodeId, ()>;
+}
 
-impl Handler<RaftMetrics> for Network {
+impl Handler<GetGroupLeader> for Network {
+    type Result = ResponseActFuture<Self, NodeId, ()>;
+
+    fn handle(&mut self, msg: GetGroupLeader, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(leader) = self.metrics.get(&msg.0).and_then(|m| m.current_leader) {
+            Box::new(fut::result(Ok(leader)))
+        } else {
+            Box::new(
+                fut::wrap_future::<_, Self>(self.clock.delay(Duration::from_secs(1)))
+                    .map_err(|_, _, _| ())
+                    .and_then(|_, _, ctx| {
+                        fut::wrap_future::<_, Self>(ctx.address().send(msg))
+                            .map_err(|_, _, _| ())
+                            .and_then(|res, _, _| fut::result(res))
+                    })
+            )
+        }
+    }
+}
+
+/// Leader of a raft group other than the suspects, each mapped to when it became suspect.
+///
+/// A suspect is the leader again once its connection was established after it became suspect,
+/// or `timeout` after it became suspect, on the network's clock.
+pub struct GetGroupLeaderAvoiding {
+    pub group: GroupId,
+    pub suspects: HashMap<NodeId, Instant>,
+    pub timeout: Duration,
+}
+
+impl Message for GetGroupLeaderAvoiding {
+    type Result = Result<NodeId, ()>;
+}
+
+impl Handler<GetGroupLeaderAvoiding> for Network {
+    type Result = ResponseActFuture<Self, NodeId, ()>;
+
+    fn handle(&mut self, msg: GetGroupLeaderAvoiding, _ctx: &mut Context<Self>) -> Self::Result {
+        let leader = self.metrics.get(&msg.group).and_then(|m| m.current_leader);
+        let now = self.clock.now();
+        let usable = leader.map(|leader| match msg.suspects.get(&leader) {
+            Some(since) => {
+                *since + msg.timeout <= now || self.connected_at.get(&leader).map(|at| at > since).unwrap_or(false)
+            }
+            None => true,
+        });
+
+        match (leader, usable) {
+            (Some(leader), Some(true)) => Box::new(fut::result(Ok(leader))),
+            _ => Box::new(
+                fut::wrap_future::<_, Self>(self.clock.delay(Duration::from_secs(1)))
+                    .map_err(|_, _, _| ())
+                    .and_then(|_, _, ctx| {
+                        fut::wrap_future::<_, Self>(ctx.address().send(msg))
+                            .map_err(|_, _, _| ())
+                            .and_then(|res, _, _| fut::result(res))
+                    })
+            ),
+        }
+    }
+}
+
+/// Leader of a raft group or, while no leader is known, a random connected peer other than
+/// `exclude`. Resolves with whether the node is such a blind pick, and waits for a leader like
+/// `GetGroupLeader` when no peer is connected either.
+pub struct GetGroupLeaderOrPeer {
+    pub group: GroupId,
+    pub exclude: Vec<NodeId>,
+}
+
+impl Message for GetGroupLeaderOrPeer {
+    type Result = Result<(NodeId, bool), ()>;
+}
+
+impl Handler<GetGroupLeaderOrPeer> for Network {
+    type Result = ResponseActFuture<Self, (NodeId, bool), ()>;
+
+    fn handle(&mut self, msg: GetGroupLeaderOrPeer, ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(leader) = self.metrics.get(&msg.group).and_then(|m| m.current_leader) {
+            return Box::new(fut::ok((leader, false)));
+        }
+
+        let peers: Vec<NodeId> = self
+            .nodes_connected
+            .iter()
+            .filter(|id| **id != self.id && !msg.exclude.contains(id) && !self.isolated_nodes.contains(id))
+            .cloned()
+            .collect();
+        if !peers.is_empty() {
+            let peer = peers[jitter(peers.len() as u64) as usize];
+            return Box::new(fut::ok((peer, true)));
+        }
+
+        Box::new(<Self as Handler<GetGroupLeader>>::handle(self, GetGroupLeader(msg.group), ctx).map(|leader, _, _| (leader, false)))
+    }
+}
+
+/// Last log index applied by the local raft node of a group
+#[derive(Serialize, Deserialize)]
+pub struct GetLastApplied(pub GroupId);
+
+impl Message for GetLastApplied {
+    type Result = Result<u64, ()>;
+}
+
+impl Handler<GetLastApplied> for Network {
+    type Result = Result<u64, ()>;
+
+    fn handle(&mut self, msg: GetLastApplied, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.metrics.get(&msg.0).map(|m| m.last_applied).unwrap_or(0))
+    }
+}
+
+/// State of the connection from one node to a peer, as seen by the former
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkState {
+    Connected,
+    /// connected but cut off with `isolated_nodes`
+    Isolated,
+    Disconnected,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Link {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub state: LinkState,
+}
+
+impl Network {
+    /// Connections of this node to every peer it knows of
+    fn links(&self) -> Vec<Link> {
+        let mut peers: Vec<NodeId> = self.nodes_info.keys().cloned().filter(|id| *id != self.id).collect();
+        peers.sort();
+
+        peers
+            .into_iter()
+            .map(|id| Link {
+                from: self.id,
+                to: id,
+                state: if self.isolated_nodes.contains(&id) {
+                    LinkState::Isolated
+                } else if self.nodes_connected.contains(&id) {
+                    LinkState::Connected
+                } else {
+                    LinkState::Disconnected
+                },
+            })
+            .collect()
+    }
+}
+
+/// Connections of the receiving node to its peers, queried by `GetTopology`
+#[derive(Serialize, Deserialize)]
+pub struct GetPeerLinks;
+
+impl Message for GetPeerLinks {
+    type Result = Result<Vec<Link>, ()>;
+}
+
+impl Handler<GetPeerLinks> for Network {
+    type Result = Result<Vec<Link>, ()>;
+
+    fn handle(&mut self, _: GetPeerLinks, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.links())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TopologyRole {
+    Leader,
+    Voter,
+    NonVoter,
+    /// not in the membership of the default group, e.g. an observer
+    None,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopologyNode {
+    pub id: NodeId,
+    pub cluster_addr: String,
+    pub role: TopologyRole,
+}
+
+/// The cluster as a graph, assembled on one node from its own connections and those reported
+/// by the peers it reaches
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Topology {
+    pub leader: Option<NodeId>,
+    pub nodes: Vec<TopologyNode>,
+    pub links: Vec<Link>,
+    /// connected peers which didn't report their connections
+    pub unreachable: Vec<NodeId>,
+}
+
+impl Topology {
+    /// Render as a Graphviz digraph, links which aren't connected are dashed
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cluster {\n");
+        for node in self.nodes.iter() {
+            let role = serde_json::to_string(&node.role).unwrap_or_default();
+            dot.push_str(&format!(
+                "  \"{}\" [label=\"{}\\n{}\\n{}\"];\n",
+                node.id,
+                node.id,
+                node.cluster_addr,
+                role.trim_matches('"')
+            ));
+        }
+        for link in self.links.iter() {
+            let style = match link.state {
+                LinkState::Connected => "solid",
+                LinkState::Isolated | LinkState::Disconnected => "dashed",
+            };
+            let state = serde_json::to_string(&link.state).unwrap_or_default();
+            dot.push_str(&format!(
+                "  \"{}\" -> \"{}\" [label=\"{}\", style={}];\n",
+                link.from,
+                link.to,
+                state.trim_matches('"'),
+                style
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Get the topology of the cluster: every known node with its role in the default group, the
+/// connections between them and the leader. Peers get a second to report their connections.
+pub struct GetTopology;
+
+impl Message for GetTopology {
+    type Result = Result<Topology, ()>;
+}
+
+impl Handler<GetTopology> for Network {
+    type Result = Response<Topology, ()>;
+
+    fn handle(&mut self, _: GetTopology, _ctx: &mut Context<Self>) -> Self::Result {
+        let metrics = self.metrics.get(&DEFAULT_GROUP);
+        let leader = metrics.and_then(|m| m.current_leader);
+        let role = |id: &NodeId| match metrics {
+            _ if leader == Some(*id) => TopologyRole::Leader,
+            Some(m) if m.membership_config.members.contains(id) => TopologyRole::Voter,
+            Some(m) if m.membership_config.non_voters.contains(id) => TopologyRole::NonVoter,
+            _ => TopologyRole::None,
+        };
+
+        let mut nodes: Vec<TopologyNode> = self
+            .nodes_info
+            .iter()
+            .map(|(id, info)| TopologyNode {
+                id: *id,
+                cluster_addr: info.cluster_addr.clone(),
+                role: role(id),
+            })
+            .collect();
+        if !self.nodes_info.contains_key(&self.id) {
+            nodes.push(TopologyNode {
+                id: self.id,
+                cluster_addr: self.info.cluster_addr.clone(),
+                role: role(&self.id),
+            });
+        }
+        nodes.sort_by_key(|node| node.id);
+
+        let local = self.links();
+        let reports: Vec<_> = self
+            .nodes_connected
+            .iter()
+            .filter(|id| **id != self.id && !self.isolated_nodes.contains(id))
+            .filter_map(|id| self.get_node(*id).map(|node| (*id, node.clone())))
+            .map(|(id, node)| {
+                node.send(SendRemoteMessage(GetPeerLinks))
+                    .map_err(|_| ())
+                    .and_then(|res| res)
+                    .timeout(Duration::from_secs(1))
+                    .then(move |res| Ok::<_, ()>((id, res.ok())))
+            })
+            .collect();
+
+        Response::fut(futures::future::join_all(reports).map(move |reports| {
+            let mut topology = Topology {
+                leader,
+                nodes,
+                links: local,
+                unreachable: vec![],
+            };
+            for (id, links) in reports {
+                match links {
+                    Some(links) => topology.links.extend(links),
+                    None => topology.unreachable.push(id),
+                }
+            }
+            topology
+        }))
+    }
+}
+
+/// Latest metrics reported by the raft of a group on this node
+pub struct GetGroupMetrics(pub GroupId);
+
+impl Message for GetGroupMetrics {
+    type Result = Result<RaftMetrics, ()>;
+}
+
+impl Handler<GetGroupMetrics> for Network {
+    type Result = Result<RaftMetrics, ()>;
+
+    fn handle(&mut self, msg: GetGroupMetrics, _ctx: &mut Context<Self>) -> Self::Result {
+        self.metrics.get(&msg.0).cloned().ok_or(())
+    }
+}
+
+/// Membership of a group as seen by this node, a new change is only
+/// accepted once `changing` is false again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigState {
+    /// a change is in its joint consensus phase
+    pub changing: bool,
+    pub voters: Vec<NodeId>,
+    /// voters once the change in progress completes, same as `voters` otherwise
+    pub new_voters: Vec<NodeId>,
+    /// nodes being synced before they are added as voters
+    pub non_voters: Vec<NodeId>,
+    /// since when the change is in joint consensus, at metrics resolution
+    pub changing_ms: Option<u64>,
+    /// the change outlived `joint_consensus_timeout_ms` in joint consensus, or the sync of one of
+    /// its non-voters made no progress for as long, see `GetStuckChange`
+    pub stuck: bool,
+}
+
+pub struct GetConfigState(pub GroupId);
+
+impl Message for GetConfigState {
+    type Result = Result<ConfigState, ()>;
+}
+
+impl Handler<GetConfigState> for Network {
+    type Result = Result<ConfigState, ()>;
+
+    fn handle(&mut self, msg: GetConfigState, _ctx: &mut Context<Self>) -> Self::Result {
+        let config = &self.metrics.get(&msg.0).ok_or(())?.membership_config;
+        let changing_for = self.joint_since.get(&msg.0).map(|since| self.clock.now().duration_since(*since));
+
+        Ok(ConfigState {
+            changing: config.is_in_joint_consensus,
+            voters: config.members.clone(),
+            new_voters: new_voters(config),
+            non_voters: config.non_voters.clone(),
+            changing_ms: changing_for.map(|elapsed| elapsed.as_millis() as u64),
+            stuck: self.is_stuck(changing_for) || !self.stalled_syncs(msg.0).is_empty(),
+        })
+    }
+}
+
+/// Voters once the change in progress completes
+fn new_voters(config: &MembershipConfig) -> Vec<NodeId> {
+    let mut new_voters = config
+        .members
+        .iter()
+        .filter(|id| !config.removing.contains(id))
+        .cloned()
+        .collect::<Vec<_>>();
+    if config.is_in_joint_consensus {
+        new_voters.extend(config.non_voters.iter().cloned());
+    }
+    new_voters
+}
+
+impl Network {
+    fn is_stuck(&self, changing_for: Option<Duration>) -> bool {
+        match (changing_for, self.joint_consensus_timeout) {
+            (Some(changing_for), Some(timeout)) => changing_for > timeout,
+            _ => false,
+        }
+    }
+
+    /// The non-voters whose sync made no progress for longer than `joint_consensus_timeout_ms`,
+    /// with how long
+    fn stalled_syncs(&self, group: GroupId) -> Vec<(NodeId, Duration)> {
+        let now = self.clock.now();
+        let mut stalled = self
+            .non_voter_syncs
+            .get(&group)
+            .map(|syncs| syncs.iter().map(|(id, (_, advanced))| (*id, now.duration_since(*advanced))).collect::<Vec<_>>())
+            .unwrap_or_default();
+        stalled.retain(|(_, stalled_for)| self.is_stuck(Some(*stalled_for)));
+        stalled.sort();
+        stalled
+    }
+
+    /// The local node, or a peer which is connected and not isolated
+    fn is_reachable(&self, id: &NodeId) -> bool {
+        *id == self.id || (self.nodes_connected.contains(id) && !self.isolated_nodes.contains(id))
+    }
+}
+
+/// A membership change in joint consensus for longer than `joint_consensus_timeout_ms`, or one
+/// whose non-voters made no sync progress for as long. Joint consensus commits with a majority of
+/// both the old and the new voters, so a change whose new voters can't form a majority wedges the
+/// group, and a change only enters it once all its non-voters caught up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StuckChange {
+    /// how long it is in joint consensus, or how long the longest stalled sync made no progress
+    pub changing_ms: u64,
+    /// the change is still syncing its non-voters, it isn't in joint consensus yet
+    pub syncing: bool,
+    /// non-voters whose sync made no progress
+    pub stalled: Vec<NodeId>,
+    pub voters: Vec<NodeId>,
+    pub new_voters: Vec<NodeId>,
+    /// nodes of either config which aren't connected
+    pub unreachable: Vec<NodeId>,
+    /// nodes of either config isolated with `NodeDisconnect`
+    pub isolated: Vec<NodeId>,
+    /// a majority of the voters is reachable
+    pub old_quorum: bool,
+    /// a majority of the new voters is reachable
+    pub new_quorum: bool,
+}
+
+/// The change of a group stuck in joint consensus, if any
+pub struct GetStuckChange(pub GroupId);
+
+impl Message for GetStuckChange {
+    type Result = Result<Option<StuckChange>, ()>;
+}
+
+impl Handler<GetStuckChange> for Network {
+    type Result = Result<Option<StuckChange>, ()>;
+
+    fn handle(&mut self, msg: GetStuckChange, _ctx: &mut Context<Self>) -> Self::Result {
+        let config = &self.metrics.get(&msg.0).ok_or(())?.membership_config;
+        let stalled = self.stalled_syncs(msg.0);
+        let changing_for = match self.joint_since.get(&msg.0).map(|since| self.clock.now().duration_since(*since)) {
+            Some(changing_for) if self.is_stuck(Some(changing_for)) => changing_for,
+            _ if !config.is_in_joint_consensus && !stalled.is_empty() => stalled.iter().map(|(_, stalled_for)| *stalled_for).max().unwrap(),
+            _ => return Ok(None),
+        };
+
+        let voters = config.members.clone();
+        let mut new_voters = new_voters(config);
+        // the non-voters being synced become voters as the change enters joint consensus
+        if !config.is_in_joint_consensus {
+            new_voters.extend(config.non_voters.iter().cloned());
+        }
+        let mut nodes = voters.iter().chain(new_voters.iter()).cloned().collect::<Vec<_>>();
+        nodes.sort();
+        nodes.dedup();
+        let quorum = |nodes: &[NodeId]| nodes.iter().filter(|id| self.is_reachable(id)).count() > nodes.len() / 2;
+
+        Ok(Some(StuckChange {
+            changing_ms: changing_for.as_millis() as u64,
+            syncing: !config.is_in_joint_consensus,
+            stalled: stalled.into_iter().map(|(id, _)| id).collect(),
+            unreachable: nodes.iter().filter(|id| !self.is_reachable(id)).cloned().collect(),
+            isolated: nodes.iter().filter(|id| self.isolated_nodes.contains(id)).cloned().collect(),
+            old_quorum: quorum(&voters),
+            new_quorum: quorum(&new_voters),
+            voters: voters,
+            new_voters: new_voters,
+        }))
+    }
+}
+
+/// Sent by the raft network of a group when a follower acknowledged entries
+#[derive(Message)]
+pub struct FollowerProgressed {
+    pub group: GroupId,
+    pub target: NodeId,
+    pub match_index: u64,
+    pub next_index: u64,
+}
+
+impl Handler<FollowerProgressed> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: FollowerProgressed, _ctx: &mut Context<Self>) {
+        self.follower_progress
+            .entry(msg.group)
+            .or_insert_with(HashMap::new)
+            .insert(msg.target, (msg.match_index, msg.next_index));
+    }
+}
+
+/// Sent by the raft network of a group when a follower answered an RPC sent at `sent` in the
+/// term it was sent in, acknowledging the leadership of the local node as of then
+#[derive(Message)]
+pub struct LeaderAcked {
+    pub group: GroupId,
+    pub target: NodeId,
+    pub term: u64,
+    pub sent: Instant,
+}
+
+impl Handler<LeaderAcked> for Network {
     type Result = ();
 
-    fn handle(&mut self, msg: RaftMetrics, _: &mut Context<Self>) -> Self::Result {
-        debug!("Metrics: node={} state={:?} leader={:?} term={} index={} applied={} cfg={{join={} members={:?} non_voters={:?} removing={:?}}}",
-               msg.id, msg.state, msg.current_leader, msg.current_term, msg.last_log_index, msg.last_applied,
+    fn handle(&mut self, msg: LeaderAcked, _ctx: &mut Context<Self>) {
+        let acks = self.leader_acks.entry(msg.group).or_insert_with(HashMap::new);
+        let newer = acks.get(&msg.target).map(|(term, sent)| (msg.term, msg.sent) > (*term, *sent)).unwrap_or(true);
+        if newer {
+            acks.insert(msg.target, (msg.term, msg.sent));
+        }
+    }
+}
+
+/// Since when a majority of the voters of a group acknowledged the local node leads it in its
+/// current term, only known on its leader. No other leader is elected before an election timeout
+/// passed since then. `None` until a majority answered in the term.
+pub struct GetLeadershipConfirmed(pub GroupId);
+
+impl Message for GetLeadershipConfirmed {
+    type Result = Result<Option<Instant>, ()>;
+}
+
+impl Handler<GetLeadershipConfirmed> for Network {
+    type Result = Result<Option<Instant>, ()>;
+
+    fn handle(&mut self, msg: GetLeadershipConfirmed, _ctx: &mut Context<Self>) -> Self::Result {
+        let metrics = self.metrics.get(&msg.0).ok_or(())?;
+        if metrics.state != State::Leader {
+            return Err(());
+        }
+
+        let voters = &metrics.membership_config.members;
+        let acks = self.leader_acks.get(&msg.0);
+        let mut sent: Vec<Instant> = voters
+            .iter()
+            .filter(|id| **id != self.id)
+            .filter_map(|id| acks.and_then(|acks| acks.get(id)))
+            .filter(|(term, _)| *term == metrics.current_term)
+            .map(|(_, sent)| *sent)
+            .collect();
+        // the most recent acks first, the leader's own vote is current
+        sent.sort_by(|a, b| b.cmp(a));
+        let needed = (voters.len() / 2 + 1).saturating_sub(if voters.contains(&self.id) { 1 } else { 0 });
+        if needed == 0 {
+            return Ok(Some(self.clock.now()));
+        }
+        Ok(sent.get(needed - 1).cloned())
+    }
+}
+
+/// Voters of a group which are reachable, the local node included
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VoterHealth {
+    /// The local node leads the group
+    pub leader: bool,
+    pub healthy: usize,
+    pub voters: usize,
+}
+
+pub struct GetVoterHealth(pub GroupId);
+
+impl Message for GetVoterHealth {
+    type Result = Result<VoterHealth, ()>;
+}
+
+impl Handler<GetVoterHealth> for Network {
+    type Result = Result<VoterHealth, ()>;
+
+    fn handle(&mut self, msg: GetVoterHealth, _ctx: &mut Context<Self>) -> Self::Result {
+        let metrics = self.metrics.get(&msg.0).ok_or(())?;
+        let voters = &metrics.membership_config.members;
+        let healthy = voters.iter().filter(|id| self.is_reachable(id)).count();
+
+        Ok(VoterHealth {
+            leader: metrics.state == State::Leader,
+            healthy: healthy,
+            voters: voters.len(),
+        })
+    }
+}
+
+/// Replication progress of a follower, as seen by the leader
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FollowerProgress {
+    pub id: NodeId,
+    /// last entry known to be replicated on the follower
+    pub match_index: u64,
+    /// next entry the leader sends it
+    pub next_index: u64,
+    /// entries behind the leader's last log index
+    pub lag: u64,
+    /// lags more than `at_risk_lag`, it may need a snapshot to catch up
+    pub at_risk: bool,
+}
+
+/// Replication progress of every follower of a group, only known on its leader
+pub struct GetFollowerProgress(pub GroupId);
+
+impl Message for GetFollowerProgress {
+    type Result = Result<Vec<FollowerProgress>, ()>;
+}
+
+impl Handler<GetFollowerProgress> for Network {
+    type Result = Result<Vec<FollowerProgress>, ()>;
+
+    fn handle(&mut self, msg: GetFollowerProgress, _ctx: &mut Context<Self>) -> Self::Result {
+        let metrics = self.metrics.get(&msg.0).ok_or(())?;
+        if metrics.state != State::Leader {
+            return Err(());
+        }
+
+        let progress = self.follower_progress.get(&msg.0);
+        let config = &metrics.membership_config;
+        Ok(config
+            .members
+            .iter()
+            .chain(config.non_voters.iter())
+            .filter(|id| **id != self.id)
+            .map(|id| {
+                let (match_index, next_index) = progress.and_then(|p| p.get(id)).cloned().unwrap_or((0, 0));
+                let lag = metrics.last_log_index.saturating_sub(match_index);
+                FollowerProgress {
+                    id: *id,
+                    match_index,
+                    next_index,
+                    lag,
+                    at_risk: lag > self.at_risk_lag,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Resolves once every voter of the group applied `index`, or fails at
+/// `deadline` with the voters which did and the full voter set
+pub struct AwaitApplied {
+    pub group: GroupId,
+    pub index: u64,
+    pub deadline: Instant,
+}
+
+impl Message for AwaitApplied {
+    type Result = Result<(), (Vec<NodeId>, Vec<NodeId>)>;
+}
+
+impl Handler<AwaitApplied> for Network {
+    type Result = ResponseActFuture<Self, (), (Vec<NodeId>, Vec<NodeId>)>;
+
+    fn handle(&mut self, msg: AwaitApplied, _ctx: &mut Context<Self>) -> Self::Result {
+        let group = msg.group;
+        let index = msg.index;
+        let deadline = msg.deadline;
+        let voters = self
+            .metrics
+            .get(&group)
+            .map(|m| m.membership_config.members.clone())
+            .unwrap_or_default();
+        let local_applied = self.metrics.get(&group).map(|m| m.last_applied).unwrap_or(0);
+
+        let checks: Vec<Box<dyn Future<Item = (NodeId, u64), Error = ()>>> = voters
+            .iter()
+            .map(|id| -> Box<dyn Future<Item = (NodeId, u64), Error = ()>> {
+                let id = *id;
+                if id == self.id {
+                    return Box::new(futures::future::ok((id, local_applied)));
+                }
+
+                match self.get_node(id) {
+                    Some(node) => Box::new(
+                        node.send(SendRemoteMessage(GetLastApplied(group)))
+                            .timeout(APPLIED_POLL_INTERVAL)
+                            .then(move |res| match res {
+                                Ok(Ok(applied)) => Ok((id, applied)),
+                                _ => Ok((id, 0)),
+                            }),
+                    ),
+                    None => Box::new(futures::future::ok((id, 0))),
+                }
+            })
+            .collect();
+
+        Box::new(
+            fut::wrap_future::<_, Self>(futures::future::join_all(checks))
+                .map_err(|_, _, _| (vec![], vec![]))
+                .and_then(move |applied, act: &mut Self, _ctx| {
+                    let acked: Vec<NodeId> = applied
+                        .iter()
+                        .filter(|(_, applied)| *applied >= index)
+                        .map(|(id, _)| *id)
+                        .collect();
+
+                    if !voters.is_empty() && acked.len() == voters.len() {
+                        return fut::Either::A(fut::ok(()));
+                    }
+
+                    // the deadline was taken on the client's clock, which is shared with the network
+                    if act.clock.now() >= deadline {
+                        return fut::Either::A(fut::err((acked, voters)));
+                    }
+
+                    let err = (acked, voters);
+                    fut::Either::B(
+                        fut::wrap_future::<_, Self>(act.clock.delay(APPLIED_POLL_INTERVAL))
+                            .and_then(move |_, _, ctx: &mut Context<Self>| {
+                                fut::wrap_future::<_, Self>(ctx.address().send(AwaitApplied { group, index, deadline }))
+                                    .map_err(|_, _, _| ())
+                            })
+                            .then(move |res, _, _| match res {
+                                Ok(res) => fut::result(res),
+                                Err(_) => fut::err(err),
+                            }),
+                    )
+                }),
+        )
+    }
+}
+
+/// Resolve the peer a raft RPC from `source` to `target` is sent to,
+/// fails if either side is isolated or the target is unknown
+pub struct RouteRpc {
+    pub target: NodeId,
+    pub source: NodeId,
+    /// route over a bulk connection so large transfers don't delay heartbeats and votes
+    pub bulk: bool,
+}
+
+impl Message for RouteRpc {
+    type Result = Result<Addr<Node>, ()>;
+}
+
+impl Handler<RouteRpc> for Network {
+    type Result = Result<Addr<Node>, ()>;
+
+    fn handle(&mut self, msg: RouteRpc, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.isolated_nodes.contains(&msg.target) || self.isolated_nodes.contains(&msg.source) {
+            return Err(());
+        }
+
+        // raft only sends RPCs as leader or candidate, the raft of a node under maintenance is
+        // restarted without an election timer and must not lead in the meantime
+        if self.maintenance && msg.source == self.id {
+            return Err(());
+        }
+
+        if msg.bulk {
+            self.get_bulk_node(msg.target).cloned().ok_or(())
+        } else {
+            self.get_node(msg.target).cloned().ok_or(())
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// RaftMetrics ///////////////////////////////////////////////////////////////
+
+/// Metrics reported by the raft of a group
+#[derive(Message)]
+pub struct GroupMetrics(pub GroupId, pub RaftMetrics);
+
+impl Handler<GroupMetrics> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: GroupMetrics, _: &mut Context<Self>) -> Self::Result {
+        let (group, msg) = (msg.0, msg.1);
+        debug!("Metrics: group={} node={} state={:?} leader={:?} term={} index={} applied={} cfg={{join={} members={:?} non_voters={:?} removing={:?}}}",
+               group, msg.id, msg.state, msg.current_leader, msg.current_term, msg.last_log_index, msg.last_applied,
                msg.membership_config.is_in_joint_consensus, msg.membership_config.members,
                msg.membership_config.non_voters, msg.membership_config.removing,
         );
-        self.metrics = Some(msg);
+        if msg.current_leader.map(|leader| leader != msg.id).unwrap_or(false) {
+            self.leader_seen_at.insert(group, self.clock.now());
+        }
+        if let Some(prev) = self.metrics.get(&group) {
+            if let Some(result) = ElectionResult::from_transition(prev, &msg) {
+                info!("Election in group {}: {:?}", group, result);
+                self.last_election.insert(group, result);
+            }
+            if msg.current_term > prev.current_term {
+                self.record_election(group);
+            }
+        }
+        self.record_applied(group, msg.last_applied);
+        if msg.membership_config.is_in_joint_consensus {
+            let now = self.clock.now();
+            self.joint_since.entry(group).or_insert(now);
+        } else if let Some(since) = self.joint_since.remove(&group) {
+            debug!("Group {} left joint consensus after {:?}", group, self.clock.now().duration_since(since));
+        }
+        self.track_non_voter_syncs(group, &msg);
+
+        // the acks were reported to a former term's leader, or by nodes which left the group
+        let same_leader = self
+            .metrics
+            .get(&group)
+            .map(|prev| prev.current_term == msg.current_term && prev.current_leader == msg.current_leader)
+            .unwrap_or(false);
+        if msg.state != State::Leader || !same_leader {
+            self.follower_progress.remove(&group);
+            self.leader_acks.remove(&group);
+        } else {
+            let config = &msg.membership_config;
+            if let Some(progress) = self.follower_progress.get_mut(&group) {
+                progress.retain(|id, _| config.members.contains(id) || config.non_voters.contains(id));
+            }
+            if let Some(acks) = self.leader_acks.get_mut(&group) {
+                acks.retain(|id, _| config.members.contains(id));
+            }
+        }
+
+        self.metrics.insert(group, msg);
+    }
+}
+
+impl Network {
+    /// Note when the match index of every non-voter the local leader syncs last advanced, so a
+    /// sync which stalled, e.g. as the node being added died, is told apart from a slow one
+    fn track_non_voter_syncs(&mut self, group: GroupId, metrics: &RaftMetrics) {
+        let config = &metrics.membership_config;
+        if metrics.state != State::Leader || config.is_in_joint_consensus || config.non_voters.is_empty() {
+            self.non_voter_syncs.remove(&group);
+            return;
+        }
+
+        let now = self.clock.now();
+        let progress = self.follower_progress.get(&group);
+        let syncs = self.non_voter_syncs.entry(group).or_insert_with(HashMap::new);
+        syncs.retain(|id, _| config.non_voters.contains(id));
+        for id in config.non_voters.iter() {
+            let matched = progress.and_then(|progress| progress.get(id)).map(|(matched, _)| *matched).unwrap_or(0);
+            let sync = syncs.entry(*id).or_insert((matched, now));
+            if matched > sync.0 {
+                *sync = (matched, now);
+            }
+        }
+    }
+
+    /// Count a term change of a group, warning when elections happen too often
+    fn record_election(&mut self, group: GroupId) {
+        let now = self.clock.now();
+        *self.elections_total.entry(group).or_insert(0) += 1;
+        self.metrics_sink.counter("raftor_elections_total", &[("group", &group.to_string())], 1);
+        let elections = self.elections.entry(group).or_insert_with(VecDeque::new);
+        elections.push_back(now);
+        while elections.front().map(|at| now.duration_since(*at) > ELECTION_WINDOW).unwrap_or(false) {
+            elections.pop_front();
+        }
+
+        let max = self.max_elections_per_minute as usize;
+        if max > 0 && elections.len() > max {
+            warn!(
+                "Group {} had {} elections in the last {}s, leadership is unstable",
+                group,
+                elections.len(),
+                ELECTION_WINDOW.as_secs()
+            );
+        }
+    }
+}
+
+impl Network {
+    /// Keep the applied index of a group over `THROUGHPUT_WINDOW`, plus the sample just before it
+    fn record_applied(&mut self, group: GroupId, applied: u64) {
+        let now = self.clock.now();
+        let samples = self.applied_samples.entry(group).or_insert_with(VecDeque::new);
+        samples.push_back((now, applied));
+        while samples.len() > 2 && now.duration_since(samples[1].0) >= THROUGHPUT_WINDOW {
+            samples.pop_front();
+        }
+    }
+}
+
+/// Rate entries of a group were committed at over the last `window_ms`. Raft metrics carry no
+/// commit index, it is measured on the applied index, which follows it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThroughputStats {
+    /// entries committed within the window
+    pub committed: u64,
+    pub per_sec: f64,
+    /// span of the samples, shorter than `THROUGHPUT_WINDOW` right after the start
+    pub window_ms: u64,
+}
+
+pub struct GetThroughputStats(pub GroupId);
+
+impl Message for GetThroughputStats {
+    type Result = Result<ThroughputStats, ()>;
+}
+
+impl Handler<GetThroughputStats> for Network {
+    type Result = Result<ThroughputStats, ()>;
+
+    fn handle(&mut self, msg: GetThroughputStats, _ctx: &mut Context<Self>) -> Self::Result {
+        let samples = self.applied_samples.get(&msg.0).ok_or(())?;
+        let (first, last) = match (samples.front(), samples.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(()),
+        };
+
+        // a snapshot may move the applied index back
+        let committed = last.1.saturating_sub(first.1);
+        let window = last.0.duration_since(first.0);
+        let secs = window.as_secs() as f64 + window.subsec_nanos() as f64 / 1e9;
+        Ok(ThroughputStats {
+            committed: committed,
+            per_sec: if secs > 0.0 { committed as f64 / secs } else { 0.0 },
+            window_ms: window.as_millis() as u64,
+        })
+    }
+}
+
+/// Election counts of a group, derived from the term changes in its metrics
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ElectionStats {
+    /// elections since the node started
+    pub total: u64,
+    /// elections within the last `window_secs`
+    pub recent: usize,
+    pub window_secs: u64,
+}
+
+pub struct GetElectionStats(pub GroupId);
+
+/// Forget the elections counted for a group
+#[derive(Message)]
+pub struct ClearElectionStats(pub GroupId);
+
+impl Handler<ClearElectionStats> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClearElectionStats, _ctx: &mut Context<Self>) {
+        self.elections.remove(&msg.0);
+        self.elections_total.remove(&msg.0);
+    }
+}
+
+impl Message for GetElectionStats {
+    type Result = Result<ElectionStats, ()>;
+}
+
+impl Handler<GetElectionStats> for Network {
+    type Result = Result<ElectionStats, ()>;
+
+    fn handle(&mut self, msg: GetElectionStats, _ctx: &mut Context<Self>) -> Self::Result {
+        let now = self.clock.now();
+        let recent = self
+            .elections
+            .get(&msg.0)
+            .map(|elections| elections.iter().filter(|at| now.duration_since(**at) <= ELECTION_WINDOW).count())
+            .unwrap_or(0);
+
+        Ok(ElectionStats {
+            total: self.elections_total.get(&msg.0).cloned().unwrap_or(0),
+            recent: recent,
+            window_secs: ELECTION_WINDOW.as_secs(),
+        })
+    }
+}
+
+/// Outcome of the last election this node ran as a candidate
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ElectionResult {
+    Won { term: u64 },
+    /// another node became leader
+    Lost { term: u64, leader: Option<NodeId> },
+    /// no leader was elected and a new election started
+    Retried { term: u64 },
+}
+
+impl ElectionResult {
+    fn from_transition(prev: &RaftMetrics, next: &RaftMetrics) -> Option<Self> {
+        let term = prev.current_term;
+        match (&prev.state, &next.state) {
+            (State::Candidate, State::Leader) => Some(ElectionResult::Won { term: next.current_term }),
+            (State::Candidate, State::Candidate) if next.current_term > term => Some(ElectionResult::Retried { term }),
+            (State::Candidate, State::Follower) | (State::Candidate, State::NonVoter) => {
+                Some(ElectionResult::Lost { term, leader: next.current_leader })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Cached election state of a group, the raw material of `LeaderDiagnostics`
+pub struct GetElectionState(pub GroupId);
+
+pub struct ElectionState {
+    pub metrics: RaftMetrics,
+    pub leader_seen_at: Option<Instant>,
+    pub last_election: Option<ElectionResult>,
+}
+
+impl Message for GetElectionState {
+    type Result = Result<ElectionState, ()>;
+}
+
+impl Handler<GetElectionState> for Network {
+    type Result = Result<ElectionState, ()>;
+
+    fn handle(&mut self, msg: GetElectionState, _ctx: &mut Context<Self>) -> Self::Result {
+        let metrics = self.metrics.get(&msg.0).cloned().ok_or(())?;
+
+        Ok(ElectionState {
+            metrics: metrics,
+            leader_seen_at: self.leader_seen_at.get(&msg.0).cloned(),
+            last_election: self.last_election.get(&msg.0).cloned(),
+        })
+    }
+}
+
+/// Everything the network knows of a group, sampled in one go, the raw material of
+/// `MetricsSnapshot`
+pub struct GetGroupSample(pub GroupId);
+
+pub struct GroupSample {
+    pub metrics: RaftMetrics,
+    /// empty unless this node leads the group
+    pub followers: Vec<FollowerProgress>,
+    pub throughput: Option<ThroughputStats>,
+    pub elections: ElectionStats,
+    pub connected: Vec<NodeId>,
+}
+
+impl Message for GetGroupSample {
+    type Result = Result<GroupSample, ()>;
+}
+
+impl Handler<GetGroupSample> for Network {
+    type Result = Result<GroupSample, ()>;
+
+    fn handle(&mut self, msg: GetGroupSample, ctx: &mut Context<Self>) -> Self::Result {
+        let group = msg.0;
+        let metrics = self.metrics.get(&group).cloned().ok_or(())?;
+
+        Ok(GroupSample {
+            metrics: metrics,
+            followers: <Self as Handler<GetFollowerProgress>>::handle(self, GetFollowerProgress(group), ctx).unwrap_or_default(),
+            throughput: <Self as Handler<GetThroughputStats>>::handle(self, GetThroughputStats(group), ctx).ok(),
+            elections: <Self as Handler<GetElectionStats>>::handle(self, GetElectionStats(group), ctx)?,
+            connected: self.nodes_connected.iter().cloned().filter(|id| *id != self.id).collect(),
+        })
+    }
+}
+
+impl Handler<RaftMetrics> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: RaftMetrics, ctx: &mut Context<Self>) -> Self::Result {
+        <Self as Handler<GroupMetrics>>::handle(self, GroupMetrics(DEFAULT_GROUP, msg), ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use crate::test_utils::{config, detached};
+    use crate::utils::MockClock;
+
+    fn info(id: NodeId, cluster_addr: String) -> NodeInfo {
+        NodeInfo {
+            cluster_addr,
+            app_addr: "127.0.0.1:1".to_owned(),
+            public_addr: "127.0.0.1:2".to_owned(),
+            id: Some(id),
+            readonly: false,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn an_applied_add_dials_the_new_member() {
+        let mut sys = System::new("test");
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = info(2, listener.local_addr().unwrap().to_string());
+        let (accepted, connected) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = accepted.send(listener.accept().is_ok());
+        });
+
+        let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+        let local = info(1, "127.0.0.1:3".to_owned());
+        let mut net = Network::new(1, Ring::new(1), registry, NetworkType::Cluster, detached(), "127.0.0.1:8080".to_owned(), local);
+        // known from the handshake of a peer it never dialed
+        net.nodes_info.insert(2, peer);
+        let mut ctx = Context::<Network>::new();
+        net.handle(MembershipApplied::Added(2), &mut ctx);
+        assert!(net.nodes.contains_key(&2));
+
+        let mut dialed = false;
+        for _ in 0..50 {
+            sys.block_on(tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))).unwrap();
+            if let Ok(ok) = connected.try_recv() {
+                dialed = ok;
+                break;
+            }
+        }
+        assert!(dialed, "the new member was never dialed");
+    }
+
+    fn syncing(non_voters: Vec<NodeId>) -> RaftMetrics {
+        RaftMetrics {
+            id: 1,
+            state: State::Leader,
+            current_term: 1,
+            last_log_index: 10,
+            last_applied: 10,
+            current_leader: Some(1),
+            membership_config: MembershipConfig {
+                is_in_joint_consensus: false,
+                members: vec![1],
+                non_voters,
+                removing: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn a_non_voter_whose_sync_stalled_is_reported_stuck() {
+        let _sys = System::new("test");
+        let clock = Arc::new(MockClock::new());
+        let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+        let mut net = Network::new(1, Ring::new(1), registry, NetworkType::Cluster, detached(), "127.0.0.1:8080".to_owned(), info(1, "127.0.0.1:3".to_owned()));
+        net.configure(config("joint_consensus_timeout_ms = 1000"));
+        net.set_clock(clock.clone());
+        let mut ctx = Context::<Network>::new();
+
+        net.handle(GroupMetrics(0, syncing(vec![2, 3])), &mut ctx);
+        // node 2 catches up slowly, node 3 never answers
+        for matched in 1..=3 {
+            clock.advance(Duration::from_millis(600));
+            net.handle(FollowerProgressed { group: 0, target: 2, match_index: matched, next_index: matched + 1 }, &mut ctx);
+            net.handle(GroupMetrics(0, syncing(vec![2, 3])), &mut ctx);
+        }
+
+        let stuck = net.handle(GetStuckChange(0), &mut ctx).unwrap().expect("the stalled sync to be reported");
+        assert!(stuck.syncing);
+        assert_eq!(stuck.stalled, vec![3]);
+        assert!(net.handle(GetConfigState(0), &mut ctx).unwrap().stuck);
+
+        // once the sync completed, nothing is stuck
+        net.handle(GroupMetrics(0, syncing(vec![])), &mut ctx);
+        assert!(net.handle(GetStuckChange(0), &mut ctx).unwrap().is_none());
     }
 }
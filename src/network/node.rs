@@ -2,21 +2,23 @@ use actix::prelude::*;
 use actix_raft::NodeId;
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::codec::FramedRead;
 use tokio::io::{AsyncRead, WriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::oneshot;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::network::{
-    remote::{RemoteMessage, RemoteMessageResult, SendRemoteMessage, DispatchMessage},
-    ClientNodeCodec, Network, NodeRequest, NodeResponse, PeerConnected,
+    remote::{group_type_id, RemoteMessage, RemoteMessageResult, SendGroupMessage, SendRemoteMessage, DispatchMessage},
+    Capabilities, ClientNodeCodec, InitialMembers, Network, NodeRequest, NodeResponse, NodeStopped, PeerConnected, Undeliverable,
 };
 
-use crate::config::{NetworkType, NodeInfo};
+use crate::config::{ConnectBackoff, NetworkType, NodeInfo, SocketOptions};
+use crate::utils::{is_host_port, peer_stream, resolve_addr, set_socket_options, Backoff, PeerStream, SharedClock, SystemClock};
 
 #[derive(PartialEq)]
 enum NodeState {
@@ -30,11 +32,28 @@ pub struct Node {
     mid: u64,
     state: NodeState,
     peer_addr: String,
-    framed: Option<actix::io::FramedWrite<WriteHalf<TcpStream>, ClientNodeCodec>>,
+    framed: Option<actix::io::FramedWrite<WriteHalf<PeerStream>, ClientNodeCodec>>,
     requests: HashMap<u64, oneshot::Sender<String>>,
+    /// requests neither raft RPCs nor client proposals, kept until they are answered
+    undelivered: HashMap<u64, Undeliverable>,
     network: Addr<Network>,
     net_type: NetworkType,
     info: NodeInfo,
+    backoff: Backoff,
+    max_connect_retries: Option<u32>,
+    /// extra connection for bulk traffic, it doesn't join and isn't tracked as the peer itself
+    bulk: bool,
+    clock: SharedClock,
+    socket_options: SocketOptions,
+    /// replaced by a connection to a new address of the peer, it is already deregistered
+    retired: bool,
+    initial_members: Option<InitialMembers>,
+    /// the peer answered the join of the current connection
+    joined: bool,
+    /// next ping on the current connection
+    hb: Option<SpawnHandle>,
+    /// the peer closed the connection on a `JoinWithCapabilities`, it predates them
+    legacy_join: bool,
 }
 
 impl Node {
@@ -48,12 +67,55 @@ impl Node {
             peer_addr: peer_addr,
             framed: None,
             requests: HashMap::new(),
+            undelivered: HashMap::new(),
             network: network,
             net_type: net_type,
             info: info,
+            backoff: Backoff::new(Duration::from_millis(200), Duration::from_secs(10)),
+            max_connect_retries: None,
+            bulk: false,
+            clock: Arc::new(SystemClock),
+            socket_options: SocketOptions::default(),
+            retired: false,
+            initial_members: None,
+            joined: false,
+            hb: None,
+            legacy_join: false,
         }
     }
 
+    /// Retry the connect to the peer with the given backoff
+    pub fn with_backoff(mut self, connect_backoff: &ConnectBackoff) -> Self {
+        self.backoff = Backoff::new(
+            Duration::from_millis(connect_backoff.base_ms),
+            Duration::from_millis(connect_backoff.max_ms),
+        );
+        self.max_connect_retries = connect_backoff.max_retries;
+        self
+    }
+
+    /// Make this an extra connection for bulk traffic
+    pub fn bulk(mut self) -> Self {
+        self.bulk = true;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_socket_options(mut self, options: &SocketOptions) -> Self {
+        self.socket_options = options.clone();
+        self
+    }
+
+    /// Announce the initial member set in the join
+    pub fn with_initial_members(mut self, initial_members: Option<InitialMembers>) -> Self {
+        self.initial_members = initial_members;
+        self
+    }
+
     fn connect(&mut self, ctx: &mut Context<Self>) {
         // node is already connected
         if self.state == NodeState::Connected {
@@ -62,22 +124,43 @@ impl Node {
 
         debug!("Connecting to node #{}", self.id);
 
-        let remote_addr = self.peer_addr.as_str().parse().unwrap();
-        let conn = TcpStream::connect(&remote_addr)
-            .map_err(|e| {
-                 println!("Error: {:?}", e);
-            })
-            .map(TcpConnect)
-            .into_stream();
+        if !is_host_port(&self.peer_addr) {
+            error!("Invalid address {:?} of node #{}", self.peer_addr, self.id);
+            ctx.stop();
+            return ();
+        }
+        // a dns name is resolved on every attempt, it may not be served yet or point elsewhere now
+        let remote_addr = match resolve_addr(&self.peer_addr) {
+            Ok(addr) => addr,
+            Err(err) => return self.connect_failed(err, ctx),
+        };
+        fut::wrap_future::<_, Self>(TcpStream::connect(&remote_addr))
+            .map(|stream, _act, ctx| ctx.notify(TcpConnect(stream)))
+            .map_err(|err, act, ctx| act.connect_failed(err, ctx))
+            .spawn(ctx);
+    }
 
-        ctx.add_message_stream(conn);
+    fn connect_failed(&mut self, err: std::io::Error, ctx: &mut Context<Self>) {
+        if let Some(max_retries) = self.max_connect_retries {
+            if self.backoff.attempts() >= max_retries {
+                error!("Giving up connecting to node #{} after {} attempts: {:?}", self.id, max_retries, err);
+                ctx.stop();
+                return ();
+            }
+        }
+
+        debug!("Connecting to node #{} failed: {:?}", self.id, err);
+        ctx.notify(Connect);
     }
 
-    fn hb(&self, ctx: &mut Context<Self>) {
-        ctx.run_later(Duration::new(1, 0), |act, ctx| {
-            act.framed.as_mut().unwrap().write(NodeRequest::Ping);
-            act.hb(ctx);
-        });
+    fn hb(&mut self, ctx: &mut Context<Self>) {
+        let hb = fut::wrap_future::<_, Self>(self.clock.delay(Duration::new(1, 0)))
+            .map(|_, act, ctx| {
+                act.framed.as_mut().unwrap().write(NodeRequest::Ping);
+                act.hb(ctx);
+            })
+            .spawn(ctx);
+        self.hb = Some(hb);
     }
 }
 
@@ -88,9 +171,32 @@ impl Actor for Node {
         ctx.notify(Connect);
     }
 
-    fn stopped(&mut self, _ctx: &mut Context<Self>) {
+    fn stopped(&mut self, ctx: &mut Context<Self>) {
         info!("Node #{} disconnected", self.id);
         self.state = NodeState::Registered;
+        // the peer may have handled them before it went away
+        for (_, msg) in self.undelivered.drain() {
+            self.network.do_send(Undeliverable {
+                maybe_delivered: true,
+                ..msg
+            });
+        }
+        if !self.bulk && !self.retired {
+            self.network.do_send(NodeStopped(self.id, ctx.address()));
+        }
+    }
+}
+
+/// Close the connection of a node the network no longer tracks
+#[derive(Message)]
+pub struct Retire;
+
+impl Handler<Retire> for Node {
+    type Result = ();
+
+    fn handle(&mut self, _: Retire, ctx: &mut Context<Self>) {
+        self.retired = true;
+        ctx.stop();
     }
 }
 
@@ -106,15 +212,27 @@ impl Handler<TcpConnect> for Node {
     fn handle(&mut self, msg: TcpConnect, ctx: &mut Context<Self>) {
         //        println!("Connected to remote node #{}", self.id);
         self.state = NodeState::Connected;
-        let (r, w) = msg.0.split();
+        self.backoff.reset();
+        set_socket_options(&msg.0, &self.socket_options);
+        let stream = peer_stream(msg.0, &self.socket_options, self.net_type == NetworkType::Cluster);
+        let (r, w) = stream.split();
         Node::add_stream(FramedRead::new(r, ClientNodeCodec), ctx);
         self.framed = Some(actix::io::FramedWrite::new(w, ClientNodeCodec, ctx));
 
-        self.network.do_send(PeerConnected(self.id));
-        self.framed
-            .as_mut()
-            .unwrap()
-            .write(NodeRequest::Join(self.local_id, self.info.clone()));
+        if !self.bulk {
+            self.network.do_send(PeerConnected(self.id));
+            self.joined = false;
+            let join = if self.legacy_join {
+                NodeRequest::Join(self.local_id, self.info.clone())
+            } else {
+                NodeRequest::JoinWithCapabilities(
+                    self.local_id,
+                    self.info.clone(),
+                    Capabilities::local().with_initial_members(self.initial_members.clone()),
+                )
+            };
+            self.framed.as_mut().unwrap().write(join);
+        }
 
         match self.net_type {
             NetworkType::Cluster => self.hb(ctx),
@@ -147,15 +265,57 @@ where
     type Result = RemoteMessageResult<M>;
 
     fn handle(&mut self, msg: SendRemoteMessage<M>, _ctx: &mut Context<Self>) -> Self::Result {
+        self.request(M::type_id().to_owned(), &msg.0)
+    }
+}
+
+impl<M> Handler<SendGroupMessage<M>> for Node
+where
+    M: RemoteMessage + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    type Result = RemoteMessageResult<M>;
+
+    fn handle(&mut self, msg: SendGroupMessage<M>, _ctx: &mut Context<Self>) -> Self::Result {
+        self.request(group_type_id(M::type_id(), msg.0), &msg.1)
+    }
+}
+
+impl Node {
+    /// write a request to the peer, the result resolves with its response
+    fn request<M>(&mut self, type_id: String, msg: &M) -> RemoteMessageResult<M>
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+    {
         let (tx, rx) = oneshot::channel::<String>();
+        let body = serde_json::to_string::<M>(msg).unwrap();
+        // raft resends lost RPCs and the raft client dead-letters lost proposals itself
+        let peer = self.id;
+        let undeliverable = if M::is_raft_rpc() || M::is_proposal() {
+            None
+        } else {
+            Some(Undeliverable {
+                peer: peer,
+                type_id: type_id.clone(),
+                body: body.clone(),
+                maybe_delivered: false,
+            })
+        };
 
         if let Some(ref mut framed) = self.framed {
             self.mid += 1;
             self.requests.insert(self.mid, tx);
+            if let Some(msg) = undeliverable {
+                self.undelivered.insert(self.mid, msg);
+            }
 
-            let body = serde_json::to_string::<M>(&msg.0).unwrap();
-            let request = NodeRequest::Message(self.mid, M::type_id().to_owned(), body);
+            let request = NodeRequest::Message(self.mid, type_id, body);
             framed.write(request);
+        } else if let Some(msg) = undeliverable {
+            self.network.do_send(msg);
+        } else {
+            debug!("Dropping {} to node #{}, not connected", type_id, peer);
         }
 
         RemoteMessageResult {
@@ -169,19 +329,33 @@ impl Handler<Connect> for Node {
     type Result = ();
 
     fn handle(&mut self, _msg: Connect, ctx: &mut Context<Self>) {
-        ctx.run_later(Duration::new(2, 0), |act, ctx| {
-            act.connect(ctx);
-            ctx.notify(Connect);
-        });
+        // the first attempt is delayed as well to stagger peers booting together
+        let delay = self.backoff.next_delay();
+        fut::wrap_future::<_, Self>(self.clock.delay(delay))
+            .map(|_, act, ctx| act.connect(ctx))
+            .spawn(ctx);
     }
 }
 
 impl actix::io::WriteHandler<std::io::Error> for Node {}
 
 impl StreamHandler<NodeResponse, std::io::Error> for Node {
-    fn handle(&mut self, msg: NodeResponse, _ctx: &mut Context<Self>) {
+    fn handle(&mut self, msg: NodeResponse, ctx: &mut Context<Self>) {
         match msg {
+            NodeResponse::Joined => {
+                self.joined = true;
+                debug!("Joined node #{} with {:?}", self.id, Capabilities::legacy());
+            }
+            NodeResponse::JoinedWithCapabilities(capabilities) => {
+                self.joined = true;
+                debug!("Joined node #{} with {:?}", self.id, capabilities);
+            }
+            NodeResponse::Rejected(reason) => {
+                error!("Node #{} rejected the join: {}", self.id, reason);
+                ctx.stop();
+            }
             NodeResponse::Result(mid, data) => {
+                self.undelivered.remove(&mid);
                 if let Some(tx) = self.requests.remove(&mid) {
                     let _ = tx.send(data);
                 }
@@ -189,7 +363,22 @@ impl StreamHandler<NodeResponse, std::io::Error> for Node {
             NodeResponse::Ping => {
                 // println!("Client got Ping from {}", self.id);
             }
-            _ => (),
         }
     }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        // an older build closes the connection on a join it can't decode, it gets a plain one next
+        if !self.bulk && !self.joined && !self.legacy_join {
+            warn!("Node #{} closed the connection before answering the join, joining it without capabilities", self.id);
+            self.legacy_join = true;
+            self.state = NodeState::Registered;
+            self.framed = None;
+            if let Some(hb) = self.hb.take() {
+                ctx.cancel_future(hb);
+            }
+            ctx.notify(Connect);
+            return ();
+        }
+        ctx.stop();
+    }
 }
@@ -6,10 +6,13 @@ use std::sync::Arc;
 use tokio::sync::oneshot::Sender;
 use std::marker::PhantomData;
 
-use crate::network::remote::RemoteMessage;
+use crate::network::remote::{group_type_id, RemoteMessage};
+use crate::network::tap::{self, RpcDirection};
+use crate::raft::{GroupId, DEFAULT_GROUP};
 
 pub trait RemoteMessageHandler: Send + Sync {
-    fn handle(&self, msg: String, sender: Sender<String>);
+    /// Deserialize and handle a message, fails if the message doesn't deserialize
+    fn handle(&self, msg: String, sender: Sender<String>) -> Result<(), String>;
 }
 
 /// Remote message handler
@@ -21,6 +24,8 @@ where
     A::Context: ToEnvelope<A, M>,
 {
     pub recipient: Addr<A>,
+    /// group the handler is registered for, reported by the RPC tap
+    pub group: GroupId,
     pub m: PhantomData<M>,
 }
 
@@ -31,8 +36,10 @@ where
     A: Actor + Handler<M>,
     A::Context: ToEnvelope<A, M>,
 {
-    fn handle(&self, msg: String, sender: Sender<String>) {
-        let msg = serde_json::from_slice::<M>(msg.as_ref()).unwrap();
+    fn handle(&self, msg: String, sender: Sender<String>) -> Result<(), String> {
+        let msg = serde_json::from_slice::<M>(msg.as_ref())
+            .map_err(|err| format!("{}: {}", M::type_id(), err))?;
+        tap::record(RpcDirection::Inbound, self.group, &msg);
         Arbiter::spawn(self.recipient.send(msg).then(|res| {
             match res {
                 Ok(res) => {
@@ -42,11 +49,12 @@ where
                 Err(_e) => (),
             }
             Ok::<_, ()>(())
-        }))
+        }));
+        Ok(())
     }
 }
 
-pub type Handlers = HashMap<&'static str, Arc<dyn RemoteMessageHandler>>;
+pub type Handlers = HashMap<String, Arc<dyn RemoteMessageHandler>>;
 
 pub struct HandlerRegistry {
     handlers: Handlers,
@@ -66,8 +74,39 @@ impl HandlerRegistry {
         A: Actor + Handler<M>,
         A::Context: ToEnvelope<A, M>,
     {
-        self.handlers
-            .insert(M::type_id(), Arc::new(Provider { recipient: r, m: PhantomData }));
+        self.register_group::<M, A>(DEFAULT_GROUP, r);
+    }
+
+    /// register a handler for messages addressed to a raft group
+    pub fn register_group<M, A>(&mut self, group: GroupId, r: Addr<A>)
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+        A: Actor + Handler<M>,
+        A::Context: ToEnvelope<A, M>,
+    {
+        self.handlers.insert(
+            group_type_id(M::type_id(), group),
+            Arc::new(Provider { recipient: r, group: group, m: PhantomData }),
+        );
+    }
+
+    /// remove the handler registered for the message type, if any
+    pub fn unregister<M>(&mut self)
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+    {
+        self.unregister_group::<M>(DEFAULT_GROUP);
+    }
+
+    /// remove the handler registered for the message type in a raft group, if any
+    pub fn unregister_group<M>(&mut self, group: GroupId)
+    where
+        M: RemoteMessage + 'static,
+        M::Result: Send + Serialize + DeserializeOwned,
+    {
+        self.handlers.remove(&group_type_id(M::type_id(), group));
     }
 
     pub fn get(&self, type_id: &str) -> Option<&Arc<dyn RemoteMessageHandler>> {
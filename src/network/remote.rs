@@ -6,8 +6,11 @@ use serde::{de::DeserializeOwned, Serialize};
 use std::marker::PhantomData;
 use tokio::sync::oneshot;
 
-use crate::network::Node;
-use crate::raft::{MemRaft, ChangeRaftClusterConfig};
+use crate::network::{tap::RpcSummary, GetLastApplied, GetPeerLinks, Node, PeerAddressChanged};
+use crate::raft::storage::{GetClusterMembers, MembershipEpoch};
+use crate::raft::{GroupId, MemRaft, ChangeMembership, ChangeRaftClusterConfig, FetchLog, ForwardClientRequest, GrantReadLease, ReadQuery, RelayClientRequest, RevokeReadLease, DEFAULT_GROUP};
+use crate::raft::storage::StateMachineDigest;
+use crate::raftor::ShardedRequest;
 use crate::server;
 
 pub trait RemoteMessage: Message + Send + Sync + Serialize + DeserializeOwned
@@ -15,6 +18,22 @@ where
     Self::Result: Send + Serialize + DeserializeOwned,
 {
     fn type_id() -> &'static str;
+
+    /// Whether the message carries a client proposal. An undeliverable proposal fails the request
+    /// of its sender, which hands it to the dead letter recipient of the raft client.
+    fn is_proposal() -> bool {
+        false
+    }
+
+    /// Whether the message is a raft RPC, raft sends it again when it is lost
+    fn is_raft_rpc() -> bool {
+        false
+    }
+
+    /// What the RPC tap logs of a raft RPC, `None` for other messages
+    fn rpc_summary(&self) -> Option<RpcSummary> {
+        None
+    }
 }
 
 /// SendRemoteMessage(Message)
@@ -38,6 +57,31 @@ where
     type Result = M::Result;
 }
 
+/// SendGroupMessage(GroupId, Message), a remote message addressed to
+/// the handlers of a raft group on the peer
+pub struct SendGroupMessage<M>(pub GroupId, pub M)
+where
+    M: RemoteMessage + 'static,
+    M::Result: Send + Serialize + DeserializeOwned;
+
+impl<M> Message for SendGroupMessage<M>
+where
+    M: RemoteMessage + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    type Result = M::Result;
+}
+
+/// Type id used on the wire for a message of a raft group, the default
+/// group keeps the plain type id
+pub fn group_type_id(type_id: &str, group: GroupId) -> String {
+    if group == DEFAULT_GROUP {
+        type_id.to_owned()
+    } else {
+        format!("{}@{}", type_id, group)
+    }
+}
+
 pub struct RemoteMessageResult<M>
 where
     M: RemoteMessage + 'static,
@@ -47,6 +91,31 @@ where
     pub m: PhantomData<M>,
 }
 
+impl<M> RemoteMessageResult<M>
+where
+    M: RemoteMessage + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    fn reply<F>(self, reply: F)
+    where
+        F: FnOnce(M::Result) + 'static,
+    {
+        Arbiter::spawn(self.rx.map_err(|e| error!("{:?}", e)).and_then(move |msg| {
+            // Raft node has not been initialized yet
+            if msg == "" {
+                return Err(());
+            }
+
+            // the sender sees the request fail once the reply is dropped
+            let msg = serde_json::from_slice::<M::Result>(msg.as_ref()).map_err(|err| {
+                error!("Dropping undecodable response to {}: {}", M::type_id(), err)
+            })?;
+            reply(msg);
+            Ok(())
+        }));
+    }
+}
+
 impl<M> MessageResponse<Node, SendRemoteMessage<M>> for RemoteMessageResult<M>
 where
     M: RemoteMessage + 'static,
@@ -57,18 +126,29 @@ where
         _: &mut Context<Node>,
         tx: Option<R>,
     ) {
-        Arbiter::spawn(self.rx.map_err(|e| error!("{:?}", e)).and_then(move |msg| {
-            // Raft node has not been initialized yet
-            if msg == "" {
-                return Err(());
+        self.reply(move |msg| {
+            if let Some(tx) = tx {
+                let _ = tx.send(msg);
             }
+        });
+    }
+}
 
-            let msg = serde_json::from_slice::<M::Result>(msg.as_ref()).unwrap();
+impl<M> MessageResponse<Node, SendGroupMessage<M>> for RemoteMessageResult<M>
+where
+    M: RemoteMessage + 'static,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    fn handle<R: ResponseChannel<SendGroupMessage<M>>>(
+        self,
+        _: &mut Context<Node>,
+        tx: Option<R>,
+    ) {
+        self.reply(move |msg| {
             if let Some(tx) = tx {
                 let _ = tx.send(msg);
             }
-            Ok(())
-        }));
+        });
     }
 }
 
@@ -80,18 +160,71 @@ impl<D: AppData> RemoteMessage for messages::AppendEntriesRequest<D> {
     fn type_id() -> &'static str {
         "AppendEntriesRequest"
     }
+
+    fn is_raft_rpc() -> bool {
+        true
+    }
+
+    fn rpc_summary(&self) -> Option<RpcSummary> {
+        Some(RpcSummary {
+            source: self.leader_id,
+            target: self.target,
+            term: self.term,
+            detail: format!(
+                "prev={}/{} entries={} commit={}",
+                self.prev_log_index,
+                self.prev_log_term,
+                self.entries.len(),
+                self.leader_commit
+            ),
+        })
+    }
 }
 
 impl RemoteMessage for messages::VoteRequest {
     fn type_id() -> &'static str {
         "VoteRequest"
     }
+
+    fn is_raft_rpc() -> bool {
+        true
+    }
+
+    fn rpc_summary(&self) -> Option<RpcSummary> {
+        Some(RpcSummary {
+            source: self.candidate_id,
+            target: self.target,
+            term: self.term,
+            detail: format!("last_log={}/{}", self.last_log_index, self.last_log_term),
+        })
+    }
 }
 
 impl RemoteMessage for messages::InstallSnapshotRequest {
     fn type_id() -> &'static str {
         "InstallSnapshotRequest"
     }
+
+    fn is_raft_rpc() -> bool {
+        true
+    }
+
+    // the snapshot data is reduced to its size
+    fn rpc_summary(&self) -> Option<RpcSummary> {
+        Some(RpcSummary {
+            source: self.leader_id,
+            target: self.target,
+            term: self.term,
+            detail: format!(
+                "last_included={}/{} offset={} bytes={} done={}",
+                self.last_included_index,
+                self.last_included_term,
+                self.offset,
+                self.data.len(),
+                self.done
+            ),
+        })
+    }
 }
 
 impl<D: AppData, R: AppDataResponse, E: AppError> RemoteMessage
@@ -100,6 +233,10 @@ impl<D: AppData, R: AppDataResponse, E: AppError> RemoteMessage
     fn type_id() -> &'static str {
         "ClientPayload"
     }
+
+    fn is_proposal() -> bool {
+        true
+    }
 }
 
 impl RemoteMessage for ChangeRaftClusterConfig {
@@ -108,6 +245,102 @@ impl RemoteMessage for ChangeRaftClusterConfig {
     }
 }
 
+impl RemoteMessage for ChangeMembership {
+    fn type_id() -> &'static str {
+        "ChangeMembership"
+    }
+}
+
+impl RemoteMessage for ForwardClientRequest {
+    fn type_id() -> &'static str {
+        "ForwardClientRequest"
+    }
+
+    fn is_proposal() -> bool {
+        true
+    }
+}
+
+impl RemoteMessage for ShardedRequest {
+    fn type_id() -> &'static str {
+        "ShardedRequest"
+    }
+
+    fn is_proposal() -> bool {
+        true
+    }
+}
+
+impl RemoteMessage for RelayClientRequest {
+    fn type_id() -> &'static str {
+        "RelayClientRequest"
+    }
+
+    fn is_proposal() -> bool {
+        true
+    }
+}
+
+impl RemoteMessage for FetchLog {
+    fn type_id() -> &'static str {
+        "FetchLog"
+    }
+}
+
+impl RemoteMessage for StateMachineDigest {
+    fn type_id() -> &'static str {
+        "StateMachineDigest"
+    }
+}
+
+impl RemoteMessage for GetClusterMembers {
+    fn type_id() -> &'static str {
+        "GetClusterMembers"
+    }
+}
+
+impl RemoteMessage for ReadQuery {
+    fn type_id() -> &'static str {
+        "ReadQuery"
+    }
+}
+
+impl RemoteMessage for MembershipEpoch {
+    fn type_id() -> &'static str {
+        "MembershipEpoch"
+    }
+}
+
+impl RemoteMessage for GrantReadLease {
+    fn type_id() -> &'static str {
+        "GrantReadLease"
+    }
+}
+
+impl RemoteMessage for RevokeReadLease {
+    fn type_id() -> &'static str {
+        "RevokeReadLease"
+    }
+}
+
+impl RemoteMessage for GetPeerLinks {
+    fn type_id() -> &'static str {
+        "GetPeerLinks"
+    }
+}
+
+impl RemoteMessage for PeerAddressChanged {
+    fn type_id() -> &'static str {
+        "PeerAddressChanged"
+    }
+}
+
+impl RemoteMessage for GetLastApplied {
+    fn type_id() -> &'static str {
+        "GetLastApplied"
+    }
+}
+
 /// Impl RemoteMessage for Application Messages
 impl RemoteMessage for server::Join {
     fn type_id() -> &'static str {
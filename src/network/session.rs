@@ -1,14 +1,16 @@
 use actix::prelude::*;
 use actix_raft::NodeId;
 
+use std::net::IpAddr;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use tokio::io::WriteHalf;
-use tokio::net::TcpStream;
 use tokio::sync::oneshot;
+use log::{error, info, warn};
 
-use crate::network::{HandlerRegistry, Network, NodeCodec, NodeRequest, NodeResponse, NodeDisconnect, Handshake};
-use crate::config::NetworkType;
+use crate::network::{Capabilities, DeserializeFailed, InitialMembers, HandlerRegistry, Network, NodeCodec, NodeRequest, NodeResponse, NodeDisconnect, Handshake, SessionClosed};
+use crate::config::{DeserializeFailurePolicy, NetworkType, NodeInfo};
+use crate::utils::{PeerStream, SharedClock};
 
 
 // NodeSession
@@ -16,38 +18,107 @@ pub struct NodeSession {
     hb: Instant,
     network: Addr<Network>,
     net_type: NetworkType,
-    framed: actix::io::FramedWrite<WriteHalf<TcpStream>, NodeCodec>,
+    framed: actix::io::FramedWrite<WriteHalf<PeerStream>, NodeCodec>,
     id: Option<NodeId>,
     registry: Arc<RwLock<HandlerRegistry>>,
+    clock: SharedClock,
+    deserialize_failure_policy: DeserializeFailurePolicy,
+    /// counted against the connection limits of the network until the session stops
+    peer_ip: Option<IpAddr>,
+    /// peers joining with another initial member set are rejected
+    initial_members: Option<InitialMembers>,
 }
 
 impl NodeSession {
     pub fn new(
-        framed: actix::io::FramedWrite<WriteHalf<TcpStream>, NodeCodec>,
+        framed: actix::io::FramedWrite<WriteHalf<PeerStream>, NodeCodec>,
         network: Addr<Network>,
         registry: Arc<RwLock<HandlerRegistry>>,
         net_type: NetworkType,
+        clock: SharedClock,
+        deserialize_failure_policy: DeserializeFailurePolicy,
     ) -> NodeSession {
         NodeSession {
-            hb: Instant::now(),
+            hb: clock.now(),
             framed: framed,
             network,
             id: None,
             registry: registry,
             net_type: net_type,
+            clock: clock,
+            deserialize_failure_policy: deserialize_failure_policy,
+            peer_ip: None,
+            initial_members: None,
         }
     }
 
+    pub fn with_peer_ip(mut self, ip: IpAddr) -> Self {
+        self.peer_ip = Some(ip);
+        self
+    }
+
+    pub fn with_initial_members(mut self, initial_members: Option<InitialMembers>) -> Self {
+        self.initial_members = initial_members;
+        self
+    }
+
     fn hb(&self, ctx: &mut Context<Self>) {
-        ctx.run_interval(Duration::new(1, 0), |act, ctx| {
-            if Instant::now().duration_since(act.hb) > Duration::new(10, 0) {
-                println!("Client heartbeat failed, disconnecting!");
+        fut::wrap_future::<_, Self>(self.clock.delay(Duration::new(1, 0)))
+            .map(|_, act, ctx| {
+                if act.clock.now().duration_since(act.hb) > Duration::new(10, 0) {
+                    println!("Client heartbeat failed, disconnecting!");
+                    ctx.stop();
+                    return ();
+                }
+
+                // Reply heartbeat
+                act.framed.write(NodeResponse::Ping);
+                act.hb(ctx);
+            })
+            .spawn(ctx);
+    }
+}
+
+impl NodeSession {
+    /// count a message which failed to deserialize and apply the failure policy
+    fn deserialize_failed(&mut self, err: String, ctx: &mut Context<Self>) {
+        self.network.do_send(DeserializeFailed(self.id));
+
+        match self.deserialize_failure_policy {
+            DeserializeFailurePolicy::DropAndLog => {
+                error!("Dropping undecodable message from node {:?}: {}", self.id, err);
+            }
+            DeserializeFailurePolicy::DropAndIncrementMetric => (),
+            DeserializeFailurePolicy::CloseConnection => {
+                error!("Closing connection to node {:?} after an undecodable message: {}", self.id, err);
                 ctx.stop();
             }
+        }
+    }
 
-            // Reply heartbeat
-            act.framed.write(NodeResponse::Ping);
-        });
+    /// a `legacy` join is answered with a plain `Joined`, the only answer its build decodes
+    fn join(&mut self, id: NodeId, info: NodeInfo, capabilities: Capabilities, legacy: bool) {
+        let local = Capabilities::local().with_initial_members(self.initial_members.clone());
+        let negotiated = match local.negotiate(&capabilities) {
+            Ok(negotiated) => negotiated,
+            Err(reason) => {
+                error!("Rejecting node {}: {}", id, reason);
+                if !legacy {
+                    self.framed.write(NodeResponse::Rejected(reason));
+                }
+                self.framed.close();
+                return ();
+            }
+        };
+
+        info!("Node {} joined with {:?}", id, negotiated);
+        self.id = Some(id);
+        if legacy {
+            self.framed.write(NodeResponse::Joined);
+        } else {
+            self.framed.write(NodeResponse::JoinedWithCapabilities(negotiated.clone()));
+        }
+        self.network.do_send(Handshake(id, info, negotiated));
     }
 }
 
@@ -62,29 +133,46 @@ impl Actor for NodeSession {
     }
 
     fn stopped(&mut self, _ctx: &mut Context<Self>) {
-        self.network.do_send(NodeDisconnect(self.id.unwrap()));
+        if let Some(ip) = self.peer_ip {
+            self.network.do_send(SessionClosed(ip));
+        }
+        // bulk connections never join, losing one doesn't disconnect the peer
+        if let Some(id) = self.id {
+            self.network.do_send(NodeDisconnect(id));
+        }
     }
 }
 
 impl actix::io::WriteHandler<std::io::Error> for NodeSession {}
 
 impl StreamHandler<NodeRequest, std::io::Error> for NodeSession {
+    fn error(&mut self, err: std::io::Error, _ctx: &mut Context<Self>) -> Running {
+        if err.kind() == std::io::ErrorKind::TimedOut {
+            warn!("Closing connection to node {:?}: {}", self.id, err);
+            return Running::Stop;
+        }
+        // the frame boundary is lost, the connection can't be used anymore
+        error!("Closing connection to node {:?} after an undecodable frame: {}", self.id, err);
+        self.network.do_send(DeserializeFailed(self.id));
+        Running::Stop
+    }
+
     fn handle(&mut self, msg: NodeRequest, ctx: &mut Context<Self>) {
         match msg {
             NodeRequest::Ping => {
-                self.hb = Instant::now();
-            }
-            NodeRequest::Join(id, info) =>
-            {
-                self.id = Some(id);
-                self.network.do_send(Handshake(self.id.unwrap(), info));
+                self.hb = self.clock.now();
             }
+            NodeRequest::Join(id, info) => self.join(id, info, Capabilities::legacy(), true),
+            NodeRequest::JoinWithCapabilities(id, info, capabilities) => self.join(id, info, capabilities, false),
             NodeRequest::Message(mid, type_id, body) => {
                 let (tx, rx) = oneshot::channel();
                 let registry = self.registry.read().unwrap();
 
-                if let Some(ref handler) = registry.get(type_id.as_str()) {
-                    handler.handle(body, tx);
+                if let Some(handler) = registry.get(type_id.as_str()).cloned() {
+                    drop(registry);
+                    if let Err(err) = handler.handle(body, tx) {
+                        return self.deserialize_failed(err, ctx);
+                    }
 
                     fut::wrap_future::<_, Self>(rx)
                         .then(move |res, act, _| {
@@ -102,8 +190,11 @@ impl StreamHandler<NodeRequest, std::io::Error> for NodeSession {
                 let (tx, rx) = oneshot::channel();
                 let registry = self.registry.read().unwrap();
 
-                if let Some(ref handler) = registry.get(type_id.as_str()) {
-                    handler.handle(body, tx);
+                if let Some(handler) = registry.get(type_id.as_str()).cloned() {
+                    drop(registry);
+                    if let Err(err) = handler.handle(body, tx) {
+                        return self.deserialize_failed(err, ctx);
+                    }
 
                     fut::wrap_future::<_, Self>(rx)
                         .then(|_, _, _| fut::ok(()))
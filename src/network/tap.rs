@@ -0,0 +1,68 @@
+use actix_raft::NodeId;
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::network::remote::RemoteMessage;
+use crate::raft::GroupId;
+
+/// Log target of the tapped RPCs
+const TARGET: &str = "raftor::rpc_tap";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the raft RPCs are tapped
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Log a summary of every raft RPC sent and received, or stop. Dispatch is the same either way.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RpcDirection {
+    Inbound,
+    Outbound,
+}
+
+/// What the tap logs of a raft RPC, entries and snapshot data are reduced to counts and sizes
+pub struct RpcSummary {
+    pub source: NodeId,
+    pub target: NodeId,
+    pub term: u64,
+    pub detail: String,
+}
+
+/// Log a raft RPC of a group when the tap is enabled, other messages are skipped
+pub fn record<M>(direction: RpcDirection, group: GroupId, msg: &M)
+where
+    M: RemoteMessage,
+    M::Result: Send + Serialize + DeserializeOwned,
+{
+    if !enabled() {
+        return;
+    }
+    if let Some(summary) = msg.rpc_summary() {
+        info!(
+            target: TARGET,
+            "{:?} {} group={} {}->{} term={} {}",
+            direction,
+            M::type_id(),
+            group,
+            summary.source,
+            summary.target,
+            summary.term,
+            summary.detail
+        );
+    }
+}
+
+/// Log the response of `target` to a raft RPC this node sent, when the tap is enabled
+pub fn record_response(group: GroupId, rpc: &str, target: NodeId, term: u64, detail: String) {
+    if !enabled() {
+        return;
+    }
+    info!(target: TARGET, "Response {} group={} from={} term={} {}", rpc, group, target, term, detail);
+}
@@ -2,20 +2,26 @@ use actix::prelude::*;
 use actix_raft::{
     admin::{InitWithConfig, ProposeConfigChange},
     messages::*,
+    metrics::{RaftMetrics, State},
     NodeId,
 };
 
-use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
+use tokio::sync::oneshot;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, RwLock};
 use serde::{Serialize, Deserialize};
-use tokio::timer::Delay;
-use crate::network::{Network, remote::SendRemoteMessage, GetCurrentLeader, GetNodeById, HandlerRegistry};
+use crate::config::{CommitQuorum, ConfigSchema, InitFailurePolicy, MembershipResponseMode, SnapshotSource};
+use crate::network::{framed_len, result_frame_overhead, Network, MAX_FRAME_SIZE, remote::{SendGroupMessage, SendRemoteMessage}, AwaitApplied, ClearElectionStats, ElectionResult, ElectionStats, FollowerProgress, GetElectionState, GetGroupLeader, GetGroupLeaderAvoiding, GetGroupLeaderOrPeer, GetGroupMetrics, GetGroupSample, GetHttpAddr, GetLeadershipConfirmed, GetStuckChange, GetThroughputStats, ThroughputStats, THROUGHPUT_WINDOW, GetLastApplied, GetNodeById, GetNodes, GetVoterHealth, HandlerRegistry};
 use crate::raft::{
-    storage::{MemoryStorageData, MemoryStorageError, MemoryStorageResponse},
-    RaftBuilder, MemRaft,
+    storage::{ApplyObserved, ClearDiagnostics, Entry, ClusterMember, DrainApply, GetAppliedEntries, GetClusterMembers, GetCounter, GetApplyState, GetNextExpiry, GetStorageStats, GetVote, GetMembershipEpoch, MembershipEpoch, MemoryStorage, MemoryStorageData, MemoryStorageError, MemoryStorageResponse, ExportLog, ReadError, ResyncRing, ExportLogIfChanged, SetMetricsSink, StateDigest, StateMachineDigest, StorageStats, SubscribeFreeze, SubscribeMembership, WritesFrozen},
+    state_machine::{DefaultStateMachine, StateMachine},
+    GroupId, RaftBuilder, MemRaft, StartedRaft, DEFAULT_GROUP, ELECTION_TIMEOUT_MIN,
 };
 use crate::hash_ring::RingType;
 use crate::server::Server;
+use crate::utils::{generate_correlation_id, jitter, ClockTimeout, NoopMetrics, SharedClock, SharedMetrics, SystemClock};
 
 type ClientResponseHandler = Result<
     ClientPayloadResponse<MemoryStorageResponse>,
@@ -24,40 +30,330 @@ type ClientResponseHandler = Result<
 
 pub type Payload = ClientPayload<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>;
 
+/// How long (in seconds) to wait for every voter to apply an entry with `CommitQuorum::All`
+const COMMIT_QUORUM_TIMEOUT: u64 = 10;
+
+/// How long (in seconds) the node reports itself degraded after an internal error
+const DEGRADED_WINDOW: u64 = 60;
+
+/// How often an observer pulls new entries
+const OBSERVER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upper bound on the entries returned by a single `FetchLog`, fewer are returned when they
+/// don't fit a frame
+const OBSERVER_FETCH_SIZE: u64 = 1000;
+
+/// Longest wait for a membership change to be applied
+const MEMBERSHIP_CHANGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the metrics are checked while waiting for a membership change
+const MEMBERSHIP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often the leader checks for a membership change stuck in joint consensus
+const JOINT_CONSENSUS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a bootstrapping node asks its peers whether their raft started
+const RAFT_PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Longest read lease, a follower stops serving under it well before another leader is elected
+const MAX_READ_LEASE_MS: u64 = ELECTION_TIMEOUT_MIN / 2;
+
+/// How long a read forwarded to the leader waits for it to be known and to answer
+const READ_FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct RaftClient {
     id: NodeId,
+    group: GroupId,
     ring: RingType,
     raft: Option<Addr<MemRaft>>,
+    /// Arbiter running the raft and the directory it was started with, to restart it
+    raft_arbiter: Option<(Arbiter, String)>,
+    /// Under maintenance the raft runs without an election timer
+    elections_paused: bool,
+    storage: Option<Addr<MemoryStorage>>,
     registry: Arc<RwLock<HandlerRegistry>>,
     net: Option<Addr<Network>>,
+    config: ConfigSchema,
+    inflight: HashMap<CorrelationId, Inflight>,
+    /// in-flight requests whose next retry fails with `Cancelled`
+    cancelled: HashSet<CorrelationId>,
+    observer: Option<Observer>,
+    /// Last internal error hit talking to the raft or storage actors
+    last_error: Option<(Instant, String)>,
+    /// Why initializing the raft failed, with `InitFailurePolicy::MarkFailed`
+    init_failed: Option<String>,
+    clock: SharedClock,
+    /// Receives the client requests that failed for good
+    dead_letter: Option<Recipient<DeadLetter>>,
+    /// Decides what happens to a request the state machine rejected
+    application_errors: Option<Arc<dyn ApplicationErrorHandler>>,
+    /// failed with an application error the handler chose to surface, not dead lettered
+    surfaced: HashSet<CorrelationId>,
+    /// Application logic of the group, handed to its storage once it is created
+    state_machine: Option<Box<dyn StateMachine>>,
+    /// consecutive failed forwards per leader
+    forward_failures: HashMap<NodeId, u32>,
+    /// leaders requests aren't forwarded to, since when
+    suspect_leaders: HashMap<NodeId, Instant>,
+    /// requests received while no leader was known, see `queue_until_leader`
+    leader_queue: VecDeque<QueuedRequest>,
+    flushing: bool,
+    /// until when client requests are rejected, shared by the clients of all groups
+    write_freeze: WriteFreeze,
+    /// read lease delegated to this follower by the leader
+    read_lease: Option<ReadLease>,
+    /// read leases this leader delegated, by holder
+    lease_grants: HashMap<NodeId, ReadLease>,
+    /// term and voters the leases were granted under, a change revokes them
+    lease_epoch: Option<(u64, Vec<NodeId>)>,
+    /// a `Tick` for expired counters is being proposed
+    expiring: bool,
+    /// last `ReplaceNode` coordinated by this node
+    replacement: Option<ReplaceProgress>,
+    /// last leader this node resolved
+    leader_hint: Option<LeaderHint>,
+    /// what the last membership change found stuck waits on
+    stuck_change: Option<String>,
+    metrics: SharedMetrics,
+}
+
+/// A read lease, on the holder `node` is the leader which granted it and on the leader the holder
+struct ReadLease {
+    node: NodeId,
+    term: u64,
+    /// applied index of the leader when it granted the lease
+    index: u64,
+    expires: Instant,
+}
+
+/// Until when writes are frozen cluster-wide on the replicated clock, see `FreezeWrites`. Cleared
+/// by the storage of the default group once it applies the unfreeze or a `Tick` past the deadline.
+pub type WriteFreeze = Arc<RwLock<Option<u64>>>;
+
+/// Log following state of an observer
+struct Observer {
+    /// members the log is pulled from, in turn when one fails
+    sources: Vec<NodeId>,
+    next: usize,
+    polling: bool,
+}
+
+/// Tracking of a client request until it reached a terminal result
+struct Inflight {
+    attempts: u32,
+    received: Instant,
+    target: Option<NodeId>,
 }
 
 impl Actor for RaftClient {
     type Context = Context<Self>;
 
-    fn started(&mut self, _ctx: &mut Context<Self>) {}
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        // re-register after a supervisor restart
+        if let Some(raft) = self.raft.clone() {
+            self.register_handlers(raft, ctx.address());
+        }
+    }
+
+    fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        self.unregister_handlers();
+    }
+}
+
+impl Supervised for RaftClient {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        // drop handlers pointing at the old mailbox until started() runs again
+        self.unregister_handlers();
+    }
 }
 
 impl RaftClient {
-    pub fn new(id: NodeId, ring: RingType, registry: Arc<RwLock<HandlerRegistry>>) -> RaftClient {
+    pub fn new(id: NodeId, group: GroupId, ring: RingType, registry: Arc<RwLock<HandlerRegistry>>, config: ConfigSchema) -> RaftClient {
         RaftClient {
             id: id,
+            group: group,
             ring: ring,
             raft: None,
+            raft_arbiter: None,
+            elections_paused: false,
+            storage: None,
             registry: registry,
             net: None,
+            config: config,
+            inflight: HashMap::new(),
+            cancelled: HashSet::new(),
+            observer: None,
+            last_error: None,
+            init_failed: None,
+            clock: Arc::new(SystemClock),
+            dead_letter: None,
+            application_errors: None,
+            surfaced: HashSet::new(),
+            state_machine: None,
+            forward_failures: HashMap::new(),
+            suspect_leaders: HashMap::new(),
+            leader_queue: VecDeque::new(),
+            flushing: false,
+            write_freeze: Arc::new(RwLock::new(None)),
+            read_lease: None,
+            lease_grants: HashMap::new(),
+            lease_epoch: None,
+            expiring: false,
+            replacement: None,
+            leader_hint: None,
+            stuck_change: None,
+            metrics: Arc::new(NoopMetrics),
+        }
+
+    }
+
+    /// Route the client requests that failed for good, e.g. after exhausting their retries,
+    /// to `recipient` so they can be persisted or alerted on
+    pub fn with_dead_letter(mut self, recipient: Recipient<DeadLetter>) -> Self {
+        self.dead_letter = Some(recipient);
+        self
+    }
+
+    /// Let `handler` decide what happens to the requests the state machine rejects, instead of
+    /// failing and dead lettering them
+    pub fn with_application_error_handler(mut self, handler: Arc<dyn ApplicationErrorHandler>) -> Self {
+        self.application_errors = Some(handler);
+        self
+    }
+
+    /// Run `state_machine` on the entries committed to the group instead of the default one
+    pub fn with_state_machine(mut self, state_machine: Box<dyn StateMachine>) -> Self {
+        self.state_machine = Some(state_machine);
+        self
+    }
+
+    /// Share the write freeze with the clients of the other groups, only the default group
+    /// replicates freezes
+    pub fn with_write_freeze(mut self, write_freeze: WriteFreeze) -> Self {
+        self.write_freeze = write_freeze;
+        self
+    }
+
+    /// Report the client requests of the group and the latencies of its storage to `metrics`
+    pub fn with_metrics(mut self, metrics: SharedMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Use `storage` for the group, the network follows the membership it applies to the ring
+    fn set_storage(&mut self, storage: Addr<MemoryStorage>, ctx: &mut Context<Self>) {
+        storage.do_send(SetMetricsSink(self.metrics.clone()));
+        if let (DEFAULT_GROUP, Some(net)) = (self.group, self.net.as_ref()) {
+            storage.do_send(SubscribeMembership(net.clone().recipient()));
+            storage.do_send(SubscribeFreeze(ctx.address().recipient()));
         }
+        self.storage = Some(storage);
+    }
+
+    /// Whether writes are frozen
+    fn writes_frozen(&self) -> bool {
+        self.write_freeze.read().unwrap().is_some()
+    }
+
+    fn take_state_machine(&mut self) -> Box<dyn StateMachine> {
+        self.state_machine.take().unwrap_or_else(|| Box::new(DefaultStateMachine))
+    }
+
+    /// Drive the delays, timeouts and timestamps of this client from `clock`
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Run `f` once `delay` passed on the client's clock
+    fn run_after<F>(&self, delay: Duration, f: F, ctx: &mut Context<Self>)
+    where
+        F: FnOnce(&mut Self, &mut Context<Self>) + 'static,
+    {
+        fut::wrap_future::<_, Self>(self.clock.delay(delay))
+            .map(move |_, act: &mut Self, ctx| f(act, ctx))
+            .spawn(ctx);
+    }
 
+    /// Run `f` every `interval` on the client's clock, like `run_interval` does on the timer
+    fn run_every<F>(&self, interval: Duration, mut f: F, ctx: &mut Context<Self>)
+    where
+        F: FnMut(&mut Self, &mut Context<Self>) + 'static,
+    {
+        self.run_after(
+            interval,
+            move |act, ctx| {
+                f(act, ctx);
+                act.run_every(interval, f, ctx);
+            },
+            ctx,
+        );
+    }
+
+    /// Request proposing a membership entry, resolving per `membership_response_mode`
+    fn membership_request(&self, data: MemoryStorageData) -> ClientRequest {
+        let mode = match self.config.membership_response_mode {
+            MembershipResponseMode::Committed => ResponseMode::Committed,
+            MembershipResponseMode::Applied => ResponseMode::Applied,
+        };
+        ClientRequest::with_priority(data, Priority::High).with_mode(mode)
+    }
+
+    fn record_error(&mut self, err: String) {
+        debug!("Group {} internal error: {}", self.group, err);
+        self.last_error = Some((self.clock.now(), err));
+    }
+
+    /// The newest of the client's own last error and `storage_error`
+    fn newest_error(&self, storage_error: Option<(Instant, String)>) -> Option<(Instant, String)> {
+        match (self.last_error.clone(), storage_error) {
+            (Some(own), Some(storage)) => Some(if storage.0 > own.0 { storage } else { own }),
+            (own, storage) => own.or(storage),
+        }
     }
 
     fn register_handlers(&mut self, raft: Addr<MemRaft>, client: Addr<Self>) {
         let mut registry = self.registry.write().unwrap();
 
-        registry.register::<AppendEntriesRequest<MemoryStorageData>, _>(raft.clone());
-        registry.register::<VoteRequest, _>(raft.clone());
-        registry.register::<InstallSnapshotRequest, _>(raft.clone());
-        registry.register::<ChangeRaftClusterConfig, _>(client.clone());
-        registry.register::<ClientPayload<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>, _>(raft.clone());
+        registry.register_group::<AppendEntriesRequest<MemoryStorageData>, _>(self.group, raft.clone());
+        registry.register_group::<VoteRequest, _>(self.group, raft.clone());
+        registry.register_group::<InstallSnapshotRequest, _>(self.group, raft.clone());
+        registry.register_group::<ChangeRaftClusterConfig, _>(self.group, client.clone());
+        registry.register_group::<ChangeMembership, _>(self.group, client.clone());
+        registry.register_group::<ForwardClientRequest, _>(self.group, client.clone());
+        registry.register_group::<RelayClientRequest, _>(self.group, client.clone());
+        registry.register_group::<StateMachineDigest, _>(self.group, client.clone());
+        registry.register_group::<FetchLog, _>(self.group, client.clone());
+        registry.register_group::<GetClusterMembers, _>(self.group, client.clone());
+        registry.register_group::<ReadQuery, _>(self.group, client.clone());
+        registry.register_group::<GrantReadLease, _>(self.group, client.clone());
+        registry.register_group::<RevokeReadLease, _>(self.group, client.clone());
+        registry.register_group::<ClientPayload<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>, _>(self.group, raft.clone());
+        if let (DEFAULT_GROUP, Some(storage)) = (self.group, self.storage.as_ref()) {
+            registry.register::<MembershipEpoch, _>(storage.clone());
+        }
+    }
+
+    fn unregister_handlers(&mut self) {
+        let mut registry = self.registry.write().unwrap();
+
+        registry.unregister_group::<AppendEntriesRequest<MemoryStorageData>>(self.group);
+        registry.unregister_group::<VoteRequest>(self.group);
+        registry.unregister_group::<InstallSnapshotRequest>(self.group);
+        registry.unregister_group::<ChangeRaftClusterConfig>(self.group);
+        registry.unregister_group::<ChangeMembership>(self.group);
+        registry.unregister_group::<ForwardClientRequest>(self.group);
+        registry.unregister_group::<RelayClientRequest>(self.group);
+        registry.unregister_group::<StateMachineDigest>(self.group);
+        registry.unregister_group::<FetchLog>(self.group);
+        registry.unregister_group::<GetClusterMembers>(self.group);
+        registry.unregister_group::<ReadQuery>(self.group);
+        registry.unregister_group::<GrantReadLease>(self.group);
+        registry.unregister_group::<RevokeReadLease>(self.group);
+        registry.unregister_group::<ClientPayload<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>>(self.group);
+        if self.group == DEFAULT_GROUP {
+            registry.unregister::<MembershipEpoch>();
+        }
     }
 }
 
@@ -69,218 +365,3593 @@ pub struct InitRaft {
     pub join_mode: bool,
 }
 
-#[derive(Message)]
+/// Put a node on the ring. A node the local storage already has as a member is left alone, so
+/// reconciliation loops may re-add nodes without growing the log.
 pub struct AddNode(pub NodeId);
 
-#[derive(Serialize, Deserialize ,Message, Clone)]
+impl Message for AddNode {
+    type Result = Result<AddNodeOutcome, RaftClientError>;
+}
+
+/// Propose the `Add` entry of a node even when it already is a member
+pub struct ForceAddNode(pub NodeId);
+
+impl Message for ForceAddNode {
+    type Result = Result<AddNodeOutcome, RaftClientError>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AddNodeOutcome {
+    /// The `Add` entry was committed
+    Added,
+    /// Nothing was proposed, the node already is a member
+    AlreadyMember,
+}
+
+/// Change the voters of the group on its leader, forwarded there from any node. Fails with
+/// `WouldDestroyCluster` when no voter would be left.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ChangeRaftClusterConfig(pub Vec<NodeId>, pub Vec<NodeId>);
 
+impl Message for ChangeRaftClusterConfig {
+    type Result = Result<(), RaftClientError>;
+}
+
 impl Handler<ChangeRaftClusterConfig> for RaftClient {
-    type Result = ();
+    type Result = ResponseActFuture<Self, (), RaftClientError>;
 
-    fn handle(&mut self, msg: ChangeRaftClusterConfig, ctx: &mut Context<Self>) {
+    fn handle(&mut self, msg: ChangeRaftClusterConfig, _ctx: &mut Context<Self>) -> Self::Result {
+        let id = generate_correlation_id();
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(RaftClientError::NotInitialized { id })),
+        };
         let nodes_to_add = msg.0.clone();
         let nodes_to_remove = msg.1.clone();
 
         let payload = ProposeConfigChange::new(nodes_to_add.clone(), nodes_to_remove.clone());
 
-        ctx.spawn(
-            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetCurrentLeader))
-                .map_err(|err, _, _| panic!(err))
-                .and_then(move |res, act, _ctx| {
-                    let leader = res.unwrap();
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetGroupLeader(self.group)))
+                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                .and_then(move |res, act, ctx| -> ResponseActFuture<Self, (), RaftClientError> {
+                    let leader = match res {
+                        Ok(leader) => leader,
+                        Err(_) => {
+                            let reason = "no leader to propose the config change to".to_owned();
+                            return Box::new(fut::err(RaftClientError::MembershipChange { id, reason }));
+                        }
+                    };
+                    act.note_leader(leader, ctx);
 
                     if leader == act.id {
-                        if let Some(ref raft) = act.raft {
+                        if let Some(raft) = act.raft.clone() {
                             println!(" ------------- About to propose config change");
-                            return fut::Either::A(
-                                fut::wrap_future::<_, Self>(raft.send(payload))
-                                    .map_err(|err, _, _| panic!(err))
-                                    .and_then(move |_res, _act, ctx| {
+                            return Box::new(
+                                act.check_voters_left(id, &nodes_to_add, &nodes_to_remove)
+                                    .and_then(move |_, _act, _ctx| {
+                                        fut::wrap_future::<_, Self>(raft.send(payload))
+                                            .map_err(move |_, _, _| RaftClientError::Internal { id })
+                                            .and_then(move |res, _act, _ctx| {
+                                                fut::result(res.map_err(|err| {
+                                                    let reason = format!("{:?}", err);
+                                                    RaftClientError::MembershipChange { id, reason }
+                                                }))
+                                            })
+                                    })
+                                    .map(move |_res, _act, ctx| {
                                         for id in nodes_to_add.iter() {
                                             ctx.notify(AddNode(*id));
                                         }
-
-                                        fut::ok(())
                                     }),
                             );
                         }
                     }
 
-                    fut::Either::B(
+                    Box::new(
                         fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetNodeById(leader)))
-                            .map_err(move |_err, _, _| panic!("Node {} not found", leader))
-                            .and_then(move |node, _act, _ctx| {
+                            .map_err(move |_, _, _| RaftClientError::Internal { id })
+                            .and_then(move |node, _act, _ctx| fut::result(node.map_err(|_| RaftClientError::Undeliverable { id, peer: leader })))
+                            .and_then(move |node, act, _ctx| {
                                 println!("-------------- Sending remote proposal to leader");
-                                fut::wrap_future::<_, Self>(
-                                    node.unwrap().send(SendRemoteMessage(msg.clone())),
-                                )
-                                    .map_err(|err, _, _| println!("Error {:?}", err))
-                                    .and_then(|_res, _act, _ctx| {
-                                        fut::ok(())
+                                fut::wrap_future::<_, Self>(node.send(SendGroupMessage(act.group, msg)))
+                                    .map_err(move |err, _, _| {
+                                        println!("Error {:?}", err);
+                                        RaftClientError::Undeliverable { id, peer: leader }
                                     })
+                                    .and_then(|res, _act, _ctx| fut::result(res))
                             }),
                     )
                 }),
-        );
+        )
     }
 }
 
-#[derive(Message)]
-pub struct RemoveNode(pub NodeId);
+/// Freeze writes on every node for `max_freeze_secs`, until `UnfreezeWrites`. The freeze is
+/// itself a replicated entry so all nodes agree on it, the client requests proposed meanwhile
+/// fail with `Frozen`. It ends on the replicated clock, with the first `Tick` the leader proposes
+/// past its deadline.
+pub struct FreezeWrites;
 
-impl Handler<AddNode> for RaftClient {
-    type Result = ();
+impl Message for FreezeWrites {
+    type Result = Result<(), RaftClientError>;
+}
+
+/// Accept writes again after `FreezeWrites`
+pub struct UnfreezeWrites;
+
+impl Message for UnfreezeWrites {
+    type Result = Result<(), RaftClientError>;
+}
+
+impl Handler<FreezeWrites> for RaftClient {
+    type Result = ResponseActFuture<Self, (), RaftClientError>;
 
-    fn handle(&mut self, msg: AddNode, ctx: &mut Context<Self>) {
-        let payload = add_node(msg.0);
-        ctx.notify(ClientRequest(payload));
+    fn handle(&mut self, _: FreezeWrites, ctx: &mut Context<Self>) -> Self::Result {
+        let until = self.freeze_deadline();
+        self.propose_freeze(MemoryStorageData::Freeze(until), ctx)
     }
 }
 
-impl Handler<RemoveNode> for RaftClient {
-    type Result = ();
+impl Handler<UnfreezeWrites> for RaftClient {
+    type Result = ResponseActFuture<Self, (), RaftClientError>;
 
-    fn handle(&mut self, msg: RemoveNode, ctx: &mut Context<Self>) {
-        let payload = remove_node(msg.0);
-        ctx.notify(ClientRequest(payload));
-        ctx.notify(ChangeRaftClusterConfig(vec![], vec![msg.0]));
+    fn handle(&mut self, _: UnfreezeWrites, ctx: &mut Context<Self>) -> Self::Result {
+        self.propose_freeze(MemoryStorageData::Unfreeze, ctx)
     }
 }
 
-impl Handler<InitRaft> for RaftClient {
+impl Handler<WritesFrozen> for RaftClient {
     type Result = ();
 
-    fn handle(&mut self, msg: InitRaft, ctx: &mut Context<Self>) {
-        let nodes = msg.nodes;
-        self.net = Some(msg.net);
-        let server = msg.server;
-
-        let nodes = if msg.join_mode {
-            vec![self.id]
-        } else {
-            nodes.clone()
-        };
+    fn handle(&mut self, msg: WritesFrozen, _ctx: &mut Context<Self>) {
+        match msg.0 {
+            Some(until) => println!("Writes frozen cluster-wide until {}", until),
+            None => println!("Writes unfrozen"),
+        }
+        *self.write_freeze.write().unwrap() = msg.0;
+    }
+}
 
-        let raft =
-            RaftBuilder::new(self.id, nodes.clone(), self.net.as_ref().unwrap().clone(), self.ring.clone(), server);
-        self.register_handlers(raft.clone(), ctx.address().clone());
-        self.raft = Some(raft);
+impl RaftClient {
+    /// Latest end of a freeze proposed now. Like the `Tick`s ending it, it is taken on the clock of
+    /// the proposing node, every replica then compares the two at the same index.
+    fn freeze_deadline(&self) -> u64 {
+        let until = SystemTime::now() + Duration::from_secs(self.config.max_freeze_secs);
+        until.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+    }
 
-        if msg.join_mode {
-            return ();
+    fn propose_freeze(&mut self, data: MemoryStorageData, ctx: &mut Context<Self>) -> ResponseActFuture<Self, (), RaftClientError> {
+        // freezes are replicated by the default group only
+        if self.group != DEFAULT_GROUP {
+            let id = generate_correlation_id();
+            return Box::new(fut::err(RaftClientError::Application { id, err: MemoryStorageError }));
         }
 
-        fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(5)))
-            .map_err(|_, _, _| ())
-            .and_then(move |_, act, _ctx| {
-                fut::wrap_future::<_, Self>(
-                    act.raft
-                        .as_ref()
-                        .unwrap()
-                        .send(InitWithConfig::new(nodes.clone())),
-                )
-                    .map_err(|err, _, _| panic!(err))
-                    .and_then(|_, _, _| {
-                        println!("Inited with config!");
-                        fut::wrap_future::<_, Self>(Delay::new(
-                            Instant::now() + Duration::from_secs(5),
-                        ))
-                    })
-                    .map_err(|_, _, _| ())
-                    .and_then(|_, act, ctx| {
-                        let payload = add_node(act.id);
-                        ctx.notify(ClientRequest(payload));
-                        fut::ok(())
-                    })
-            })
-            .spawn(ctx);
+        let request = ClientRequest::with_priority(data, Priority::High);
+        let id = request.id;
+        Box::new(
+            fut::wrap_future::<_, Self>(ctx.address().send(request))
+                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .map(|_, _act, _ctx| ()),
+        )
     }
 }
 
-pub struct ClientRequest(pub MemoryStorageData);
+#[derive(Message)]
+pub struct RemoveNode(pub NodeId);
+
+/// Make a caught up standby node a voter, the manual counterpart of automatic promotion during
+/// a failover. The lag is measured against the log of this node.
+pub struct PromoteToVoter(pub NodeId);
 
-impl Message for ClientRequest {
-    type Result = ();
+impl Message for PromoteToVoter {
+    type Result = Result<(), PromoteError>;
 }
 
-impl Handler<ClientRequest> for RaftClient {
-    type Result = ();
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PromoteError {
+    /// This node has no raft metrics yet
+    NotInitialized,
+    AlreadyVoter,
+    /// The node did not report its applied index
+    Unreachable,
+    NotCaughtUp {
+        last_applied: u64,
+        last_log_index: u64,
+        lag: u64,
+        max_lag: u64,
+    },
+}
 
-    fn handle(&mut self, msg: ClientRequest, ctx: &mut Context<Self>) {
-        let entry = EntryNormal {
-            data: msg.0.clone(),
+impl Handler<PromoteToVoter> for RaftClient {
+    type Result = ResponseActFuture<Self, (), PromoteError>;
+
+    fn handle(&mut self, msg: PromoteToVoter, _ctx: &mut Context<Self>) -> Self::Result {
+        let id = msg.0;
+        let net = self.net.as_ref().unwrap().clone();
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetGroupMetrics(self.group)))
+                .map_err(|_, _, _| PromoteError::NotInitialized)
+                .and_then(|res, _act, _ctx| fut::result(res.map_err(|_| PromoteError::NotInitialized)))
+                .and_then(move |metrics, act, _ctx| {
+                    if metrics.membership_config.members.contains(&id) {
+                        return fut::Either::A(fut::err(PromoteError::AlreadyVoter));
+                    }
+
+                    let last_log_index = metrics.last_log_index;
+                    let group = act.group;
+                    fut::Either::B(
+                        fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetNodeById(id)))
+                            .map_err(|_, _, _| PromoteError::Unreachable)
+                            .and_then(|res, _act, _ctx| fut::result(res.map_err(|_| PromoteError::Unreachable)))
+                            .and_then(move |node, act: &mut Self, _ctx| {
+                                fut::wrap_future::<_, Self>(
+                                    node.send(SendRemoteMessage(GetLastApplied(group)))
+                                        .timeout_on(&act.clock, Duration::from_secs(5)),
+                                )
+                                    .map_err(|_, _, _| PromoteError::Unreachable)
+                                    .and_then(|res, _act, _ctx| fut::result(res.map_err(|_| PromoteError::Unreachable)))
+                            })
+                            .and_then(move |last_applied, act, ctx| {
+                                let lag = last_log_index.saturating_sub(last_applied);
+                                let max_lag = act.config.promote_max_lag;
+
+                                if lag > max_lag {
+                                    println!("Refusing to promote node {}, it is {} entries behind", id, lag);
+                                    return fut::err(PromoteError::NotCaughtUp { last_applied, last_log_index, lag, max_lag });
+                                }
+
+                                println!("Promoting node {} to voter, {} entries behind", id, lag);
+                                ctx.notify(ChangeRaftClusterConfig(vec![id], vec![]));
+                                fut::ok(())
+                            }),
+                    )
+                }),
+        )
+    }
+}
+
+/// Change the voters of the group, resolving once the new config is applied
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChangeMembership {
+    pub id: CorrelationId,
+    pub add: Vec<NodeId>,
+    pub remove: Vec<NodeId>,
+}
+
+impl ChangeMembership {
+    pub fn new(add: Vec<NodeId>, remove: Vec<NodeId>) -> Self {
+        ChangeMembership {
+            id: generate_correlation_id(),
+            add: add,
+            remove: remove,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MembershipChangeOutcome {
+    pub voters: Vec<NodeId>,
+    /// index the new config was applied through
+    pub index: u64,
+}
+
+impl Message for ChangeMembership {
+    type Result = Result<MembershipChangeOutcome, RaftClientError>;
+}
+
+impl Handler<ChangeMembership> for RaftClient {
+    type Result = ResponseActFuture<Self, MembershipChangeOutcome, RaftClientError>;
+
+    fn handle(&mut self, msg: ChangeMembership, _ctx: &mut Context<Self>) -> Self::Result {
+        let id = msg.id;
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(RaftClientError::Internal { id })),
         };
 
-        let payload = Payload::new(entry, ResponseMode::Applied);
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetGroupLeader(self.group)))
+                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                .and_then(move |res, act, ctx| {
+                    let leader = match res {
+                        Ok(leader) => leader,
+                        Err(_) => return fut::Either::B(fut::Either::A(fut::err(RaftClientError::Internal { id }))),
+                    };
+                    act.note_leader(leader, ctx);
 
-        ctx.spawn(
-            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetCurrentLeader))
-                .map_err(|err, _, _| panic!(err))
-                .and_then(move |res, act, _ctx| {
-                    let leader = res.unwrap();
+                    if leader != act.id {
+                        println!("[{}] Forwarding membership change to leader {}", id, leader);
+                        return fut::Either::B(fut::Either::B(
+                            fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetNodeById(leader)))
+                                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                                .and_then(move |node, act, _ctx| match node {
+                                    Ok(node) => fut::Either::A(
+                                        fut::wrap_future::<_, Self>(node.send(SendGroupMessage(act.group, msg)))
+                                            .map_err(move |_, _, _| RaftClientError::Internal { id })
+                                            .and_then(|res, _act, _ctx| fut::result(res)),
+                                    ),
+                                    Err(_) => fut::Either::B(fut::err(RaftClientError::Internal { id })),
+                                }),
+                        ));
+                    }
 
-                    if leader == act.id {
-                        if let Some(ref raft) = act.raft {
-                            return fut::Either::A(
+                    let raft = act.raft.as_ref().unwrap().clone();
+                    let payload = ProposeConfigChange::new(msg.add.clone(), msg.remove.clone());
+                    let deadline = act.clock.now() + MEMBERSHIP_CHANGE_TIMEOUT;
+                    fut::Either::A(
+                        act.check_voters_left(id, &msg.add, &msg.remove)
+                            .and_then(move |_, _act, _ctx| {
                                 fut::wrap_future::<_, Self>(raft.send(payload))
-                                    .map_err(|err, _, _| panic!(err))
-                                    .and_then(|res, _act, ctx| {
-                                        fut::ok(handle_client_response(res, ctx, msg))
-                                    }),
-                            );
+                                    .map_err(move |_, _, _| RaftClientError::Internal { id })
+                            })
+                            .and_then(move |res, _act, _ctx| {
+                                fut::result(res.map_err(|err| RaftClientError::MembershipChange {
+                                    id,
+                                    reason: format!("{:?}", err),
+                                }))
+                            })
+                            .and_then(move |_, _act, ctx: &mut Context<Self>| {
+                                for node in msg.add.iter() {
+                                    ctx.notify(AddNode(*node));
+                                }
+
+                                fut::wrap_future::<_, Self>(ctx.address().send(AwaitMembership { change: msg, deadline }))
+                                    .map_err(move |_, _, _| RaftClientError::Internal { id })
+                                    .and_then(|res, _act, _ctx| fut::result(res))
+                            }),
+                    )
+                }),
+        )
+    }
+}
+
+/// Resolves once the applied config of the group has the voters of `change`, or fails at `deadline`
+struct AwaitMembership {
+    change: ChangeMembership,
+    deadline: Instant,
+}
+
+impl Message for AwaitMembership {
+    type Result = Result<MembershipChangeOutcome, RaftClientError>;
+}
+
+impl Handler<AwaitMembership> for RaftClient {
+    type Result = ResponseActFuture<Self, MembershipChangeOutcome, RaftClientError>;
+
+    fn handle(&mut self, msg: AwaitMembership, _ctx: &mut Context<Self>) -> Self::Result {
+        let id = msg.change.id;
+
+        Box::new(
+            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetGroupMetrics(self.group)))
+                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                .and_then(move |res, act, _ctx| {
+                    if let Ok(metrics) = res {
+                        let config = &metrics.membership_config;
+                        let landed = !config.is_in_joint_consensus
+                            && msg.change.add.iter().all(|node| config.members.contains(node))
+                            && !msg.change.remove.iter().any(|node| config.members.contains(node));
+
+                        if landed && metrics.last_applied >= metrics.last_log_index {
+                            return fut::Either::A(fut::ok(MembershipChangeOutcome {
+                                voters: config.members.clone(),
+                                index: metrics.last_applied,
+                            }));
                         }
                     }
 
+                    if act.clock.now() >= msg.deadline {
+                        return fut::Either::A(fut::err(RaftClientError::MembershipChange {
+                            id,
+                            reason: "timed out waiting for the new config to be applied".to_owned(),
+                        }));
+                    }
+
                     fut::Either::B(
-                        fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetNodeById(leader)))
-                            .map_err(move |_err, _, _| panic!("Node {} not found", leader))
-                            .and_then(move |node, _act, _ctx| {
-                                println!("About to do something with node {}", leader);
-                                fut::wrap_future::<_, Self>(
-                                    node.unwrap().send(SendRemoteMessage(payload)),
-                                )
-                                    .map_err(|err, _, _| println!("Error {:?}", err))
-                                    .and_then(|res, _act, ctx| {
-                                        fut::ok(handle_client_response(res, ctx, msg))
-                                    })
+                        fut::wrap_future::<_, Self>(act.clock.delay(MEMBERSHIP_POLL_INTERVAL))
+                            .map_err(move |_, _, _| RaftClientError::Internal { id })
+                            .and_then(move |_, _act, ctx: &mut Context<Self>| {
+                                fut::wrap_future::<_, Self>(ctx.address().send(msg))
+                                    .map_err(move |_, _, _| RaftClientError::Internal { id })
+                                    .and_then(|res, _act, _ctx| fut::result(res))
                             }),
                     )
                 }),
-        );
+        )
     }
 }
 
-fn add_node(id: NodeId) -> MemoryStorageData {
-    MemoryStorageData::Add(id)
+impl Handler<AddNode> for RaftClient {
+    type Result = ResponseActFuture<Self, AddNodeOutcome, RaftClientError>;
+
+    fn handle(&mut self, msg: AddNode, _ctx: &mut Context<Self>) -> Self::Result {
+        let id = msg.0;
+        let members = match self.storage {
+            Some(ref storage) => futures::future::Either::A(storage.send(GetClusterMembers).map_err(|_| ())),
+            None => futures::future::Either::B(futures::future::err(())),
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(members).then(move |res, act, ctx| -> Self::Result {
+                match res {
+                    Ok(Ok(ref members)) if members.iter().any(|member| member.id == id) => {
+                        debug!("Not adding node {}, it already is a member", id);
+                        Box::new(fut::ok(AddNodeOutcome::AlreadyMember))
+                    }
+                    // unknown membership, the proposal decides
+                    _ => act.propose_add(id, ctx),
+                }
+            }),
+        )
+    }
 }
 
-fn remove_node(id: NodeId) -> MemoryStorageData {
-    MemoryStorageData::Remove(id)
+impl Handler<ForceAddNode> for RaftClient {
+    type Result = ResponseActFuture<Self, AddNodeOutcome, RaftClientError>;
+
+    fn handle(&mut self, msg: ForceAddNode, ctx: &mut Context<Self>) -> Self::Result {
+        self.propose_add(msg.0, ctx)
+    }
 }
 
-fn handle_client_response(
-    res: ClientResponseHandler,
-    ctx: &mut Context<RaftClient>,
-    msg: ClientRequest,
-) {
-    match res {
-        Ok(_) => (),
-        Err(err) => match err {
-            ClientError::Internal => {
-                println!("TEST: resending client request.");
-                ctx.notify(msg);
-            }
-            ClientError::Application(err) => {
-                println!(
-                    "Unexpected application error from client request: {:?}",
-                    err
-                );
-            }
-            ClientError::ForwardToLeader { .. } => {
-                println!("TEST: received ForwardToLeader error. Updating leader and forwarding.");
-                ctx.notify(msg);
-            }
-        },
+impl RaftClient {
+    fn propose_add(&mut self, id: NodeId, ctx: &mut Context<Self>) -> ResponseActFuture<Self, AddNodeOutcome, RaftClientError> {
+        let request = self.membership_request(add_node(id));
+        let correlation_id = request.id;
+        Box::new(
+            fut::wrap_future::<_, Self>(ctx.address().send(request))
+                .map_err(move |_, _, _| RaftClientError::Internal { id: correlation_id })
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .map(|_, _act, _ctx| AddNodeOutcome::Added),
+        )
+    }
+}
+
+impl Handler<RemoveNode> for RaftClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveNode, ctx: &mut Context<Self>) {
+        let node = msg.0;
+        self.check_voters_left(generate_correlation_id(), &[], &[node])
+            .map(move |_, act, ctx| {
+                ctx.notify(act.membership_request(remove_node(node)));
+                ctx.notify(ChangeRaftClusterConfig(vec![], vec![node]));
+            })
+            .map_err(|_, _, _| ())
+            .spawn(ctx);
+    }
+}
+
+/// Replace the voter `old` by `new`. `new` is added first, raft catches it up as a non-voter
+/// before making it a voter, and `old` is only removed once it is. The group never has fewer
+/// voters than before, so it tolerates as many failures throughout. Resolves with the config
+/// after the removal, the stages are reported by `GetReplaceProgress`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplaceNode {
+    pub old: NodeId,
+    pub new: NodeId,
+}
+
+impl Message for ReplaceNode {
+    type Result = Result<MembershipChangeOutcome, RaftClientError>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReplaceStage {
+    /// `new` is caught up as a non-voter, then made a voter
+    AddingNew,
+    RemovingOld,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplaceProgress {
+    pub old: NodeId,
+    pub new: NodeId,
+    pub stage: ReplaceStage,
+    /// why the replacement failed
+    pub error: Option<String>,
+}
+
+/// Progress of the last `ReplaceNode` coordinated by this node, if any
+pub struct GetReplaceProgress;
+
+impl Message for GetReplaceProgress {
+    type Result = Result<Option<ReplaceProgress>, ()>;
+}
+
+impl Handler<GetReplaceProgress> for RaftClient {
+    type Result = Result<Option<ReplaceProgress>, ()>;
+
+    fn handle(&mut self, _: GetReplaceProgress, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.replacement.clone())
+    }
+}
+
+impl Handler<ReplaceNode> for RaftClient {
+    type Result = ResponseActFuture<Self, MembershipChangeOutcome, RaftClientError>;
+
+    fn handle(&mut self, msg: ReplaceNode, _ctx: &mut Context<Self>) -> Self::Result {
+        let id = generate_correlation_id();
+        let (old, new) = (msg.old, msg.new);
+
+        let running = match self.replacement {
+            Some(ref progress) => progress.stage == ReplaceStage::AddingNew || progress.stage == ReplaceStage::RemovingOld,
+            None => false,
+        };
+        if running {
+            let reason = "another node replacement is in progress".to_owned();
+            return Box::new(fut::err(RaftClientError::MembershipChange { id, reason }));
+        }
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(RaftClientError::NotInitialized { id })),
+        };
+        // claimed before anything is sent, a second replacement arriving meanwhile is refused;
+        // every error below ends in `Failed`
+        self.set_replace_stage(old, new, ReplaceStage::AddingNew, None);
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetGroupMetrics(self.group)))
+                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                .and_then(move |res, _act, _ctx| {
+                    let voters = match res {
+                        Ok(metrics) => metrics.membership_config.members,
+                        Err(_) => return fut::err(RaftClientError::NotInitialized { id }),
+                    };
+                    if !voters.contains(&old) || voters.contains(&new) {
+                        let reason = format!("node {} must be a voter and node {} must not", old, new);
+                        return fut::err(RaftClientError::MembershipChange { id, reason });
+                    }
+                    fut::ok(())
+                })
+                .and_then(move |_, _act, ctx| {
+                    fut::wrap_future::<_, Self>(ctx.address().send(ChangeMembership::new(vec![new], vec![])))
+                        .map_err(move |_, _, _| RaftClientError::Internal { id })
+                        .and_then(|res, _act, _ctx| fut::result(res))
+                })
+                .and_then(move |_, act, ctx| {
+                    act.set_replace_stage(old, new, ReplaceStage::RemovingOld, None);
+                    fut::wrap_future::<_, Self>(ctx.address().send(ChangeMembership::new(vec![], vec![old])))
+                        .map_err(move |_, _, _| RaftClientError::Internal { id })
+                        .and_then(|res, _act, _ctx| fut::result(res))
+                })
+                .then(move |res, act, ctx| match res {
+                    Ok(outcome) => {
+                        // the voter change doesn't touch the ring, `new` was put on it as it was added
+                        ctx.notify(act.membership_request(remove_node(old)));
+                        act.set_replace_stage(old, new, ReplaceStage::Done, None);
+                        fut::ok(outcome)
+                    }
+                    Err(err) => {
+                        act.set_replace_stage(old, new, ReplaceStage::Failed, Some(format!("{:?}", err)));
+                        fut::err(err)
+                    }
+                }),
+        )
+    }
+}
+
+impl RaftClient {
+    fn set_replace_stage(&mut self, old: NodeId, new: NodeId, stage: ReplaceStage, error: Option<String>) {
+        println!("Replacing node {} with {}: {:?}", old, new, stage);
+        self.replacement = Some(ReplaceProgress { old, new, stage, error });
+    }
+
+    /// Fail with `WouldDestroyCluster` when a config change would leave the group without a
+    /// voter, from which it could never recover
+    fn check_voters_left(&self, id: CorrelationId, add: &[NodeId], remove: &[NodeId]) -> ResponseActFuture<Self, (), RaftClientError> {
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(RaftClientError::NotInitialized { id })),
+        };
+        let (add, remove) = (add.to_vec(), remove.to_vec());
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetGroupMetrics(self.group)))
+                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                .and_then(move |res, _act, _ctx| {
+                    let voters = match res {
+                        Ok(metrics) => metrics.membership_config.members,
+                        Err(_) => return fut::err(RaftClientError::NotInitialized { id }),
+                    };
+                    let left: HashSet<NodeId> = voters.iter().chain(add.iter()).filter(|node| !remove.contains(node)).cloned().collect();
+                    if left.is_empty() {
+                        println!("[{}] Refusing config change removing {:?}, it would leave no voter", id, remove);
+                        return fut::err(RaftClientError::WouldDestroyCluster { id });
+                    }
+                    fut::ok(())
+                }),
+        )
+    }
+}
+
+impl Handler<InitRaft> for RaftClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: InitRaft, ctx: &mut Context<Self>) {
+        let nodes = msg.nodes;
+        self.net = Some(msg.net);
+        let server = msg.server;
+
+        if self.config.observer {
+            println!("Starting group {} as an observer", self.group);
+            let state_machine = self.take_state_machine();
+            let storage = RaftBuilder::observer(self.group, self.ring.clone(), server, state_machine, &self.config);
+            self.set_storage(storage, ctx);
+            self.observer = Some(Observer {
+                sources: nodes.into_iter().filter(|id| *id != self.id).collect(),
+                next: 0,
+                polling: false,
+            });
+            self.run_every(OBSERVER_POLL_INTERVAL, |act, ctx| act.poll_log(ctx), ctx);
+            return;
+        }
+
+        let peers: Vec<NodeId> = nodes.iter().cloned().filter(|id| *id != self.id).collect();
+        let nodes = if msg.join_mode {
+            vec![self.id]
+        } else {
+            nodes.clone()
+        };
+
+        let quorum = &self.config.commit_quorum;
+        if *quorum == CommitQuorum::All && nodes.len() > 1 {
+            println!(
+                "commit_quorum = all: writes are acknowledged once all {} voters applied them, they still commit with a majority (tolerating {} failures)",
+                nodes.len(),
+                CommitQuorum::Majority.tolerated_failures(nodes.len())
+            );
+        }
+
+        if let (true, SnapshotSource::Follower(source)) = (msg.join_mode, &self.config.snapshot_source) {
+            if *source != self.id {
+                let source = *source;
+                self.seed_from_follower(source, peers, nodes, server, ctx);
+                return;
+            }
+        }
+
+        let state_machine = self.take_state_machine();
+        let (raft, storage) = RaftBuilder::new(
+            self.id,
+            self.group,
+            nodes.clone(),
+            self.net.as_ref().unwrap().clone(),
+            self.ring.clone(),
+            server,
+            state_machine,
+            &self.config,
+            !self.elections_paused,
+            self.clock.clone(),
+        );
+        self.set_storage(storage, ctx);
+        self.run_raft(raft, ctx);
+        if let Some(duration) = self.read_lease_duration() {
+            self.run_every(duration / 2, |act, ctx| act.renew_read_leases(ctx), ctx);
+        }
+        if self.config.expiry_interval_ms > 0 {
+            let interval = Duration::from_millis(self.config.expiry_interval_ms);
+            self.run_every(interval, |act, ctx| act.expire_counters(ctx), ctx);
+        }
+        self.run_every(THROUGHPUT_WINDOW, |act, ctx| act.detect_stall(ctx), ctx);
+        if self.config.joint_consensus_timeout_ms > 0 {
+            self.run_every(JOINT_CONSENSUS_CHECK_INTERVAL, |act, ctx| act.diagnose_stuck_change(ctx), ctx);
+        }
+
+        if msg.join_mode {
+            return;
+        }
+
+        self.init_cluster(nodes, ctx);
+    }
+}
+
+impl RaftClient {
+    /// Initialize the raft with the bootstrap members once every other one answers for its raft,
+    /// or at the latest after `max_raft_init_timeout`. A failed init is handled by the
+    /// `init_failure_policy` instead of taking the node down.
+    fn init_cluster(&mut self, nodes: Vec<NodeId>, ctx: &mut Context<Self>) {
+        let deadline = self.clock.now() + Duration::from_secs(self.config.max_raft_init_timeout);
+        self.await_peer_rafts(nodes, deadline, ctx);
+    }
+
+    /// Probe the bootstrap peers until all of them started their raft, a peer answers a group
+    /// message only once it registered the handlers of its raft
+    fn await_peer_rafts(&mut self, nodes: Vec<NodeId>, deadline: Instant, ctx: &mut Context<Self>) {
+        let (id, group, clock) = (self.id, self.group, self.clock.clone());
+        let net = self.net.as_ref().unwrap().clone();
+        let probes = nodes.clone().into_iter().filter(|peer| *peer != id).map(move |peer| {
+            net.send(GetNodeById(peer))
+                .map_err(|_| ())
+                .and_then(|res| res)
+                .and_then(move |node| {
+                    node.send(SendGroupMessage(group, GetClusterMembers))
+                        .map_err(|_| ())
+                        .and_then(|res| res)
+                })
+                .timeout_on(&clock, RAFT_PROBE_INTERVAL)
+                .then(move |res| Ok::<_, ()>((peer, res.is_ok())))
+        });
+
+        fut::wrap_future::<_, Self>(futures::future::join_all(probes))
+            .and_then(move |probes, act, ctx| -> ResponseActFuture<Self, (), ()> {
+                let waiting: Vec<NodeId> = probes.into_iter().filter(|(_, ready)| !ready).map(|(peer, _)| peer).collect();
+                if waiting.is_empty() {
+                    act.init_with_config(nodes, ctx);
+                } else if act.clock.now() >= deadline {
+                    warn!("Group {} peers {:?} didn't start their raft in time, initializing anyway", act.group, waiting);
+                    act.init_with_config(nodes, ctx);
+                } else {
+                    return Box::new(
+                        fut::wrap_future::<_, Self>(act.clock.delay(RAFT_PROBE_INTERVAL))
+                            .map_err(|_, _, _| ())
+                            .map(move |_, act: &mut Self, ctx| act.await_peer_rafts(nodes, deadline, ctx)),
+                    );
+                }
+                Box::new(fut::ok(()))
+            })
+            .spawn(ctx);
+    }
+
+    fn init_with_config(&mut self, nodes: Vec<NodeId>, ctx: &mut Context<Self>) {
+        // the jitter keeps nodes started together from all becoming candidates at once and
+        // splitting the vote
+        let jitter = Duration::from_millis(jitter(self.config.election_jitter_ms));
+        fut::wrap_future::<_, Self>(self.clock.delay(jitter))
+            .map_err(|_, _, _| ())
+            .and_then(move |_, act, _ctx| {
+                fut::wrap_future::<_, Self>(
+                    act.raft
+                        .as_ref()
+                        .unwrap()
+                        .send(InitWithConfig::new(nodes.clone())),
+                )
+                    .then(move |res, act, ctx| {
+                        let err = match res {
+                            Ok(Ok(_)) => None,
+                            Ok(Err(err)) => Some(format!("{:?}", err)),
+                            Err(err) => Some(format!("raft unreachable: {}", err)),
+                        };
+
+                        match err {
+                            None => {
+                                println!("Inited with config!");
+                                // the request itself waits until a leader is elected
+                                if act.config.auto_self_add {
+                                    let payload = add_node(act.id);
+                                    ctx.notify(act.membership_request(payload));
+                                }
+                            }
+                            Some(err) => act.init_cluster_failed(err, nodes, ctx),
+                        }
+                        fut::ok(())
+                    })
+            })
+            .spawn(ctx);
+    }
+
+    fn init_cluster_failed(&mut self, err: String, nodes: Vec<NodeId>, ctx: &mut Context<Self>) {
+        match self.config.init_failure_policy {
+            InitFailurePolicy::MarkFailed => {
+                error!("Group {} failed to initialize its raft: {}", self.group, err);
+                self.init_failed = Some(err);
+            }
+            InitFailurePolicy::Retry => {
+                warn!("Group {} failed to initialize its raft, retrying: {}", self.group, err);
+                self.record_error(format!("raft init failed: {}", err));
+                let retry = Duration::from_secs(self.config.max_raft_init_timeout);
+                fut::wrap_future::<_, Self>(self.clock.delay(retry))
+                    .map_err(|_, _, _| ())
+                    .map(move |_, act: &mut Self, ctx| act.init_cluster(nodes, ctx))
+                    .spawn(ctx);
+            }
+        }
+    }
+}
+
+impl RaftClient {
+    /// Seed the storage of a joining node with the log of an up to date follower, then start the
+    /// raft so the leader only replicates the entries committed since
+    fn seed_from_follower(
+        &mut self,
+        source: NodeId,
+        mut peers: Vec<NodeId>,
+        nodes: Vec<NodeId>,
+        server: Addr<Server>,
+        ctx: &mut Context<Self>,
+    ) {
+        let state_machine = self.take_state_machine();
+        let (storage, snapshot_dir) = RaftBuilder::learner_storage(self.group, nodes, self.ring.clone(), server, state_machine, &self.config);
+        self.set_storage(storage, ctx);
+
+        if !peers.contains(&source) {
+            peers.push(source);
+        }
+        let net = self.net.as_ref().unwrap().clone();
+        let (group, clock) = (self.group, self.clock.clone());
+        let applied = peers.into_iter().map(move |peer| {
+            net.send(GetNodeById(peer))
+                .map_err(|_| ())
+                .and_then(|res| res)
+                .and_then(move |node| {
+                    node.send(SendRemoteMessage(GetLastApplied(group)))
+                        .map_err(|_| ())
+                        .and_then(|res| res)
+                })
+                .timeout_on(&clock, OBSERVER_POLL_INTERVAL * 5)
+                .then(move |res| Ok::<_, ()>((peer, res.unwrap_or(0))))
+        });
+
+        fut::wrap_future::<_, Self>(futures::future::join_all(applied))
+            .map(move |applied, act, ctx| {
+                let newest = applied.iter().map(|(_, applied)| *applied).max().unwrap_or(0);
+                match applied.iter().find(|(peer, _)| *peer == source) {
+                    Some((_, applied)) if *applied > 0 && newest - *applied <= act.config.promote_max_lag => {
+                        println!("Seeding group {} from node {}, applied through {}", act.group, source, applied);
+                        act.pull_from_follower(source, 1, snapshot_dir, ctx);
+                    }
+                    _ => {
+                        warn!("Node {} is not up to date, replicating group {} from the leader", source, act.group);
+                        act.start_learner(snapshot_dir, ctx);
+                    }
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn pull_from_follower(&mut self, source: NodeId, from: u64, snapshot_dir: String, ctx: &mut Context<Self>) {
+        self.fetch_log(source, from)
+            .and_then(move |entries, act, _ctx| {
+                // a reply is bounded by its frame, the source is caught up with once it has none
+                if entries.is_empty() {
+                    return fut::Either::A(fut::ok(None));
+                }
+                fut::Either::B(
+                    fut::wrap_future::<_, Self>(act.storage.as_ref().unwrap().send(ApplyObserved(entries)))
+                        .map_err(|_, _, _| None)
+                        .and_then(|res, _act, _ctx| fut::result(res.map_err(|_| None)))
+                        .map(|last_applied, _act, _ctx| Some(last_applied)),
+                )
+            })
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Some(last_applied)) => act.pull_from_follower(source, last_applied + 1, snapshot_dir, ctx),
+                    Ok(None) => {
+                        println!("Seeded group {} from node {} through {}", act.group, source, from - 1);
+                        act.start_learner(snapshot_dir, ctx);
+                    }
+                    Err(Some(ReadError::EntryTooLarge(index))) => {
+                        warn!(
+                            "Seeding from node {} failed, entry {} overflows a frame, replicating the rest from the leader",
+                            source, index
+                        );
+                        act.start_learner(snapshot_dir, ctx);
+                    }
+                    Err(_) => {
+                        warn!("Seeding from node {} failed, replicating the rest from the leader", source);
+                        act.start_learner(snapshot_dir, ctx);
+                    }
+                }
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Fetch the entries from index `from` which fit a frame from `source`. Fails with the
+    /// refusal of the source, `None` when it wasn't reached.
+    fn fetch_log(&self, source: NodeId, from: u64) -> ResponseActFuture<Self, Vec<Entry>, Option<ReadError>> {
+        let fetch = FetchLog {
+            from: from,
+            max: OBSERVER_FETCH_SIZE,
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetNodeById(source)))
+                .map_err(|_, _, _| None)
+                .and_then(|res, _act, _ctx| fut::result(res.map_err(|_| None)))
+                .and_then(move |node, act: &mut Self, _ctx| {
+                    fut::wrap_future::<_, Self>(
+                        node.send(SendGroupMessage(act.group, fetch))
+                            .timeout_on(&act.clock, OBSERVER_POLL_INTERVAL * 5),
+                    )
+                        .map_err(|_, _, _| None)
+                        .and_then(|res, _act, _ctx| fut::result(res.map_err(Some)))
+                }),
+        )
+    }
+
+    /// Start the raft of a joining node over its, possibly seeded, storage
+    fn start_learner(&mut self, snapshot_dir: String, ctx: &mut Context<Self>) {
+        let raft = RaftBuilder::start(
+            self.id,
+            self.group,
+            snapshot_dir,
+            self.net.as_ref().unwrap().clone(),
+            self.storage.as_ref().unwrap().clone(),
+            &self.config,
+            !self.elections_paused,
+            self.clock.clone(),
+        );
+        self.run_raft(raft, ctx);
+    }
+
+    /// Route the RPCs and client requests of the group to `raft`
+    fn run_raft(&mut self, raft: StartedRaft, ctx: &mut Context<Self>) {
+        self.register_handlers(raft.addr.clone(), ctx.address());
+        self.raft = Some(raft.addr);
+        self.raft_arbiter = Some((raft.arbiter, raft.snapshot_dir));
+    }
+}
+
+/// Start or stop the election timer of the raft of the group, see `SetMaintenance`
+///
+/// Actix-raft can't stop the timer of a running raft, so the raft is restarted over its storage,
+/// which keeps the log and the vote. Paused, a leader steps down as the restarted raft starts as a
+/// follower, and the node keeps replicating and voting but never campaigns, so no term is spent on
+/// elections it can't win and nothing disrupts the leader once the maintenance ends.
+#[derive(Message)]
+pub struct PauseElections(pub bool);
+
+impl Handler<PauseElections> for RaftClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: PauseElections, ctx: &mut Context<Self>) {
+        if self.elections_paused == msg.0 {
+            return;
+        }
+        self.elections_paused = msg.0;
+
+        let (arbiter, snapshot_dir) = match self.raft_arbiter.take() {
+            Some(started) => started,
+            // an observer has no raft, a raft not started yet starts with the flag
+            None => return,
+        };
+        info!("Group {} elections {}, restarting its raft", self.group, if msg.0 { "paused" } else { "resumed" });
+        arbiter.stop();
+
+        let raft = RaftBuilder::start(
+            self.id,
+            self.group,
+            snapshot_dir,
+            self.net.as_ref().unwrap().clone(),
+            self.storage.as_ref().unwrap().clone(),
+            &self.config,
+            !msg.0,
+            self.clock.clone(),
+        );
+        self.run_raft(raft, ctx);
+    }
+}
+
+/// Id attached to a client request to trace it across nodes
+pub type CorrelationId = u64;
+
+/// Priority of a client request, high priority requests (e.g. membership
+/// changes) bypass the in-flight limit
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+pub struct ClientRequest {
+    pub id: CorrelationId,
+    pub data: MemoryStorageData,
+    pub priority: Priority,
+    /// When the request resolves, once its entry is applied by default
+    pub mode: ResponseMode,
+}
+
+impl ClientRequest {
+    pub fn new(data: MemoryStorageData) -> Self {
+        ClientRequest::with_priority(data, Priority::default())
+    }
+
+    pub fn with_priority(data: MemoryStorageData, priority: Priority) -> Self {
+        ClientRequest {
+            id: generate_correlation_id(),
+            data: data,
+            priority: priority,
+            mode: ResponseMode::Applied,
+        }
+    }
+
+    /// Resolve once the entry is committed, or applied, instead of the default
+    pub fn with_mode(mut self, mode: ResponseMode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+impl Message for ClientRequest {
+    type Result = Result<ClientPayloadResponse<MemoryStorageResponse>, RaftClientError>;
+}
+
+type ClientRequestResult = Result<ClientPayloadResponse<MemoryStorageResponse>, RaftClientError>;
+
+/// A client request held back until a leader is known
+struct QueuedRequest {
+    request: ClientRequest,
+    tx: oneshot::Sender<ClientRequestResult>,
+}
+
+/// Propose bytes the application serialized itself, they are applied by its state machine as a
+/// `MemoryStorageData::Raw` entry. Membership and counters still go through typed entries.
+pub struct ProposeRaw(pub Vec<u8>);
+
+impl Message for ProposeRaw {
+    type Result = Result<ClientPayloadResponse<MemoryStorageResponse>, RaftClientError>;
+}
+
+impl Handler<ProposeRaw> for RaftClient {
+    type Result = ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError>;
+
+    fn handle(&mut self, msg: ProposeRaw, ctx: &mut Context<Self>) -> Self::Result {
+        // validated against MAX_RAW_ENTRY_LEN like any other client request
+        <Self as Handler<ClientRequest>>::handle(self, ClientRequest::new(MemoryStorageData::Raw(msg.0)), ctx)
+    }
+}
+
+impl Handler<ClientRequest> for RaftClient {
+    type Result = ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError>;
+
+    fn handle(&mut self, msg: ClientRequest, ctx: &mut Context<Self>) -> Self::Result {
+        if self.config.readonly && self.net.is_some() {
+            return self.relay(msg);
+        }
+
+        if self.raft.is_none() || self.net.is_none() {
+            println!("[{}] Rejecting client request, raft is not initialized", msg.id);
+            return Box::new(fut::err(RaftClientError::NotInitialized { id: msg.id }));
+        }
+
+        if !msg.data.is_valid() {
+            println!("[{}] Rejecting invalid client request {:?}", msg.id, msg.data);
+            return Box::new(fut::err(RaftClientError::Application { id: msg.id, err: MemoryStorageError }));
+        }
+
+        if self.writes_frozen() && !msg.data.passes_freeze() {
+            println!("[{}] Rejecting client request, writes are frozen", msg.id);
+            return Box::new(fut::err(RaftClientError::Frozen { id: msg.id }));
+        }
+
+        // a freeze proposed directly may ask for longer than allowed
+        let mut msg = msg;
+        if let MemoryStorageData::Freeze(until) = msg.data {
+            msg.data = MemoryStorageData::Freeze(until.min(self.freeze_deadline()));
+        }
+
+        if self.cancelled.remove(&msg.id) {
+            println!("[{}] Client request was cancelled, not retrying it", msg.id);
+            return Box::new(fut::err(RaftClientError::Cancelled { id: msg.id }));
+        }
+
+        let limit = match self.config.queue_until_leader {
+            Some(limit) => limit,
+            None => return self.propose(msg, ctx),
+        };
+
+        // requests queued earlier go first
+        if !self.leader_queue.is_empty() {
+            return self.enqueue(msg, limit, ctx);
+        }
+
+        let id = msg.id;
+        Box::new(
+            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetGroupMetrics(self.group)))
+                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                .and_then(move |res, act, ctx| {
+                    let leader = res.ok().and_then(|metrics| metrics.current_leader);
+                    if leader.is_some() && act.leader_queue.is_empty() {
+                        act.propose(msg, ctx)
+                    } else {
+                        act.enqueue(msg, limit, ctx)
+                    }
+                }),
+        )
+    }
+}
+
+impl RaftClient {
+    /// Hold a request back until a leader is known, rejecting it when `limit` requests are queued
+    fn enqueue(
+        &mut self,
+        msg: ClientRequest,
+        limit: usize,
+        ctx: &mut Context<Self>,
+    ) -> ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError> {
+        let id = msg.id;
+        if self.leader_queue.len() >= limit {
+            println!("[{}] Rejecting client request, {} requests wait for a leader", id, limit);
+            return Box::new(fut::err(RaftClientError::Backpressure { id }));
+        }
+
+        debug!("[{}] Queueing client request until a leader is known", id);
+        let (tx, rx) = oneshot::channel();
+        self.leader_queue.push_back(QueuedRequest { request: msg, tx });
+        self.flush_when_leader(ctx);
+
+        Box::new(
+            fut::wrap_future::<_, Self>(rx)
+                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                .and_then(|res, _act, _ctx| fut::result(res)),
+        )
+    }
+
+    /// Propose the queued requests in order once a leader is known
+    fn flush_when_leader(&mut self, ctx: &mut Context<Self>) {
+        if self.flushing {
+            return;
+        }
+        self.flushing = true;
+
+        // resolves once the metrics name a leader
+        fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetGroupLeader(self.group)))
+            .then(|res, act, ctx| {
+                act.flushing = false;
+                match res {
+                    Ok(Ok(leader)) => {
+                        act.note_leader(leader, ctx);
+                        println!("Leader {} elected, flushing {} queued client requests", leader, act.leader_queue.len());
+                        for queued in act.leader_queue.drain(..).collect::<Vec<_>>() {
+                            let tx = queued.tx;
+                            act.propose(queued.request, ctx)
+                                .then(move |res, _act, _ctx| {
+                                    let _ = tx.send(res);
+                                    fut::ok(())
+                                })
+                                .spawn(ctx);
+                        }
+                    }
+                    _ => {
+                        act.run_after(Duration::from_secs(1), |act, ctx| act.flush_when_leader(ctx), ctx);
+                    }
+                }
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    fn propose(
+        &mut self,
+        msg: ClientRequest,
+        _ctx: &mut Context<Self>,
+    ) -> ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError> {
+        let id = msg.id;
+        let entry = EntryNormal {
+            data: msg.data.clone(),
+        };
+
+        let payload = Payload::new(entry, msg.mode.clone());
+        debug!("[{}] Client request {:?}", id, msg.data);
+
+        // retries come back through this handler with the same id
+        if !self.inflight.contains_key(&id) && msg.priority != Priority::High {
+            if let Some(limit) = self.config.max_inflight_requests {
+                if self.inflight.len() >= limit {
+                    println!("[{}] Rejecting client request, {} requests in flight", id, limit);
+                    return Box::new(fut::err(RaftClientError::Backpressure { id }));
+                }
+            }
+        }
+
+        let inflight = self.inflight.entry(id).or_insert(Inflight {
+            attempts: 0,
+            received: self.clock.now(),
+            target: None,
+        });
+        inflight.attempts += 1;
+
+        if let Some(max) = self.config.max_client_retries {
+            if inflight.attempts > max + 1 {
+                let attempts = inflight.attempts - 1;
+                println!("[{}] Giving up on client request after {} attempts", id, attempts);
+                return Box::new(fut::err(RaftClientError::RetriesExhausted { id, attempts }));
+            }
+        }
+        let data = msg.data.clone();
+        let priority = msg.priority;
+
+        let net = self.net.as_ref().unwrap();
+        // with blind forwarding the target is a random peer while no leader is known
+        let leader = if self.config.blind_forward_hops > 0 && self.config.forward_client_requests {
+            futures::future::Either::A(net.send(GetGroupLeaderOrPeer { group: self.group, exclude: vec![self.id] }))
+        } else if self.suspect_leaders.is_empty() {
+            futures::future::Either::B(futures::future::Either::A(
+                net.send(GetGroupLeader(self.group)).map(|res| res.map(|leader| (leader, false))),
+            ))
+        } else {
+            futures::future::Either::B(futures::future::Either::B(
+                net.send(GetGroupLeaderAvoiding {
+                    group: self.group,
+                    suspects: self.suspect_leaders.clone(),
+                    timeout: Duration::from_millis(self.config.leader_breaker_timeout_ms),
+                })
+                .map(|res| res.map(|leader| (leader, false))),
+            ))
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(leader)
+                .map_err(move |err, act: &mut Self, _| {
+                    act.record_error(format!("network unreachable: {}", err));
+                    RaftClientError::Internal { id }
+                })
+                .and_then(move |res, _act, _ctx| {
+                    fut::result(res.map_err(|_| {
+                        println!("[{}] Failed to look up the leader", id);
+                        RaftClientError::Internal { id }
+                    }))
+                })
+                .and_then(move |(leader, blind), act, ctx| {
+                    if !blind && act.suspect_leaders.remove(&leader).is_some() {
+                        println!("[{}] Leader {} reconnected or was avoided long enough, forwarding to it again", id, leader);
+                        act.forward_failures.remove(&leader);
+                    }
+                    if !blind {
+                        act.note_leader(leader, ctx);
+                    }
+
+                    if let Some(inflight) = act.inflight.get_mut(&id) {
+                        inflight.target = Some(leader);
+                    }
+
+                    if leader == act.id {
+                        if let Some(ref raft) = act.raft {
+                            let raft = raft.clone();
+                            return fut::Either::A(
+                                act.check_replication(priority)
+                                    .map_err(move |(healthy, required), _, _| {
+                                        println!("[{}] Rejecting client request, {} of {} voters healthy", id, healthy, required);
+                                        RaftClientError::InsufficientReplication { id, healthy, required }
+                                    })
+                                    .and_then(move |_, _act, _ctx| {
+                                        fut::wrap_future::<_, Self>(raft.send(payload))
+                                            .map_err(move |err, act: &mut Self, _| {
+                                                act.record_error(format!("raft unreachable: {}", err));
+                                                RaftClientError::Internal { id }
+                                            })
+                                    })
+                                    .and_then(|res, act, ctx| {
+                                        act.handle_client_response(res, ctx, msg)
+                                    }),
+                            );
+                        }
+                    }
+
+                    if !act.config.forward_client_requests {
+                        println!("[{}] Not the leader, pointing the client to leader {}", id, leader);
+                        return fut::Either::B(fut::Either::B(
+                            fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetHttpAddr(leader)))
+                                .then(move |addr, _, _| {
+                                    let addr = addr.ok().and_then(|addr| addr.ok());
+                                    fut::err(RaftClientError::NotLeader { id, leader: Some(leader), addr })
+                                }),
+                        ));
+                    }
+
+                    fut::Either::B(fut::Either::A(
+                        fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetNodeById(leader)))
+                            .map_err(move |_, _, _| RaftClientError::Internal { id })
+                            .and_then(move |node, _act, _ctx| {
+                                fut::result(node.map_err(|_| {
+                                    println!("[{}] No connection to node {}, can't forward the client request", id, leader);
+                                    RaftClientError::Undeliverable { id, peer: leader }
+                                }))
+                            })
+                            .and_then(move |node, act, _ctx| {
+                                let blind_hops = if blind { 1 } else { 0 };
+                                if blind {
+                                    println!("[{}] No leader known, forwarding client request to peer {}", id, leader);
+                                } else {
+                                    println!("[{}] Forwarding client request to leader {}", id, leader);
+                                }
+                                fut::wrap_future::<_, Self>(
+                                    node.send(SendGroupMessage(act.group, ForwardClientRequest { id, payload, priority, blind_hops })),
+                                )
+                                    // the leader went away before answering, like when it left the cluster
+                                    .map_err(move |err, act: &mut Self, _| {
+                                        println!("[{}] Forward to {} failed: {:?}", id, leader, err);
+                                        if !blind {
+                                            act.record_forward_failure(leader);
+                                        }
+                                        RaftClientError::Undeliverable { id, peer: leader }
+                                    })
+                                    .and_then(move |res, act, ctx| {
+                                        act.forward_failures.remove(&leader);
+                                        match res {
+                                            Ok(res) => act.handle_client_response(Ok(res), ctx, msg),
+                                            Err(ForwardError::Raft(err)) => act.handle_client_response(Err(err), ctx, msg),
+                                            Err(ForwardError::InsufficientReplication { healthy, required }) => {
+                                                println!("[{}] Leader {} rejected the client request, {} of {} voters healthy", id, leader, healthy, required);
+                                                Box::new(fut::err(RaftClientError::InsufficientReplication { id, healthy, required }))
+                                            }
+                                        }
+                                    })
+                            }),
+                    ))
+                })
+                .then(move |res, act, _ctx| {
+                    // an entry which committed all the same is reported, the caller must not retry it
+                    let res = match res {
+                        Err(_) if act.cancelled.remove(&id) => Err(RaftClientError::Cancelled { id }),
+                        res => {
+                            act.cancelled.remove(&id);
+                            res
+                        }
+                    };
+                    // retries finish first, only the innermost one still finds the request
+                    if let Some(inflight) = act.inflight.remove(&id) {
+                        act.record_proposal(id, &inflight, res.is_ok());
+                        let surfaced = act.surfaced.remove(&id);
+                        if let Err(ref err) = res {
+                            if !surfaced {
+                                act.send_dead_letter(data, err);
+                            }
+                        }
+                    }
+                    fut::result(res)
+                }),
+        )
+    }
+}
+
+/// A client request that failed for good, with its final error
+#[derive(Message)]
+pub struct DeadLetter {
+    pub data: MemoryStorageData,
+    pub err: RaftClientError,
+}
+
+/// What becomes of a client request whose entry the state machine rejected
+pub enum ApplicationErrorAction {
+    /// fail the request with the error and hand it to the dead letter recipient, the default
+    DeadLetter,
+    /// only fail the request with the error, the caller deals with it
+    Surface,
+    /// propose this entry in its place, counted as a retry of the request
+    Retry(MemoryStorageData),
+}
+
+/// Decides what happens to the client requests the state machine rejected, registered with
+/// `RaftClient::with_application_error_handler`
+pub trait ApplicationErrorHandler: Send + Sync {
+    /// `attempts` counts the proposals of the request so far, retries included. `Retry` is
+    /// refused once `max_application_retries` retries were made.
+    fn handle(&self, id: CorrelationId, data: &MemoryStorageData, err: &MemoryStorageError, attempts: u32) -> ApplicationErrorAction;
+}
+
+impl RaftClient {
+    /// Open the circuit breaker of a leader after `leader_breaker_threshold` failed forwards in a row
+    fn record_forward_failure(&mut self, leader: NodeId) {
+        let failures = self.forward_failures.entry(leader).or_insert(0);
+        *failures += 1;
+
+        let threshold = self.config.leader_breaker_threshold;
+        if threshold > 0 && *failures >= threshold && !self.suspect_leaders.contains_key(&leader) {
+            println!("Leader {} failed {} forwards in a row, avoiding it until it reconnects", leader, failures);
+            self.suspect_leaders.insert(leader, self.clock.now());
+        }
+    }
+
+    fn send_dead_letter(&self, data: MemoryStorageData, err: &RaftClientError) {
+        // rejected requests were never attempted, the sender is expected to retry them
+        if let RaftClientError::Backpressure { .. } = err {
+            return;
+        }
+
+        if let Some(ref recipient) = self.dead_letter {
+            if let Err(e) = recipient.do_send(DeadLetter { data, err: err.clone() }) {
+                warn!("[{}] Dead letter dropped: {}", err.correlation_id(), e);
+            }
+        }
+    }
+
+    /// Report the outcome of a client request, warning when it took longer than `slow_proposal_ms`
+    fn record_proposal(&self, id: CorrelationId, inflight: &Inflight, ok: bool) {
+        let threshold = self.config.slow_proposal_ms;
+        let elapsed = self.clock.now().duration_since(inflight.received);
+
+        let group = self.group.to_string();
+        let result = if ok { "ok" } else { "error" };
+        self.metrics.counter("raftor_client_requests_total", &[("group", &group), ("result", result)], 1);
+        self.metrics.histogram("raftor_client_request_latency_us", &[("group", &group)], elapsed.as_micros() as f64);
+        if threshold == 0 || elapsed < Duration::from_millis(threshold) {
+            return;
+        }
+
+        warn!(
+            "[{}] Slow client request took {}ms (ok: {}, attempts: {}, target: {:?})",
+            id,
+            elapsed.as_millis(),
+            ok,
+            inflight.attempts,
+            inflight.target
+        );
+    }
+}
+
+/// Health of the local raft node
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeHealth {
+    pub healthy: bool,
+    pub initialized: bool,
+    /// Reason the state machine halted applying entries, if it did
+    pub apply_halted: Option<String>,
+    /// An internal error was hit recently
+    pub degraded: bool,
+    pub last_error: Option<String>,
+    /// A snapshot is being installed, local reads are refused until it completes
+    pub catching_up: bool,
+    /// Why initializing the raft failed, the node doesn't take part in the cluster
+    pub init_failed: Option<String>,
+    /// The node leads while fewer than `min_voters_for_writes` voters are healthy and rejects writes
+    pub insufficient_replication: bool,
+}
+
+pub struct GetHealth;
+
+impl Message for GetHealth {
+    type Result = Result<NodeHealth, ()>;
+}
+
+impl Handler<GetHealth> for RaftClient {
+    type Result = ResponseActFuture<Self, NodeHealth, ()>;
+
+    fn handle(&mut self, _: GetHealth, _ctx: &mut Context<Self>) -> Self::Result {
+        let storage = match self.storage {
+            Some(ref storage) => storage,
+            None => {
+                return Box::new(fut::ok(NodeHealth {
+                    healthy: false,
+                    initialized: false,
+                    apply_halted: None,
+                    degraded: false,
+                    last_error: None,
+                    catching_up: false,
+                    init_failed: None,
+                    insufficient_replication: false,
+                }))
+            }
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(storage.send(GetApplyState))
+                .map_err(|err, act: &mut Self, _| act.record_error(format!("storage unreachable: {}", err)))
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .map(|state, act, _ctx| {
+                    let last_error = act.newest_error(state.last_error);
+                    let degraded = last_error
+                        .as_ref()
+                        .map(|(at, _)| act.clock.now().duration_since(*at) < Duration::from_secs(DEGRADED_WINDOW))
+                        .unwrap_or(false);
+
+                    NodeHealth {
+                        healthy: state.halted.is_none() && act.init_failed.is_none() && !degraded,
+                        initialized: true,
+                        apply_halted: state.halted,
+                        degraded: degraded,
+                        last_error: last_error.map(|(_, err)| err),
+                        catching_up: state.installing_snapshot,
+                        init_failed: act.init_failed.clone(),
+                        insufficient_replication: false,
+                    }
+                })
+                .and_then(|health, act, _ctx| -> ResponseActFuture<Self, NodeHealth, ()> {
+                    let (required, net) = match (act.config.min_voters_for_writes, act.net.as_ref()) {
+                        (Some(required), Some(net)) => (required, net),
+                        _ => return Box::new(fut::ok(health)),
+                    };
+
+                    Box::new(
+                        fut::wrap_future::<_, Self>(net.send(GetVoterHealth(act.group)))
+                            .map_err(|_, _, _| ())
+                            .map(move |res, _act, _ctx| match res {
+                                Ok(ref voters) if voters.leader && voters.healthy < required => NodeHealth {
+                                    healthy: false,
+                                    insufficient_replication: true,
+                                    ..health
+                                },
+                                _ => health,
+                            }),
+                    )
+                }),
+        )
+    }
+}
+
+impl Handler<StateMachineDigest> for RaftClient {
+    type Result = ResponseActFuture<Self, StateDigest, ()>;
+
+    fn handle(&mut self, msg: StateMachineDigest, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.storage {
+            Some(ref storage) => Box::new(
+                fut::wrap_future::<_, Self>(storage.send(msg))
+                    .map_err(|_, _, _| ())
+                    .and_then(|res, _act, _ctx| fut::result(res)),
+            ),
+            None => Box::new(fut::err(())),
+        }
+    }
+}
+
+/// Compare the state machine digest of every member at a committed index, `None` picks the last
+/// index applied by this node. Only the leader runs the check.
+pub struct VerifyCluster(pub Option<u64>);
+
+impl Message for VerifyCluster {
+    type Result = Result<ClusterVerification, ()>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterVerification {
+    pub index: u64,
+    /// digest of the leader's state machine
+    pub digest: String,
+    /// members whose digest differs from the leader's
+    pub divergent: Vec<NodeId>,
+    /// members which didn't answer or hadn't applied `index` yet
+    pub unavailable: Vec<NodeId>,
+}
+
+impl Handler<VerifyCluster> for RaftClient {
+    type Result = ResponseActFuture<Self, ClusterVerification, ()>;
+
+    fn handle(&mut self, msg: VerifyCluster, _ctx: &mut Context<Self>) -> Self::Result {
+        Box::new(
+            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetGroupMetrics(self.group)))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .and_then(move |metrics, act, ctx: &mut Context<Self>| {
+                    if metrics.state != State::Leader {
+                        warn!("Refusing to verify the cluster, node {} is not the leader", act.id);
+                        return fut::Either::A(fut::err(()));
+                    }
+
+                    let index = msg.0.unwrap_or(metrics.last_applied);
+                    let members = metrics.membership_config.members;
+                    fut::Either::B(
+                        fut::wrap_future::<_, Self>(ctx.address().send(StateMachineDigest(index)))
+                            .map_err(|_, _, _| ())
+                            .and_then(|res, _act, _ctx| fut::result(res))
+                            .and_then(move |local, act, _ctx| {
+                                let (id, group, clock) = (act.id, act.group, act.clock.clone());
+                                let net = act.net.as_ref().unwrap().clone();
+                                let digests = members.into_iter().filter(|member| *member != id).map(move |member| {
+                                    net.send(GetNodeById(member))
+                                        .map_err(|_| ())
+                                        .and_then(|res| res)
+                                        .and_then(move |node| {
+                                            node.send(SendGroupMessage(group, StateMachineDigest(index)))
+                                                .map_err(|_| ())
+                                                .and_then(|res| res)
+                                        })
+                                        .timeout_on(&clock, Duration::from_secs(COMMIT_QUORUM_TIMEOUT))
+                                        .then(move |res| Ok::<_, ()>((member, res.ok())))
+                                });
+
+                                fut::wrap_future::<_, Self>(futures::future::join_all(digests)).map(
+                                    move |digests, _act, _ctx| {
+                                        let mut report = ClusterVerification {
+                                            index,
+                                            digest: local.digest.clone(),
+                                            divergent: vec![],
+                                            unavailable: vec![],
+                                        };
+                                        for (member, digest) in digests {
+                                            match digest {
+                                                Some(ref digest) if *digest == local => (),
+                                                Some(_) => report.divergent.push(member),
+                                                None => report.unavailable.push(member),
+                                            }
+                                        }
+
+                                        if !report.divergent.is_empty() {
+                                            error!("State machines diverged at index {} on {:?}", index, report.divergent);
+                                        }
+                                        report
+                                    },
+                                )
+                            }),
+                    )
+                }),
+        )
+    }
+}
+
+/// Delegate a read lease of `duration_ms` to a follower, renewed by the leader every half lease.
+/// Until it expires the follower answers `Staleness { lease: true, .. }` reads from its own state
+/// machine, once it applied up to `index`, instead of asking the leader.
+///
+/// A lease only holds while no other leader can be elected: the leader grants it once a majority
+/// of the voters answered its RPCs in the term, for `duration_ms` from when the latest of those
+/// RPCs was sent, never more than half the election timeout. Its holder drops it as soon as it
+/// sees another term or leader.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GrantReadLease {
+    pub leader: NodeId,
+    pub term: u64,
+    pub index: u64,
+    pub duration_ms: u64,
+}
+
+impl Message for GrantReadLease {
+    type Result = Result<(), ()>;
+}
+
+/// Drop the read lease granted by `leader` in `term`, sent when its voters or term change
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RevokeReadLease {
+    pub leader: NodeId,
+    pub term: u64,
+}
+
+impl Message for RevokeReadLease {
+    type Result = Result<(), ()>;
+}
+
+/// The read leases this node holds and, as the leader, delegated
+pub struct GetReadLeases;
+
+impl Message for GetReadLeases {
+    type Result = Result<ReadLeaseStatus, ()>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadLeaseStatus {
+    pub held: Option<HeldReadLease>,
+    /// leases which didn't expire yet
+    pub granted: Vec<GrantedReadLease>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeldReadLease {
+    pub leader: NodeId,
+    pub term: u64,
+    /// reads are served once the node applied up to this index
+    pub index: u64,
+    pub remaining_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GrantedReadLease {
+    pub holder: NodeId,
+    pub term: u64,
+    pub remaining_ms: u64,
+}
+
+impl Handler<GrantReadLease> for RaftClient {
+    type Result = ResponseActFuture<Self, (), ()>;
+
+    fn handle(&mut self, msg: GrantReadLease, _ctx: &mut Context<Self>) -> Self::Result {
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(())),
+        };
+        // the lease runs from before the grant is checked, never from after it was sent
+        let received = self.clock.now();
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetGroupMetrics(self.group)))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .and_then(move |metrics, act, _ctx| {
+                    // a deposed leader, or one this node doesn't follow yet
+                    if metrics.current_term != msg.term || metrics.current_leader != Some(msg.leader) {
+                        debug!("Refusing read lease of node {} in term {}", msg.leader, msg.term);
+                        return fut::err(());
+                    }
+
+                    let duration = Duration::from_millis(msg.duration_ms.min(MAX_READ_LEASE_MS));
+                    act.read_lease = Some(ReadLease {
+                        node: msg.leader,
+                        term: msg.term,
+                        index: msg.index,
+                        expires: received + duration,
+                    });
+                    fut::ok(())
+                }),
+        )
+    }
+}
+
+impl Handler<RevokeReadLease> for RaftClient {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, msg: RevokeReadLease, _ctx: &mut Context<Self>) -> Self::Result {
+        let revoked = match self.read_lease {
+            Some(ref lease) => lease.node == msg.leader && lease.term <= msg.term,
+            None => false,
+        };
+        if revoked {
+            debug!("Node {} revoked the read lease", msg.leader);
+            self.read_lease = None;
+        }
+        Ok(())
+    }
+}
+
+impl Handler<GetReadLeases> for RaftClient {
+    type Result = Result<ReadLeaseStatus, ()>;
+
+    fn handle(&mut self, _: GetReadLeases, _ctx: &mut Context<Self>) -> Self::Result {
+        let now = self.clock.now();
+        let remaining = |lease: &ReadLease| {
+            if lease.expires > now {
+                Some(lease.expires.duration_since(now).as_millis() as u64)
+            } else {
+                None
+            }
+        };
+
+        Ok(ReadLeaseStatus {
+            held: self.read_lease.as_ref().and_then(|lease| {
+                remaining(lease).map(|remaining_ms| HeldReadLease {
+                    leader: lease.node,
+                    term: lease.term,
+                    index: lease.index,
+                    remaining_ms,
+                })
+            }),
+            granted: self
+                .lease_grants
+                .values()
+                .filter_map(|lease| {
+                    remaining(lease).map(|remaining_ms| GrantedReadLease {
+                        holder: lease.node,
+                        term: lease.term,
+                        remaining_ms,
+                    })
+                })
+                .collect(),
+        })
+    }
+}
+
+impl RaftClient {
+    /// How long the read leases delegated by this node last, when enabled
+    fn read_lease_duration(&self) -> Option<Duration> {
+        self.config
+            .read_lease_ms
+            .filter(|ms| *ms > 0)
+            .map(|ms| Duration::from_millis(ms.min(MAX_READ_LEASE_MS)))
+    }
+
+    /// Whether this follower may serve a read under its lease
+    fn holds_read_lease(&self, metrics: &RaftMetrics) -> bool {
+        match self.read_lease {
+            Some(ref lease) => {
+                lease.term == metrics.current_term
+                    && metrics.current_leader == Some(lease.node)
+                    && self.clock.now() < lease.expires
+                    && metrics.last_applied >= lease.index
+            }
+            None => false,
+        }
+    }
+
+    /// Warn when nothing was committed over a whole `THROUGHPUT_WINDOW` while client requests
+    /// older than it are still in flight
+    fn detect_stall(&mut self, ctx: &mut Context<Self>) {
+        let now = self.clock.now();
+        let waiting = self
+            .inflight
+            .values()
+            .filter(|inflight| now.duration_since(inflight.received) >= THROUGHPUT_WINDOW)
+            .count();
+        if waiting == 0 {
+            return ();
+        }
+
+        fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetThroughputStats(self.group)))
+            .map_err(|_, _, _| ())
+            .and_then(|res, _act, _ctx| fut::result(res))
+            .map(move |stats, act, _ctx| {
+                if stats.committed == 0 && stats.window_ms >= THROUGHPUT_WINDOW.as_millis() as u64 {
+                    warn!(
+                        "Group {} committed nothing in {}ms while {} client requests are waiting, the cluster is stalled",
+                        act.group, stats.window_ms, waiting
+                    );
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// On the leader, diagnose a membership change stuck in joint consensus or syncing its
+    /// non-voters. Actix-raft refuses any config change while one is in progress, so the stuck
+    /// change can't be rolled back, only completed by the nodes it waits on. Nodes isolated with
+    /// `NodeDisconnect` are left for the operator to restore.
+    fn diagnose_stuck_change(&mut self, ctx: &mut Context<Self>) {
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return (),
+        };
+        let group = self.group;
+
+        fut::wrap_future::<_, Self>(net.send(GetGroupMetrics(group)).join(net.send(GetStuckChange(group))))
+            .map_err(|_, _, _| ())
+            .map(move |(metrics, stuck), act, _ctx| {
+                let stuck = match (metrics, stuck) {
+                    (Ok(ref metrics), Ok(Some(stuck))) if metrics.state == State::Leader => stuck,
+                    // the change completed, or another node leads it now
+                    _ => {
+                        act.stuck_change = None;
+                        return ();
+                    }
+                };
+                let phase = if stuck.syncing { "syncing its non-voters" } else { "in joint consensus" };
+                warn!("Membership change of group {} stuck {} for {}ms: {:?}", group, phase, stuck.changing_ms, stuck);
+
+                let mut waiting_for = stuck.unreachable.iter().chain(stuck.stalled.iter()).cloned().collect::<Vec<_>>();
+                waiting_for.sort();
+                waiting_for.dedup();
+                act.stuck_change = Some(if !stuck.isolated.is_empty() {
+                    format!("waiting for {:?}, isolated with NodeDisconnect, restore them to complete the change", stuck.isolated)
+                } else if stuck.new_quorum && stuck.stalled.is_empty() {
+                    "completing, the new voters have a quorum".to_owned()
+                } else {
+                    format!(
+                        "waiting for {:?}, the change can't be rolled back as actix-raft refuses config changes while one is in progress",
+                        waiting_for
+                    )
+                });
+            })
+            .spawn(ctx);
+    }
+
+    /// On the leader, propose a `Tick` once the earliest expiring counter is due, so every
+    /// replica removes it at the same index
+    fn expire_counters(&mut self, ctx: &mut Context<Self>) {
+        let (net, storage) = match (&self.net, &self.storage) {
+            (Some(net), Some(storage)) if !self.expiring => (net.clone(), storage.clone()),
+            _ => return (),
+        };
+        self.expiring = true;
+
+        fut::wrap_future::<_, Self>(net.send(GetGroupMetrics(self.group)))
+            .map_err(|_, _, _| ())
+            .and_then(|res, _act, _ctx| fut::result(res))
+            .and_then(move |metrics, _act, _ctx| {
+                let leading = metrics.state == State::Leader;
+                fut::wrap_future::<_, Self>(storage.send(GetNextExpiry))
+                    .map_err(|_, _, _| ())
+                    .and_then(move |res, _act, _ctx| fut::result(res.map_err(|_| ()).map(|next| (leading, next))))
+            })
+            .and_then(|(leading, next), _act, ctx| {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+                match next {
+                    Some(at) if leading && at <= now => fut::Either::A(
+                        fut::wrap_future::<_, Self>(ctx.address().send(ClientRequest::new(MemoryStorageData::Tick(now))))
+                            .map_err(|_, _, _| ())
+                            .map(|res, act, _ctx| {
+                                if let Err(err) = res {
+                                    println!("[{}] Failed to expire counters: {:?}", act.id, err);
+                                }
+                            }),
+                    ),
+                    _ => fut::Either::B(fut::ok(())),
+                }
+            })
+            .then(|_, act, _ctx| {
+                act.expiring = false;
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Drop a lease outlived by its leader and, on the leader, renew the leases of the holders.
+    /// A new term or a change of the voters revokes the outstanding leases first.
+    fn renew_read_leases(&mut self, ctx: &mut Context<Self>) {
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return (),
+        };
+
+        fut::wrap_future::<_, Self>(net.send(GetGroupMetrics(self.group)))
+            .map_err(|_, _, _| ())
+            .and_then(|res, _act, _ctx| fut::result(res))
+            .and_then(move |metrics, act, ctx| {
+                let outlived = match act.read_lease {
+                    Some(ref lease) => lease.term != metrics.current_term || metrics.current_leader != Some(lease.node),
+                    None => false,
+                };
+                if outlived {
+                    act.read_lease = None;
+                }
+
+                let config = &metrics.membership_config;
+                let mut voters = config.members.clone();
+                voters.sort();
+                let epoch = (metrics.current_term, voters);
+                let leading = metrics.state == State::Leader;
+                if !leading || act.lease_epoch.as_ref() != Some(&epoch) {
+                    act.revoke_read_leases(ctx);
+                }
+                if !leading {
+                    act.lease_epoch = None;
+                    return fut::Either::A(fut::ok(()));
+                }
+                act.lease_epoch = Some(epoch);
+
+                // the voters are about to change, leases wait for the new config
+                if config.is_in_joint_consensus {
+                    return fut::Either::A(fut::ok(()));
+                }
+                let holders: Vec<NodeId> = config
+                    .members
+                    .iter()
+                    .cloned()
+                    .filter(|id| *id != act.id)
+                    .filter(|id| act.config.read_lease_holders.is_empty() || act.config.read_lease_holders.contains(id))
+                    .collect();
+                let (term, index) = (metrics.current_term, metrics.last_applied);
+                // a lease only runs from when a majority last acknowledged the leadership
+                fut::Either::B(
+                    fut::wrap_future::<_, Self>(net.send(GetLeadershipConfirmed(act.group)))
+                        .map_err(|_, _, _| ())
+                        .and_then(|res, _act, _ctx| fut::result(res))
+                        .map(move |confirmed, act: &mut Self, ctx| match confirmed {
+                            Some(confirmed) => {
+                                for holder in holders {
+                                    act.grant_read_lease(holder, term, index, confirmed, ctx);
+                                }
+                            }
+                            None => debug!("Group {} leadership not acknowledged by a majority, no read leases", act.group),
+                        }),
+                )
+            })
+            .spawn(ctx);
+    }
+
+    /// Grant the lease running for `duration` from `confirmed`, when a majority of the voters last
+    /// acknowledged the leadership. The holder counts the rest of it from when it received it.
+    fn grant_read_lease(&mut self, holder: NodeId, term: u64, index: u64, confirmed: Instant, ctx: &mut Context<Self>) {
+        let duration = match self.read_lease_duration() {
+            Some(duration) => duration,
+            None => return (),
+        };
+        let (expires, now) = (confirmed + duration, self.clock.now());
+        if expires <= now {
+            return ();
+        }
+        let grant = GrantReadLease {
+            leader: self.id,
+            term,
+            index,
+            duration_ms: expires.duration_since(now).as_millis() as u64,
+        };
+        let group = self.group;
+
+        fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetNodeById(holder)))
+            .map_err(|_, _, _| ())
+            .and_then(|res, _act, _ctx| fut::result(res))
+            .and_then(move |node, act: &mut Self, _ctx| {
+                fut::wrap_future::<_, Self>(node.send(SendGroupMessage(group, grant)).timeout_on(&act.clock, duration))
+                    .map_err(|_, _, _| ())
+                    .and_then(|res, _act, _ctx| fut::result(res))
+            })
+            .map(move |_, act, _ctx| {
+                // revoked meanwhile
+                if act.lease_epoch.as_ref().map(|epoch| epoch.0) == Some(term) {
+                    act.lease_grants.insert(holder, ReadLease { node: holder, term, index, expires });
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn revoke_read_leases(&mut self, ctx: &mut Context<Self>) {
+        let term = match self.lease_epoch {
+            Some((term, _)) => term,
+            None => return (),
+        };
+        let holders: Vec<NodeId> = self.lease_grants.drain().map(|(holder, _)| holder).collect();
+
+        for holder in holders {
+            let (group, revoke) = (self.group, RevokeReadLease { leader: self.id, term });
+            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetNodeById(holder)))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .and_then(move |node, _act, _ctx| {
+                    fut::wrap_future::<_, Self>(node.send(SendGroupMessage(group, revoke)))
+                        .map_err(|_, _, _| ())
+                        .map(|_, _act, _ctx| ())
+                })
+                .spawn(ctx);
+        }
+    }
+}
+
+impl Handler<DrainApply> for RaftClient {
+    type Result = ResponseActFuture<Self, u64, MemoryStorageError>;
+
+    fn handle(&mut self, msg: DrainApply, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.storage {
+            Some(ref storage) => Box::new(
+                fut::wrap_future::<_, Self>(storage.send(msg))
+                    .map_err(|_, _, _| MemoryStorageError)
+                    .and_then(|res, _act, _ctx| fut::result(res)),
+            ),
+            None => Box::new(fut::err(MemoryStorageError)),
+        }
+    }
+}
+
+impl Handler<GetClusterMembers> for RaftClient {
+    type Result = ResponseActFuture<Self, Vec<ClusterMember>, ()>;
+
+    fn handle(&mut self, msg: GetClusterMembers, _ctx: &mut Context<Self>) -> Self::Result {
+        let (storage, net) = match (self.storage.as_ref(), self.net.as_ref()) {
+            (Some(storage), Some(net)) => (storage.clone(), net.clone()),
+            _ => return Box::new(fut::err(())),
+        };
+        Box::new(
+            fut::wrap_future::<_, Self>(storage.send(msg))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .and_then(move |members, _act, _ctx| {
+                    // the storage doesn't know the addresses, a lookup failure leaves them unset
+                    fut::wrap_future::<_, Self>(net.send(GetNodes)).then(move |res, _act, _ctx| {
+                        let nodes = match res {
+                            Ok(Ok(nodes)) => nodes,
+                            _ => return fut::ok(members),
+                        };
+                        fut::ok(
+                            members
+                                .into_iter()
+                                .map(|member| {
+                                    let info = nodes.get(&member.id);
+                                    ClusterMember {
+                                        http_addr: info.map(|info| info.public_addr.clone()),
+                                        metadata: info.map(|info| info.metadata.clone()).unwrap_or_default(),
+                                        ..member
+                                    }
+                                })
+                                .collect(),
+                        )
+                    })
+                }),
+        )
+    }
+}
+
+impl Handler<ClearDiagnostics> for RaftClient {
+    type Result = ResponseActFuture<Self, (), ()>;
+
+    fn handle(&mut self, msg: ClearDiagnostics, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(ref net) = self.net {
+            net.do_send(ClearElectionStats(self.group));
+        }
+        match self.storage {
+            Some(ref storage) => Box::new(
+                fut::wrap_future::<_, Self>(storage.send(msg))
+                    .map_err(|_, _, _| ())
+                    .and_then(|res, _act, _ctx| fut::result(res)),
+            ),
+            None => Box::new(fut::err(())),
+        }
+    }
+}
+
+impl Handler<GetStorageStats> for RaftClient {
+    type Result = ResponseActFuture<Self, StorageStats, ()>;
+
+    fn handle(&mut self, msg: GetStorageStats, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.storage {
+            Some(ref storage) => Box::new(
+                fut::wrap_future::<_, Self>(storage.send(msg))
+                    .map_err(|_, _, _| ())
+                    .and_then(|res, _act, _ctx| fut::result(res)),
+            ),
+            None => Box::new(fut::err(())),
+        }
+    }
+}
+
+impl Handler<GetCounter> for RaftClient {
+    type Result = ResponseActFuture<Self, Option<i64>, ReadError>;
+
+    fn handle(&mut self, msg: GetCounter, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.storage {
+            Some(ref storage) => Box::new(
+                fut::wrap_future::<_, Self>(storage.send(msg))
+                    .map_err(|_, _, _| ReadError::Unavailable)
+                    .and_then(|res, _act, _ctx| fut::result(res)),
+            ),
+            None => Box::new(fut::err(ReadError::Unavailable)),
+        }
+    }
+}
+
+/// How stale a read served by this replica may be, bounds left unset aren't checked
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Staleness {
+    /// most entries this replica may have applied fewer of than the leader
+    pub max_entries: Option<u64>,
+    /// longest time since this replica last heard of the leader, in milliseconds
+    pub max_age_ms: Option<u64>,
+    /// serve the read only under a read lease delegated by the leader, see `GrantReadLease`
+    #[serde(default)]
+    pub lease: bool,
+}
+
+/// Get the cluster members from this replica when it is within the staleness bound, from the
+/// leader otherwise
+pub struct ReadClusterMembers(pub Staleness);
+
+impl Message for ReadClusterMembers {
+    type Result = Result<Vec<ClusterMember>, ()>;
+}
+
+impl RaftClient {
+    /// Whether this replica is within the staleness bound, fails when no leader is known
+    fn within_staleness(&mut self, bound: Staleness) -> ResponseActFuture<Self, bool, ()> {
+        let (max_entries, max_age_ms, lease) = (bound.max_entries, bound.max_age_ms, bound.lease);
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(())),
+        };
+        let group = self.group;
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetElectionState(group)))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .and_then(move |state, act, _ctx| -> ResponseActFuture<Self, bool, ()> {
+                    let leader = match state.metrics.current_leader {
+                        Some(leader) if leader != act.id => leader,
+                        Some(_) => return Box::new(fut::ok(true)),
+                        None => return Box::new(fut::err(())),
+                    };
+                    if lease {
+                        return Box::new(fut::ok(act.holds_read_lease(&state.metrics)));
+                    }
+
+                    let fresh = max_age_ms
+                        .map(|ms| match state.leader_seen_at {
+                            Some(at) => act.clock.now().duration_since(at) <= Duration::from_millis(ms),
+                            None => false,
+                        })
+                        .unwrap_or(true);
+                    let max_entries = match max_entries {
+                        Some(max_entries) if fresh => max_entries,
+                        _ => return Box::new(fut::ok(fresh)),
+                    };
+
+                    // the leader's applied index stands in for the commit index
+                    let applied = state.metrics.last_applied;
+                    Box::new(
+                        fut::wrap_future::<_, Self>(net.send(GetNodeById(leader)))
+                            .map_err(|_, _, _| ())
+                            .and_then(|res, _act, _ctx| fut::result(res))
+                            .and_then(move |node, act: &mut Self, _ctx| {
+                                fut::wrap_future::<_, Self>(
+                                    node.send(SendRemoteMessage(GetLastApplied(group)))
+                                        .timeout_on(&act.clock, Duration::from_secs(1)),
+                                )
+                                    .then(move |res, _act, _ctx| match res {
+                                        Ok(Ok(leader_applied)) => fut::ok(leader_applied.saturating_sub(applied) <= max_entries),
+                                        _ => fut::ok(false),
+                                    })
+                            }),
+                    )
+                }),
+        )
+    }
+
+    /// Serve a read on the leader, `CatchingUp` when no leader other than this node is known in time
+    fn forward_read(&mut self, msg: ReadQuery) -> ResponseActFuture<Self, Option<i64>, ReadError> {
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(ReadError::Unavailable)),
+        };
+        let group = self.group;
+        // the leader serves it locally, it is never forwarded again
+        let read = ReadQuery {
+            staleness: Staleness::default(),
+            forwarded: true,
+            ..msg
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetGroupLeader(group)).timeout_on(&self.clock, READ_FORWARD_TIMEOUT))
+                .then(|res, _act, _ctx| match res {
+                    Ok(Ok(leader)) => fut::ok(leader),
+                    _ => fut::err(ReadError::CatchingUp),
+                })
+                .and_then(move |leader, act: &mut Self, ctx| -> ResponseActFuture<Self, Option<i64>, ReadError> {
+                    act.note_leader(leader, ctx);
+                    if leader == act.id {
+                        return Box::new(fut::err(ReadError::CatchingUp));
+                    }
+
+                    debug!("Forwarding the read of {:?} to the leader {}", read.key, leader);
+                    Box::new(
+                        fut::wrap_future::<_, Self>(net.send(GetNodeById(leader)))
+                            .then(|res, _act, _ctx| match res {
+                                Ok(Ok(node)) => fut::ok(node),
+                                _ => fut::err(ReadError::Unavailable),
+                            })
+                            .and_then(move |node, act: &mut Self, _ctx| {
+                                fut::wrap_future::<_, Self>(
+                                    node.send(SendGroupMessage(group, read)).timeout_on(&act.clock, READ_FORWARD_TIMEOUT),
+                                )
+                                .then(|res, _act, _ctx| match res {
+                                    Ok(res) => fut::result(res),
+                                    Err(_) => fut::err(ReadError::Unavailable),
+                                })
+                            }),
+                    )
+                }),
+        )
+    }
+}
+
+impl Handler<ReadClusterMembers> for RaftClient {
+    type Result = ResponseActFuture<Self, Vec<ClusterMember>, ()>;
+
+    fn handle(&mut self, msg: ReadClusterMembers, ctx: &mut Context<Self>) -> Self::Result {
+        let bound = msg.0;
+        if bound.max_entries.is_none() && bound.max_age_ms.is_none() && !bound.lease {
+            return <Self as Handler<GetClusterMembers>>::handle(self, GetClusterMembers, ctx);
+        }
+
+        Box::new(
+            self.within_staleness(bound.clone())
+                .and_then(move |fresh, act, ctx| {
+                    if fresh {
+                        return fut::Either::A(<Self as Handler<GetClusterMembers>>::handle(act, GetClusterMembers, ctx));
+                    }
+
+                    debug!("Replica is staler than {:?}, reading the members from the leader", bound);
+                    fut::Either::B(
+                        fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetGroupLeader(act.group)))
+                            .map_err(|_, _, _| ())
+                            .and_then(|res, _act, _ctx| fut::result(res))
+                            .and_then(|leader, act, ctx| {
+                                act.note_leader(leader, ctx);
+                                fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetNodeById(leader)))
+                                    .map_err(|_, _, _| ())
+                                    .and_then(|res, _act, _ctx| fut::result(res))
+                            })
+                            .and_then(|node, act, _ctx| {
+                                fut::wrap_future::<_, Self>(node.send(SendGroupMessage(act.group, GetClusterMembers)))
+                                    .map_err(|_, _, _| ())
+                                    .and_then(|res, _act, _ctx| fut::result(res))
+                            }),
+                    )
+                }),
+        )
+    }
+}
+
+/// Read a counter, from this replica when it is within the staleness bound and from the leader
+/// otherwise.
+///
+/// Without a bound the read is eventually consistent and served locally, unless the replica is
+/// installing a snapshot: its state machine is torn meanwhile, so the read goes to the leader, and
+/// fails with `ReadError::CatchingUp` when there is none to go to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadQuery {
+    pub key: String,
+    #[serde(default)]
+    pub staleness: Staleness,
+    /// set on a read forwarded to the leader, which serves it locally whatever its state
+    #[serde(default)]
+    pub forwarded: bool,
+}
+
+impl ReadQuery {
+    /// Eventually consistent read of `key`
+    pub fn new(key: String) -> Self {
+        ReadQuery {
+            key,
+            staleness: Staleness::default(),
+            forwarded: false,
+        }
+    }
+}
+
+impl Message for ReadQuery {
+    type Result = Result<Option<i64>, ReadError>;
+}
+
+impl Handler<ReadQuery> for RaftClient {
+    type Result = ResponseActFuture<Self, Option<i64>, ReadError>;
+
+    fn handle(&mut self, msg: ReadQuery, _ctx: &mut Context<Self>) -> Self::Result {
+        let bound = msg.staleness.clone();
+        let fresh: ResponseActFuture<Self, bool, ReadError> =
+            if msg.forwarded || (bound.max_entries.is_none() && bound.max_age_ms.is_none() && !bound.lease) {
+                Box::new(fut::ok(true))
+            } else {
+                Box::new(self.within_staleness(bound).map_err(|_, _, _| ReadError::Unavailable))
+            };
+
+        Box::new(fresh.and_then(move |fresh, act, _ctx| -> ResponseActFuture<Self, Option<i64>, ReadError> {
+            if !fresh {
+                return act.forward_read(msg);
+            }
+            let storage = match act.storage {
+                Some(ref storage) => storage.clone(),
+                None => return Box::new(fut::err(ReadError::Unavailable)),
+            };
+
+            Box::new(
+                fut::wrap_future::<_, Self>(storage.send(GetCounter(msg.key.clone())))
+                    .map_err(|_, _, _| ReadError::Unavailable)
+                    .and_then(move |res, act, _ctx| -> ResponseActFuture<Self, Option<i64>, ReadError> {
+                        match res {
+                            Err(ReadError::CatchingUp) if !msg.forwarded => act.forward_read(msg),
+                            res => Box::new(fut::result(res)),
+                        }
+                    }),
+            )
+        }))
+    }
+}
+
+impl Handler<ResyncRing> for RaftClient {
+    type Result = ResponseActFuture<Self, Vec<NodeId>, ()>;
+
+    fn handle(&mut self, msg: ResyncRing, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.storage {
+            Some(ref storage) => Box::new(
+                fut::wrap_future::<_, Self>(storage.send(msg))
+                    .map_err(|_, _, _| ())
+                    .and_then(|res, _act, _ctx| fut::result(res)),
+            ),
+            None => Box::new(fut::err(())),
+        }
+    }
+}
+
+impl Handler<ExportLog> for RaftClient {
+    type Result = ResponseActFuture<Self, u64, MemoryStorageError>;
+
+    fn handle(&mut self, msg: ExportLog, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.storage {
+            Some(ref storage) => Box::new(
+                fut::wrap_future::<_, Self>(storage.send(msg))
+                    .map_err(|_, _, _| MemoryStorageError)
+                    .and_then(|res, _act, _ctx| fut::result(res)),
+            ),
+            None => Box::new(fut::err(MemoryStorageError)),
+        }
+    }
+}
+
+impl Handler<ExportLogIfChanged> for RaftClient {
+    type Result = ResponseActFuture<Self, Option<u64>, MemoryStorageError>;
+
+    fn handle(&mut self, msg: ExportLogIfChanged, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.storage {
+            Some(ref storage) => Box::new(
+                fut::wrap_future::<_, Self>(storage.send(msg))
+                    .map_err(|_, _, _| MemoryStorageError)
+                    .and_then(|res, _act, _ctx| fut::result(res)),
+            ),
+            None => Box::new(fut::err(MemoryStorageError)),
+        }
+    }
+}
+
+/// Fetch up to `max` applied entries starting at index `from`, observers follow the log with it
+#[derive(Serialize, Deserialize)]
+pub struct FetchLog {
+    pub from: u64,
+    pub max: u64,
+}
+
+impl Message for FetchLog {
+    type Result = Result<Vec<Entry>, ReadError>;
+}
+
+impl Handler<FetchLog> for RaftClient {
+    type Result = ResponseActFuture<Self, Vec<Entry>, ReadError>;
+
+    fn handle(&mut self, msg: FetchLog, _ctx: &mut Context<Self>) -> Self::Result {
+        let fetch = GetAppliedEntries {
+            from: msg.from,
+            max: msg.max.min(OBSERVER_FETCH_SIZE),
+        };
+
+        match self.storage {
+            Some(ref storage) => Box::new(
+                fut::wrap_future::<_, Self>(storage.send(fetch))
+                    .map_err(|_, _, _| ReadError::Unavailable)
+                    .and_then(|res, _act, _ctx| fut::result(res.and_then(fit_frame))),
+            ),
+            None => Box::new(fut::err(ReadError::Unavailable)),
+        }
+    }
+}
+
+/// The entries of a `FetchLog` reply which fit its frame, the caller fetches the rest from the
+/// last one returned. Fails when not even the first one fits.
+fn fit_frame(mut entries: Vec<Entry>) -> Result<Vec<Entry>, ReadError> {
+    let empty: Result<Vec<Entry>, ReadError> = Ok(vec![]);
+    let mut size = result_frame_overhead() + framed_len(&empty);
+    let mut fitting = 0;
+    for entry in entries.iter() {
+        // and the comma separating it
+        size += framed_len(entry) + 1;
+        if size > MAX_FRAME_SIZE {
+            break;
+        }
+        fitting += 1;
+    }
+    match entries.first() {
+        Some(first) if fitting == 0 => Err(ReadError::EntryTooLarge(first.index)),
+        _ => {
+            entries.truncate(fitting);
+            Ok(entries)
+        }
+    }
+}
+
+impl RaftClient {
+    /// Pull the entries applied since the last poll from a member and apply them locally
+    fn poll_log(&mut self, ctx: &mut Context<Self>) {
+        let source = match self.observer {
+            Some(ref mut observer) if !observer.polling && !observer.sources.is_empty() => {
+                observer.polling = true;
+                observer.sources[observer.next % observer.sources.len()]
+            }
+            _ => return (),
+        };
+
+        fut::wrap_future::<_, Self>(self.storage.as_ref().unwrap().send(GetApplyState))
+            .map_err(|_, _, _| ())
+            .and_then(|res, _act, _ctx| fut::result(res))
+            .and_then(move |state, act, _ctx| {
+                // a source installing a snapshot is skipped like an unreachable one
+                act.fetch_log(source, state.last_applied + 1).map_err(move |err, _act, _ctx| {
+                    if let Some(err) = err {
+                        debug!("Node {} refused to serve the log: {:?}", source, err);
+                    }
+                })
+            })
+            .and_then(|entries, act, _ctx| {
+                fut::wrap_future::<_, Self>(act.storage.as_ref().unwrap().send(ApplyObserved(entries)))
+                    .map_err(|_, _, _| ())
+                    .and_then(|res, _act, _ctx| fut::result(res.map_err(|_| ())))
+            })
+            .then(move |res, act, _ctx| {
+                if let Some(ref mut observer) = act.observer {
+                    observer.polling = false;
+                    if res.is_err() {
+                        debug!("Observer failed to fetch the log from {}, trying the next node", source);
+                        observer.next += 1;
+                    }
+                }
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+/// Explain the election state of this node, e.g. why it is not the leader
+pub struct LeaderDiagnostics;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeaderDiagnosticsReport {
+    pub role: String,
+    pub term: u64,
+    pub current_leader: Option<NodeId>,
+    /// vote cast in the current term
+    pub voted_for: Option<NodeId>,
+    /// since the metrics last named another node as leader, at metrics resolution
+    pub last_leader_contact_ms: Option<u64>,
+    pub last_election: Option<ElectionResult>,
+    /// leaders client requests aren't forwarded to until they reconnect
+    pub suspect_leaders: Vec<NodeId>,
+    /// what the last membership change found stuck waits on
+    pub stuck_change: Option<String>,
+    /// membership changes applied to the ring, unset for groups which don't own it
+    pub membership_epoch: Option<MembershipEpoch>,
+}
+
+impl Message for LeaderDiagnostics {
+    type Result = Result<LeaderDiagnosticsReport, ()>;
+}
+
+impl Handler<LeaderDiagnostics> for RaftClient {
+    type Result = ResponseActFuture<Self, LeaderDiagnosticsReport, ()>;
+
+    fn handle(&mut self, _: LeaderDiagnostics, _ctx: &mut Context<Self>) -> Self::Result {
+        let (net, storage) = match (self.net.as_ref(), self.storage.as_ref()) {
+            (Some(net), Some(storage)) => (net.clone(), storage.clone()),
+            _ => return Box::new(fut::err(())),
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetElectionState(self.group)))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .and_then(move |state, _act, _ctx| {
+                    let epoch = storage.send(GetMembershipEpoch);
+                    fut::wrap_future::<_, Self>(storage.send(GetVote).join(epoch))
+                        .map_err(|_, _, _| ())
+                        .and_then(|(vote, epoch), _act, _ctx| fut::result(vote.map(|vote| (vote, epoch.ok()))))
+                        .map(move |((term, voted_for), epoch), act, _ctx| LeaderDiagnosticsReport {
+                            role: format!("{:?}", state.metrics.state),
+                            term: state.metrics.current_term,
+                            current_leader: state.metrics.current_leader,
+                            // the hard state may already be in a newer term than the metrics
+                            voted_for: if term == state.metrics.current_term { voted_for } else { None },
+                            last_leader_contact_ms: state.leader_seen_at.map(|at| act.clock.now().duration_since(at).as_millis() as u64),
+                            last_election: state.last_election,
+                            suspect_leaders: act.suspect_leaders.keys().cloned().collect(),
+                            stuck_change: act.stuck_change.clone(),
+                            membership_epoch: epoch.filter(|_| act.group == DEFAULT_GROUP),
+                        })
+                }),
+        )
+    }
+}
+
+/// One coherent sample of the metrics of the group on this node, for tools which would otherwise
+/// scrape several endpoints. The network side is sampled in a single message and the client side
+/// as it arrives, so the parts agree with each other.
+pub struct MetricsSnapshot;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetricsSnapshotReport {
+    pub id: NodeId,
+    pub group: GroupId,
+    pub role: String,
+    pub term: u64,
+    pub current_leader: Option<NodeId>,
+    pub last_log_index: u64,
+    pub last_applied: u64,
+    pub voters: Vec<NodeId>,
+    pub non_voters: Vec<NodeId>,
+    pub in_joint_consensus: bool,
+    /// replication progress per follower, empty unless this node leads
+    pub followers: Vec<FollowerProgress>,
+    /// peers with an open connection
+    pub connected: Vec<NodeId>,
+    pub inflight: usize,
+    pub throughput: Option<ThroughputStats>,
+    pub elections: ElectionStats,
+    /// unix time in milliseconds the sample was taken at
+    pub sampled_at: u64,
+}
+
+impl Message for MetricsSnapshot {
+    type Result = Result<MetricsSnapshotReport, ()>;
+}
+
+impl Handler<MetricsSnapshot> for RaftClient {
+    type Result = ResponseActFuture<Self, MetricsSnapshotReport, ()>;
+
+    fn handle(&mut self, _: MetricsSnapshot, _ctx: &mut Context<Self>) -> Self::Result {
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(())),
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetGroupSample(self.group)))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .map(|sample, act, _ctx| {
+                    let metrics = sample.metrics;
+                    let config = metrics.membership_config;
+                    MetricsSnapshotReport {
+                        id: act.id,
+                        group: act.group,
+                        role: format!("{:?}", metrics.state),
+                        term: metrics.current_term,
+                        current_leader: metrics.current_leader,
+                        last_log_index: metrics.last_log_index,
+                        last_applied: metrics.last_applied,
+                        voters: config.members,
+                        non_voters: config.non_voters,
+                        in_joint_consensus: config.is_in_joint_consensus,
+                        followers: sample.followers,
+                        connected: sample.connected,
+                        inflight: act.inflight.len(),
+                        throughput: sample.throughput,
+                        elections: sample.elections,
+                        sampled_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+                    }
+                }),
+        )
+    }
+}
+
+/// Last leader this node resolved, for a client request, a membership change or a read, with the
+/// HTTP address clients can reach it at once it was looked up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LeaderHint {
+    pub id: NodeId,
+    pub addr: Option<String>,
+}
+
+/// Get the last known leader, so a client can connect to it directly next time
+pub struct GetLeaderHint;
+
+impl Message for GetLeaderHint {
+    type Result = Result<Option<LeaderHint>, ()>;
+}
+
+impl Handler<GetLeaderHint> for RaftClient {
+    type Result = Result<Option<LeaderHint>, ()>;
+
+    fn handle(&mut self, _: GetLeaderHint, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.leader_hint.clone())
+    }
+}
+
+impl RaftClient {
+    /// Cache a resolved leader, called wherever the leader of the group is looked up. Its address
+    /// is looked up when the leader changed.
+    fn note_leader(&mut self, leader: NodeId, ctx: &mut Context<Self>) {
+        if self.leader_hint.as_ref().map(|hint| hint.id) == Some(leader) {
+            return;
+        }
+        self.leader_hint = Some(LeaderHint { id: leader, addr: None });
+
+        fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetHttpAddr(leader)))
+            .map_err(|_, _, _| ())
+            .map(move |addr, act, _ctx| match (addr, act.leader_hint.as_mut()) {
+                (Ok(addr), Some(hint)) if hint.id == leader => hint.addr = Some(addr),
+                _ => (),
+            })
+            .spawn(ctx);
+    }
+}
+
+/// Get the last internal error of the group, talking to the raft or storage actors or
+/// raised by the storage itself (apply, divergence and snapshot failures), and when it happened
+pub struct GetLastError;
+
+impl Message for GetLastError {
+    type Result = Result<Option<(Instant, String)>, ()>;
+}
+
+impl Handler<GetLastError> for RaftClient {
+    type Result = ResponseActFuture<Self, Option<(Instant, String)>, ()>;
+
+    fn handle(&mut self, _: GetLastError, _ctx: &mut Context<Self>) -> Self::Result {
+        let storage = match self.storage {
+            Some(ref storage) => storage,
+            None => return Box::new(fut::ok(self.last_error.clone())),
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(storage.send(GetApplyState))
+                .then(|res, act: &mut Self, _ctx| {
+                    let storage_error = match res {
+                        Ok(Ok(state)) => state.last_error,
+                        _ => None,
+                    };
+                    fut::ok(act.newest_error(storage_error))
+                }),
+        )
+    }
+}
+
+/// A snapshot of a client request which has not reached a terminal result yet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InflightRequest {
+    pub id: CorrelationId,
+    pub attempts: u32,
+    pub age_ms: u64,
+    pub target: Option<NodeId>,
+}
+
+/// List all client requests currently in flight on this node
+pub struct ListInflight;
+
+impl Message for ListInflight {
+    type Result = Result<Vec<InflightRequest>, ()>;
+}
+
+impl Handler<ListInflight> for RaftClient {
+    type Result = Result<Vec<InflightRequest>, ()>;
+
+    fn handle(&mut self, _: ListInflight, _ctx: &mut Context<Self>) -> Self::Result {
+        let now = self.clock.now();
+
+        Ok(self
+            .inflight
+            .iter()
+            .map(|(id, inflight)| {
+                let age = now.duration_since(inflight.received);
+                InflightRequest {
+                    id: *id,
+                    attempts: inflight.attempts,
+                    age_ms: age.as_secs() * 1000 + u64::from(age.subsec_millis()),
+                    target: inflight.target,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Stop retrying an in-flight client request, it fails with `Cancelled` once its current attempt
+/// failed instead of being resent. An attempt already proposed may still commit, the request then
+/// succeeds.
+pub struct CancelRequest(pub CorrelationId);
+
+impl Message for CancelRequest {
+    type Result = Result<(), CancelError>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CancelError {
+    /// The request is unknown or already completed, e.g. committed
+    NotPending(CorrelationId),
+}
+
+impl Handler<CancelRequest> for RaftClient {
+    type Result = Result<(), CancelError>;
+
+    fn handle(&mut self, msg: CancelRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        let id = msg.0;
+
+        // a queued request was never proposed, it fails right away
+        if let Some(pos) = self.leader_queue.iter().position(|queued| queued.request.id == id) {
+            let queued = self.leader_queue.remove(pos).unwrap();
+            let _ = queued.tx.send(Err(RaftClientError::Cancelled { id }));
+            println!("[{}] Cancelled queued client request", id);
+            return Ok(());
+        }
+
+        // it stays in flight until its attempt completes, which then fails as cancelled
+        if self.inflight.contains_key(&id) {
+            println!("[{}] Cancelling client request", id);
+            self.cancelled.insert(id);
+            Ok(())
+        } else {
+            Err(CancelError::NotPending(id))
+        }
+    }
+}
+
+/// A client payload forwarded to the leader together with its correlation id
+#[derive(Serialize, Deserialize)]
+pub struct ForwardClientRequest {
+    pub id: CorrelationId,
+    pub payload: Payload,
+    #[serde(default)]
+    pub priority: Priority,
+    /// random peers the request was forwarded to while no leader was known
+    #[serde(default)]
+    pub blind_hops: u8,
+}
+
+impl Message for ForwardClientRequest {
+    type Result = Result<ClientPayloadResponse<MemoryStorageResponse>, ForwardError>;
+}
+
+/// Why the node a client request was forwarded to failed it. Untagged, so the errors of raft keep
+/// the encoding they had before the other failures were reported to the forwarding node.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ForwardError {
+    Raft(ClientError<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>),
+    /// fewer voters than `min_voters_for_writes` are healthy on the leader
+    InsufficientReplication { healthy: usize, required: usize },
+}
+
+impl RaftClient {
+    /// Fails with the healthy and required voters when fewer than `min_voters_for_writes` voters
+    /// are healthy. High priority requests, e.g. membership changes, are let through so a
+    /// degraded cluster can still grow.
+    fn check_replication(&self, priority: Priority) -> ResponseActFuture<Self, (), (usize, usize)> {
+        let required = match self.config.min_voters_for_writes {
+            Some(required) if priority != Priority::High => required,
+            _ => return Box::new(fut::ok(())),
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetVoterHealth(self.group)))
+                .map_err(move |_, _, _| (0, required))
+                .and_then(move |res, _act, _ctx| match res {
+                    Ok(ref health) if !health.leader || health.healthy >= required => fut::ok(()),
+                    Ok(health) => fut::err((health.healthy, required)),
+                    Err(_) => fut::err((0, required)),
+                }),
+        )
+    }
+}
+
+/// A client request relayed by a read-only node to a read-write one, which proposes it like its own
+#[derive(Serialize, Deserialize)]
+pub struct RelayClientRequest {
+    pub id: CorrelationId,
+    pub data: MemoryStorageData,
+    pub priority: Priority,
+    /// resolve once committed instead of applied
+    pub committed: bool,
+}
+
+impl Message for RelayClientRequest {
+    type Result = ClientRequestResult;
+}
+
+impl RaftClient {
+    /// Hand a write received by a read-only node to a read-write peer, picked by correlation id
+    /// so that writes spread over the peers
+    fn relay(&mut self, msg: ClientRequest) -> ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError> {
+        let (id, group) = (msg.id, self.group);
+        let relay = RelayClientRequest {
+            id,
+            data: msg.data,
+            priority: msg.priority,
+            committed: match msg.mode {
+                ResponseMode::Committed => true,
+                _ => false,
+            },
+        };
+
+        Box::new(
+            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(GetNodes))
+                .map_err(move |_, _, _| RaftClientError::ReadOnly { id })
+                .and_then(move |res, act, _ctx| {
+                    let mut writable: Vec<NodeId> = res
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|(node, info)| *node != act.id && !info.readonly)
+                        .map(|(node, _)| node)
+                        .collect();
+                    writable.sort();
+                    if writable.is_empty() {
+                        println!("[{}] Rejecting client request, no read-write node is known", id);
+                        return fut::Either::A(fut::err(RaftClientError::ReadOnly { id }));
+                    }
+
+                    let target = writable[(id % writable.len() as u64) as usize];
+                    println!("[{}] Relaying client request to read-write node {}", id, target);
+                    fut::Either::B(
+                        fut::wrap_future::<_, Self>(act.net.as_ref().unwrap().send(GetNodeById(target)))
+                            .map_err(|_, _, _| ())
+                            .and_then(|res, _act, _ctx| fut::result(res))
+                            .and_then(move |node, _act, _ctx| {
+                                fut::wrap_future::<_, Self>(node.send(SendGroupMessage(group, relay))).map_err(|_, _, _| ())
+                            })
+                            .map_err(move |_, _, _| RaftClientError::ReadOnly { id })
+                            .and_then(|res, _act, _ctx| fut::result(res)),
+                    )
+                }),
+        )
+    }
+}
+
+impl Handler<RelayClientRequest> for RaftClient {
+    type Result = ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError>;
+
+    fn handle(&mut self, msg: RelayClientRequest, ctx: &mut Context<Self>) -> Self::Result {
+        // relays aren't passed on, the sender picks another node
+        if self.config.readonly {
+            return Box::new(fut::err(RaftClientError::ReadOnly { id: msg.id }));
+        }
+
+        let request = ClientRequest {
+            id: msg.id,
+            data: msg.data,
+            priority: msg.priority,
+            mode: if msg.committed { ResponseMode::Committed } else { ResponseMode::Applied },
+        };
+        <Self as Handler<ClientRequest>>::handle(self, request, ctx)
+    }
+}
+
+impl Handler<ForwardClientRequest> for RaftClient {
+    type Result = ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, ForwardError>;
+
+    fn handle(&mut self, msg: ForwardClientRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        println!("[{}] Received forwarded client request", msg.id);
+        if msg.blind_hops > 0 {
+            return self.relay_blind(msg);
+        }
+        self.propose_forwarded(msg)
+    }
+}
+
+impl RaftClient {
+    /// Pass on a request forwarded blindly: to the leader once this node knows it, or to another
+    /// random peer within `blind_forward_hops`. The sender retries when the hops ran out.
+    fn relay_blind(
+        &mut self,
+        msg: ForwardClientRequest,
+    ) -> ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, ForwardError> {
+        let net = match self.net {
+            Some(ref net) => net.clone(),
+            None => return Box::new(fut::err(ForwardError::Raft(ClientError::Internal))),
+        };
+        let (id, group, max_hops) = (msg.id, self.group, self.config.blind_forward_hops);
+
+        Box::new(
+            fut::wrap_future::<_, Self>(net.send(GetGroupLeaderOrPeer { group, exclude: vec![self.id] }))
+                .map_err(|_, _, _| ForwardError::Raft(ClientError::Internal))
+                .and_then(|res, _act, _ctx| fut::result(res.map_err(|_| ForwardError::Raft(ClientError::Internal))))
+                .and_then(move |(target, blind), act, ctx| -> ResponseActFuture<Self, _, _> {
+                    if !blind {
+                        act.note_leader(target, ctx);
+                    }
+                    if target == act.id {
+                        return act.propose_forwarded(msg);
+                    }
+                    if blind && msg.blind_hops >= max_hops {
+                        println!("[{}] Still no leader known after {} blind forwards", id, msg.blind_hops);
+                        return Box::new(fut::err(ForwardError::Raft(ClientError::Internal)));
+                    }
+
+                    println!("[{}] Relaying blindly forwarded client request to {}", id, target);
+                    let forward = ForwardClientRequest {
+                        blind_hops: if blind { msg.blind_hops + 1 } else { msg.blind_hops },
+                        ..msg
+                    };
+                    Box::new(
+                        fut::wrap_future::<_, Self>(net.send(GetNodeById(target)))
+                            .map_err(|_, _, _| ForwardError::Raft(ClientError::Internal))
+                            .and_then(|res, _act, _ctx| fut::result(res.map_err(|_| ForwardError::Raft(ClientError::Internal))))
+                            .and_then(move |node, _act, _ctx| {
+                                fut::wrap_future::<_, Self>(node.send(SendGroupMessage(group, forward)))
+                                    .map_err(|_, _, _| ForwardError::Raft(ClientError::Internal))
+                                    .and_then(|res, _act, _ctx| fut::result(res))
+                            }),
+                    )
+                }),
+        )
+    }
+
+    fn propose_forwarded(
+        &mut self,
+        msg: ForwardClientRequest,
+    ) -> ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, ForwardError> {
+        let id = msg.id;
+        if let Some(ref raft) = self.raft {
+            let raft = raft.clone();
+            let payload = msg.payload;
+            Box::new(
+                self.check_replication(msg.priority)
+                    // the forwarding node fails the request like its own, it isn't retried
+                    .map_err(move |(healthy, required), _, _| {
+                        println!("[{}] Rejecting forwarded client request, {} of {} voters healthy", id, healthy, required);
+                        ForwardError::InsufficientReplication { healthy, required }
+                    })
+                    .and_then(move |_, _act, _ctx| fut::wrap_future::<_, Self>(raft.send(payload))
+                    .map_err(move |err, act: &mut Self, _| {
+                        println!("[{}] Error {:?}", id, err);
+                        act.record_error(format!("raft unreachable: {}", err));
+                        ForwardError::Raft(ClientError::Internal)
+                    })
+                    .and_then(move |res, _act, _ctx| {
+                        if res.is_err() {
+                            println!("[{}] Forwarded client request was rejected", id);
+                        }
+                        fut::result(res.map_err(ForwardError::Raft))
+                    })),
+            )
+        } else {
+            Box::new(fut::err(ForwardError::Raft(ClientError::Internal)))
+        }
+    }
+}
+
+/// Errors returned to the sender of a `ClientRequest`, each carrying the
+/// request's correlation id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RaftClientError {
+    /// The request could not be delivered to the raft actors
+    Internal { id: CorrelationId },
+    /// Too many requests are in flight on this node, retry later
+    Backpressure { id: CorrelationId },
+    /// The state machine rejected the entry
+    Application {
+        id: CorrelationId,
+        err: MemoryStorageError,
+    },
+    /// The request was retried `max_client_retries` times without succeeding
+    RetriesExhausted { id: CorrelationId, attempts: u32 },
+    /// The membership change was rejected or didn't land in time
+    MembershipChange { id: CorrelationId, reason: String },
+    /// The request arrived before `InitRaft` started the raft, wait for the node to be ready
+    NotInitialized { id: CorrelationId },
+    /// The request was cancelled with `CancelRequest` before it completed
+    Cancelled { id: CorrelationId },
+    /// Fewer voters than `min_voters_for_writes` are healthy
+    InsufficientReplication { id: CorrelationId, healthy: usize, required: usize },
+    /// The node is read-only and no read-write node took the request
+    ReadOnly { id: CorrelationId },
+    /// The request was forwarded to `peer`, which went away without answering. It may or may
+    /// not have been proposed.
+    Undeliverable { id: CorrelationId, peer: NodeId },
+    /// Writes are frozen cluster-wide with `FreezeWrites`
+    Frozen { id: CorrelationId },
+    /// The config change would leave the group without a voter
+    WouldDestroyCluster { id: CorrelationId },
+    /// This node isn't the leader and `forward_client_requests` is off, send the request to
+    /// `leader`, reachable over HTTP at `addr`
+    NotLeader {
+        id: CorrelationId,
+        leader: Option<NodeId>,
+        addr: Option<String>,
+    },
+}
+
+impl RaftClientError {
+    pub fn correlation_id(&self) -> CorrelationId {
+        match self {
+            RaftClientError::Internal { id } => *id,
+            RaftClientError::Backpressure { id } => *id,
+            RaftClientError::Application { id, .. } => *id,
+            RaftClientError::RetriesExhausted { id, .. } => *id,
+            RaftClientError::MembershipChange { id, .. } => *id,
+            RaftClientError::NotInitialized { id } => *id,
+            RaftClientError::Cancelled { id } => *id,
+            RaftClientError::InsufficientReplication { id, .. } => *id,
+            RaftClientError::ReadOnly { id } => *id,
+            RaftClientError::Undeliverable { id, .. } => *id,
+            RaftClientError::Frozen { id } => *id,
+            RaftClientError::WouldDestroyCluster { id } => *id,
+            RaftClientError::NotLeader { id, .. } => *id,
+        }
+    }
+}
+
+fn add_node(id: NodeId) -> MemoryStorageData {
+    MemoryStorageData::Add(id)
+}
+
+fn remove_node(id: NodeId) -> MemoryStorageData {
+    MemoryStorageData::Remove(id)
+}
+
+impl RaftClient {
+    fn handle_client_response(
+        &mut self,
+        res: ClientResponseHandler,
+        ctx: &mut Context<Self>,
+        msg: ClientRequest,
+    ) -> ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError> {
+        let id = msg.id;
+
+        match res {
+            Ok(res) => self.await_commit_quorum(id, res),
+            Err(err) => match err {
+                ClientError::Internal => {
+                    println!("[{}] TEST: resending client request.", id);
+                    self.record_error(format!("[{}] raft returned an internal error", id));
+                    resend_client_request(ctx, msg)
+                }
+                ClientError::Application(err) => {
+                    println!(
+                        "[{}] Unexpected application error from client request: {:?}",
+                        id, err
+                    );
+                    let attempts = self.inflight.get(&id).map(|inflight| inflight.attempts).unwrap_or(1);
+                    let action = match self.application_errors {
+                        Some(ref handler) => handler.handle(id, &msg.data, &err, attempts),
+                        None => ApplicationErrorAction::DeadLetter,
+                    };
+                    match action {
+                        ApplicationErrorAction::DeadLetter => Box::new(fut::err(RaftClientError::Application { id, err })),
+                        ApplicationErrorAction::Surface => {
+                            self.surfaced.insert(id);
+                            Box::new(fut::err(RaftClientError::Application { id, err }))
+                        }
+                        ApplicationErrorAction::Retry(_) if attempts > self.config.max_application_retries => {
+                            println!("[{}] Giving up on the rejected client request after {} retries", id, attempts - 1);
+                            Box::new(fut::err(RaftClientError::Application { id, err }))
+                        }
+                        ApplicationErrorAction::Retry(data) => {
+                            println!("[{}] Retrying the rejected client request with {:?}", id, data);
+                            resend_client_request(ctx, ClientRequest { data, ..msg })
+                        }
+                    }
+                }
+                ClientError::ForwardToLeader { leader, .. } if !self.config.forward_client_requests => {
+                    println!("[{}] Lost leadership, pointing the client to leader {:?}", id, leader);
+                    let addr = self.leader_hint.as_ref().filter(|hint| Some(hint.id) == leader).and_then(|hint| hint.addr.clone());
+                    Box::new(fut::err(RaftClientError::NotLeader { id, leader, addr }))
+                }
+                ClientError::ForwardToLeader { .. } => {
+                    println!("[{}] TEST: received ForwardToLeader error. Updating leader and forwarding.", id);
+                    resend_client_request(ctx, msg)
+                }
+            },
+        }
+    }
+
+    /// With `CommitQuorum::All` hold the response back until every voter applied the entry. The
+    /// entry is committed and applied either way, so the write succeeds with the voters lagging
+    /// behind attached: failing it would make a retrying caller apply it twice.
+    fn await_commit_quorum(
+        &mut self,
+        id: CorrelationId,
+        res: ClientPayloadResponse<MemoryStorageResponse>,
+    ) -> ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError> {
+        if self.config.commit_quorum != CommitQuorum::All {
+            return Box::new(fut::ok(res));
+        }
+
+        let index = match &res {
+            ClientPayloadResponse::Committed { index } => *index,
+            ClientPayloadResponse::Applied { index, .. } => *index,
+        };
+        let deadline = self.clock.now() + Duration::from_secs(COMMIT_QUORUM_TIMEOUT);
+
+        Box::new(
+            fut::wrap_future::<_, Self>(self.net.as_ref().unwrap().send(AwaitApplied { group: self.group, index, deadline }))
+                .map_err(move |_, _, _| RaftClientError::Internal { id })
+                .map(move |applied, _act, _ctx| match (applied, res) {
+                    (Ok(_), res) => res,
+                    (Err((acked, voters)), res) => {
+                        let lagging: Vec<NodeId> = voters.into_iter().filter(|voter| !acked.contains(voter)).collect();
+                        warn!("[{}] Entry {} was not applied by all voters in time, lagging: {:?}", id, index, lagging);
+                        match res {
+                            ClientPayloadResponse::Applied { index, mut data } => {
+                                data.lagging = lagging;
+                                ClientPayloadResponse::Applied { index, data }
+                            }
+                            // nothing to carry them, the caller asked not to wait for the apply
+                            committed => committed,
+                        }
+                    }
+                }),
+        )
+    }
+}
+
+fn resend_client_request(
+    ctx: &mut Context<RaftClient>,
+    msg: ClientRequest,
+) -> ResponseActFuture<RaftClient, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError> {
+    let id = msg.id;
+
+    Box::new(
+        fut::wrap_future::<_, RaftClient>(ctx.address().send(msg))
+            .map_err(move |_, _, _| RaftClientError::Internal { id })
+            .and_then(|res, _, _| fut::result(res)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_raft::messages::MembershipConfig;
+    use std::collections::BTreeMap;
+    use crate::config::{NetworkType, NodeInfo};
+    use crate::hash_ring::Ring;
+    use crate::network::GroupMetrics;
+    use crate::raft::storage::{MembershipApplied, MAX_COUNTER_KEY_LEN};
+    use crate::test_utils::{adds, config, detached, entry, storage};
+    use crate::utils::MockClock;
+
+    #[test]
+    fn an_error_degrades_the_node_until_the_window_passed_on_its_clock() {
+        let mut sys = System::new("test");
+        let clock = Arc::new(MockClock::new());
+        let shared: SharedClock = clock.clone();
+        let config = config("");
+        let storage = storage(1, &config, Box::new(DefaultStateMachine));
+        let client = RaftClient::create(move |ctx| {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            let mut client = RaftClient::new(1, 1, Ring::new(1), registry, config).with_clock(shared);
+            client.set_storage(storage, ctx);
+            client.record_error("storage unreachable".to_owned());
+            client
+        });
+
+        let health = sys.block_on(client.send(GetHealth)).unwrap().unwrap();
+        assert!(health.degraded);
+        assert!(!health.healthy);
+
+        clock.advance(Duration::from_secs(DEGRADED_WINDOW));
+        let health = sys.block_on(client.send(GetHealth)).unwrap().unwrap();
+        assert!(!health.degraded);
+        assert!(health.healthy);
+    }
+
+    #[test]
+    fn a_read_lease_expires_on_the_clock_only() {
+        let clock = Arc::new(MockClock::new());
+        let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+        let mut client = RaftClient::new(2, 1, Ring::new(1), registry, config("")).with_clock(clock.clone());
+        client.read_lease = Some(ReadLease {
+            node: 1,
+            term: 3,
+            index: 5,
+            expires: clock.now() + Duration::from_millis(500),
+        });
+        let metrics = RaftMetrics {
+            id: 2,
+            state: State::Follower,
+            current_term: 3,
+            last_log_index: 5,
+            last_applied: 5,
+            current_leader: Some(1),
+            membership_config: MembershipConfig {
+                is_in_joint_consensus: false,
+                members: vec![1, 2, 3],
+                non_voters: vec![],
+                removing: vec![],
+            },
+        };
+
+        // the time of the system passing, or being stepped, doesn't matter to the lease
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(client.holds_read_lease(&metrics));
+        clock.advance(Duration::from_millis(499));
+        assert!(client.holds_read_lease(&metrics));
+        clock.advance(Duration::from_millis(1));
+        assert!(!client.holds_read_lease(&metrics));
+    }
+
+    #[test]
+    fn a_conflicting_stored_config_marks_the_init_failed() {
+        let mut sys = System::new("test");
+        let config = config("election_jitter_ms = 0");
+        let storage = storage(1, &config, Box::new(DefaultStateMachine));
+        // the log already holds a config of other members, raft refuses to be initialized over it
+        sys.block_on(storage.send(ApplyObserved(adds(&[5, 6])))).unwrap().unwrap();
+
+        let dir = tempfile::tempdir_in("/tmp").unwrap().into_path().to_string_lossy().to_string();
+        let raft = RaftBuilder::start(1, 1, dir, detached(), storage.clone(), &config, false, Arc::new(SystemClock));
+        let client = RaftClient::create(move |ctx| {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            let mut client = RaftClient::new(1, 1, Ring::new(1), registry, config);
+            client.set_storage(storage, ctx);
+            client.raft = Some(raft.addr);
+            client.init_with_config(vec![1], ctx);
+            client
+        });
+
+        let mut health = None;
+        for _ in 0..50 {
+            sys.block_on(tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))).unwrap();
+            let current = sys.block_on(client.send(GetHealth)).unwrap().unwrap();
+            if current.init_failed.is_some() {
+                health = Some(current);
+                break;
+            }
+        }
+        let health = health.expect("the init never failed");
+        assert!(!health.healthy);
+    }
+
+    #[test]
+    fn adding_a_member_twice_proposes_nothing() {
+        let mut sys = System::new("test");
+        let config = config("");
+        let storage = storage(1, &config, Box::new(DefaultStateMachine));
+        sys.block_on(storage.send(ApplyObserved(adds(&[1, 2])))).unwrap().unwrap();
+        let client = RaftClient::create(move |ctx| {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            let mut client = RaftClient::new(1, 1, Ring::new(1), registry, config);
+            client.set_storage(storage, ctx);
+            client
+        });
+
+        // without a raft any proposal fails, so only the membership check can answer
+        for _ in 0..2 {
+            let outcome = sys.block_on(client.send(AddNode(2))).unwrap().unwrap();
+            assert_eq!(outcome, AddNodeOutcome::AlreadyMember);
+        }
+        match sys.block_on(client.send(ForceAddNode(2))).unwrap() {
+            Err(RaftClientError::NotInitialized { .. }) => (),
+            res => panic!("expected the forced add to be proposed, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn a_full_fetch_of_the_largest_counter_entries_fits_its_frames() {
+        use bytes::BytesMut;
+        use tokio::codec::{Decoder, Encoder};
+        use crate::network::{ClientNodeCodec, NodeCodec, NodeResponse};
+
+        let mut sys = System::new("test");
+        let config = config("");
+        let storage = storage(1, &config, Box::new(DefaultStateMachine));
+        let entries: Vec<Entry> = (1..=OBSERVER_FETCH_SIZE)
+            .map(|index| entry(index, MemoryStorageData::SetExpiring("\"".repeat(MAX_COUNTER_KEY_LEN), i64::min_value(), u64::max_value())))
+            .collect();
+        sys.block_on(storage.send(ApplyObserved(entries))).unwrap().unwrap();
+        let client = RaftClient::create(move |ctx| {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            let mut client = RaftClient::new(1, 1, Ring::new(1), registry, config);
+            client.set_storage(storage, ctx);
+            client
+        });
+
+        let mut from = 1;
+        let mut fetches = 0;
+        loop {
+            let fetched = sys.block_on(client.send(FetchLog { from, max: OBSERVER_FETCH_SIZE })).unwrap();
+            // sent back the way a remote message is answered
+            let body = serde_json::to_string(&fetched).unwrap();
+            let mut frame = BytesMut::new();
+            NodeCodec.encode(NodeResponse::Result(u64::max_value(), body), &mut frame).unwrap();
+            assert!(frame.len() <= MAX_FRAME_SIZE + 2, "a frame of {} bytes", frame.len());
+
+            let body = match ClientNodeCodec.decode(&mut frame).unwrap() {
+                Some(NodeResponse::Result(_, body)) => body,
+                res => panic!("expected the fetched entries, got {:?}", res),
+            };
+            let entries: Result<Vec<Entry>, ReadError> = serde_json::from_str(&body).unwrap();
+            let entries = entries.unwrap();
+            match entries.last() {
+                Some(last) => from = last.index + 1,
+                None => break,
+            }
+            fetches += 1;
+        }
+        assert_eq!(from, OBSERVER_FETCH_SIZE + 1);
+        assert!(fetches > 1, "the whole batch took a single frame");
+    }
+
+    #[test]
+    fn removing_the_sole_member_would_destroy_the_cluster() {
+        let mut sys = System::new("test");
+        let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+        let info = NodeInfo {
+            cluster_addr: "127.0.0.1:0".to_owned(),
+            app_addr: "127.0.0.1:0".to_owned(),
+            public_addr: "127.0.0.1:0".to_owned(),
+            id: Some(1),
+            readonly: false,
+            metadata: BTreeMap::new(),
+        };
+        let net = Network::create(move |_| {
+            // nothing answers the discovery host, the network keeps running on its own
+            let mut net = Network::new(1, Ring::new(1), registry, NetworkType::App, detached(), "127.0.0.1:1".to_owned(), info);
+            net.bind("127.0.0.1:0");
+            net
+        });
+        net.do_send(GroupMetrics(
+            1,
+            RaftMetrics {
+                id: 1,
+                state: State::Leader,
+                current_term: 1,
+                last_log_index: 1,
+                last_applied: 1,
+                current_leader: Some(1),
+                membership_config: MembershipConfig {
+                    is_in_joint_consensus: false,
+                    members: vec![1],
+                    non_voters: vec![],
+                    removing: vec![],
+                },
+            },
+        ));
+
+        let client = RaftClient::create(move |_| {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            let mut client = RaftClient::new(1, 1, Ring::new(1), registry, config(""));
+            client.net = Some(net);
+            // refused before anything reaches the raft
+            client.raft = Some(detached());
+            client
+        });
+
+        match sys.block_on(client.send(ChangeRaftClusterConfig(vec![], vec![1]))).unwrap() {
+            Err(RaftClientError::WouldDestroyCluster { .. }) => (),
+            res => panic!("expected WouldDestroyCluster, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn a_cancelled_request_stays_in_flight_until_its_attempt_completes() {
+        let mut sys = System::new("test");
+        let id = generate_correlation_id();
+        let client = RaftClient::create(move |_| {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            let mut client = RaftClient::new(1, 1, Ring::new(1), registry, config(""));
+            let received = client.clock.now();
+            client.inflight.insert(id, Inflight { attempts: 1, received, target: None });
+            client
+        });
+
+        sys.block_on(client.send(CancelRequest(id))).unwrap().unwrap();
+        let inflight = sys.block_on(client.send(ListInflight)).unwrap().unwrap();
+        assert_eq!(inflight.iter().map(|request| request.id).collect::<Vec<_>>(), vec![id]);
+    }
+
+    #[test]
+    fn a_replacement_is_claimed_before_its_first_request() {
+        let mut sys = System::new("test");
+        let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+        let info = NodeInfo {
+            cluster_addr: "127.0.0.1:0".to_owned(),
+            app_addr: "127.0.0.1:0".to_owned(),
+            public_addr: "127.0.0.1:0".to_owned(),
+            id: Some(1),
+            readonly: false,
+            metadata: BTreeMap::new(),
+        };
+        // knows no metrics of the group, the replacement fails once it asks
+        let net = Network::create(move |_| {
+            let mut net = Network::new(1, Ring::new(1), registry, NetworkType::App, detached(), "127.0.0.1:1".to_owned(), info);
+            net.bind("127.0.0.1:0");
+            net
+        });
+        let client = RaftClient::create(move |_| {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            let mut client = RaftClient::new(1, 1, Ring::new(1), registry, config(""));
+            client.net = Some(net);
+            client
+        });
+
+        // both are queued before the network answers the first
+        let first = client.send(ReplaceNode { old: 1, new: 2 });
+        let second = client.send(ReplaceNode { old: 1, new: 3 });
+        let (first, second) = sys.block_on(first.join(second)).unwrap();
+        match first {
+            Err(RaftClientError::NotInitialized { .. }) => (),
+            res => panic!("expected NotInitialized, got {:?}", res),
+        }
+        match second {
+            Err(RaftClientError::MembershipChange { .. }) => (),
+            res => panic!("expected the second replacement to be refused, got {:?}", res),
+        }
+
+        let progress = sys.block_on(client.send(GetReplaceProgress)).unwrap().unwrap().unwrap();
+        assert_eq!((progress.old, progress.new, progress.stage), (1, 2, ReplaceStage::Failed));
+        // and a new replacement may start
+        match sys.block_on(client.send(ReplaceNode { old: 1, new: 3 })).unwrap() {
+            Err(RaftClientError::NotInitialized { .. }) => (),
+            res => panic!("expected NotInitialized, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn nodes_started_together_elect_a_leader_within_the_bound() {
+        let mut sys = System::new("test");
+        let ids: Vec<NodeId> = vec![1, 2, 3];
+        let infos: Vec<NodeInfo> = ids
+            .iter()
+            .map(|id| {
+                // a free port, released for the network to bind
+                let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+                NodeInfo {
+                    cluster_addr: format!("127.0.0.1:{}", port),
+                    app_addr: "127.0.0.1:0".to_owned(),
+                    public_addr: "127.0.0.1:0".to_owned(),
+                    id: Some(*id),
+                    readonly: false,
+                    metadata: BTreeMap::new(),
+                }
+            })
+            .collect();
+        let mut config = config("election_jitter_ms = 500\nauto_self_add = false");
+        config.nodes = infos.clone();
+
+        let mut nets = vec![];
+        for (id, info) in ids.iter().cloned().zip(infos.iter().cloned()) {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            let (client_registry, client_config) = (registry.clone(), config.clone());
+            let client = RaftClient::create(move |_| RaftClient::new(id, DEFAULT_GROUP, Ring::new(1), client_registry, client_config));
+            let (net_client, net_config) = (client.clone(), config.clone());
+            let net = Network::create(move |_| {
+                // nothing answers the discovery host, the peers are dialed below
+                let mut net = Network::new(id, Ring::new(1), registry, NetworkType::Cluster, net_client, "127.0.0.1:1".to_owned(), info.clone());
+                net.configure(net_config);
+                net.bind(&info.cluster_addr);
+                net
+            });
+            for peer in ids.iter().filter(|peer| **peer != id) {
+                net.do_send(MembershipApplied::Added(*peer));
+            }
+            client.do_send(InitRaft { nodes: ids.clone(), net: net.clone(), server: detached(), join_mode: false });
+            nets.push(net);
+        }
+
+        // a few election timeouts, a vote split on every round would never get there
+        let started = Instant::now();
+        let mut leader = None;
+        while leader.is_none() && started.elapsed() < Duration::from_secs(20) {
+            sys.block_on(tokio::timer::Delay::new(Instant::now() + Duration::from_millis(200))).unwrap();
+            leader = nets.iter().find_map(|net| {
+                let metrics = sys.block_on(net.send(GetGroupMetrics(DEFAULT_GROUP))).unwrap().ok()?;
+                if metrics.state == State::Leader { Some(metrics.id) } else { None }
+            });
+        }
+        assert!(leader.is_some(), "no leader elected within {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn a_request_before_init_fails_with_not_initialized() {
+        let mut sys = System::new("test");
+        let config = config("");
+        let client = RaftClient::create(move |_| {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            RaftClient::new(1, 1, Ring::new(1), registry, config)
+        });
+
+        let request = ClientRequest::new(MemoryStorageData::Incr("hits".to_owned(), 1));
+        let id = request.id;
+        match sys.block_on(client.send(request)).unwrap() {
+            Err(RaftClientError::NotInitialized { id: rejected }) => assert_eq!(rejected, id),
+            res => panic!("expected NotInitialized, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    /// Hands a raft response to the client as if its raft had answered the request with it
+    struct Respond(ClientResponseHandler, ClientRequest);
+
+    impl Message for Respond {
+        type Result = Result<ClientPayloadResponse<MemoryStorageResponse>, RaftClientError>;
+    }
+
+    impl Handler<Respond> for RaftClient {
+        type Result = ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, RaftClientError>;
+
+        fn handle(&mut self, msg: Respond, ctx: &mut Context<Self>) -> Self::Result {
+            self.handle_client_response(msg.0, ctx, msg.1)
+        }
+    }
+
+    /// Answers every rejected request with the same action, counting the calls
+    struct FixedAction(MemoryStorageData, Arc<RwLock<u32>>);
+
+    impl ApplicationErrorHandler for FixedAction {
+        fn handle(&self, _id: CorrelationId, _data: &MemoryStorageData, _err: &MemoryStorageError, _attempts: u32) -> ApplicationErrorAction {
+            *self.1.write().unwrap() += 1;
+            match self.0 {
+                MemoryStorageData::Incr(..) => ApplicationErrorAction::Retry(self.0.clone()),
+                _ => ApplicationErrorAction::Surface,
+            }
+        }
+    }
+
+    fn rejected(sys: &mut SystemRunner, extra: &str, handler: Option<FixedAction>) -> Result<ClientPayloadResponse<MemoryStorageResponse>, RaftClientError> {
+        let config = config(extra);
+        let client = RaftClient::create(move |_| {
+            let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+            let client = RaftClient::new(1, 1, Ring::new(1), registry, config);
+            match handler {
+                Some(handler) => client.with_application_error_handler(Arc::new(handler)),
+                None => client,
+            }
+        });
+        let request = ClientRequest::new(MemoryStorageData::Set("hits".to_owned(), 1));
+        sys.block_on(client.send(Respond(Err(ClientError::Application(MemoryStorageError)), request))).unwrap()
+    }
+
+    #[test]
+    fn an_application_error_resolves_the_request_as_the_handler_decides() {
+        let mut sys = System::new("test");
+
+        // dead-lettered by default, surfaced as the same error
+        for handler in vec![None, Some(FixedAction(MemoryStorageData::Set("hits".to_owned(), 1), Arc::new(RwLock::new(0))))] {
+            match rejected(&mut sys, "", handler) {
+                Err(RaftClientError::Application { .. }) => (),
+                res => panic!("expected Application, got {:?}", res.map(|_| ())),
+            }
+        }
+
+        // a retry is proposed again, which fails as the raft isn't started
+        let calls = Arc::new(RwLock::new(0));
+        let retry = FixedAction(MemoryStorageData::Incr("hits".to_owned(), 1), calls.clone());
+        match rejected(&mut sys, "", Some(retry)) {
+            Err(RaftClientError::NotInitialized { .. }) => (),
+            res => panic!("expected the retry to be proposed, got {:?}", res.map(|_| ())),
+        }
+        assert_eq!(*calls.read().unwrap(), 1);
+
+        // without retries left the rejection stands
+        let retry = FixedAction(MemoryStorageData::Incr("hits".to_owned(), 1), calls.clone());
+        match rejected(&mut sys, "max_application_retries = 0", Some(retry)) {
+            Err(RaftClientError::Application { .. }) => (),
+            res => panic!("expected Application, got {:?}", res.map(|_| ())),
+        }
     }
 }
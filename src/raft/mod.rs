@@ -5,65 +5,238 @@ use actix_raft::{
     config::{Config, SnapshotPolicy},
     NodeId, Raft,
 };
+use log::{error, info};
 
+use crate::config::{ConfigSchema, SeedSnapshot};
 use crate::hash_ring::RingType;
 use crate::network::Network;
 use crate::server::{Server};
+use crate::utils::SharedClock;
+use std::path::PathBuf;
 use std::time::Duration;
 use tempfile::tempdir_in;
 
 pub mod network;
+pub mod state_machine;
 pub mod storage;
 mod client;
 
 pub use self::{
-    client::{RaftClient, InitRaft, AddNode, RemoveNode, ChangeRaftClusterConfig}
+    client::{RaftClient, ApplicationErrorAction, ApplicationErrorHandler, InitRaft, AddNode, AddNodeOutcome, ForceAddNode, RemoveNode, CancelError, CancelRequest, ChangeMembership, ChangeRaftClusterConfig, ClientRequest, CorrelationId, DeadLetter, FetchLog, FreezeWrites, UnfreezeWrites, WriteFreeze, ForwardClientRequest, ForwardError, GetHealth, GetLastError, GetLeaderHint, LeaderHint, InflightRequest, LeaderDiagnostics, LeaderDiagnosticsReport, MetricsSnapshot, MetricsSnapshotReport, ClusterVerification, ListInflight, MembershipChangeOutcome, NodeHealth, PauseElections, Priority, PromoteError, PromoteToVoter, ProposeRaw, RaftClientError, ReadClusterMembers, ReadQuery, RelayClientRequest, Staleness, VerifyCluster, GetReadLeases, GrantReadLease, GrantedReadLease, HeldReadLease, ReadLeaseStatus, RevokeReadLease, GetReplaceProgress, ReplaceNode, ReplaceProgress, ReplaceStage}
 };
 
-use self::storage::{MemoryStorage, MemoryStorageData, MemoryStorageError, MemoryStorageResponse};
+use self::network::GroupNetwork;
+use self::state_machine::StateMachine;
+use self::storage::{ImportLog, LoadSnapshotFromFile, MemoryStorage, ReconcileRing, MemoryStorageData, MemoryStorageError, MemoryStorageResponse};
+
+/// Identifies one of the independent raft groups running in a process
+pub type GroupId = u64;
+
+/// The raft group replicating cluster membership and owning the hash ring
+pub const DEFAULT_GROUP: GroupId = 0;
+
+/// Shortest election timeout (in milliseconds), a peer doesn't campaign before it heard nothing
+/// from the leader for that long
+pub const ELECTION_TIMEOUT_MIN: u64 = 3000;
+
+/// Election timeout (in milliseconds) of a raft whose elections are paused, it never campaigns
+const PAUSED_ELECTION_TIMEOUT: u64 = 365 * 24 * 3600 * 1000;
 
 pub type MemRaft =
-    Raft<MemoryStorageData, MemoryStorageResponse, MemoryStorageError, Network, MemoryStorage>;
+    Raft<MemoryStorageData, MemoryStorageResponse, MemoryStorageError, GroupNetwork, MemoryStorage>;
 
 pub struct RaftBuilder;
 
+/// The raft of a group started by the `RaftBuilder`
+pub struct StartedRaft {
+    pub addr: Addr<MemRaft>,
+    /// Runs the raft and its network only
+    pub arbiter: Arbiter,
+    /// Directory the raft was configured with, a restarted raft reuses it
+    pub snapshot_dir: String,
+}
+
 impl RaftBuilder {
     pub fn new(
         id: NodeId,
+        group: GroupId,
         members: Vec<NodeId>,
         network: Addr<Network>,
         ring: RingType,
         server: Addr<Server>,
-    ) -> Addr<MemRaft> {
-        let id = id;
-        let raft_members = members.clone();
-        let metrics_rate = 1;
+        state_machine: Box<dyn StateMachine>,
+        app_config: &ConfigSchema,
+        campaigns: bool,
+        clock: SharedClock,
+    ) -> (StartedRaft, Addr<MemoryStorage>) {
+        let (storage, snapshot_dir) = RaftBuilder::learner_storage(group, members, ring, server, state_machine, app_config);
+
+        // queued ahead of the raft reading its initial state from the storage
+        if group == DEFAULT_GROUP {
+            let import = app_config.import_log.as_ref().map(PathBuf::from).filter(|path| path.exists());
+            match (import, app_config.seed_snapshot.as_ref()) {
+                (Some(path), _) => RaftBuilder::import_log(&storage, path),
+                (None, Some(seed)) => RaftBuilder::seed_snapshot(&storage, seed),
+                (None, None) => {
+                    if let Some(ref path) = app_config.import_log {
+                        info!("No log export at {} yet, starting with an empty log", path);
+                    }
+                }
+            }
+        }
+
+        let raft = RaftBuilder::start(id, group, snapshot_dir, network, storage.clone(), app_config, campaigns, clock);
+        (raft, storage)
+    }
+
+    /// Import the export at `path` into the storage, the node stops rather than start from an
+    /// empty log when it fails
+    fn import_log(storage: &Addr<MemoryStorage>, path: PathBuf) {
+        Arbiter::spawn(storage.send(ImportLog { path: path.clone() }).then(move |res| {
+            match res {
+                Ok(Ok(imported)) => info!("Imported {} entries from {}", imported, path.display()),
+                _ => {
+                    error!("Refusing to start, failed to import the log from {}", path.display());
+                    System::current().stop_with_code(1);
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    /// Load the snapshot copied from the leader into the storage, the node stops rather than
+    /// replicate the whole state when it doesn't match the index and term the leader reported
+    fn seed_snapshot(storage: &Addr<MemoryStorage>, seed: &SeedSnapshot) {
+        let path = PathBuf::from(&seed.path);
+        let load = LoadSnapshotFromFile { path: path.clone(), index: seed.index, term: seed.term };
+        Arbiter::spawn(storage.send(load).then(move |res| {
+            match res {
+                Ok(Ok(loaded)) => info!("Seeded {} entries from the snapshot {}", loaded, path.display()),
+                _ => {
+                    error!("Refusing to start, failed to load the snapshot {}", path.display());
+                    System::current().stop_with_code(1);
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    /// Storage of a node joining a cluster, which may be seeded before its raft starts. Also
+    /// returns the snapshot dir to start the raft with.
+    pub fn learner_storage(
+        group: GroupId,
+        members: Vec<NodeId>,
+        ring: RingType,
+        server: Addr<Server>,
+        state_machine: Box<dyn StateMachine>,
+        app_config: &ConfigSchema,
+    ) -> (Addr<MemoryStorage>, String) {
         let temp_dir = tempdir_in("/tmp").expect("Tempdir to be created without error.");
         let snapshot_dir = temp_dir.path().to_string_lossy().to_string();
-        let config = Config::build(snapshot_dir.clone())
-            .election_timeout_min(3000)
-            .election_timeout_max(5000)
+        let storage = RaftBuilder::storage(group, members, snapshot_dir.clone(), ring, server, state_machine, app_config);
+
+        (storage, snapshot_dir)
+    }
+
+    /// Start the raft of a group over an existing storage, in an arbiter of its own. Without
+    /// `campaigns` the raft follows and votes but its election timer never fires.
+    pub fn start(
+        id: NodeId,
+        group: GroupId,
+        snapshot_dir: String,
+        network: Addr<Network>,
+        storage: Addr<MemoryStorage>,
+        app_config: &ConfigSchema,
+        campaigns: bool,
+        clock: SharedClock,
+    ) -> StartedRaft {
+        let metrics_rate = 1;
+        let (election_timeout_min, election_timeout_max) = if campaigns {
+            (ELECTION_TIMEOUT_MIN, 5000)
+        } else {
+            (PAUSED_ELECTION_TIMEOUT, PAUSED_ELECTION_TIMEOUT + 1)
+        };
+        let mut config = Config::build(snapshot_dir.clone())
+            .election_timeout_min(election_timeout_min)
+            .election_timeout_max(election_timeout_max)
             .heartbeat_interval(300)
-            .metrics_rate(Duration::from_secs(metrics_rate))
+            .metrics_rate(Duration::from_secs(metrics_rate));
+        if let Some(max_entries) = app_config.max_entries_per_append {
+            config = config.max_payload_entries(max_entries.max(1));
+        }
+        let config = config
             .snapshot_policy(SnapshotPolicy::default())
             .snapshot_max_chunk_size(10000)
             .validate()
             .expect("Raft config to be created without error.");
 
-        let storage =
-            MemoryStorage::create(move |_| MemoryStorage::new(raft_members, snapshot_dir, ring, server));
+        let mut raft_network = GroupNetwork::new(group, network)
+            .with_snapshot_rate(app_config.snapshot_bytes_per_sec)
+            .with_clock(clock);
+        if group == DEFAULT_GROUP {
+            raft_network = raft_network.with_membership_epoch(storage.clone());
+        }
+        // stopping the arbiter stops the raft with its network, the storage outlives them
+        let arbiter = Arbiter::new();
+        let raft_network = GroupNetwork::start_in_arbiter(&arbiter, move |_| raft_network);
 
-        let raft_network = network.clone();
-        let raft_storage = storage.clone();
-
-        Raft::create(move |_| {
+        let addr = Raft::start_in_arbiter(&arbiter, move |ctx| {
+            // the ring may be stale relative to the restored or seeded membership, the raft handles
+            // nothing before it was repaired
+            if group == DEFAULT_GROUP {
+                ctx.wait(fut::wrap_future::<_, MemRaft>(storage.send(ReconcileRing)).then(|res, _, _| {
+                    match res {
+                        Ok(Ok(ref repair)) if !repair.is_empty() => info!("Repaired the ring before starting the raft: {:?}", repair),
+                        Ok(Ok(_)) => (),
+                        _ => {
+                            error!("Refusing to start, failed to reconcile the ring with the membership");
+                            System::current().stop_with_code(1);
+                        }
+                    }
+                    fut::ok(())
+                }));
+            }
             Raft::new(
                 id,
                 config,
                 raft_network.clone(),
-                raft_storage,
+                storage,
                 raft_network.recipient(),
             )
+        });
+        StartedRaft {
+            addr,
+            arbiter,
+            snapshot_dir,
+        }
+    }
+
+    /// Storage of an observer, which follows the log of a group without running a raft
+    pub fn observer(
+        group: GroupId,
+        ring: RingType,
+        server: Addr<Server>,
+        state_machine: Box<dyn StateMachine>,
+        app_config: &ConfigSchema,
+    ) -> Addr<MemoryStorage> {
+        let temp_dir = tempdir_in("/tmp").expect("Tempdir to be created without error.");
+        let snapshot_dir = temp_dir.path().to_string_lossy().to_string();
+
+        RaftBuilder::storage(group, vec![], snapshot_dir, ring, server, state_machine, app_config)
+    }
+
+    fn storage(
+        group: GroupId,
+        members: Vec<NodeId>,
+        snapshot_dir: String,
+        ring: RingType,
+        server: Addr<Server>,
+        state_machine: Box<dyn StateMachine>,
+        app_config: &ConfigSchema,
+    ) -> Addr<MemoryStorage> {
+        let app_config = app_config.clone();
+        MemoryStorage::create(move |_| {
+            MemoryStorage::new(group, members, snapshot_dir, ring, server, state_machine, &app_config)
         })
     }
 }
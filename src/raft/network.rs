@@ -1,15 +1,112 @@
 use actix::prelude::*;
-use actix_raft::{messages, RaftNetwork};
+use actix_raft::{messages, metrics::State, NodeId, RaftMetrics, RaftNetwork};
 use log::error;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
 
-use crate::network::{remote::SendRemoteMessage, Network};
-use crate::raft::storage::MemoryStorageData as Data;
+use crate::network::tap::{self, RpcDirection};
+use crate::network::{framed_len, message_frame_overhead, remote::{group_type_id, DispatchMessage, RemoteMessage, SendGroupMessage}, FollowerProgressed, GroupMetrics, LeaderAcked, Network, Node, RouteRpc, MAX_FRAME_SIZE};
+use crate::raft::storage::{GetMembershipEpoch, MemoryStorage, MembershipEpoch, MemoryStorageData as Data};
+use crate::raft::GroupId;
+use crate::utils::{SharedClock, SystemClock, TokenBucket};
 
 const ERR_ROUTING_FAILURE: &str = "Failed to send RCP to node target.";
 
-impl RaftNetwork<Data> for Network {}
+/// The `RaftNetwork` of a single raft group.
+///
+/// RPCs are routed over the shared cluster `Network` and tagged with the group id, so that the
+/// peer dispatches them to the raft of the same group.
+pub struct GroupNetwork {
+    group: GroupId,
+    net: Addr<Network>,
+    /// bytes per second snapshots are sent to each follower at, unlimited when unset
+    snapshot_rate: Option<u64>,
+    snapshot_buckets: HashMap<NodeId, TokenBucket>,
+    /// last (match, next) index reported for each follower
+    progress: HashMap<NodeId, (u64, u64)>,
+    /// storage the membership epoch sent with the heartbeats is read from, by the ring owner only
+    storage: Option<Addr<MemoryStorage>>,
+    membership_epoch: Option<MembershipEpoch>,
+    /// the RPCs are stamped on it when sent, acknowledged leaderships are anchored there
+    clock: SharedClock,
+}
+
+impl GroupNetwork {
+    pub fn new(group: GroupId, net: Addr<Network>) -> Self {
+        GroupNetwork {
+            group: group,
+            net: net,
+            snapshot_rate: None,
+            snapshot_buckets: HashMap::new(),
+            progress: HashMap::new(),
+            storage: None,
+            membership_epoch: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Stamp the RPCs on `clock`, the one of the raft client
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Send the membership epoch of `storage` along with every heartbeat
+    pub fn with_membership_epoch(mut self, storage: Addr<MemoryStorage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Cap the bandwidth of snapshots sent to every follower
+    pub fn with_snapshot_rate(mut self, rate: Option<u64>) -> Self {
+        self.snapshot_rate = rate;
+        self
+    }
+
+    /// how long to hold back a snapshot chunk of `len` bytes to `target`
+    fn snapshot_delay(&mut self, target: NodeId, len: usize) -> Duration {
+        match self.snapshot_rate {
+            Some(rate) => self
+                .snapshot_buckets
+                .entry(target)
+                .or_insert_with(|| TokenBucket::new(rate))
+                .take(len as u64),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// report the replication progress of a follower to the network, when it changed
+    fn progressed(&mut self, target: NodeId, match_index: u64, next_index: u64) {
+        if self.progress.insert(target, (match_index, next_index)) != Some((match_index, next_index)) {
+            self.net.do_send(FollowerProgressed {
+                group: self.group,
+                target,
+                match_index,
+                next_index,
+            });
+        }
+    }
+
+    /// resolve the peer an RPC should be sent to
+    fn route(
+        &self,
+        route: RouteRpc,
+    ) -> impl ActorFuture<Actor = Self, Item = Addr<Node>, Error = ()> {
+        fut::wrap_future(self.net.send(route))
+            .map_err(|_, _, _| error!("{}", ERR_ROUTING_FAILURE))
+            .and_then(|res, _, _| fut::result(res))
+    }
+}
 
-impl Handler<messages::AppendEntriesRequest<Data>> for Network {
+impl Actor for GroupNetwork {
+    type Context = Context<Self>;
+}
+
+impl RaftNetwork<Data> for GroupNetwork {}
+
+impl Handler<messages::AppendEntriesRequest<Data>> for GroupNetwork {
     type Result = ResponseActFuture<Self, messages::AppendEntriesResponse, ()>;
 
     fn handle(
@@ -17,52 +114,142 @@ impl Handler<messages::AppendEntriesRequest<Data>> for Network {
         msg: messages::AppendEntriesRequest<Data>,
         _ctx: &mut Context<Self>,
     ) -> Self::Result {
-        let target_id = msg.target;
-        if let Some(node) = self.get_node(msg.target) {
-
-            if self.isolated_nodes.contains(&msg.target) || self.isolated_nodes.contains(&msg.leader_id) {
-                return Box::new(fut::err(()));
-            }
-
-            let req = node.send(SendRemoteMessage(msg));
-
-            return Box::new(
-                fut::wrap_future(req)
-                    .map_err(move |_, _, _| error!("{} {}", ERR_ROUTING_FAILURE, target_id))
-                    .and_then(|res, _, _| fut::result(res)),
-            );
-
+        let type_id = group_type_id(messages::AppendEntriesRequest::<Data>::type_id(), self.group);
+        let mut requests = split_append_entries(msg, message_frame_overhead(&type_id)).into_iter();
+        let mut sent = self.send_append_entries(requests.next().unwrap());
+        // the raft hears of the last answer, or of the first one refusing the entries
+        for request in requests {
+            sent = Box::new(sent.and_then(move |res, act, _| -> ResponseActFuture<Self, messages::AppendEntriesResponse, ()> {
+                if !res.success {
+                    return Box::new(fut::ok(res));
+                }
+                act.send_append_entries(request)
+            }));
         }
-
-        Box::new(fut::err(()))
+        sent
     }
 }
 
-impl Handler<messages::VoteRequest> for Network {
-    type Result = ResponseActFuture<Self, messages::VoteResponse, ()>;
+/// Split an append entries RPC into ones whose frames, `overhead` bytes besides the request, fit
+/// by the encoded size of their entries. Each continues the log of the one before and only
+/// commits through its own entries. An entry which doesn't fit a frame on its own is sent alone
+/// and refused by the codec.
+fn split_append_entries(msg: messages::AppendEntriesRequest<Data>, overhead: usize) -> Vec<messages::AppendEntriesRequest<Data>> {
+    let messages::AppendEntriesRequest { target, term, leader_id, prev_log_index, prev_log_term, entries, leader_commit } = msg;
+    let empty = messages::AppendEntriesRequest::<Data> {
+        target,
+        term,
+        leader_id,
+        prev_log_index: u64::max_value(),
+        prev_log_term: u64::max_value(),
+        entries: vec![],
+        leader_commit: u64::max_value(),
+    };
+    let base = overhead + framed_len(&empty);
 
-    fn handle(&mut self, msg: messages::VoteRequest, _ctx: &mut Context<Self>) -> Self::Result {
-        if let Some(node) = self.get_node(msg.target) {
+    let mut requests = vec![];
+    let (mut prev_log_index, mut prev_log_term) = (prev_log_index, prev_log_term);
+    let mut chunk: Vec<messages::Entry<Data>> = vec![];
+    let mut size = base;
+    for entry in entries {
+        // and the comma separating it
+        let len = framed_len(&entry) + 1;
+        if base + len > MAX_FRAME_SIZE {
+            error!("Entry {} of {} bytes doesn't fit a frame", entry.index, len);
+        }
+        if !chunk.is_empty() && size + len > MAX_FRAME_SIZE {
+            let last = chunk.last().map(|last| (last.index, last.term)).unwrap();
+            requests.push(messages::AppendEntriesRequest {
+                target,
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries: chunk.drain(..).collect(),
+                leader_commit: leader_commit.min(last.0),
+            });
+            prev_log_index = last.0;
+            prev_log_term = last.1;
+            size = base;
+        }
+        size += len;
+        chunk.push(entry);
+    }
+    requests.push(messages::AppendEntriesRequest { target, term, leader_id, prev_log_index, prev_log_term, entries: chunk, leader_commit });
+    requests
+}
 
+impl GroupNetwork {
+    fn send_append_entries(
+        &mut self,
+        msg: messages::AppendEntriesRequest<Data>,
+    ) -> ResponseActFuture<Self, messages::AppendEntriesResponse, ()> {
+        let target_id = msg.target;
+        let route = RouteRpc {
+            target: msg.target,
+            source: msg.leader_id,
+            bulk: false,
+        };
+        // the follower holds everything through the last entry sent once it accepts the request
+        let replicated = msg.prev_log_index + msg.entries.len() as u64;
+        let prev_match = self.progress.get(&target_id).map(|p| p.0).unwrap_or(0);
+        // a follower behind on entries catches up by applying them, only heartbeats carry the epoch
+        let epoch = self.membership_epoch.filter(|_| msg.entries.is_empty());
+        // an answer in the same term confirms the leadership as of before the request left
+        let (term, sent) = (msg.term, self.clock.now());
+        tap::record(RpcDirection::Outbound, self.group, &msg);
 
-            if self.isolated_nodes.contains(&msg.target) || self.isolated_nodes.contains(&msg.candidate_id) {
-                return Box::new(fut::err(()));
+        Box::new(self.route(route).and_then(move |node, act, _| {
+            if let Some(epoch) = epoch {
+                node.do_send(DispatchMessage(epoch));
             }
+            fut::wrap_future(node.send(SendGroupMessage(act.group, msg)))
+                .map_err(move |_, _, _| error!("{} {}", ERR_ROUTING_FAILURE, target_id))
+                .and_then(|res, _, _| fut::result(res))
+                .map(move |res: messages::AppendEntriesResponse, act: &mut Self, _| {
+                    if tap::enabled() {
+                        let detail = format!("success={} conflict={:?}", res.success, res.conflict_opt.as_ref().map(|c| (c.term, c.index)));
+                        tap::record_response(act.group, "AppendEntriesResponse", target_id, res.term, detail);
+                    }
+                    match (res.success, &res.conflict_opt) {
+                        (true, _) => act.progressed(target_id, replicated, replicated + 1),
+                        (false, Some(conflict)) => act.progressed(target_id, prev_match, conflict.index),
+                        (false, None) => (),
+                    }
+                    if res.term == term {
+                        act.net.do_send(LeaderAcked { group: act.group, target: target_id, term, sent });
+                    }
+                    res
+                })
+        }))
+    }
+}
 
-            let req = node.send(SendRemoteMessage(msg));
+impl Handler<messages::VoteRequest> for GroupNetwork {
+    type Result = ResponseActFuture<Self, messages::VoteResponse, ()>;
 
-            return Box::new(
-                fut::wrap_future(req)
-                    .map_err(|_, _, _| error!("{}", ERR_ROUTING_FAILURE))
-                    .and_then(|res, _, _| fut::result(res)),
-            );
-        }
+    fn handle(&mut self, msg: messages::VoteRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        let route = RouteRpc {
+            target: msg.target,
+            source: msg.candidate_id,
+            bulk: false,
+        };
+        let target_id = msg.target;
+        tap::record(RpcDirection::Outbound, self.group, &msg);
 
-        Box::new(fut::err(()))
+        Box::new(self.route(route).and_then(move |node, act, _| {
+            fut::wrap_future(node.send(SendGroupMessage(act.group, msg)))
+                .map_err(|_, _, _| error!("{}", ERR_ROUTING_FAILURE))
+                .and_then(|res, _, _| fut::result(res))
+                .map(move |res: messages::VoteResponse, act: &mut Self, _| {
+                    tap::record_response(act.group, "VoteResponse", target_id, res.term, format!("granted={}", res.vote_granted));
+                    res
+                })
+        }))
     }
 }
 
-impl Handler<messages::InstallSnapshotRequest> for Network {
+impl Handler<messages::InstallSnapshotRequest> for GroupNetwork {
     type Result = ResponseActFuture<Self, messages::InstallSnapshotResponse, ()>;
 
     fn handle(
@@ -70,20 +257,91 @@ impl Handler<messages::InstallSnapshotRequest> for Network {
         msg: messages::InstallSnapshotRequest,
         _ctx: &mut Context<Self>,
     ) -> Self::Result {
-        if let Some(node) = self.get_node(msg.target) {
-            if self.isolated_nodes.contains(&msg.target) || self.isolated_nodes.contains(&msg.leader_id) {
-                return Box::new(fut::err(()));
-            }
+        let route = RouteRpc {
+            target: msg.target,
+            source: msg.leader_id,
+            bulk: true,
+        };
+        let delay = self.snapshot_delay(msg.target, msg.data.len());
+        let (target_id, done, last_included) = (msg.target, msg.done, msg.last_included_index);
+        tap::record(RpcDirection::Outbound, self.group, &msg);
 
-            let req = node.send(SendRemoteMessage(msg));
+        Box::new(
+            fut::wrap_future::<_, Self>(Delay::new(Instant::now() + delay))
+                .map_err(|_, _, _| ())
+                .and_then(move |_, act, _| act.route(route))
+                .and_then(move |node, act, _| {
+                    fut::wrap_future(node.send(SendGroupMessage(act.group, msg)))
+                        .map_err(|_, _, _| error!("{}", ERR_ROUTING_FAILURE))
+                        .and_then(|res, _, _| fut::result(res))
+                })
+                .map(move |res: messages::InstallSnapshotResponse, act: &mut Self, _| {
+                    tap::record_response(act.group, "InstallSnapshotResponse", target_id, res.term, String::new());
+                    if done {
+                        act.progressed(target_id, last_included, last_included + 1);
+                    }
+                    res
+                }),
+        )
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// RaftMetrics ///////////////////////////////////////////////////////////////
+
+impl Handler<RaftMetrics> for GroupNetwork {
+    type Result = ();
+
+    fn handle(&mut self, msg: RaftMetrics, ctx: &mut Context<Self>) -> Self::Result {
+        match (msg.state == State::Leader, self.storage.as_ref()) {
+            (true, Some(storage)) => fut::wrap_future::<_, Self>(storage.send(GetMembershipEpoch))
+                .map_err(|_, _, _| ())
+                .map(|res, act, _| act.membership_epoch = res.ok())
+                .spawn(ctx),
+            _ => self.membership_epoch = None,
+        }
+        self.net.do_send(GroupMetrics(self.group, msg));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NodeRequest;
+    use crate::raft::storage::MAX_COUNTER_KEY_LEN;
+    use crate::test_utils::entry;
+
+    fn request(entries: Vec<messages::Entry<Data>>, leader_commit: u64) -> messages::AppendEntriesRequest<Data> {
+        messages::AppendEntriesRequest { target: 2, term: 3, leader_id: 1, prev_log_index: 10, prev_log_term: 1, entries, leader_commit }
+    }
 
-            return Box::new(
-                fut::wrap_future(req)
-                    .map_err(|_, _, _| error!("{}", ERR_ROUTING_FAILURE))
-                    .and_then(|res, _, _| fut::result(res)),
-            );
+    #[test]
+    fn a_batch_of_the_largest_counter_entries_is_split_into_frames() {
+        let type_id = group_type_id(messages::AppendEntriesRequest::<Data>::type_id(), 1);
+        let entries = (11..1011).map(|index| entry(index, Data::SetExpiring("\"".repeat(MAX_COUNTER_KEY_LEN), i64::min_value(), u64::max_value()))).collect();
+        let requests = split_append_entries(request(entries, 1010), message_frame_overhead(&type_id));
+        assert!(requests.len() > 1);
+
+        let mut prev = (10, 1);
+        for request in requests.iter() {
+            let body = serde_json::to_string(request).unwrap();
+            let frame = serde_json::to_string(&NodeRequest::Message(u64::max_value(), type_id.clone(), body)).unwrap();
+            assert!(frame.len() <= MAX_FRAME_SIZE, "a frame of {} bytes", frame.len());
+
+            assert_eq!((request.prev_log_index, request.prev_log_term), prev);
+            let last = request.entries.last().unwrap();
+            assert!(request.leader_commit <= last.index);
+            prev = (last.index, last.term);
         }
+        assert_eq!(prev.0, 1010);
+        assert_eq!(requests.last().unwrap().leader_commit, 1010);
+    }
 
-        Box::new(fut::err(()))
+    #[test]
+    fn a_heartbeat_is_sent_as_is() {
+        let requests = split_append_entries(request(vec![], 7), message_frame_overhead("AppendEntriesRequest"));
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].entries.is_empty());
+        assert_eq!((requests[0].prev_log_index, requests[0].leader_commit), (10, 7));
     }
 }
@@ -0,0 +1,36 @@
+use futures::Future;
+
+use crate::raft::storage::{Entry, MemoryStorageData, MemoryStorageError, MemoryStorageResponse};
+
+/// Application logic run on the entries committed to a raft group.
+///
+/// The storage keeps the log, maintains the ring membership and the counters itself, and hands
+/// every applied normal entry to the state machine of its group. Entries are handed over one at a
+/// time in index order, the next one only once the future of the previous one resolved, so `apply`
+/// may have asynchronous side effects such as writing to a database. A failed apply is handled by
+/// the `apply_error_policy` like any other apply error.
+pub trait StateMachine: Send + 'static {
+    fn apply(
+        &mut self,
+        index: u64,
+        data: &MemoryStorageData,
+    ) -> Box<dyn Future<Item = MemoryStorageResponse, Error = MemoryStorageError>>;
+
+    /// Replace the state with the entries of an installed snapshot, which were not applied one by
+    /// one. Ignored by default.
+    fn restore(&mut self, _entries: &[Entry]) {}
+}
+
+/// The state machine used unless one is given to the `RaftBuilder`. It adds nothing to the
+/// membership and counters kept by the storage.
+pub struct DefaultStateMachine;
+
+impl StateMachine for DefaultStateMachine {
+    fn apply(
+        &mut self,
+        _index: u64,
+        _data: &MemoryStorageData,
+    ) -> Box<dyn Future<Item = MemoryStorageResponse, Error = MemoryStorageError>> {
+        Box::new(futures::future::ok(MemoryStorageResponse::default()))
+    }
+}
@@ -1,12 +1,17 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     fs::{self, File},
-    io::{Seek, SeekFrom, Write},
+    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
     path::PathBuf,
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use actix::prelude::*;
-use log::{debug, error};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use log::{debug, error, info, warn};
 use rmp_serde as rmps;
 use serde::{Deserialize, Serialize};
 
@@ -20,23 +25,83 @@ use actix_raft::{
     AppData, AppDataResponse, AppError, NodeId,
 };
 
-use crate::hash_ring::RingType;
+use crate::config::{ApplyErrorPolicy, ConfigSchema, DivergencePolicy, EntryEncoding, SnapshotRetention, SnapshotSync};
+use crate::hash_ring::{Ring, RingType};
+use crate::raft::{state_machine::StateMachine, GroupId, DEFAULT_GROUP};
 use crate::server::{Server, Rebalance};
+use crate::utils::{LatencyHistogram, LatencySummary, NoopMetrics, SharedMetrics};
 
-type Entry = RaftEntry<MemoryStorageData>;
+pub type Entry = RaftEntry<MemoryStorageData>;
 
 /// The concrete data type used by the `MemoryStorage` system.
+///
+/// Besides the membership it replicates a map of named counters, an example of application state.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum MemoryStorageData {
     Add(NodeId),
     Remove(NodeId),
+    /// Add to a counter, a missing counter starts at 0
+    Incr(String, i64),
+    Set(String, i64),
+    /// Every node rejects client requests until the given unix time in milliseconds
+    Freeze(u64),
+    Unfreeze,
+    /// Rebuild the ring of every node with this many virtual nodes per node
+    RingReplicas(u32),
+    /// Set a counter which is removed once the replicated clock reaches the given unix time in
+    /// milliseconds
+    SetExpiring(String, i64, u64),
+    /// Advance the replicated clock to the given unix time in milliseconds, removing the counters
+    /// expired by then. Proposed by the leader, so every replica expires at the same index.
+    Tick(u64),
+    /// Bytes serialized by the application, the storage doesn't interpret them and hands them
+    /// to the state machine as is
+    Raw(Vec<u8>),
+}
+
+/// Longest counter name, bounds the size of an entry
+pub const MAX_COUNTER_KEY_LEN: usize = 64;
+
+/// Most virtual nodes per node, bounds the size of the ring
+pub const MAX_RING_REPLICAS: u32 = 1000;
+
+/// Largest raw entry, in bytes. A byte takes up to 4 json characters, twice that once the RPC
+/// body is nested in its frame, so an append entries RPC carrying one still fits a frame.
+pub const MAX_RAW_ENTRY_LEN: usize = 4000;
+
+impl MemoryStorageData {
+    /// Whether the entry fits the bounds of the replicated state
+    pub fn is_valid(&self) -> bool {
+        match self {
+            MemoryStorageData::Incr(key, _) | MemoryStorageData::Set(key, _) | MemoryStorageData::SetExpiring(key, _, _) => {
+                key.len() <= MAX_COUNTER_KEY_LEN
+            }
+            MemoryStorageData::RingReplicas(replicas) => *replicas > 0 && *replicas <= MAX_RING_REPLICAS,
+            MemoryStorageData::Raw(bytes) => bytes.len() <= MAX_RAW_ENTRY_LEN,
+            _ => true,
+        }
+    }
+
+    /// Whether the entry is accepted while writes are frozen: it freezes, unfreezes or advances the
+    /// replicated clock which ends the freeze
+    pub fn passes_freeze(&self) -> bool {
+        match self {
+            MemoryStorageData::Freeze(_) | MemoryStorageData::Unfreeze | MemoryStorageData::Tick(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl AppData for MemoryStorageData {}
 
 /// The concrete data type used for responding from the storage engine when applying logs to the state machine.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
-pub struct MemoryStorageResponse;
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct MemoryStorageResponse {
+    /// voters which hadn't applied the entry yet when the write was acknowledged, only reported
+    /// with `CommitQuorum::All`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lagging: Vec<NodeId>,
+}
 
 impl AppDataResponse for MemoryStorageResponse {}
 
@@ -63,20 +128,86 @@ impl AppError for MemoryStorageError {}
 /// This storage implementation structures its data as an append-only immutable log. The contents
 /// of the entries given to this storage implementation are not ready or manipulated.
 pub struct MemoryStorage {
+    group: GroupId,
     hs: HardState,
     log: BTreeMap<u64, Entry>,
     snapshot_data: Option<CurrentSnapshotData>,
     snapshot_dir: String,
     state_machine: BTreeMap<u64, Entry>,
+    /// application logic run on the applied entries
+    app: Box<dyn StateMachine>,
+    /// applied entries not handed to `app` yet
+    app_pending: VecDeque<Entry>,
     snapshot_actor: Addr<SnapshotActor>,
     ring: RingType,
+    /// nodes this storage put on the ring
+    ring_members: BTreeSet<NodeId>,
+    /// virtual nodes per node, from `ring_replicas` until an entry replaces it
+    ring_replicas: u32,
+    /// membership changes applied to the ring, see `MembershipEpoch`
+    membership_epoch: u64,
+    /// told about every membership change applied to the ring
+    membership_subscribers: Vec<Recipient<MembershipApplied>>,
+    /// told about every freeze and unfreeze applied
+    freeze_subscribers: Vec<Recipient<WritesFrozen>>,
+    /// replicated clock, the latest `Tick` applied, in unix milliseconds
+    replicated_now: u64,
+    /// end of the write freeze applied, on the replicated clock
+    frozen_until: Option<u64>,
+    /// when each expiring counter applied expires, on the replicated clock
+    expiries: BTreeMap<String, u64>,
+    /// the expiring counters by expiry, the earliest first
+    expiry_order: BTreeSet<(u64, String)>,
+    /// latest membership changes applied, oldest first
+    membership_audit: VecDeque<MembershipEvent>,
+    membership_audit_size: usize,
+    /// latest entries applied, oldest first
+    recent_entries: VecDeque<RecentEntry>,
+    recent_entries_size: usize,
     server: Addr<Server>,
+    snapshot_retention: SnapshotRetention,
+    apply_error_policy: ApplyErrorPolicy,
+    divergence_policy: DivergencePolicy,
+    entry_encoding: EntryEncoding,
+    max_snapshot_bytes: Option<u64>,
+    /// Set once an apply error halted the state machine
+    apply_halted: Option<String>,
+    /// Last apply, divergence or snapshot failure, and when it happened
+    last_error: Option<(Instant, String)>,
+    /// Committed entries refused while halted, applied by `DrainApply`
+    pending_apply: BTreeMap<u64, Entry>,
+    /// The state machine is torn while a snapshot from the leader is installed
+    installing_snapshot: bool,
+    /// Most replicated entries applied as one run, see `apply_batch_size`
+    apply_batch_size: Option<usize>,
+    /// Set while a run is applied, the ring is rebalanced once at its end
+    applying_run: bool,
+    rebalance_pending: bool,
+    /// duration of every apply call, including the application state machine
+    apply_latency: LatencyHistogram,
+    /// duration of every append to the log
+    append_latency: LatencyHistogram,
+    slow_apply: Duration,
+    metrics: SharedMetrics,
+    /// file the log was last imported from or exported to, and its last index then
+    last_export: Option<(PathBuf, Option<u64>)>,
 }
 
 impl MemoryStorage {
     /// Create a new instance.
-    pub fn new(members: Vec<NodeId>, snapshot_dir: String, ring: RingType, server: Addr<Server>) -> Self {
+    pub fn new(
+        group: GroupId,
+        members: Vec<NodeId>,
+        snapshot_dir: String,
+        ring: RingType,
+        server: Addr<Server>,
+        app: Box<dyn StateMachine>,
+        config: &ConfigSchema,
+    ) -> Self {
         let snapshot_dir_pathbuf = std::path::PathBuf::from(snapshot_dir.clone());
+        let encoding = config.entry_encoding;
+        let max_snapshot_bytes = config.max_snapshot_bytes;
+        let snapshot_sync = config.snapshot_sync;
         let membership = MembershipConfig {
             members,
             non_voters: vec![],
@@ -84,6 +215,7 @@ impl MemoryStorage {
             is_in_joint_consensus: false,
         };
         Self {
+            group: group,
             hs: HardState {
                 current_term: 0,
                 voted_for: None,
@@ -93,11 +225,43 @@ impl MemoryStorage {
             snapshot_data: None,
             snapshot_dir,
             state_machine: Default::default(),
+            app: app,
+            app_pending: VecDeque::new(),
             snapshot_actor: SyncArbiter::start(1, move || {
-                SnapshotActor(snapshot_dir_pathbuf.clone())
+                SnapshotActor(snapshot_dir_pathbuf.clone(), encoding, max_snapshot_bytes, FileSyncer::new(snapshot_sync))
             }),
             ring: ring,
+            ring_members: BTreeSet::new(),
+            ring_replicas: config.ring_replicas,
+            membership_epoch: 0,
+            membership_subscribers: Vec::new(),
+            freeze_subscribers: Vec::new(),
+            replicated_now: 0,
+            frozen_until: None,
+            expiries: BTreeMap::new(),
+            expiry_order: BTreeSet::new(),
+            membership_audit: VecDeque::new(),
+            membership_audit_size: config.membership_audit_size,
+            recent_entries: VecDeque::with_capacity(config.recent_entries_size),
+            recent_entries_size: config.recent_entries_size,
             server: server,
+            snapshot_retention: config.snapshot_retention.clone(),
+            apply_error_policy: config.apply_error_policy.clone(),
+            divergence_policy: config.divergence_policy.clone(),
+            entry_encoding: encoding,
+            max_snapshot_bytes: max_snapshot_bytes,
+            apply_halted: None,
+            last_error: None,
+            pending_apply: BTreeMap::new(),
+            installing_snapshot: false,
+            apply_batch_size: config.apply_batch_size,
+            applying_run: false,
+            rebalance_pending: false,
+            apply_latency: LatencyHistogram::new(),
+            append_latency: LatencyHistogram::new(),
+            slow_apply: Duration::from_millis(config.slow_apply_ms),
+            metrics: Arc::new(NoopMetrics),
+            last_export: None,
         }
     }
 }
@@ -169,7 +333,12 @@ impl Handler<AppendEntryToLog<MemoryStorageData, MemoryStorageError>> for Memory
         msg: AppendEntryToLog<MemoryStorageData, MemoryStorageError>,
         _: &mut Self::Context,
     ) -> Self::Result {
+        let started = Instant::now();
+        if let Err(err) = self.check_divergence(&msg.entry) {
+            return Box::new(fut::err(err));
+        }
         self.log.insert(msg.entry.index, (*msg.entry).clone());
+        self.record_append(started);
         Box::new(fut::ok(()))
     }
 }
@@ -182,9 +351,14 @@ impl Handler<ReplicateToLog<MemoryStorageData, MemoryStorageError>> for MemorySt
         msg: ReplicateToLog<MemoryStorageData, MemoryStorageError>,
         _: &mut Self::Context,
     ) -> Self::Result {
-        msg.entries.iter().for_each(|e| {
+        let started = Instant::now();
+        for e in msg.entries.iter() {
+            if let Err(err) = self.check_divergence(e) {
+                return Box::new(fut::err(err));
+            }
             self.log.insert(e.index, e.clone());
-        });
+        }
+        self.record_append(started);
         Box::new(fut::ok(()))
     }
 }
@@ -199,32 +373,15 @@ impl Handler<ApplyEntryToStateMachine<MemoryStorageData, MemoryStorageResponse,
         msg: ApplyEntryToStateMachine<MemoryStorageData, MemoryStorageResponse, MemoryStorageError>,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        let res = if let Some(old) = self
-            .state_machine
-            .insert(msg.payload.index, (*msg.payload).clone())
-        {
-            println!("Critical error. State machine entires are not allowed to be overwritten. Entry: {:?}", old);
-            Err(MemoryStorageError)
-        } else {
-            if let EntryPayload::Normal(entry) = &msg.payload.payload {
-                let mut ring = self.ring.write().unwrap();
-                match (*entry).data {
-                    MemoryStorageData::Add(node_id) => {
-                        println!("Adding node {}", node_id);
-                        ring.add_node(&node_id);
-                        self.server.do_send(Rebalance)
-                    }
-                    MemoryStorageData::Remove(node_id) => {
-                        println!("Removing node {}", node_id);
-                        ring.remove_node(&node_id)
-                    }
-                }
-            } else {
-            }
-
-            Ok(MemoryStorageResponse)
-        };
-        Box::new(fut::result(res))
+        let started = Instant::now();
+        let index = msg.payload.index;
+        match self.apply_entry(&msg.payload) {
+            Ok(_) => Box::new(self.apply_to_app().then(move |res, act: &mut Self, _ctx| {
+                act.record_apply(started, index, 1);
+                fut::result(res)
+            })),
+            Err(err) => Box::new(fut::err(err)),
+        }
     }
 }
 
@@ -236,31 +393,14 @@ impl Handler<ReplicateToStateMachine<MemoryStorageData, MemoryStorageError>> for
         msg: ReplicateToStateMachine<MemoryStorageData, MemoryStorageError>,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        let res = msg.payload.iter().try_for_each(|e| {
-            if let Some(old) = self.state_machine.insert(e.index, e.clone()) {
-                println!("Critical error. State machine entires are not allowed to be overwritten. Entry: {:?}", old);
-                return Err(MemoryStorageError)
-            }
-            if let EntryPayload::Normal(entry) = &e.payload {
-                let mut ring = self.ring.write().unwrap();
-                match entry.data {
-                    MemoryStorageData::Add(node_id) => {
-                        println!("Adding node {}", node_id);
-                        ring.add_node(&node_id);
-                        self.server.do_send(Rebalance)
-                    }
-                    MemoryStorageData::Remove(node_id) => {
-                        println!("Removing node {}", node_id);
-                        ring.remove_node(&node_id)
-                    }
-                }
-            } else {
-
-            }
-
-            Ok(())
-        });
-        Box::new(fut::result(res))
+        let started = Instant::now();
+        let res = self.apply_committed(&msg.payload);
+        let (index, count) = (msg.payload.last().map(|e| e.index).unwrap_or(0), msg.payload.len());
+        // the entries applied before a failure are handed to the application unless it halted
+        Box::new(self.apply_to_app().then(move |app, act: &mut Self, _ctx| {
+            act.record_apply(started, index, count);
+            fut::result(app.and(res))
+        }))
     }
 }
 
@@ -285,10 +425,11 @@ impl Handler<CreateSnapshot<MemoryStorageError>> for MemoryStorage {
             .collect::<Vec<_>>();
         debug!("Creating snapshot with {} entries.", entries.len());
         let (index, term) = entries.last().map(|e| (e.index, e.term)).unwrap_or((0, 0));
-        let snapdata = match rmps::to_vec(&entries) {
+        let snapdata = match encode_entries(self.entry_encoding, &entries) {
             Ok(snapdata) => snapdata,
             Err(err) => {
                 error!("Error serializing log for creating a snapshot. {}", err);
+                self.record_error(format!("snapshot serialization failed: {}", err));
                 return Box::new(fut::err(MemoryStorageError));
             }
         };
@@ -302,7 +443,12 @@ impl Handler<CreateSnapshot<MemoryStorageError>> for MemoryStorage {
                     .send(CreateSnapshotWithData(filepath.clone(), snapdata)),
             )
             .map_err(|err, _, _| panic!("Error communicating with snapshot actor. {}", err))
-            .and_then(|res, _, _| fut::result(res))
+            .and_then(|res, act: &mut Self, _| {
+                if res.is_err() {
+                    act.record_error("writing a snapshot failed".to_owned());
+                }
+                fut::result(res)
+            })
             // Clean up old log entries which are now part of the new snapshot.
             .and_then(move |_, act: &mut Self, _| {
                 let path = filepath.to_string_lossy().to_string();
@@ -320,6 +466,7 @@ impl Handler<CreateSnapshot<MemoryStorageError>> for MemoryStorage {
                     pointer,
                 };
                 act.snapshot_data = Some(current_snap_data.clone());
+                act.prune_snapshots(filepath);
 
                 fut::ok(current_snap_data)
             }),
@@ -336,6 +483,7 @@ impl Handler<InstallSnapshot<MemoryStorageError>> for MemoryStorage {
         _: &mut Self::Context,
     ) -> Self::Result {
         let (index, term) = (msg.index, msg.term);
+        self.installing_snapshot = true;
         Box::new(
             fut::wrap_future(self.snapshot_actor.send(SyncInstallSnapshot(msg)))
                 .map_err(|err, _, _| panic!("Error communicating with snapshot actor. {}", err))
@@ -354,6 +502,7 @@ impl Handler<InstallSnapshot<MemoryStorageError>> for MemoryStorage {
                     let entry = Entry::new_snapshot_pointer(pointer.clone(), index, term);
                     act.log = act.log.split_off(&index);
                     let previous = act.log.insert(index, entry);
+                    act.prune_snapshots(PathBuf::from(pointer.path.clone()));
 
                     // If there are any logs newer than `index`, then we are done. Else, the state
                     // machine should be reset, and recreated from the new snapshot.
@@ -368,6 +517,13 @@ impl Handler<InstallSnapshot<MemoryStorageError>> for MemoryStorage {
                             fut::Either::B(act.rebuild_state_machine_from_snapshot(ctx, pathbuf))
                         }
                     }
+                })
+                .then(|res, act: &mut Self, _| {
+                    act.installing_snapshot = false;
+                    if res.is_err() {
+                        act.record_error("installing a snapshot failed".to_owned());
+                    }
+                    fut::result(res)
                 }),
         )
     }
@@ -387,6 +543,440 @@ impl Handler<GetCurrentSnapshot<MemoryStorageError>> for MemoryStorage {
 }
 
 impl MemoryStorage {
+    /// Apply a committed entry to the state machine, failures are handled by the apply error
+    /// policy. Once halted no further entries are applied.
+    fn apply_entry(&mut self, entry: &Entry) -> Result<(), MemoryStorageError> {
+        if let Some(ref reason) = self.apply_halted {
+            error!("State machine is halted, refusing to apply entry {}. {}", entry.index, reason);
+            self.pending_apply.insert(entry.index, entry.clone());
+            return Err(MemoryStorageError);
+        }
+
+        if let Some(old) = self.state_machine.get(&entry.index) {
+            let reason = format!("Critical error. State machine entires are not allowed to be overwritten. Entry: {:?}", old);
+            return self.handle_apply_error(entry.index, reason);
+        }
+
+        self.state_machine.insert(entry.index, entry.clone());
+        self.apply_effects(entry);
+
+        Ok(())
+    }
+
+    /// Apply an entry already in the state machine to the ring, the replicated clock, the
+    /// diagnostics and queue it for the application
+    fn apply_effects(&mut self, entry: &Entry) {
+        if let EntryPayload::Normal(normal) = &entry.payload {
+            self.apply_to_ring(&normal.data);
+            self.apply_to_clock(&normal.data);
+            self.audit_membership(entry.index, &normal.data);
+            self.record_recent(entry.index, &normal.data);
+            self.app_pending.push_back(entry.clone());
+        }
+    }
+
+    fn record_append(&mut self, started: Instant) {
+        let elapsed = started.elapsed();
+        self.append_latency.record(elapsed);
+        let group = self.group.to_string();
+        self.metrics.histogram("raftor_append_latency_us", &[("group", &group)], elapsed.as_micros() as f64);
+    }
+
+    /// Record the duration of an apply call of `count` entries through `index`
+    fn record_apply(&mut self, started: Instant, index: u64, count: usize) {
+        let elapsed = started.elapsed();
+        self.apply_latency.record(elapsed);
+        let group = self.group.to_string();
+        self.metrics.histogram("raftor_apply_latency_us", &[("group", &group)], elapsed.as_micros() as f64);
+        self.metrics.counter("raftor_applied_entries_total", &[("group", &group)], count as u64);
+        if self.slow_apply > Duration::from_secs(0) && elapsed > self.slow_apply {
+            warn!("Applying {} entries through {} took {:?}", count, index, elapsed);
+        }
+    }
+
+    /// Hand the entries applied since the last call to the application state machine, one at a
+    /// time in index order. Resolves with the response to the last one.
+    ///
+    /// Once halted the entries wait for `DrainApply`: an entry the application failed on is queued
+    /// again with the ones after it, so none of them is lost.
+    fn apply_to_app(&mut self) -> ResponseActFuture<Self, MemoryStorageResponse, MemoryStorageError> {
+        if self.apply_halted.is_some() {
+            return Box::new(fut::err(MemoryStorageError));
+        }
+        let entries: VecDeque<Entry> = self.app_pending.drain(..).collect();
+        self.apply_batch_to_app(entries, MemoryStorageResponse::default())
+    }
+
+    fn apply_batch_to_app(
+        &mut self,
+        mut entries: VecDeque<Entry>,
+        last: MemoryStorageResponse,
+    ) -> ResponseActFuture<Self, MemoryStorageResponse, MemoryStorageError> {
+        let entry = match entries.pop_front() {
+            Some(entry) => entry,
+            None => return Box::new(fut::ok(last)),
+        };
+        let index = entry.index;
+        let apply = match entry.payload {
+            EntryPayload::Normal(ref normal) => self.app.apply(index, &normal.data),
+            _ => Box::new(futures::future::ok(MemoryStorageResponse::default())),
+        };
+        Box::new(fut::wrap_future::<_, Self>(apply).then(move |res, act: &mut Self, _ctx| match res {
+            Ok(res) => act.apply_batch_to_app(entries, res),
+            Err(err) => {
+                let reason = format!("State machine failed to apply entry. {:?}", err);
+                if let Err(err) = act.handle_apply_error(index, reason) {
+                    // ahead of the entries applied meanwhile
+                    entries.push_front(entry);
+                    for entry in entries.into_iter().rev() {
+                        act.app_pending.push_front(entry);
+                    }
+                    return Box::new(fut::err(err)) as ResponseActFuture<Self, _, _>;
+                }
+                act.apply_batch_to_app(entries, MemoryStorageResponse::default())
+            }
+        }))
+    }
+
+    /// Let the application catch up with the entries applied by a synchronous handler before
+    /// the next message is handled
+    fn wait_for_app(&mut self, ctx: &mut Context<Self>) {
+        if !self.app_pending.is_empty() {
+            ctx.wait(self.apply_to_app().map(|_, _, _| ()).map_err(|_, _, _| ()));
+        }
+    }
+
+    /// Apply committed entries in runs of `apply_batch_size`, or one by one when it is unset
+    fn apply_committed(&mut self, entries: &[Entry]) -> Result<(), MemoryStorageError> {
+        match self.apply_batch_size {
+            Some(size) => entries.chunks(size.max(1)).try_for_each(|run| self.apply_run(run)),
+            None => entries.iter().try_for_each(|e| self.apply_entry(e)),
+        }
+    }
+
+    /// Apply a contiguous run of committed entries: the state machine takes the whole run at once,
+    /// then the effects of every entry are applied in order, subscribers still hear of each
+    /// membership change, and the ring is rebalanced once at the end
+    fn apply_run(&mut self, run: &[Entry]) -> Result<(), MemoryStorageError> {
+        self.applying_run = true;
+        let res = if self.apply_halted.is_some() || run.iter().any(|e| self.state_machine.contains_key(&e.index)) {
+            // refused or overwriting, the apply error policy handles the entries one by one
+            run.iter().try_for_each(|e| self.apply_entry(e))
+        } else {
+            self.state_machine.extend(run.iter().map(|e| (e.index, e.clone())));
+            run.iter().for_each(|e| self.apply_effects(e));
+            Ok(())
+        };
+        self.applying_run = false;
+
+        if self.rebalance_pending {
+            self.rebalance_pending = false;
+            self.server.do_send(Rebalance);
+        }
+        if let Some(last) = run.last() {
+            debug!("Applied a run of {} entries through {}.", run.len(), last.index);
+        }
+        res
+    }
+
+    /// Compare an entry about to be stored with the one at its index. Replacing an uncommitted
+    /// entry of another term is the normal raft truncation, replacing an applied one means the
+    /// logs diverged and is handled by the divergence policy.
+    fn check_divergence(&mut self, entry: &Entry) -> Result<(), MemoryStorageError> {
+        let existing = match self.log.get(&entry.index) {
+            Some(existing) if existing.term != entry.term => existing.term,
+            _ => return Ok(()),
+        };
+
+        if !self.state_machine.contains_key(&entry.index) {
+            debug!("Truncating uncommitted entry {} of term {} for term {}.", entry.index, existing, entry.term);
+            return Ok(());
+        }
+
+        let reason = format!(
+            "log diverged at applied entry {}: local term {}, leader term {}",
+            entry.index, existing, entry.term
+        );
+        match self.divergence_policy {
+            DivergencePolicy::Overwrite => {
+                error!("Group {} {}, overwriting with the leader's entry.", self.group, reason);
+                Ok(())
+            }
+            DivergencePolicy::Halt => {
+                error!("Group {} {}, halting for inspection.", self.group, reason);
+                self.record_error(reason.clone());
+                self.apply_halted = Some(reason);
+                Err(MemoryStorageError)
+            }
+        }
+    }
+
+    fn record_error(&mut self, err: String) {
+        debug!("Group {} storage error: {}", self.group, err);
+        self.last_error = Some((Instant::now(), err));
+    }
+
+    fn handle_apply_error(&mut self, index: u64, reason: String) -> Result<(), MemoryStorageError> {
+        self.record_error(format!("entry {}: {}", index, reason));
+        match self.apply_error_policy {
+            ApplyErrorPolicy::Halt => {
+                error!("Halting state machine at entry {}. {}", index, reason);
+                self.apply_halted = Some(format!("entry {}: {}", index, reason));
+                Err(MemoryStorageError)
+            }
+            ApplyErrorPolicy::SkipAndLog => {
+                error!("Skipping entry {}. {}", index, reason);
+                Ok(())
+            }
+        }
+    }
+
+    /// Apply a membership entry to the hash ring. Only the default group owns the ring.
+    fn apply_to_ring(&mut self, data: &MemoryStorageData) {
+        if self.group != DEFAULT_GROUP {
+            return;
+        }
+
+        if let MemoryStorageData::RingReplicas(replicas) = *data {
+            println!("Rebuilding the ring with {} virtual nodes per node", replicas);
+            self.ring_replicas = replicas;
+            Ring::reset(&self.ring, self.ring_members.iter().cloned().collect(), replicas as isize);
+            if self.applying_run {
+                self.rebalance_pending = true;
+            } else {
+                self.server.do_send(Rebalance)
+            }
+            return;
+        }
+
+        let mut ring = self.ring.write().unwrap();
+        match *data {
+            MemoryStorageData::Add(node_id) => {
+                println!("Adding node {}", node_id);
+                ring.add_node(&node_id);
+                self.ring_members.insert(node_id);
+                self.membership_epoch += 1;
+                self.notify_membership(MembershipApplied::Added(node_id));
+                if self.applying_run {
+                    self.rebalance_pending = true;
+                } else {
+                    self.server.do_send(Rebalance)
+                }
+            }
+            MemoryStorageData::Remove(node_id) => {
+                println!("Removing node {}", node_id);
+                ring.remove_node(&node_id);
+                self.ring_members.remove(&node_id);
+                self.membership_epoch += 1;
+                self.notify_membership(MembershipApplied::Removed(node_id));
+            }
+            _ => (),
+        }
+    }
+
+    /// Apply an entry to the replicated clock, telling the subscribers when the freeze changed
+    fn apply_to_clock(&mut self, data: &MemoryStorageData) {
+        let before = self.frozen_until;
+        self.advance_replicated_clock(data);
+        self.notify_freeze(before);
+    }
+
+    /// Follow the replicated clock, the expiring counters and the freeze of the default group,
+    /// which covers all groups. A counter expires, and a freeze ends, once a `Tick` reaches its
+    /// deadline, at the same index on every replica.
+    fn advance_replicated_clock(&mut self, data: &MemoryStorageData) {
+        match *data {
+            MemoryStorageData::Freeze(until) if self.group == DEFAULT_GROUP => self.frozen_until = Some(until),
+            MemoryStorageData::Unfreeze if self.group == DEFAULT_GROUP => self.frozen_until = None,
+            // an increment keeps the expiry of the counter
+            MemoryStorageData::Set(ref key, _) => self.forget_expiry(key),
+            MemoryStorageData::SetExpiring(ref key, _, at) => {
+                self.forget_expiry(key);
+                if at > self.replicated_now {
+                    self.expiries.insert(key.clone(), at);
+                    self.expiry_order.insert((at, key.clone()));
+                }
+            }
+            MemoryStorageData::Tick(now) => {
+                self.replicated_now = self.replicated_now.max(now);
+                while let Some((at, key)) = self.expiry_order.iter().next().cloned() {
+                    if at > self.replicated_now {
+                        break;
+                    }
+                    self.forget_expiry(&key);
+                }
+            }
+            _ => return,
+        }
+        if self.frozen_until.map(|until| until <= self.replicated_now).unwrap_or(false) {
+            self.frozen_until = None;
+        }
+    }
+
+    fn forget_expiry(&mut self, key: &str) {
+        if let Some(at) = self.expiries.remove(key) {
+            self.expiry_order.remove(&(at, key.to_owned()));
+        }
+    }
+
+    /// Replay the replicated clock, the expiring counters and the freeze from the state machine,
+    /// whose entries weren't applied one by one when they came with a snapshot
+    fn restore_replicated_clock(&mut self) {
+        let before = self.frozen_until;
+        self.replicated_now = 0;
+        self.frozen_until = None;
+        self.expiries.clear();
+        self.expiry_order.clear();
+        let data: Vec<MemoryStorageData> = self
+            .state_machine
+            .values()
+            .filter_map(|entry| match &entry.payload {
+                EntryPayload::Normal(normal) => Some(normal.data.clone()),
+                _ => None,
+            })
+            .collect();
+        data.iter().for_each(|data| self.advance_replicated_clock(data));
+        self.notify_freeze(before);
+    }
+
+    fn notify_freeze(&mut self, before: Option<u64>) {
+        if self.frozen_until != before {
+            let frozen = WritesFrozen(self.frozen_until);
+            self.freeze_subscribers.retain(|subscriber| subscriber.do_send(frozen.clone()).is_ok());
+        }
+    }
+
+    fn notify_membership(&mut self, change: MembershipApplied) {
+        // drop the subscribers which stopped
+        self.membership_subscribers.retain(|subscriber| subscriber.do_send(change.clone()).is_ok());
+    }
+
+    /// Record a membership change of the default group in the audit buffer
+    fn audit_membership(&mut self, index: u64, data: &MemoryStorageData) {
+        match data {
+            MemoryStorageData::Add(_) | MemoryStorageData::Remove(_) if self.group == DEFAULT_GROUP => (),
+            _ => return,
+        }
+
+        if self.membership_audit_size == 0 {
+            return;
+        }
+        while self.membership_audit.len() >= self.membership_audit_size {
+            self.membership_audit.pop_front();
+        }
+        self.membership_audit.push_back(MembershipEvent {
+            index: index,
+            change: data.clone(),
+            applied_at: SystemTime::now(),
+        });
+    }
+
+    /// Keep an applied entry in the buffer served by `GetRecentEntries`
+    fn record_recent(&mut self, index: u64, data: &MemoryStorageData) {
+        if self.recent_entries_size == 0 {
+            return;
+        }
+        while self.recent_entries.len() >= self.recent_entries_size {
+            self.recent_entries.pop_front();
+        }
+        self.recent_entries.push_back(RecentEntry {
+            index: index,
+            data: data.clone(),
+        });
+    }
+
+    /// Members according to the entries applied through `index`
+    fn applied_members(&self, index: u64) -> BTreeSet<NodeId> {
+        let mut members = BTreeSet::new();
+        for (_, entry) in self.state_machine.range(..=index) {
+            if let EntryPayload::Normal(normal) = &entry.payload {
+                match normal.data {
+                    MemoryStorageData::Add(node_id) => members.insert(node_id),
+                    MemoryStorageData::Remove(node_id) => members.remove(&node_id),
+                    _ => false,
+                };
+            }
+        }
+        members
+    }
+
+    /// Membership changes in the entries applied through `index`, the epoch of a ring in sync
+    fn applied_membership_epoch(&self, index: u64) -> u64 {
+        self.state_machine
+            .range(..=index)
+            .filter(|(_, entry)| match &entry.payload {
+                EntryPayload::Normal(normal) => match normal.data {
+                    MemoryStorageData::Add(_) | MemoryStorageData::Remove(_) => true,
+                    _ => false,
+                },
+                _ => false,
+            })
+            .count() as u64
+    }
+
+    /// Virtual nodes per node set by the last entry applied through `index`, if any
+    fn applied_ring_replicas(&self, index: u64) -> Option<u32> {
+        self.state_machine.range(..=index).rev().find_map(|(_, entry)| match &entry.payload {
+            EntryPayload::Normal(normal) => match normal.data {
+                MemoryStorageData::RingReplicas(replicas) => Some(replicas),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    /// Counters according to the entries applied through `index`. Expiry only follows the times
+    /// in the entries, never the clock of this node.
+    fn applied_counters(&self, index: u64) -> BTreeMap<String, i64> {
+        let mut counters = BTreeMap::new();
+        let mut expiries: BTreeMap<String, u64> = BTreeMap::new();
+        let mut clock = 0;
+        for (_, entry) in self.state_machine.range(..=index) {
+            if let EntryPayload::Normal(normal) = &entry.payload {
+                match normal.data {
+                    // an increment keeps the expiry of the counter
+                    MemoryStorageData::Incr(ref key, by) => {
+                        let counter = counters.entry(key.clone()).or_insert(0i64);
+                        *counter = counter.wrapping_add(by);
+                    }
+                    MemoryStorageData::Set(ref key, value) => {
+                        counters.insert(key.clone(), value);
+                        expiries.remove(key);
+                    }
+                    MemoryStorageData::SetExpiring(ref key, _, at) if at <= clock => {
+                        counters.remove(key);
+                        expiries.remove(key);
+                    }
+                    MemoryStorageData::SetExpiring(ref key, value, at) => {
+                        counters.insert(key.clone(), value);
+                        expiries.insert(key.clone(), at);
+                    }
+                    MemoryStorageData::Tick(now) => {
+                        clock = clock.max(now);
+                        let expired: Vec<String> =
+                            expiries.iter().filter(|(_, at)| **at <= clock).map(|(key, _)| key.clone()).collect();
+                        for key in expired {
+                            counters.remove(&key);
+                            expiries.remove(&key);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        counters
+    }
+
+    /// Apply the snapshot retention policy, never removing the snapshot at `current`.
+    fn prune_snapshots(&self, current: PathBuf) {
+        if self.snapshot_retention.is_enabled() {
+            self.snapshot_actor.do_send(PruneSnapshots {
+                current,
+                retention: self.snapshot_retention.clone(),
+            });
+        }
+    }
+
     /// Rebuild the state machine from the specified snapshot.
     fn rebuild_state_machine_from_snapshot(
         &mut self,
@@ -400,8 +990,13 @@ impl MemoryStorage {
             // Rebuild state machine from the deserialized data.
             .and_then(|entries, act: &mut Self, _| {
                 act.state_machine.clear();
+                // the entries covered by the snapshot weren't applied one by one
+                act.recent_entries.clear();
+                act.app_pending.clear();
+                act.app.restore(&entries);
                 act.state_machine
                     .extend(entries.into_iter().map(|e| (e.index, e)));
+                act.restore_replicated_clock();
                 fut::ok(())
             })
             .map(|_, _, _| debug!("Finished rebuilding statemachine from snapshot successfully."))
@@ -411,8 +1006,9 @@ impl MemoryStorage {
 //////////////////////////////////////////////////////////////////////////////////////////////////
 // SnapshotActor /////////////////////////////////////////////////////////////////////////////////
 
-/// A simple synchronous actor for interfacing with the filesystem for snapshots.
-struct SnapshotActor(std::path::PathBuf);
+/// A simple synchronous actor for interfacing with the filesystem for snapshots. Snapshots larger
+/// than the optional bound are refused, their entries would have to be held in memory.
+struct SnapshotActor(std::path::PathBuf, EntryEncoding, Option<u64>, FileSyncer);
 
 impl Actor for SnapshotActor {
     type Context = SyncContext<Self>;
@@ -433,10 +1029,57 @@ impl Handler<CreateSnapshotWithData> for SnapshotActor {
     type Result = Result<(), MemoryStorageError>;
 
     fn handle(&mut self, msg: CreateSnapshotWithData, _: &mut Self::Context) -> Self::Result {
-        fs::write(msg.0.clone(), msg.1).map_err(|err| {
+        let mut file = File::create(&msg.0).map_err(|err| {
+            error!("Error creating snapshot file. {}", err);
+            MemoryStorageError
+        })?;
+        file.write_all(&msg.1).map_err(|err| {
             error!("Error writing snapshot file. {}", err);
             MemoryStorageError
-        })
+        })?;
+        self.3.sync(file)
+    }
+}
+
+/// Makes the files written by the `SnapshotActor` durable according to `snapshot_sync`. Batched
+/// syncs run on a single background thread, which syncs the files queued meanwhile in one pass
+/// and stops along with the actor.
+struct FileSyncer(SnapshotSync, Option<mpsc::Sender<File>>);
+
+impl FileSyncer {
+    fn new(snapshot_sync: SnapshotSync) -> FileSyncer {
+        if snapshot_sync != SnapshotSync::Batched {
+            return FileSyncer(snapshot_sync, None);
+        }
+
+        let (tx, rx) = mpsc::channel::<File>();
+        thread::spawn(move || {
+            while let Ok(file) = rx.recv() {
+                let batch: Vec<File> = std::iter::once(file).chain(rx.try_iter()).collect();
+                for file in &batch {
+                    if let Err(err) = file.sync_all() {
+                        warn!("Error syncing file to disk. {}", err);
+                    }
+                }
+                debug!("Synced {} files to disk", batch.len());
+            }
+        });
+        FileSyncer(snapshot_sync, Some(tx))
+    }
+
+    fn sync(&self, file: File) -> Result<(), MemoryStorageError> {
+        match (self.0, self.1.as_ref()) {
+            (SnapshotSync::Always, _) => file.sync_all().map_err(|err| {
+                error!("Error syncing file to disk. {}", err);
+                MemoryStorageError
+            }),
+            // the write is acknowledged before it is durable
+            (SnapshotSync::Batched, Some(syncer)) => syncer.send(file).map_err(|_| {
+                error!("Error syncing file to disk, the syncer stopped");
+                MemoryStorageError
+            }),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -453,18 +1096,114 @@ impl Handler<DeserializeSnapshot> for SnapshotActor {
     type Result = Result<Vec<Entry>, MemoryStorageError>;
 
     fn handle(&mut self, msg: DeserializeSnapshot, _: &mut Self::Context) -> Self::Result {
-        fs::read(msg.0)
-            .map_err(|err| {
-                error!("Error reading contents of snapshot file. {}", err);
+        read_snapshot(msg.0, self.1, self.2)
+    }
+}
+
+fn read_snapshot(path: PathBuf, encoding: EntryEncoding, max_bytes: Option<u64>) -> Result<Vec<Entry>, MemoryStorageError> {
+    if let Some(max_bytes) = max_bytes {
+        let len = fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+        if len > max_bytes {
+            error!("Refusing to read snapshot {:?} of {} bytes, max_snapshot_bytes is {}.", path, len, max_bytes);
+            return Err(MemoryStorageError);
+        }
+    }
+
+    fs::read(path)
+        .map_err(|err| {
+            error!("Error reading contents of snapshot file. {}", err);
+            MemoryStorageError
+        })
+        // Deserialize the data of the snapshot file.
+        .and_then(|snapdata| {
+            decode_entries(encoding, snapdata.as_slice()).map_err(|err| {
+                error!("Error deserializing snapshot contents. {}", err);
                 MemoryStorageError
             })
-            // Deserialize the data of the snapshot file.
-            .and_then(|snapdata| {
-                rmps::from_slice::<Vec<Entry>>(snapdata.as_slice()).map_err(|err| {
-                    error!("Error deserializing snapshot contents. {}", err);
-                    MemoryStorageError
-                })
+        })
+}
+
+/// Encode entries the way snapshot files store them
+pub fn encode_entries(encoding: EntryEncoding, entries: &[Entry]) -> Result<Vec<u8>, String> {
+    match encoding {
+        EntryEncoding::MessagePack => rmps::to_vec(entries).map_err(|err| err.to_string()),
+        EntryEncoding::Json => serde_json::to_vec(entries).map_err(|err| err.to_string()),
+        EntryEncoding::Bincode => bincode::serialize(entries).map_err(|err| err.to_string()),
+    }
+}
+
+/// Decode entries encoded by `encode_entries` with the same encoding
+pub fn decode_entries(encoding: EntryEncoding, data: &[u8]) -> Result<Vec<Entry>, String> {
+    match encoding {
+        EntryEncoding::MessagePack => rmps::from_slice(data).map_err(|err| err.to_string()),
+        EntryEncoding::Json => serde_json::from_slice(data).map_err(|err| err.to_string()),
+        EntryEncoding::Bincode => bincode::deserialize(data).map_err(|err| err.to_string()),
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// PruneSnapshots ////////////////////////////////////////////////////////////
+
+/// Remove snapshot files which fall outside of the retention policy.
+///
+/// Only the current snapshot is ever sent to followers, so older files are no longer needed. A
+/// file still being streamed to a follower stays readable until it is closed.
+struct PruneSnapshots {
+    current: PathBuf,
+    retention: SnapshotRetention,
+}
+
+impl Message for PruneSnapshots {
+    type Result = Result<(), MemoryStorageError>;
+}
+
+impl Handler<PruneSnapshots> for SnapshotActor {
+    type Result = Result<(), MemoryStorageError>;
+
+    fn handle(&mut self, msg: PruneSnapshots, _: &mut Self::Context) -> Self::Result {
+        let dir = fs::read_dir(&self.0).map_err(|err| {
+            error!("Error reading snapshot directory. {}", err);
+            MemoryStorageError
+        })?;
+
+        // Snapshot files are named after the index they were taken at.
+        let mut snapshots = dir
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                let index = path.file_name()?.to_str()?.parse::<u64>().ok()?;
+                let modified = entry.metadata().and_then(|m| m.modified()).ok();
+                Some((index, path, modified))
             })
+            .collect::<Vec<_>>();
+        snapshots.sort_by_key(|(index, _, _)| *index);
+
+        let now = SystemTime::now();
+        let count = snapshots.len();
+        for (pos, (index, path, modified)) in snapshots.iter().enumerate() {
+            if *path == msg.current {
+                continue;
+            }
+
+            let too_many = msg.retention.keep_last.map(|n| count - pos > n).unwrap_or(false);
+            // file times are wall clock, a snapshot from the future after a backward step is kept
+            let too_old = match (msg.retention.max_age_secs, modified) {
+                (Some(max_age), Some(modified)) => now
+                    .duration_since(*modified)
+                    .map(|age| age > Duration::from_secs(max_age))
+                    .unwrap_or(false),
+                _ => false,
+            };
+
+            if too_many || too_old {
+                debug!("Removing snapshot {} at {:?}.", index, path);
+                if let Err(err) = fs::remove_file(path) {
+                    error!("Error removing snapshot file. {}", err);
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -499,6 +1238,18 @@ impl Handler<SyncInstallSnapshot> for SnapshotActor {
         let mut did_process_final_chunk = false;
         for chunk in chunk_stream {
             let chunk = chunk?;
+            if let Some(max_bytes) = self.2 {
+                let len = chunk.offset + chunk.data.len() as u64;
+                if len > max_bytes {
+                    error!(
+                        "Refusing snapshot {} from the leader, it exceeds max_snapshot_bytes {} at {} bytes.",
+                        msg.0.index, max_bytes, len
+                    );
+                    drop(snapfile);
+                    let _ = fs::remove_file(&filepath);
+                    return Err(MemoryStorageError);
+                }
+            }
             snapfile
                 .seek(SeekFrom::Start(chunk.offset))
                 .map_err(|err| {
@@ -522,6 +1273,7 @@ impl Handler<SyncInstallSnapshot> for SnapshotActor {
             error!("Prematurely exiting snapshot chunk stream. Never hit final chunk.");
             Err(MemoryStorageError)
         } else {
+            self.3.sync(snapfile)?;
             Ok(EntrySnapshotPointer {
                 path: filepath.to_string_lossy().to_string(),
             })
@@ -529,17 +1281,343 @@ impl Handler<SyncInstallSnapshot> for SnapshotActor {
     }
 }
 
-//////////////////////////////////////////////////////////////////////////////////////////////////
-// Other Message Types & Handlers ////////////////////////////////////////////////////////////////
-//
-// NOTE WELL: these following types, just as the MemoryStorage system overall, is intended
-// primarily for testing purposes. Don't build your application using this storage implementation.
+//////////////////////////////////////////////////////////////////////////////
+// ExportLog / ImportLog /////////////////////////////////////////////////////
 
-/// Get the current state of the storage engine.
-pub struct GetCurrentState;
+/// Number of entries written per `LogRecord::Entries` chunk of an export.
+const EXPORT_CHUNK_SIZE: usize = 1000;
 
-impl Message for GetCurrentState {
-    type Result = Result<CurrentStateData, ()>;
+/// Version of the export file format.
+const EXPORT_VERSION: u32 = 1;
+
+/// A record of an exported log file.
+///
+/// The file is a sequence of MessagePack encoded records: a `Header`, the `Snapshot` if the log
+/// was compacted, then `header.chunks` `Entries` records holding the log entries in index order.
+#[derive(Serialize, Deserialize)]
+enum LogRecord {
+    Header {
+        version: u32,
+        group: GroupId,
+        current_term: u64,
+        has_snapshot: bool,
+        chunks: u64,
+    },
+    /// Entries compacted into the latest snapshot, through `index`
+    Snapshot {
+        index: u64,
+        term: u64,
+        entries: Vec<Entry>,
+    },
+    Entries(Vec<Entry>),
+}
+
+/// Export all log entries and the latest snapshot to the file at `path`.
+///
+/// The log is copied and then written out in chunks on the snapshot actor, so replication is not
+/// blocked while the file is written. Resolves with the number of entries exported.
+pub struct ExportLog {
+    pub path: PathBuf,
+}
+
+impl Message for ExportLog {
+    type Result = Result<u64, MemoryStorageError>;
+}
+
+impl Handler<ExportLog> for MemoryStorage {
+    type Result = ResponseActFuture<Self, u64, MemoryStorageError>;
+
+    fn handle(&mut self, msg: ExportLog, _: &mut Self::Context) -> Self::Result {
+        let entries = self
+            .log
+            .values()
+            .filter(|e| match e.payload {
+                EntryPayload::SnapshotPointer(_) => false,
+                _ => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let snapshot = self
+            .snapshot_data
+            .as_ref()
+            .map(|snap| (snap.index, snap.term, PathBuf::from(snap.pointer.path.clone())));
+        let export = (msg.path.clone(), self.log.keys().last().cloned());
+
+        Box::new(
+            fut::wrap_future(self.snapshot_actor.send(WriteLogExport {
+                path: msg.path,
+                group: self.group,
+                current_term: self.hs.current_term,
+                snapshot,
+                entries,
+            }))
+            .map_err(|err, _, _| panic!("Error communicating with snapshot actor. {}", err))
+            .and_then(|res, _, _| fut::result(res))
+            .map(move |exported, act: &mut Self, _| {
+                act.last_export = Some(export);
+                exported
+            }),
+        )
+    }
+}
+
+/// `ExportLog` unless the file at `path` already holds the log, because the log was imported
+/// from or exported to it and no entry was appended since. Resolves with `None` when skipped.
+pub struct ExportLogIfChanged {
+    pub path: PathBuf,
+}
+
+impl Message for ExportLogIfChanged {
+    type Result = Result<Option<u64>, MemoryStorageError>;
+}
+
+impl Handler<ExportLogIfChanged> for MemoryStorage {
+    type Result = ResponseActFuture<Self, Option<u64>, MemoryStorageError>;
+
+    fn handle(&mut self, msg: ExportLogIfChanged, ctx: &mut Self::Context) -> Self::Result {
+        let current = (msg.path.clone(), self.log.keys().last().cloned());
+        if self.last_export.as_ref() == Some(&current) && msg.path.exists() {
+            debug!("Log unchanged since it was written to {:?}, not exporting it.", msg.path);
+            return Box::new(fut::ok(None));
+        }
+
+        Box::new(<Self as Handler<ExportLog>>::handle(self, ExportLog { path: msg.path }, ctx).map(|exported, _, _| Some(exported)))
+    }
+}
+
+/// Bootstrap an empty storage from a file written by `ExportLog`.
+///
+/// Every exported entry, including the snapshot ones, is appended to the log and applied to the
+/// state machine. The import is done synchronously so it has to be sent before the raft which
+/// uses this storage is started; storage which already holds entries is refused.
+pub struct ImportLog {
+    pub path: PathBuf,
+}
+
+impl Message for ImportLog {
+    type Result = Result<u64, MemoryStorageError>;
+}
+
+impl Handler<ImportLog> for MemoryStorage {
+    type Result = Result<u64, MemoryStorageError>;
+
+    fn handle(&mut self, msg: ImportLog, ctx: &mut Self::Context) -> Self::Result {
+        if !self.log.is_empty() || !self.state_machine.is_empty() {
+            error!("Refusing to import log from {:?}, storage is not empty.", msg.path);
+            return Err(MemoryStorageError);
+        }
+
+        let (current_term, entries) = read_log_export(&msg.path)?;
+        let res = entries.iter().try_for_each(|entry| {
+            self.log.insert(entry.index, entry.clone());
+            self.apply_entry(entry)
+        });
+        self.wait_for_app(ctx);
+        res?;
+        self.hs.current_term = current_term;
+        self.last_export = Some((msg.path.clone(), self.log.keys().last().cloned()));
+
+        debug!("Imported {} entries from {:?}.", entries.len(), msg.path);
+        Ok(entries.len() as u64)
+    }
+}
+
+/// Seed an empty storage with a snapshot copied out-of-band, e.g. from the leader's snapshot
+/// directory, so a new node only has to replicate the entries after it when it joins.
+///
+/// The snapshot must end at `index` and `term`, the values the leader reports for it. Like
+/// `ImportLog` this is done synchronously before the raft using this storage is started.
+pub struct LoadSnapshotFromFile {
+    pub path: PathBuf,
+    pub index: u64,
+    pub term: u64,
+}
+
+impl Message for LoadSnapshotFromFile {
+    type Result = Result<u64, MemoryStorageError>;
+}
+
+impl Handler<LoadSnapshotFromFile> for MemoryStorage {
+    type Result = Result<u64, MemoryStorageError>;
+
+    fn handle(&mut self, msg: LoadSnapshotFromFile, ctx: &mut Self::Context) -> Self::Result {
+        if !self.log.is_empty() || !self.state_machine.is_empty() {
+            error!("Refusing to load snapshot from {:?}, storage is not empty.", msg.path);
+            return Err(MemoryStorageError);
+        }
+
+        let entries = read_snapshot(msg.path.clone(), self.entry_encoding, self.max_snapshot_bytes)?;
+        match entries.last() {
+            Some(last) if last.index == msg.index && last.term == msg.term => (),
+            last => {
+                error!(
+                    "Snapshot {:?} ends at {:?}, expected index {} term {}.",
+                    msg.path,
+                    last.map(|e| (e.index, e.term)),
+                    msg.index,
+                    msg.term
+                );
+                return Err(MemoryStorageError);
+            }
+        }
+
+        // keep the snapshot with the ones this storage creates, under its index
+        let filepath = PathBuf::from(self.snapshot_dir.clone()).join(format!("{}", msg.index));
+        if filepath != msg.path {
+            fs::copy(&msg.path, &filepath).map_err(|err| {
+                error!("Error copying snapshot file. {}", err);
+                MemoryStorageError
+            })?;
+        }
+
+        let res = entries.iter().try_for_each(|entry| {
+            if let EntryPayload::ConfigChange(change) = &entry.payload {
+                self.hs.membership = change.membership.clone();
+            }
+            self.apply_entry(entry)
+        });
+        self.wait_for_app(ctx);
+        res?;
+
+        let pointer = EntrySnapshotPointer { path: filepath.to_string_lossy().to_string() };
+        self.log.insert(msg.index, Entry::new_snapshot_pointer(pointer.clone(), msg.index, msg.term));
+        self.snapshot_data = Some(CurrentSnapshotData {
+            index: msg.index,
+            term: msg.term,
+            membership: self.hs.membership.clone(),
+            pointer,
+        });
+        self.hs.current_term = msg.term;
+
+        debug!("Loaded snapshot through index {} from {:?}.", msg.index, msg.path);
+        Ok(entries.len() as u64)
+    }
+}
+
+/// Read an export file, returning the exported term and all entries in index order.
+fn read_log_export(path: &PathBuf) -> Result<(u64, Vec<Entry>), MemoryStorageError> {
+    let file = File::open(path).map_err(|err| {
+        error!("Error opening log export file. {}", err);
+        MemoryStorageError
+    })?;
+    let mut reader = BufReader::new(file);
+    let mut read_record = || {
+        rmps::decode::from_read::<_, LogRecord>(&mut reader).map_err(|err| {
+            error!("Error deserializing log export record. {}", err);
+            MemoryStorageError
+        })
+    };
+
+    let (current_term, has_snapshot, chunks) = match read_record()? {
+        LogRecord::Header { version, current_term, has_snapshot, chunks, .. } if version == EXPORT_VERSION => {
+            (current_term, has_snapshot, chunks)
+        }
+        _ => {
+            error!("Log export file has an unsupported header.");
+            return Err(MemoryStorageError);
+        }
+    };
+
+    let mut entries = Vec::new();
+    if has_snapshot {
+        match read_record()? {
+            LogRecord::Snapshot { entries: snapshot, .. } => entries.extend(snapshot),
+            _ => {
+                error!("Log export file is missing its snapshot record.");
+                return Err(MemoryStorageError);
+            }
+        }
+    }
+    for _ in 0..chunks {
+        match read_record()? {
+            LogRecord::Entries(chunk) => entries.extend(chunk),
+            _ => {
+                error!("Log export file has an unexpected record.");
+                return Err(MemoryStorageError);
+            }
+        }
+    }
+
+    // entries of the snapshot may also still be in the log
+    entries.sort_by_key(|e| e.index);
+    entries.dedup_by_key(|e| e.index);
+    Ok((current_term, entries))
+}
+
+/// Write a log export, see `LogRecord` for the format.
+struct WriteLogExport {
+    path: PathBuf,
+    group: GroupId,
+    current_term: u64,
+    /// index, term and file of the latest snapshot
+    snapshot: Option<(u64, u64, PathBuf)>,
+    entries: Vec<Entry>,
+}
+
+impl Message for WriteLogExport {
+    type Result = Result<u64, MemoryStorageError>;
+}
+
+impl Handler<WriteLogExport> for SnapshotActor {
+    type Result = Result<u64, MemoryStorageError>;
+
+    fn handle(&mut self, msg: WriteLogExport, _: &mut Self::Context) -> Self::Result {
+        let file = File::create(&msg.path).map_err(|err| {
+            error!("Error creating log export file. {}", err);
+            MemoryStorageError
+        })?;
+        let mut writer = BufWriter::new(file);
+        let mut write_record = |record: &LogRecord| {
+            rmps::encode::write(&mut writer, record).map_err(|err| {
+                error!("Error writing log export record. {}", err);
+                MemoryStorageError
+            })
+        };
+
+        let snapshot = match msg.snapshot {
+            Some((index, term, path)) => Some((index, term, read_snapshot(path, self.1, self.2)?)),
+            None => None,
+        };
+
+        let chunks = msg.entries.chunks(EXPORT_CHUNK_SIZE);
+        write_record(&LogRecord::Header {
+            version: EXPORT_VERSION,
+            group: msg.group,
+            current_term: msg.current_term,
+            has_snapshot: snapshot.is_some(),
+            chunks: chunks.len() as u64,
+        })?;
+
+        let mut count = 0;
+        if let Some((index, term, entries)) = snapshot {
+            count += entries.len() as u64;
+            write_record(&LogRecord::Snapshot { index, term, entries })?;
+        }
+        for chunk in chunks {
+            count += chunk.len() as u64;
+            write_record(&LogRecord::Entries(chunk.to_vec()))?;
+        }
+
+        let file = writer.into_inner().map_err(|err| {
+            error!("Error flushing log export file. {}", err);
+            MemoryStorageError
+        })?;
+        self.3.sync(file)?;
+        Ok(count)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////////////
+// Other Message Types & Handlers ////////////////////////////////////////////////////////////////
+//
+// NOTE WELL: these following types, just as the MemoryStorage system overall, is intended
+// primarily for testing purposes. Don't build your application using this storage implementation.
+
+/// Get the current state of the storage engine.
+pub struct GetCurrentState;
+
+impl Message for GetCurrentState {
+    type Result = Result<CurrentStateData, ()>;
 }
 
 /// The current state of the storage engine.
@@ -565,6 +1643,601 @@ impl Handler<GetCurrentState> for MemoryStorage {
     }
 }
 
+/// Get the current term and the vote cast in it.
+pub struct GetVote;
+
+impl Message for GetVote {
+    type Result = Result<(u64, Option<NodeId>), ()>;
+}
+
+impl Handler<GetVote> for MemoryStorage {
+    type Result = Result<(u64, Option<NodeId>), ()>;
+
+    fn handle(&mut self, _: GetVote, _: &mut Self::Context) -> Self::Result {
+        Ok((self.hs.current_term, self.hs.voted_for))
+    }
+}
+
+/// A membership change applied to the ring of the default group
+#[derive(Message, Debug, Clone)]
+pub enum MembershipApplied {
+    Added(NodeId),
+    Removed(NodeId),
+}
+
+/// Be told about every membership change this storage applies to the ring from now on
+#[derive(Message)]
+pub struct SubscribeMembership(pub Recipient<MembershipApplied>);
+
+impl Handler<SubscribeMembership> for MemoryStorage {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeMembership, _: &mut Self::Context) {
+        self.membership_subscribers.push(msg.0);
+    }
+}
+
+/// A freeze applied by the default group, writes are rejected until the replicated clock reaches
+/// the unix time in milliseconds, `None` once unfrozen or reached
+#[derive(Message, Debug, Clone)]
+pub struct WritesFrozen(pub Option<u64>);
+
+/// Be told about every freeze this storage applies from now on
+#[derive(Message)]
+pub struct SubscribeFreeze(pub Recipient<WritesFrozen>);
+
+impl Handler<SubscribeFreeze> for MemoryStorage {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeFreeze, _: &mut Self::Context) {
+        self.freeze_subscribers.push(msg.0);
+    }
+}
+
+/// Report the latencies and applied entries to `sink` from now on
+#[derive(Message)]
+pub struct SetMetricsSink(pub SharedMetrics);
+
+impl Handler<SetMetricsSink> for MemoryStorage {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetMetricsSink, _: &mut Self::Context) {
+        self.metrics = msg.0;
+    }
+}
+
+/// Latency of the appends to the log and of the applies to the state machine.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StorageStats {
+    pub apply: LatencySummary,
+    pub append: LatencySummary,
+}
+
+pub struct GetStorageStats;
+
+impl Message for GetStorageStats {
+    type Result = Result<StorageStats, ()>;
+}
+
+impl Handler<GetStorageStats> for MemoryStorage {
+    type Result = Result<StorageStats, ()>;
+
+    fn handle(&mut self, _: GetStorageStats, _: &mut Self::Context) -> Self::Result {
+        Ok(StorageStats {
+            apply: self.apply_latency.summary(),
+            append: self.append_latency.summary(),
+        })
+    }
+}
+
+/// Empty the membership audit, the recent entries and the latency histograms, e.g. to start
+/// fresh after an incident. The members keep their place, only when they were added is forgotten.
+pub struct ClearDiagnostics;
+
+impl Message for ClearDiagnostics {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<ClearDiagnostics> for MemoryStorage {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, _: ClearDiagnostics, _: &mut Self::Context) -> Self::Result {
+        info!("Group {} clearing diagnostics", self.group);
+        self.membership_audit.clear();
+        self.recent_entries.clear();
+        self.apply_latency = LatencyHistogram::new();
+        self.append_latency = LatencyHistogram::new();
+        Ok(())
+    }
+}
+
+/// Get the apply state of the state machine.
+pub struct GetApplyState;
+
+impl Message for GetApplyState {
+    type Result = Result<ApplyState, ()>;
+}
+
+/// The apply state of the state machine.
+pub struct ApplyState {
+    pub last_applied: u64,
+    /// Reason the state machine halted, if it did
+    pub halted: Option<String>,
+    pub installing_snapshot: bool,
+    /// Last failure of the storage, also when it didn't halt, and when it happened
+    pub last_error: Option<(Instant, String)>,
+}
+
+impl Handler<GetApplyState> for MemoryStorage {
+    type Result = Result<ApplyState, ()>;
+
+    fn handle(&mut self, _: GetApplyState, _: &mut Self::Context) -> Self::Result {
+        Ok(ApplyState {
+            last_applied: self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0),
+            halted: self.apply_halted.clone(),
+            installing_snapshot: self.installing_snapshot,
+            last_error: self.last_error.clone(),
+        })
+    }
+}
+
+/// Number of membership changes kept in the audit buffer, unless `membership_audit_size` is set.
+pub const MEMBERSHIP_AUDIT_SIZE: usize = 1000;
+
+/// A membership change applied to the state machine.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MembershipEvent {
+    pub index: u64,
+    pub change: MemoryStorageData,
+    /// when this node applied it
+    pub applied_at: SystemTime,
+}
+
+/// A current member and the committed entry which added it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterMember {
+    pub id: NodeId,
+    /// unknown when the add was compacted into a snapshot or left the audit buffer
+    pub added_index: Option<u64>,
+    pub added_at: Option<SystemTime>,
+    /// address of the HTTP routes of the member, unknown until the network knows the node
+    #[serde(default)]
+    pub http_addr: Option<String>,
+    /// metadata the member was configured with, empty until the network knows the node
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Get the current members of the default group, with when they were added.
+#[derive(Serialize, Deserialize)]
+pub struct GetClusterMembers;
+
+impl Message for GetClusterMembers {
+    type Result = Result<Vec<ClusterMember>, ()>;
+}
+
+impl Handler<GetClusterMembers> for MemoryStorage {
+    type Result = Result<Vec<ClusterMember>, ()>;
+
+    fn handle(&mut self, _: GetClusterMembers, _: &mut Self::Context) -> Self::Result {
+        Ok(self
+            .ring_members
+            .iter()
+            .map(|id| {
+                let added = self.membership_audit.iter().rev().find(|event| match event.change {
+                    MemoryStorageData::Add(node_id) => node_id == *id,
+                    _ => false,
+                });
+                ClusterMember {
+                    id: *id,
+                    added_index: added.map(|event| event.index),
+                    added_at: added.map(|event| event.applied_at),
+                    http_addr: None,
+                    metadata: BTreeMap::new(),
+                }
+            })
+            .collect())
+    }
+}
+
+/// Get the membership audit buffer, oldest change first.
+pub struct GetMembershipAudit;
+
+impl Message for GetMembershipAudit {
+    type Result = Result<Vec<MembershipEvent>, ()>;
+}
+
+impl Handler<GetMembershipAudit> for MemoryStorage {
+    type Result = Result<Vec<MembershipEvent>, ()>;
+
+    fn handle(&mut self, _: GetMembershipAudit, _: &mut Self::Context) -> Self::Result {
+        Ok(self.membership_audit.iter().cloned().collect())
+    }
+}
+
+/// Resume a halted state machine once the cause of the apply error is resolved.
+///
+/// The committed entries refused while halted are applied synchronously, in index order, skipping
+/// those already in the state machine. The application is then handed the entries it failed on or
+/// didn't get yet, in order too. Resolves with the resulting last applied index, or fails if an
+/// entry halts the state machine again. Being handled by the storage actor, invocations never
+/// overlap with each other nor with entries applied by the raft.
+pub struct DrainApply;
+
+impl Message for DrainApply {
+    type Result = Result<u64, MemoryStorageError>;
+}
+
+impl Handler<DrainApply> for MemoryStorage {
+    type Result = ResponseActFuture<Self, u64, MemoryStorageError>;
+
+    fn handle(&mut self, _: DrainApply, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(reason) = self.apply_halted.take() {
+            info!("Resuming state machine halted at {}", reason);
+        }
+
+        let pending = std::mem::replace(&mut self.pending_apply, BTreeMap::new());
+        for (index, entry) in pending.iter() {
+            if self.state_machine.contains_key(index) {
+                continue;
+            }
+            // a new halt queues the remaining entries again
+            let _ = self.apply_entry(entry);
+        }
+
+        let drained = pending.len();
+        let app = match self.apply_halted {
+            Some(_) => fut::Either::A(fut::err(MemoryStorageError)),
+            None => fut::Either::B(self.apply_to_app()),
+        };
+        Box::new(app.then(move |res, act: &mut Self, _ctx| {
+            let last_applied = act.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+            if res.is_err() {
+                error!("State machine halted again while draining, last applied {}.", last_applied);
+                return fut::err(MemoryStorageError);
+            }
+            info!("Drained {} pending entries, last applied {}.", drained, last_applied);
+            fut::ok(last_applied)
+        }))
+    }
+}
+
+/// Number of applied entries kept for `GetRecentEntries`, unless `recent_entries_size` is set.
+pub const RECENT_ENTRIES_SIZE: usize = 256;
+
+/// An applied entry and its decoded command.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecentEntry {
+    pub index: u64,
+    pub data: MemoryStorageData,
+}
+
+/// Get the last `n` applied entries, oldest first. `n` is capped at `recent_entries_size`.
+///
+/// Served from memory, unlike `GetAppliedEntries` it doesn't walk the state machine.
+pub struct GetRecentEntries(pub usize);
+
+impl Message for GetRecentEntries {
+    type Result = Result<Vec<RecentEntry>, ()>;
+}
+
+impl Handler<GetRecentEntries> for MemoryStorage {
+    type Result = Result<Vec<RecentEntry>, ()>;
+
+    fn handle(&mut self, msg: GetRecentEntries, _: &mut Self::Context) -> Self::Result {
+        let n = msg.0.min(self.recent_entries.len());
+        let skip = self.recent_entries.len() - n;
+        Ok(self.recent_entries.iter().skip(skip).cloned().collect())
+    }
+}
+
+/// Get up to `max` applied entries starting at index `from`.
+pub struct GetAppliedEntries {
+    pub from: u64,
+    pub max: u64,
+}
+
+impl Message for GetAppliedEntries {
+    type Result = Result<Vec<Entry>, ReadError>;
+}
+
+/// Why a read of the state machine wasn't served locally
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ReadError {
+    /// A snapshot is being installed, the state machine is torn until it completes
+    CatchingUp,
+    Unavailable,
+    /// The entry at this index doesn't fit a frame on its own, it can't be sent
+    EntryTooLarge(u64),
+}
+
+/// Earliest expiry of the counters or the write freeze applied by this replica, on the replicated
+/// clock in unix milliseconds
+pub struct GetNextExpiry;
+
+impl Message for GetNextExpiry {
+    type Result = Result<Option<u64>, ReadError>;
+}
+
+impl Handler<GetNextExpiry> for MemoryStorage {
+    type Result = Result<Option<u64>, ReadError>;
+
+    fn handle(&mut self, _: GetNextExpiry, _: &mut Self::Context) -> Self::Result {
+        if self.installing_snapshot {
+            return Err(ReadError::CatchingUp);
+        }
+
+        let counters = self.expiry_order.iter().next().map(|(at, _)| *at);
+        Ok(counters.into_iter().chain(self.frozen_until).min())
+    }
+}
+
+/// Get a counter as applied by this replica, `None` when it was never written, or expired
+#[derive(Serialize, Deserialize)]
+pub struct GetCounter(pub String);
+
+impl Message for GetCounter {
+    type Result = Result<Option<i64>, ReadError>;
+}
+
+impl Handler<GetCounter> for MemoryStorage {
+    type Result = Result<Option<i64>, ReadError>;
+
+    fn handle(&mut self, msg: GetCounter, _: &mut Self::Context) -> Self::Result {
+        if self.installing_snapshot {
+            return Err(ReadError::CatchingUp);
+        }
+
+        let last_applied = self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+        Ok(self.applied_counters(last_applied).remove(&msg.0))
+    }
+}
+
+impl Handler<GetAppliedEntries> for MemoryStorage {
+    type Result = Result<Vec<Entry>, ReadError>;
+
+    fn handle(&mut self, msg: GetAppliedEntries, _: &mut Self::Context) -> Self::Result {
+        if self.installing_snapshot {
+            return Err(ReadError::CatchingUp);
+        }
+
+        Ok(self
+            .state_machine
+            .range(msg.from..)
+            .take(msg.max as usize)
+            .map(|(_, e)| e.clone())
+            .collect())
+    }
+}
+
+/// Append and apply entries pulled from a member by an observer, which has no raft of its own, or
+/// by a learner seeding its storage before its raft starts.
+///
+/// Entries at or below the last applied index are skipped. Resolves with the new last applied index.
+pub struct ApplyObserved(pub Vec<Entry>);
+
+impl Message for ApplyObserved {
+    type Result = Result<u64, MemoryStorageError>;
+}
+
+impl Handler<ApplyObserved> for MemoryStorage {
+    type Result = Result<u64, MemoryStorageError>;
+
+    fn handle(&mut self, msg: ApplyObserved, ctx: &mut Self::Context) -> Self::Result {
+        let from = self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+        let entries: Vec<Entry> = msg.0.into_iter().filter(|e| e.index > from).collect();
+        for entry in entries.iter() {
+            if let EntryPayload::ConfigChange(change) = &entry.payload {
+                self.hs.membership = change.membership.clone();
+            }
+            self.log.insert(entry.index, entry.clone());
+        }
+        let res = self.apply_committed(&entries);
+        self.wait_for_app(ctx);
+        res?;
+
+        Ok(entries.last().map(|e| e.index).unwrap_or(from))
+    }
+}
+
+/// Get a digest of the state machine as applied through the given index.
+///
+/// The digest only covers the replicated state (the member set and the counters) so two replicas agree on the
+/// digest at the same index regardless of the order entries were applied in. Fails if the index
+/// has not been applied yet.
+#[derive(Serialize, Deserialize)]
+pub struct StateMachineDigest(pub u64);
+
+impl Message for StateMachineDigest {
+    type Result = Result<StateDigest, ()>;
+}
+
+/// Digest of the state machine at an applied index.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateDigest {
+    pub index: u64,
+    pub digest: String,
+}
+
+impl Handler<StateMachineDigest> for MemoryStorage {
+    type Result = Result<StateDigest, ()>;
+
+    fn handle(&mut self, msg: StateMachineDigest, _: &mut Self::Context) -> Self::Result {
+        let index = msg.0;
+        let last_applied = self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+        if last_applied < index || self.installing_snapshot {
+            return Err(());
+        }
+
+        // BTreeSet and BTreeMap iterate in order, which makes the digest deterministic
+        let mut hasher = Sha256::new();
+        for node_id in self.applied_members(index) {
+            hasher.input(&node_id.to_be_bytes());
+        }
+        for (key, value) in self.applied_counters(index) {
+            hasher.input(&(key.len() as u64).to_be_bytes());
+            hasher.input(key.as_bytes());
+            hasher.input(&value.to_be_bytes());
+        }
+
+        Ok(StateDigest {
+            index,
+            digest: hasher.result_str(),
+        })
+    }
+}
+
+/// Rebuild the hash ring from the members in the applied entries, a recovery tool for a ring which
+/// diverged from the membership. Safe to call at any time, only the default group owns the ring.
+/// Resolves with the nodes on the rebuilt ring.
+pub struct ResyncRing;
+
+impl Message for ResyncRing {
+    type Result = Result<Vec<NodeId>, ()>;
+}
+
+impl Handler<ResyncRing> for MemoryStorage {
+    type Result = Result<Vec<NodeId>, ()>;
+
+    fn handle(&mut self, _: ResyncRing, _: &mut Self::Context) -> Self::Result {
+        if self.group != DEFAULT_GROUP {
+            return Ok(vec![]);
+        }
+
+        let last_applied = self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+        let members = self.applied_members(last_applied);
+        info!("Resyncing ring at entry {}, before: {:?}, after: {:?}", last_applied, self.ring_members, members);
+
+        // entries installed with a snapshot weren't applied one by one
+        if let Some(replicas) = self.applied_ring_replicas(last_applied) {
+            self.ring_replicas = replicas;
+        }
+        let nodes = members.iter().cloned().collect::<Vec<_>>();
+        Ring::reset(&self.ring, nodes.clone(), self.ring_replicas as isize);
+        self.ring_members = members;
+        self.membership_epoch = self.applied_membership_epoch(last_applied);
+        self.server.do_send(Rebalance);
+
+        Ok(nodes)
+    }
+}
+
+/// Compare the ring with the members in the applied entries and repair it if they differ, sent
+/// once the storage was restored and before its raft starts. The ring itself is compared, placement
+/// by placement, with one built from the applied membership, not the members the storage believes
+/// it put on it. Resolves with what was repaired.
+pub struct ReconcileRing;
+
+impl Message for ReconcileRing {
+    type Result = Result<RingRepair, ()>;
+}
+
+/// Difference between the ring and the applied membership found by `ReconcileRing`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RingRepair {
+    /// last entry the membership was read from
+    pub index: u64,
+    /// members put on the ring
+    pub added: Vec<NodeId>,
+    /// nodes taken off the ring which aren't members anymore
+    pub removed: Vec<NodeId>,
+    /// virtual nodes per node before and after, when they differed
+    pub replicas: Option<(u32, u32)>,
+}
+
+impl RingRepair {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.replicas.is_none()
+    }
+}
+
+impl Handler<ReconcileRing> for MemoryStorage {
+    type Result = Result<RingRepair, ()>;
+
+    fn handle(&mut self, _: ReconcileRing, ctx: &mut Self::Context) -> Self::Result {
+        if self.group != DEFAULT_GROUP {
+            return Ok(RingRepair::default());
+        }
+
+        let last_applied = self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+        let members = self.applied_members(last_applied);
+        let replicas = self.applied_ring_replicas(last_applied).unwrap_or(self.ring_replicas);
+        let expected = Ring::new(replicas as isize);
+        Ring::reset(&expected, members.iter().cloned().collect(), replicas as isize);
+        let on_ring = Ring::nodes(&self.ring);
+        let repair = RingRepair {
+            index: last_applied,
+            added: members.difference(&on_ring).cloned().collect(),
+            removed: on_ring.difference(&members).cloned().collect(),
+            replicas: if replicas != self.ring_replicas { Some((self.ring_replicas, replicas)) } else { None },
+        };
+
+        if repair.is_empty() && Ring::placements(&self.ring) == Ring::placements(&expected) {
+            debug!("Ring matches the membership at entry {}", last_applied);
+            self.membership_epoch = self.applied_membership_epoch(last_applied);
+            return Ok(repair);
+        }
+        warn!(
+            "Ring diverged from the membership at entry {}, adding {:?}, removing {:?}, replicas {:?}",
+            last_applied, repair.added, repair.removed, repair.replicas
+        );
+        <Self as Handler<ResyncRing>>::handle(self, ResyncRing, ctx)?;
+        Ok(repair)
+    }
+}
+
+/// Membership changes applied to the ring of the default group through entry `index`. The leader
+/// sends its epoch along with its heartbeats, a follower which applied `index` but is at an older
+/// epoch missed a change, e.g. while a snapshot was installed, and resyncs its ring.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MembershipEpoch {
+    pub epoch: u64,
+    pub index: u64,
+}
+
+impl Message for MembershipEpoch {
+    type Result = ();
+}
+
+impl Handler<MembershipEpoch> for MemoryStorage {
+    type Result = ();
+
+    fn handle(&mut self, msg: MembershipEpoch, ctx: &mut Self::Context) {
+        if self.group != DEFAULT_GROUP || self.installing_snapshot {
+            return;
+        }
+
+        // the leader may have applied changes this node hasn't yet
+        let last_applied = self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0);
+        if last_applied < msg.index || self.membership_epoch >= msg.epoch {
+            return;
+        }
+        warn!(
+            "Ring at membership epoch {} behind the leader's {} at entry {}, resyncing",
+            self.membership_epoch, msg.epoch, msg.index
+        );
+        let _ = <Self as Handler<ResyncRing>>::handle(self, ResyncRing, ctx);
+    }
+}
+
+/// Get the membership epoch of the ring, as of the last applied entry
+pub struct GetMembershipEpoch;
+
+impl Message for GetMembershipEpoch {
+    type Result = Result<MembershipEpoch, ()>;
+}
+
+impl Handler<GetMembershipEpoch> for MemoryStorage {
+    type Result = Result<MembershipEpoch, ()>;
+
+    fn handle(&mut self, _: GetMembershipEpoch, _: &mut Self::Context) -> Self::Result {
+        Ok(MembershipEpoch {
+            epoch: self.membership_epoch,
+            index: self.state_machine.iter().last().map(|e| *e.0).unwrap_or(0),
+        })
+    }
+}
+
 pub struct GetNode(pub String);
 
 impl Message for GetNode {
@@ -583,3 +2256,173 @@ impl Handler<GetNode> for MemoryStorage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use crate::raft::state_machine::{DefaultStateMachine, StateMachine};
+    use crate::test_utils::{config, entry, storage};
+
+    /// Fails its first `failures` applies, records the entries it applied
+    struct FlakyStateMachine {
+        failures: usize,
+        applied: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl StateMachine for FlakyStateMachine {
+        fn apply(
+            &mut self,
+            index: u64,
+            _data: &MemoryStorageData,
+        ) -> Box<dyn Future<Item = MemoryStorageResponse, Error = MemoryStorageError>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Box::new(futures::future::err(MemoryStorageError));
+            }
+            self.applied.lock().unwrap().push(index);
+            Box::new(futures::future::ok(MemoryStorageResponse::default()))
+        }
+    }
+
+    fn flaky(failures: usize) -> (Box<dyn StateMachine>, Arc<Mutex<Vec<u64>>>) {
+        let applied = Arc::new(Mutex::new(vec![]));
+        (Box::new(FlakyStateMachine { failures, applied: applied.clone() }), applied)
+    }
+
+    fn incrs(count: u64) -> Vec<Entry> {
+        (1..=count).map(|index| entry(index, MemoryStorageData::Incr("hits".into(), 1))).collect()
+    }
+
+    #[test]
+    fn apply_error_halts_the_state_machine() {
+        let mut sys = System::new("test");
+        let (app, applied) = flaky(1);
+        let storage = storage(1, &config(""), app);
+
+        let state = sys
+            .block_on(storage.send(ApplyObserved(incrs(2))).and_then(move |_| storage.send(GetApplyState)))
+            .unwrap()
+            .unwrap();
+        assert!(state.halted.is_some());
+        assert!(applied.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn apply_error_is_skipped_with_skip_and_log() {
+        let mut sys = System::new("test");
+        let (app, applied) = flaky(1);
+        let storage = storage(1, &config("apply_error_policy = \"skip_and_log\""), app);
+
+        let state = sys
+            .block_on(storage.send(ApplyObserved(incrs(2))).and_then(move |_| storage.send(GetApplyState)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.halted, None);
+        assert_eq!(state.last_applied, 2);
+        assert_eq!(*applied.lock().unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn drain_apply_hands_the_failed_entries_to_the_application_again() {
+        let mut sys = System::new("test");
+        let (app, applied) = flaky(1);
+        let storage = storage(1, &config(""), app);
+
+        let drained = sys
+            .block_on(storage.send(ApplyObserved(incrs(3))).and_then(move |_| storage.send(DrainApply)))
+            .unwrap();
+        assert_eq!(drained.ok(), Some(3));
+        assert_eq!(*applied.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn apply_runs_hand_every_entry_to_the_application_in_order() {
+        let mut sys = System::new("test");
+        let (app, applied) = flaky(0);
+        let storage = storage(1, &config("apply_batch_size = 2"), app);
+
+        let state = sys
+            .block_on(storage.send(ApplyObserved(incrs(5))).and_then(move |_| storage.send(GetApplyState)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.last_applied, 5);
+        assert_eq!(*applied.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn replicas_applying_the_same_log_agree_on_the_counters() {
+        let mut sys = System::new("test");
+        let log: Vec<Entry> = vec![
+            MemoryStorageData::Incr("hits".into(), 3),
+            MemoryStorageData::Set("misses".into(), 7),
+            MemoryStorageData::Incr("hits".into(), -1),
+            MemoryStorageData::Add(42),
+            MemoryStorageData::Incr("misses".into(), 1),
+            MemoryStorageData::Set("hits".into(), 10),
+            MemoryStorageData::Incr("hits".into(), 5),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, data)| entry(i as u64 + 1, data))
+        .collect();
+
+        // each replica receives the log in batches of another size and applies it in other runs
+        let mut digests = vec![];
+        for &(batch, extra) in &[(1, ""), (3, "apply_batch_size = 2"), (7, "apply_batch_size = 16")] {
+            let replica = storage(1, &config(extra), Box::new(DefaultStateMachine));
+            for entries in log.chunks(batch) {
+                sys.block_on(replica.send(ApplyObserved(entries.to_vec()))).unwrap().unwrap();
+            }
+
+            assert_eq!(sys.block_on(replica.send(GetCounter("hits".into()))).unwrap(), Ok(Some(15)));
+            assert_eq!(sys.block_on(replica.send(GetCounter("misses".into()))).unwrap(), Ok(Some(8)));
+            digests.push(sys.block_on(replica.send(StateMachineDigest(7))).unwrap().unwrap());
+        }
+        assert!(digests.windows(2).all(|pair| pair[0] == pair[1]), "replicas diverged: {:?}", digests);
+    }
+
+    #[test]
+    fn replicas_expire_the_same_counters_at_the_same_index() {
+        let mut sys = System::new("test");
+        let log: Vec<Entry> = vec![
+            MemoryStorageData::SetExpiring("a".into(), 1, 100),
+            MemoryStorageData::SetExpiring("b".into(), 2, 300),
+            MemoryStorageData::Tick(50),
+            // keeps the expiry of `a`
+            MemoryStorageData::Incr("a".into(), 4),
+            // already expired on the replicated clock
+            MemoryStorageData::SetExpiring("c".into(), 3, 40),
+            // drops the expiry of `b`
+            MemoryStorageData::Set("b".into(), 9),
+            MemoryStorageData::SetExpiring("d".into(), 4, 200),
+            MemoryStorageData::Tick(150),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, data)| entry(i as u64 + 1, data))
+        .collect();
+        // the next expiry after each entry
+        let next = [Some(100), Some(100), Some(100), Some(100), Some(100), Some(100), Some(100), Some(200)];
+
+        let mut states = vec![];
+        for &(batch, extra) in &[(1, ""), (3, "apply_batch_size = 2"), (8, "apply_batch_size = 16")] {
+            let replica = storage(1, &config(extra), Box::new(DefaultStateMachine));
+            let mut applied = 0;
+            for entries in log.chunks(batch) {
+                sys.block_on(replica.send(ApplyObserved(entries.to_vec()))).unwrap().unwrap();
+                applied += entries.len();
+                assert_eq!(sys.block_on(replica.send(GetNextExpiry)).unwrap(), Ok(next[applied - 1]));
+            }
+
+            let counters: Vec<Option<i64>> = ["a", "b", "c", "d"]
+                .iter()
+                .map(|key| sys.block_on(replica.send(GetCounter(key.to_string()))).unwrap().unwrap())
+                .collect();
+            assert_eq!(counters, vec![None, Some(9), None, Some(4)]);
+            states.push(sys.block_on(replica.send(StateMachineDigest(8))).unwrap().unwrap());
+        }
+        assert!(states.windows(2).all(|pair| pair[0] == pair[1]), "replicas diverged: {:?}", states);
+    }
+}
@@ -1,9 +1,11 @@
+use actix::prelude::*;
 
-use crate::raftor::Raftor;
+use crate::network::{GetLastApplied, GetPeerLinks, PeerAddressChanged};
+use crate::raftor::{Raftor, ShardedRequest};
 use crate::server::{CreateRoom, GetMembers, Join, SendRecipient, SendRoom};
 
 impl Raftor {
-    pub(crate) fn register_handlers(&mut self) {
+    pub(crate) fn register_handlers(&mut self, raftor: Addr<Raftor>) {
         let mut registry = self.registry.write().unwrap();
 
         // register server handlers
@@ -12,5 +14,13 @@ impl Raftor {
         registry.register::<SendRoom, _>(self.server.clone());
         registry.register::<SendRecipient, _>(self.server.clone());
         registry.register::<Join, _>(self.server.clone());
+
+        // register cluster network handlers
+        registry.register::<GetLastApplied, _>(self.cluster_net.clone());
+        registry.register::<GetPeerLinks, _>(self.cluster_net.clone());
+
+        // register raftor handlers
+        registry.register::<ShardedRequest, _>(raftor.clone());
+        registry.register::<PeerAddressChanged, _>(raftor);
     }
 }
@@ -0,0 +1,191 @@
+use actix::prelude::*;
+use actix_web::client::Client;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use actix_raft::NodeId;
+
+use crate::network::GetGroupMetrics;
+use crate::raft::{storage::GetClusterMembers, ChangeRaftClusterConfig, DEFAULT_GROUP};
+use crate::raftor::Raftor;
+
+/// How often the progress of a join is checked
+const JOIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a join request may go unanswered before it is sent again
+const JOIN_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Progress of a node joining an existing cluster through the discovery host.
+///
+/// Every step is derived from the observed membership, never from a response or a record of its
+/// own, so a lost request or a restart in the middle of the join only delays it: the node asks
+/// again until the leader made it a voter and put it on the ring. The membership is only kept in
+/// memory, a restarted node observes it again as it catches up on the log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum JoinStep {
+    /// asked the discovery host to add the node
+    Requested,
+    /// the node is a voter of the default group but not yet on the ring
+    Voter,
+    /// the `Add` entry of the node was applied, the join is complete
+    Added,
+}
+
+/// Join progress of this node
+pub(crate) struct JoinState {
+    pub step: Option<JoinStep>,
+    requested_at: Option<Instant>,
+}
+
+impl JoinState {
+    pub fn new() -> Self {
+        JoinState {
+            step: None,
+            requested_at: None,
+        }
+    }
+
+    /// Take the step the observed membership reached, resolves with whether to ask for the join
+    /// (again)
+    fn observe(&mut self, voter: bool, added: bool) -> bool {
+        if added {
+            self.set(JoinStep::Added);
+            return false;
+        }
+        if voter {
+            self.set(JoinStep::Voter);
+        }
+
+        // the leader makes a voter and then adds it, asking again is a no-op for every step that
+        // already landed
+        let request = self.should_request();
+        if request {
+            self.requested_at = Some(Instant::now());
+            if self.step.is_none() {
+                self.set(JoinStep::Requested);
+            }
+        }
+        request
+    }
+
+    fn set(&mut self, step: JoinStep) {
+        if self.step != Some(step) {
+            println!("Join step {:?}", step);
+            self.step = Some(step);
+        }
+    }
+
+    fn should_request(&self) -> bool {
+        match self.requested_at {
+            Some(at) => at.elapsed() >= JOIN_RETRY_INTERVAL,
+            None => true,
+        }
+    }
+}
+
+/// Add a node asking to join to every raft group, each client passes the change on to the leader
+/// of its group
+#[derive(Message)]
+pub struct JoinCluster(pub NodeId);
+
+impl Handler<JoinCluster> for Raftor {
+    type Result = ();
+
+    fn handle(&mut self, msg: JoinCluster, _ctx: &mut Context<Self>) {
+        for raft in self.groups.values() {
+            raft.do_send(ChangeRaftClusterConfig(vec![msg.0], vec![]));
+        }
+    }
+}
+
+/// Take the next step of the join, repeated until the node was added
+#[derive(Message)]
+pub(crate) struct AdvanceJoin;
+
+impl Handler<AdvanceJoin> for Raftor {
+    type Result = ();
+
+    fn handle(&mut self, _: AdvanceJoin, ctx: &mut Context<Self>) {
+        let metrics = self.cluster_net.send(GetGroupMetrics(DEFAULT_GROUP));
+        let members = self.raft.send(GetClusterMembers);
+
+        fut::wrap_future::<_, Self>(metrics.join(members))
+            .then(|res, act, ctx| {
+                let (voter, added) = match res {
+                    Ok((metrics, members)) => (
+                        metrics.map(|m| m.membership_config.members.contains(&act.id)).unwrap_or(false),
+                        members.map(|m| m.iter().any(|member| member.id == act.id)).unwrap_or(false),
+                    ),
+                    Err(_) => (false, false),
+                };
+
+                if act.join.observe(voter, added) {
+                    act.request_join(ctx);
+                }
+                if act.join.step == Some(JoinStep::Added) {
+                    return fut::ok(());
+                }
+                ctx.run_later(JOIN_POLL_INTERVAL, |_act, ctx| ctx.notify(AdvanceJoin));
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Raftor {
+    fn request_join(&mut self, ctx: &mut Context<Self>) {
+        let route = format!("http://{}/cluster/join", self.discovery_host.as_str());
+
+        fut::wrap_future::<_, Self>(
+            Client::default()
+                .put(route)
+                .header("Content-Type", "application/json")
+                .send_json(&self.id),
+        )
+            .map(|_, _act, _ctx| ())
+            .map_err(|err, _act, _ctx| println!("Error joining cluster {:?}", err))
+            .spawn(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A node restarted with the membership at `voter` and `added` resumes from scratch
+    fn restarted(voter: bool, added: bool) -> (JoinState, bool) {
+        let mut join = JoinState::new();
+        let request = join.observe(voter, added);
+        (join, request)
+    }
+
+    #[test]
+    fn a_crash_before_the_request_landed_asks_again() {
+        let (join, request) = restarted(false, false);
+        assert!(request);
+        assert_eq!(join.step, Some(JoinStep::Requested));
+    }
+
+    #[test]
+    fn a_crash_once_a_voter_asks_again_for_the_add() {
+        let (join, request) = restarted(true, false);
+        assert!(request);
+        assert_eq!(join.step, Some(JoinStep::Voter));
+    }
+
+    #[test]
+    fn a_crash_once_added_completes_without_asking() {
+        let (join, request) = restarted(true, true);
+        assert!(!request);
+        assert_eq!(join.step, Some(JoinStep::Added));
+    }
+
+    #[test]
+    fn a_pending_request_is_not_repeated_before_the_retry_interval() {
+        let mut join = JoinState::new();
+        assert!(join.observe(false, false));
+        assert!(!join.observe(false, false));
+        assert!(!join.observe(true, false));
+        assert_eq!(join.step, Some(JoinStep::Voter));
+    }
+}
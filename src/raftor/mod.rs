@@ -1,35 +1,91 @@
 use actix::prelude::*;
-use actix_web::client::Client;
 use actix_raft::NodeId;
 use config;
+use log::{error, LevelFilter};
+use std::collections::BTreeMap;
 use std::env;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
-use tokio::timer::Delay;
-use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use std::time::Duration;
 
-use crate::config::{ConfigSchema, NetworkType, NodeInfo};
+use crate::config::{CommitQuorum, ConfigSchema, NetworkType, NodeInfo};
 use crate::hash_ring::{self, RingType};
-use crate::network::{HandlerRegistry, Network, DiscoverNodes, SetClusterState, NetworkState};
-use crate::raft::{RaftClient, InitRaft};
-use crate::server::Server;
-use crate::utils;
+use crate::logger;
+use crate::network::{tap, HandlerRegistry, Network, DiscoverNodes, GetGroupMetrics, GetNodes, PeerAddressChanged, SetClusterState, SetMaintenance, NetworkState};
+use crate::raft::{state_machine::StateMachine, storage::ExportLogIfChanged, ApplicationErrorHandler, DeadLetter, GroupId, RaftClient, InitRaft, PauseElections, WriteFreeze, DEFAULT_GROUP};
+use crate::server::{http::RaftHttp, Server};
+use crate::utils::{is_host_port, NoopMetrics, SharedClock, SharedMetrics, SystemClock};
 
 mod handlers;
+mod join;
+mod shard;
+
+use self::join::{AdvanceJoin, JoinState};
+pub use self::join::{JoinCluster, JoinStep};
+pub use self::shard::{ShardError, ShardedRequest};
 
 pub struct Raftor {
     id: NodeId,
+    /// client of the default raft group
     pub raft: Addr<RaftClient>,
+    /// clients of all raft groups, including the default one
+    pub groups: BTreeMap<GroupId, Addr<RaftClient>>,
     pub app_net: Addr<Network>,
     pub cluster_net: Addr<Network>,
     pub server: Addr<Server>,
     discovery_host: String,
-    _ring: RingType,
+    ring: RingType,
     registry: Arc<RwLock<HandlerRegistry>>,
     _info: NodeInfo,
+    /// observers never ask to join the membership
+    observer: bool,
+    ready: bool,
+    ready_waiters: Vec<oneshot::Sender<()>>,
+    join: JoinState,
+    /// where the log is exported to on `Shutdown`, with `snapshot_on_shutdown`
+    shutdown_export: Option<PathBuf>,
+    redirect_to_leader: bool,
+    /// a bootstrap with read-only voters can't satisfy `CommitQuorum::All`
+    commit_quorum: CommitQuorum,
+}
+
+/// What the application plugs into the raft groups of a `Raftor`
+#[derive(Default)]
+pub struct RaftorOptions {
+    /// sink of the metrics of the raft groups, the networks and the storages, `NoopMetrics` unset
+    pub metrics: Option<SharedMetrics>,
+    /// state machine of each group, the others run the `DefaultStateMachine`
+    pub state_machines: BTreeMap<GroupId, Box<dyn StateMachine>>,
+    /// time source of the raft clients and the networks, e.g. a `MockClock` in tests,
+    /// `SystemClock` unset
+    pub clock: Option<SharedClock>,
+    /// receives the client requests of every group which failed for good
+    pub dead_letter: Option<Recipient<DeadLetter>>,
+    /// decides what becomes of the requests the state machine of a group rejected, the others
+    /// are dead-lettered
+    pub application_error_handlers: BTreeMap<GroupId, Arc<dyn ApplicationErrorHandler>>,
 }
 
 impl Raftor {
     pub fn new() -> Raftor {
+        Raftor::with_options(RaftorOptions::default())
+    }
+
+    /// Report the metrics of the raft groups, the networks and the storages to `metrics`
+    pub fn with_metrics(metrics: SharedMetrics) -> Raftor {
+        Raftor::with_options(RaftorOptions {
+            metrics: Some(metrics),
+            ..RaftorOptions::default()
+        })
+    }
+
+    /// Start the node with what the application plugs into its raft groups
+    pub fn with_options(options: RaftorOptions) -> Raftor {
+        let RaftorOptions { metrics, mut state_machines, clock, dead_letter, mut application_error_handlers } = options;
+        let metrics = metrics.unwrap_or_else(|| Arc::new(NoopMetrics));
+        let clock = clock.unwrap_or_else(|| Arc::new(SystemClock));
+
         let mut config = config::Config::default();
 
         config
@@ -41,34 +97,89 @@ impl Raftor {
         let mut config = config.try_into::<ConfigSchema>().unwrap();
 
         // create consistent hash ring
-        let ring = hash_ring::Ring::new(10);
+        // the replicas are only a seed, `RingReplicas` entries replace them on every node
+        let ring = hash_ring::Ring::new(config.ring_replicas as isize);
 
         // create handlers registry
         let registry = Arc::new(RwLock::new(HandlerRegistry::new()));
+        tap::set_enabled(config.rpc_tap);
 
 
         let args: Vec<String> = env::args().collect();
-        let cluster_address = args[1].as_str();
+        // peers reach the node at the advertised address, which differs from the bound one behind NAT
+        let listen_address = config.listen_addr.clone().unwrap_or(args[1].clone());
+        let advertise_address = config.advertise_addr.clone().unwrap_or(listen_address.clone());
+        for address in &[&listen_address, &advertise_address] {
+            if !is_host_port(address) {
+                panic!("Refusing to start, {} is not a valid host:port address", address);
+            }
+        }
+        let cluster_address = advertise_address.as_str();
         let app_address = args[2].as_str();
         let public_address  = args[3].as_str();
 
+        // the configured entry of the local node may carry an explicit id or the read-only flag
+        let configured = config.nodes.iter().find(|node| node.cluster_addr == cluster_address);
+        let readonly = config.readonly || configured.map(|node| node.readonly).unwrap_or(false);
         let node_info = NodeInfo {
             cluster_addr: cluster_address.to_owned(),
             app_addr: app_address.to_owned(),
             public_addr: public_address.to_owned(),
+            id: configured.and_then(|node| node.id),
+            readonly: readonly,
+            metadata: {
+                let mut metadata = config.metadata.clone();
+                metadata.extend(configured.map(|node| node.metadata.clone()).unwrap_or_default());
+                metadata
+            },
         };
+        if let Err(err) = node_info.check_metadata() {
+            panic!("Refusing to start, {}", err);
+        }
+
+        let node_id = node_info.node_id();
 
-        // generate local node id
-        let node_id = utils::generate_node_id(cluster_address);
+        // as configured, a node started from the same config but not listed in it agrees with the others
+        let initial_members: Vec<NodeId> = config.nodes.iter().map(|node| node.node_id()).collect();
 
+        for node in config.nodes.iter_mut().filter(|node| node.cluster_addr == cluster_address) {
+            node.readonly = readonly;
+        }
         Raftor::add_node_to_config(node_info.clone(), &mut config);
 
+        // the local node included, its id may be derived from its address as well
+        if let Err(err) = config.node_ids() {
+            panic!("Refusing to start, {}", err);
+        }
+
+        // a read-only node follows the log like an observer and never joins the membership
+        config.readonly = readonly;
+        config.observer = config.observer || readonly;
+
         let cluster_arb = Arbiter::new();
         let app_arb = Arbiter::new();
         let raft_arb = Arbiter::new();
 
-        let raft_client = RaftClient::new(node_id, ring.clone(), registry.clone());
-        let raft = RaftClient::start_in_arbiter(&raft_arb, |_| raft_client);
+        // replicated by the default group, enforced by all of them
+        let write_freeze: WriteFreeze = Arc::new(RwLock::new(None));
+        let mut groups = BTreeMap::new();
+        for group in 0..config.raft_groups.max(1) {
+            let mut raft_client = RaftClient::new(node_id, group, ring.clone(), registry.clone(), config.clone())
+                .with_write_freeze(write_freeze.clone())
+                .with_metrics(metrics.clone())
+                .with_clock(clock.clone());
+            if let Some(state_machine) = state_machines.remove(&group) {
+                raft_client = raft_client.with_state_machine(state_machine);
+            }
+            if let Some(handler) = application_error_handlers.remove(&group) {
+                raft_client = raft_client.with_application_error_handler(handler);
+            }
+            if let Some(ref recipient) = dead_letter {
+                raft_client = raft_client.with_dead_letter(recipient.clone());
+            }
+            groups.insert(group, Supervisor::start_in_arbiter(&raft_arb, |_| raft_client));
+        }
+        let raft = groups[&DEFAULT_GROUP].clone();
 
         // create cluster network
         let mut cluster_net = Network::new(node_id, ring.clone(), registry.clone(), NetworkType::Cluster, raft.clone(), config.discovery_host.clone(), node_info.clone());
@@ -76,9 +187,16 @@ impl Raftor {
         let mut app_net = Network::new(node_id, ring.clone(), registry.clone(), NetworkType::App, raft.clone(), config.discovery_host.clone(), node_info.clone());
 
         cluster_net.configure(config.clone()); // configure network
-        cluster_net.bind(cluster_address); // listen on ip and port
+        cluster_net.set_metrics(metrics.clone());
+        cluster_net.set_clock(clock.clone());
+        if config.verify_initial_members {
+            cluster_net.set_initial_members(initial_members);
+        }
+        cluster_net.bind(listen_address.as_str()); // listen on ip and port
 
         app_net.configure(config.clone()); // configure network
+        app_net.set_metrics(metrics.clone());
+        app_net.set_clock(clock.clone());
         app_net.bind(app_address); // listen on ip and port
 
         let cluster_net_addr = Network::start_in_arbiter(&cluster_arb, |_| cluster_net);
@@ -87,16 +205,48 @@ impl Raftor {
         let server = Server::new(app_net_addr.clone(), ring.clone(), node_id);
         let server_addr = server.start();
 
+        // the next start imports the export
+        let shutdown_export = match (config.snapshot_on_shutdown, config.import_log.as_ref()) {
+            (true, Some(path)) => Some(PathBuf::from(path)),
+            (true, None) => {
+                println!("Ignoring snapshot_on_shutdown, import_log is not set");
+                None
+            }
+            (false, _) => None,
+        };
+
         Raftor {
             id: node_id,
             app_net: app_net_addr,
             cluster_net: cluster_net_addr,
             raft: raft,
+            groups: groups,
             server: server_addr,
-            _ring: ring,
+            ring: ring,
             registry: registry,
             discovery_host: config.discovery_host.clone(),
             _info: node_info,
+            observer: config.observer,
+            ready: false,
+            ready_waiters: Vec::new(),
+            join: JoinState::new(),
+            shutdown_export: shutdown_export,
+            redirect_to_leader: config.redirect_to_leader,
+            commit_quorum: config.commit_quorum.clone(),
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// State of the `server::http` routes of the default group, as configured
+    pub fn http(&self) -> RaftHttp {
+        RaftHttp {
+            id: self.id,
+            raft: self.raft.clone(),
+            net: self.cluster_net.clone(),
+            redirect_to_leader: self.redirect_to_leader,
         }
     }
 
@@ -116,32 +266,50 @@ impl Actor for Raftor {
         fut::wrap_future::<_, Self>(self.cluster_net.send(DiscoverNodes))
             .map_err(|err, _, _| panic!(err))
             .and_then(|res, act, _ctx| {
-                let res = res.unwrap();
-                let nodes = res.0;
-                let join_mode = res.1;
+                let (nodes, join_mode) = res.unwrap();
+                // a joining node finds the writable nodes of the cluster, only a bootstrap may lack one
+                let checked = if join_mode {
+                    fut::Either::A(fut::ok((nodes, join_mode)))
+                } else {
+                    fut::Either::B(
+                        fut::wrap_future::<_, Self>(act.cluster_net.send(GetNodes))
+                            .map_err(|err, _, _| panic!(err))
+                            .and_then(move |res, act, _ctx| {
+                                let infos = res.unwrap();
+                                let writable = nodes.iter().any(|id| infos.get(id).map(|info| !info.readonly).unwrap_or(false));
+                                if !writable {
+                                    error!("Refusing to bootstrap, every node of {:?} is read-only", nodes);
+                                    System::current().stop_with_code(1);
+                                    return fut::err(());
+                                }
+                                // a read-only voter never applies an entry
+                                let readonly: Vec<NodeId> = nodes.iter().cloned().filter(|id| infos.get(id).map(|info| info.readonly).unwrap_or(false)).collect();
+                                if act.commit_quorum == CommitQuorum::All && !readonly.is_empty() {
+                                    error!("Refusing to bootstrap with commit_quorum = all, nodes {:?} are read-only", readonly);
+                                    System::current().stop_with_code(1);
+                                    return fut::err(());
+                                }
+                                fut::ok((nodes, join_mode))
+                            }),
+                    )
+                };
+                checked
+            })
+            .and_then(|(nodes, join_mode), act, _ctx| {
+                // the other groups are joined along with the default one, see `JoinCluster`
+                for (_, raft) in act.groups.iter().filter(|(group, _)| **group != DEFAULT_GROUP) {
+                    raft.do_send(InitRaft{ nodes: nodes.clone(), net: act.cluster_net.clone(), server: act.server.clone(), join_mode: join_mode });
+                }
 
                 fut::wrap_future::<_, Self>(act.raft.send(InitRaft{ nodes, net: act.cluster_net.clone(), server: act.server.clone(),  join_mode: join_mode }))
                     .map_err(|err, _, _| panic!(err))
                     .and_then(move |_, act, ctx| {
-                        let client = Client::default();
-                        let cluster_nodes_route = format!("http://{}/cluster/join", act.discovery_host.as_str());
-
                         act.app_net.do_send(SetClusterState(NetworkState::Cluster));
                         act.cluster_net.do_send(SetClusterState(NetworkState::Cluster));
+                        ctx.notify(CheckReady);
 
-                        if join_mode {
-                            fut::wrap_future::<_, Self>(client.put(cluster_nodes_route)
-                                                        .header("Content-Type", "application/json")
-                                                        .send_json(&act.id))
-                                .map_err(|err, _, _| println!("Error joining cluster {:?}", err))
-                                .and_then(|_res, _act, _ctx| {
-                                    fut::wrap_future::<_, Self>(Delay::new(Instant::now() + Duration::from_secs(1)))
-                                        .map_err(|_, _, _| ())
-                                        .and_then(|_, _act, _ctx| {
-//                                            act.raft.do_send(AddNode(act.id));
-                                            fut::ok(())
-                                        })
-                                }).spawn(ctx);
+                        if join_mode && !act.observer {
+                            ctx.notify(AdvanceJoin);
                         }
 
                         fut::ok(())
@@ -149,6 +317,153 @@ impl Actor for Raftor {
             })
             .spawn(ctx);
 
-        self.register_handlers();
+        self.register_handlers(ctx.address());
+    }
+}
+
+/// Set the log level of the raftor targets at runtime, `None` goes back to the one of `RUST_LOG`
+pub struct SetLogLevel(pub Option<LevelFilter>);
+
+impl Message for SetLogLevel {
+    type Result = ();
+}
+
+impl Handler<SetLogLevel> for Raftor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetLogLevel, _ctx: &mut Context<Self>) {
+        println!("Log level of node {} set to {:?}", self.id, msg.0);
+        logger::set_level(msg.0);
+    }
+}
+
+impl Handler<SetMaintenance> for Raftor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetMaintenance, _ctx: &mut Context<Self>) {
+        self.cluster_net.do_send(SetMaintenance(msg.0));
+        for client in self.groups.values() {
+            client.do_send(PauseElections(msg.0));
+        }
+    }
+}
+
+impl Handler<PeerAddressChanged> for Raftor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerAddressChanged, _ctx: &mut Context<Self>) {
+        match msg.net_type {
+            NetworkType::App => self.app_net.do_send(msg),
+            NetworkType::Cluster => self.cluster_net.do_send(msg),
+        }
+    }
+}
+
+/// Log level of the raftor targets set at runtime, `None` while `RUST_LOG` decides
+pub struct GetLogLevel;
+
+impl Message for GetLogLevel {
+    type Result = Result<Option<LevelFilter>, ()>;
+}
+
+impl Handler<GetLogLevel> for Raftor {
+    type Result = Result<Option<LevelFilter>, ()>;
+
+    fn handle(&mut self, _: GetLogLevel, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(logger::level())
+    }
+}
+
+/// Stop the node gracefully. With `snapshot_on_shutdown` the log of the default group is exported
+/// to `import_log` first, unless it is unchanged since it was imported or exported there.
+#[derive(Message)]
+pub struct Shutdown;
+
+impl Handler<Shutdown> for Raftor {
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Context<Self>) {
+        let path = match self.shutdown_export.clone() {
+            Some(path) => path,
+            None => return System::current().stop(),
+        };
+
+        println!("Exporting the log to {} before shutting down", path.display());
+        fut::wrap_future::<_, Self>(self.raft.send(ExportLogIfChanged { path }))
+            .then(|res, _act, _ctx| {
+                match res {
+                    Ok(Ok(Some(exported))) => println!("Exported {} entries", exported),
+                    Ok(Ok(None)) => println!("Log unchanged since the last export"),
+                    _ => println!("Failed to export the log, shutting down anyway"),
+                }
+                System::current().stop();
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+/// Resolves once the node joined the default group and observed its leader
+pub struct WaitReady;
+
+impl Message for WaitReady {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<WaitReady> for Raftor {
+    type Result = Response<(), ()>;
+
+    fn handle(&mut self, _: WaitReady, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.ready {
+            return Response::reply(Ok(()));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.ready_waiters.push(tx);
+        Response::fut(rx.map_err(|_| ()))
+    }
+}
+
+#[derive(Message)]
+struct CheckReady;
+
+impl Handler<CheckReady> for Raftor {
+    type Result = ();
+
+    fn handle(&mut self, _: CheckReady, ctx: &mut Context<Self>) {
+        // observers are never members, they are ready once following the log
+        if self.observer {
+            return self.set_ready();
+        }
+
+        fut::wrap_future::<_, Self>(self.cluster_net.send(GetGroupMetrics(DEFAULT_GROUP)))
+            .then(|res, act, ctx| {
+                let ready = match res {
+                    Ok(Ok(metrics)) => {
+                        metrics.current_leader.is_some()
+                            && metrics.membership_config.members.contains(&act.id)
+                    }
+                    _ => false,
+                };
+
+                if ready {
+                    act.set_ready();
+                } else {
+                    ctx.run_later(Duration::from_secs(1), |_act, ctx| ctx.notify(CheckReady));
+                }
+
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Raftor {
+    fn set_ready(&mut self) {
+        println!("Node {} is ready", self.id);
+        self.ready = true;
+        for tx in self.ready_waiters.drain(..) {
+            let _ = tx.send(());
+        }
     }
 }
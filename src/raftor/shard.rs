@@ -0,0 +1,137 @@
+use actix::prelude::*;
+use actix_raft::messages::ClientPayloadResponse;
+use actix_raft::NodeId;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+use crate::network::{remote::SendRemoteMessage, GetNodeById};
+use crate::raft::{
+    storage::{MemoryStorageData, MemoryStorageResponse},
+    ClientRequest, GroupId, RaftClientError, DEFAULT_GROUP,
+};
+use crate::raftor::Raftor;
+use crate::utils::generate_node_id;
+
+/// Most times a sharded request is passed on before it fails, bounds re-routing while the ring
+/// keeps changing
+const MAX_SHARD_HOPS: u8 = 5;
+
+/// How long to wait before routing a request again after its owner was unreachable
+const REROUTE_DELAY: Duration = Duration::from_millis(500);
+
+/// Propose `data` to the raft group owning `key`, through the node owning `key` on the ring.
+///
+/// Every node accepts sharded requests and passes them on to the owner, which proposes them to
+/// the group the key hashes to. When ownership moved while the request was in flight it is
+/// routed again with the current ring.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShardedRequest {
+    pub key: String,
+    pub data: MemoryStorageData,
+    /// times the request was passed on so far
+    #[serde(default)]
+    pub hops: u8,
+}
+
+impl ShardedRequest {
+    pub fn new(key: String, data: MemoryStorageData) -> Self {
+        ShardedRequest {
+            key: key,
+            data: data,
+            hops: 0,
+        }
+    }
+}
+
+impl Message for ShardedRequest {
+    type Result = Result<ClientPayloadResponse<MemoryStorageResponse>, ShardError>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ShardError {
+    /// No node is on the ring yet
+    NoOwner,
+    /// The request was re-routed `MAX_SHARD_HOPS` times without reaching its owner
+    Unroutable { key: String },
+    /// The owning group failed the request
+    Request(RaftClientError),
+}
+
+impl Raftor {
+    /// Node owning `key` on the ring
+    fn shard_owner(&self, key: &str) -> Option<NodeId> {
+        self.ring.read().unwrap().get_node(key.to_owned()).cloned()
+    }
+
+    /// Group replicating `key`, the default group only when it is the only one
+    fn shard_group(&self, key: &str) -> GroupId {
+        let data_groups = self.groups.len() as u64 - 1;
+        if data_groups == 0 {
+            return DEFAULT_GROUP;
+        }
+        1 + generate_node_id(key) % data_groups
+    }
+}
+
+impl Handler<ShardedRequest> for Raftor {
+    type Result = ResponseActFuture<Self, ClientPayloadResponse<MemoryStorageResponse>, ShardError>;
+
+    fn handle(&mut self, msg: ShardedRequest, _ctx: &mut Context<Self>) -> Self::Result {
+        let owner = match self.shard_owner(&msg.key) {
+            Some(owner) => owner,
+            None => return Box::new(fut::err(ShardError::NoOwner)),
+        };
+
+        if owner == self.id {
+            let group = self.shard_group(&msg.key);
+            let request = ClientRequest::new(msg.data);
+            let id = request.id;
+            return Box::new(
+                fut::wrap_future::<_, Self>(self.groups[&group].send(request))
+                    .map_err(move |_, _, _| ShardError::Request(RaftClientError::Internal { id }))
+                    .and_then(|res, _act, _ctx| fut::result(res.map_err(ShardError::Request))),
+            );
+        }
+
+        if msg.hops >= MAX_SHARD_HOPS {
+            return Box::new(fut::err(ShardError::Unroutable { key: msg.key }));
+        }
+
+        let forward = ShardedRequest {
+            hops: msg.hops + 1,
+            ..msg
+        };
+        let retry = forward.clone();
+        let key = forward.key.clone();
+        Box::new(
+            fut::wrap_future::<_, Self>(self.cluster_net.send(GetNodeById(owner)))
+                .map_err(|_, _, _| ())
+                .and_then(|res, _act, _ctx| fut::result(res))
+                .and_then(move |node, _act, _ctx| {
+                    fut::wrap_future::<_, Self>(node.send(SendRemoteMessage(forward))).map_err(|_, _, _| ())
+                })
+                .then(move |res, _act, ctx| -> Self::Result {
+                    match res {
+                        Ok(res) => Box::new(fut::result(res)),
+                        // the owner is unreachable, possibly it left the ring meanwhile
+                        Err(_) => {
+                            println!("Owner {} of shard key {} unreachable, routing again", owner, key);
+                            let address = ctx.address();
+                            Box::new(
+                                fut::wrap_future::<_, Self>(Delay::new(Instant::now() + REROUTE_DELAY))
+                                    .map_err(|_, _, _| ())
+                                    .and_then(move |_, _act, _ctx| {
+                                        fut::wrap_future::<_, Self>(address.send(retry)).map_err(|_, _, _| ())
+                                    })
+                                    .then(move |res, _act, _ctx| match res {
+                                        Ok(res) => fut::result(res),
+                                        Err(_) => fut::err(ShardError::Unroutable { key }),
+                                    }),
+                            )
+                        }
+                    }
+                }),
+        )
+    }
+}
@@ -0,0 +1,330 @@
+use actix::prelude::*;
+use actix_raft::NodeId;
+use actix_web::{http::header, http::StatusCode, web, Error, HttpRequest, HttpResponse};
+use futures::{future, Future};
+use serde::{Deserialize, Serialize};
+
+use crate::network::{GetConfigState, GetCurrentLeader, GetHttpAddr, GetThroughputStats, Network};
+use crate::raft::storage::{ClearDiagnostics, GetStorageStats, MemoryStorageData};
+use crate::raft::{ChangeMembership, ClientRequest, FreezeWrites, GetLeaderHint, GetReadLeases, GetReplaceProgress, MetricsSnapshot, ReplaceNode, UnfreezeWrites, RaftClient, RaftClientError, ReadClusterMembers, ReadQuery, Staleness, VerifyCluster, DEFAULT_GROUP};
+
+/// State of the HTTP/JSON front of a `RaftClient`
+#[derive(Clone)]
+pub struct RaftHttp {
+    pub id: NodeId,
+    pub raft: Addr<RaftClient>,
+    /// the cluster network, which knows the leader and the addresses of the peers
+    pub net: Addr<Network>,
+    /// answer writes reaching a follower with a redirect to the leader, instead of
+    /// forwarding them through the raft client
+    pub redirect_to_leader: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MembershipRequest {
+    #[serde(default)]
+    pub add: Vec<NodeId>,
+    #[serde(default)]
+    pub remove: Vec<NodeId>,
+}
+
+/// Mount with `App::new().data(RaftHttp { .. }).configure(http::routes)`:
+///
+/// - `POST /raft/entries` proposes a `MemoryStorageData` entry
+/// - `GET /raft/members` lists the members and when they were added, `max_entries` and
+///   `max_age_ms` bound how stale the answer of a follower may be
+/// - `POST /raft/members` changes the membership, resolving once the new config is applied
+/// - `GET /raft/config` reports whether a membership change is in progress
+/// - `GET /raft/counters/{key}` reads a counter from the local replica, or from the leader while
+///   it installs a snapshot or is staler than the `Staleness` bound in the query
+/// - `GET /raft/verify` compares the state machine digests of all members, on the leader
+/// - `GET /raft/storage` reports the latency of log appends and state machine applies
+/// - `GET /raft/metrics` samples the metrics of the default group on this node at once
+/// - `GET /raft/throughput` reports the entries committed per second over the last 10s
+/// - `PUT /raft/freeze` freezes writes cluster-wide with `true`, unfreezes them with `false`
+/// - `GET /raft/leases` reports the read leases this node holds and delegated
+/// - `GET /raft/leader` reports the last leader this node sent a request to and its address
+/// - `POST /raft/replace` replaces the voter `old` by `new`, `GET /raft/replace` reports the
+///   stage of the last replacement
+/// - `DELETE /raft/diagnostics` empties the membership audit, recent entries, latency and
+///   election stats of this node
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/raft/entries").route(web::post().to_async(propose_route)),
+    )
+    .service(
+        web::resource("/raft/members")
+            .route(web::get().to_async(members_route))
+            .route(web::post().to_async(change_membership_route)),
+    )
+    .service(web::resource("/raft/config").route(web::get().to_async(config_route)))
+    .service(web::resource("/raft/counters/{key}").route(web::get().to_async(counter_route)))
+    .service(web::resource("/raft/verify").route(web::get().to_async(verify_route)))
+    .service(web::resource("/raft/storage").route(web::get().to_async(storage_stats_route)))
+    .service(web::resource("/raft/metrics").route(web::get().to_async(metrics_snapshot_route)))
+    .service(web::resource("/raft/throughput").route(web::get().to_async(throughput_route)))
+    .service(web::resource("/raft/freeze").route(web::put().to_async(freeze_route)))
+    .service(web::resource("/raft/leases").route(web::get().to_async(leases_route)))
+    .service(web::resource("/raft/leader").route(web::get().to_async(leader_hint_route)))
+    .service(
+        web::resource("/raft/replace")
+            .route(web::get().to_async(replace_progress_route))
+            .route(web::post().to_async(replace_route)),
+    )
+    .service(web::resource("/raft/diagnostics").route(web::delete().to_async(clear_diagnostics_route)));
+}
+
+/// Status code of a failed client request
+pub fn error_status(err: &RaftClientError) -> StatusCode {
+    match err {
+        RaftClientError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        RaftClientError::Backpressure { .. } => StatusCode::TOO_MANY_REQUESTS,
+        RaftClientError::Application { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        RaftClientError::RetriesExhausted { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        RaftClientError::MembershipChange { .. } => StatusCode::CONFLICT,
+        RaftClientError::NotInitialized { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        RaftClientError::Cancelled { .. } => StatusCode::GONE,
+        RaftClientError::InsufficientReplication { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        RaftClientError::ReadOnly { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        RaftClientError::Undeliverable { .. } => StatusCode::BAD_GATEWAY,
+        RaftClientError::Frozen { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        RaftClientError::WouldDestroyCluster { .. } => StatusCode::CONFLICT,
+        RaftClientError::NotLeader { .. } => StatusCode::MISDIRECTED_REQUEST,
+    }
+}
+
+fn error_response(err: RaftClientError) -> HttpResponse {
+    HttpResponse::build(error_status(&err)).json(err)
+}
+
+fn propose_route(
+    data: web::Json<MemoryStorageData>,
+    req: HttpRequest,
+    srv: web::Data<RaftHttp>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let raft = srv.raft.clone();
+    let data = data.into_inner();
+
+    Box::new(leader_redirect(&srv, &req).and_then(move |redirect| match redirect {
+        Some(redirect) => future::Either::A(future::ok(redirect)),
+        None => future::Either::B(
+            raft.send(ClientRequest::new(data))
+                .map_err(Error::from)
+                .map(|res| match res {
+                    Ok(res) => HttpResponse::Ok().json(res),
+                    Err(err) => error_response(err),
+                }),
+        ),
+    }))
+}
+
+fn freeze_route(
+    freeze: web::Json<bool>,
+    srv: web::Data<RaftHttp>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let res = if *freeze {
+        future::Either::A(srv.raft.send(FreezeWrites))
+    } else {
+        future::Either::B(srv.raft.send(UnfreezeWrites))
+    };
+    Box::new(res.map_err(Error::from).map(|res| match res {
+        Ok(()) => HttpResponse::Ok().json(()),
+        Err(err) => error_response(err),
+    }))
+}
+
+fn members_route(
+    staleness: web::Query<Staleness>,
+    srv: web::Data<RaftHttp>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(ReadClusterMembers(staleness.into_inner()))
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(members) => HttpResponse::Ok().json(members),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+fn change_membership_route(
+    change: web::Json<MembershipRequest>,
+    req: HttpRequest,
+    srv: web::Data<RaftHttp>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let raft = srv.raft.clone();
+    let change = change.into_inner();
+
+    Box::new(leader_redirect(&srv, &req).and_then(move |redirect| match redirect {
+        Some(redirect) => future::Either::A(future::ok(redirect)),
+        None => future::Either::B(
+            raft.send(ChangeMembership::new(change.add, change.remove))
+                .map_err(Error::from)
+                .map(|res| match res {
+                    Ok(outcome) => HttpResponse::Ok().json(outcome),
+                    Err(err) => error_response(err),
+                }),
+        ),
+    }))
+}
+
+fn replace_route(
+    replace: web::Json<ReplaceNode>,
+    srv: web::Data<RaftHttp>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(replace.into_inner())
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(outcome) => HttpResponse::Ok().json(outcome),
+            Err(err) => error_response(err),
+        })
+}
+
+fn replace_progress_route(srv: web::Data<RaftHttp>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(GetReplaceProgress)
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(progress) => HttpResponse::Ok().json(progress),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+fn config_route(srv: web::Data<RaftHttp>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.net
+        .send(GetConfigState(DEFAULT_GROUP))
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(state) => HttpResponse::Ok().json(state),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+/// `404` for a counter never written
+fn counter_route(
+    key: web::Path<String>,
+    staleness: web::Query<Staleness>,
+    srv: web::Data<RaftHttp>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let read = ReadQuery {
+        staleness: staleness.into_inner(),
+        ..ReadQuery::new(key.into_inner())
+    };
+    srv.raft
+        .send(read)
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(Some(value)) => HttpResponse::Ok().json(value),
+            Ok(None) => HttpResponse::NotFound().finish(),
+            Err(err) => HttpResponse::ServiceUnavailable().json(err),
+        })
+}
+
+fn storage_stats_route(srv: web::Data<RaftHttp>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(GetStorageStats)
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(stats) => HttpResponse::Ok().json(stats),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+fn metrics_snapshot_route(srv: web::Data<RaftHttp>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(MetricsSnapshot)
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(report) => HttpResponse::Ok().json(report),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+fn throughput_route(srv: web::Data<RaftHttp>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.net
+        .send(GetThroughputStats(DEFAULT_GROUP))
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(stats) => HttpResponse::Ok().json(stats),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+fn clear_diagnostics_route(srv: web::Data<RaftHttp>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(ClearDiagnostics)
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(()) => HttpResponse::Ok().json(()),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+fn leases_route(srv: web::Data<RaftHttp>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(GetReadLeases)
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(status) => HttpResponse::Ok().json(status),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+fn leader_hint_route(srv: web::Data<RaftHttp>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(GetLeaderHint)
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(hint) => HttpResponse::Ok().json(hint),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+#[derive(Deserialize)]
+pub struct VerifyQuery {
+    pub index: Option<u64>,
+}
+
+/// `409` with the report when some member diverged
+fn verify_route(
+    query: web::Query<VerifyQuery>,
+    srv: web::Data<RaftHttp>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.raft
+        .send(VerifyCluster(query.index))
+        .map_err(Error::from)
+        .map(|res| match res {
+            Ok(ref report) if report.divergent.is_empty() => HttpResponse::Ok().json(report),
+            Ok(report) => HttpResponse::Conflict().json(report),
+            Err(_) => HttpResponse::ServiceUnavailable().finish(),
+        })
+}
+
+/// A 307 to the same path on the leader, when redirects are enabled and this node isn't the leader
+fn leader_redirect(
+    srv: &RaftHttp,
+    req: &HttpRequest,
+) -> impl Future<Item = Option<HttpResponse>, Error = Error> {
+    if !srv.redirect_to_leader {
+        return future::Either::A(future::ok(None));
+    }
+
+    let (id, net) = (srv.id, srv.net.clone());
+    let path = req.uri().path().to_owned();
+
+    future::Either::B(
+        net.send(GetCurrentLeader)
+            .map_err(Error::from)
+            .and_then(move |leader| match leader {
+                Ok(leader) if leader != id => future::Either::A(net.send(GetHttpAddr(leader)).map_err(Error::from)),
+                _ => future::Either::B(future::ok(Err(()))),
+            })
+            .map(move |addr| {
+                addr.ok().map(|addr| {
+                    HttpResponse::TemporaryRedirect()
+                        .header(header::LOCATION, format!("http://{}{}", addr, path))
+                        .finish()
+                })
+            }),
+    )
+}
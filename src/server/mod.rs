@@ -7,6 +7,8 @@ use crate::hash_ring::RingType;
 use crate::network::{DistributeMessage, DistributeAndWait, Network};
 use crate::session::{self, Session};
 
+pub mod http;
+
 pub struct Server {
     rooms: HashMap<String, HashSet<String>>,
     sessions: HashMap<String, Addr<Session>>,
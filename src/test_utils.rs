@@ -0,0 +1,58 @@
+//! Helpers shared by the unit tests
+
+use actix::prelude::*;
+use actix_raft::messages::{EntryNormal, EntryPayload};
+use actix_raft::NodeId;
+
+use crate::config::ConfigSchema;
+use crate::hash_ring::Ring;
+use crate::raft::state_machine::{DefaultStateMachine, StateMachine};
+use crate::raft::storage::{Entry, MemoryStorage, MemoryStorageData};
+use crate::raft::GroupId;
+
+/// Configuration with the required fields only, followed by `extra` toml
+pub fn config(extra: &str) -> ConfigSchema {
+    let toml = format!("discovery_host = \"127.0.0.1:8080\"\njoin_strategy = \"static\"\nnodes = []\n{}", extra);
+    let mut config = config::Config::default();
+    config.merge(config::File::from_str(&toml, config::FileFormat::Toml)).unwrap();
+    config.try_into::<ConfigSchema>().unwrap()
+}
+
+/// Address of an actor which never runs, the messages sent to it are dropped
+pub fn detached<A>() -> Addr<A>
+where
+    A: Actor<Context = Context<A>>,
+{
+    Context::<A>::new().address()
+}
+
+/// Storage of group `group` in a temporary directory, to be started within a running system
+pub fn storage(group: GroupId, config: &ConfigSchema, state_machine: Box<dyn StateMachine>) -> Addr<MemoryStorage> {
+    let dir = tempfile::tempdir_in("/tmp").unwrap().into_path().to_string_lossy().to_string();
+    let ring = Ring::new(config.ring_replicas as isize);
+    let config = config.clone();
+    MemoryStorage::create(move |_| MemoryStorage::new(group, vec![], dir, ring, detached(), state_machine, &config))
+}
+
+/// Storage of the default group running the default state machine
+pub fn default_storage(config: &ConfigSchema) -> Addr<MemoryStorage> {
+    storage(0, config, Box::new(DefaultStateMachine))
+}
+
+/// Committed normal entry at `index`
+pub fn entry(index: u64, data: MemoryStorageData) -> Entry {
+    Entry {
+        index,
+        term: 1,
+        payload: EntryPayload::Normal(EntryNormal { data }),
+    }
+}
+
+/// Entries adding `nodes`, from index 1
+pub fn adds(nodes: &[NodeId]) -> Vec<Entry> {
+    nodes
+        .iter()
+        .enumerate()
+        .map(|(i, id)| entry(i as u64 + 1, MemoryStorageData::Add(*id)))
+        .collect()
+}
@@ -1,5 +1,178 @@
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use futures::{Async, Future, Poll};
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::timer::Delay;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::SocketOptions;
+
+/// Apply the configured options to a peer connection, failures are logged and not fatal
+pub fn set_socket_options(stream: &TcpStream, options: &SocketOptions) {
+    if let Err(err) = stream.set_nodelay(options.nodelay) {
+        warn!("Failed to set TCP_NODELAY: {}", err);
+    }
+    if let Some(size) = options.send_buffer_size {
+        if let Err(err) = stream.set_send_buffer_size(size) {
+            warn!("Failed to set the send buffer size: {}", err);
+        }
+    }
+    if let Some(size) = options.recv_buffer_size {
+        if let Err(err) = stream.set_recv_buffer_size(size) {
+            warn!("Failed to set the receive buffer size: {}", err);
+        }
+    }
+}
+
+/// A stream whose reads or writes fail with `TimedOut` once they stay blocked for longer than
+/// their timeout, so a stuck peer is disconnected instead of holding the connection forever
+pub struct TimeoutStream<S> {
+    inner: S,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    /// set while a read, or write, is blocked
+    read_deadline: Option<Delay>,
+    write_deadline: Option<Delay>,
+}
+
+impl<S> TimeoutStream<S> {
+    pub fn new(inner: S) -> Self {
+        TimeoutStream {
+            inner: inner,
+            read_timeout: None,
+            write_timeout: None,
+            read_deadline: None,
+            write_deadline: None,
+        }
+    }
+
+    /// Timeouts in milliseconds, 0 disables one
+    pub fn with_timeouts(mut self, read_ms: u64, write_ms: u64) -> Self {
+        let timeout = |ms| if ms > 0 { Some(Duration::from_millis(ms)) } else { None };
+        self.read_timeout = timeout(read_ms);
+        self.write_timeout = timeout(write_ms);
+        self
+    }
+}
+
+/// Fail once a blocked operation outlived its deadline, the deadline wakes the task up
+fn check_deadline(deadline: &mut Option<Delay>, timeout: Option<Duration>, op: &str) -> io::Result<()> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(()),
+    };
+    let delay = deadline.get_or_insert_with(|| Delay::new(Instant::now() + timeout));
+    match delay.poll() {
+        Ok(Async::Ready(())) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} blocked for more than {:?}", op, timeout),
+        )),
+        Ok(Async::NotReady) => Ok(()),
+        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+    }
+}
+
+impl<S: Read> Read for TimeoutStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                check_deadline(&mut self.read_deadline, self.read_timeout, "read")?;
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            res => {
+                self.read_deadline = None;
+                res
+            }
+        }
+    }
+}
+
+impl<S: Write> Write for TimeoutStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                check_deadline(&mut self.write_deadline, self.write_timeout, "write")?;
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            res => {
+                self.write_deadline = None;
+                res
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.flush() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                check_deadline(&mut self.write_deadline, self.write_timeout, "write")?;
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            res => {
+                self.write_deadline = None;
+                res
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for TimeoutStream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for TimeoutStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// A peer connection with read and write timeouts
+pub type PeerStream = TimeoutStream<TcpStream>;
+
+/// Wrap a peer connection with the timeouts of `options`. Only the cluster network pings, an
+/// idle application connection is fine, so it gets no read timeout.
+pub fn peer_stream(stream: TcpStream, options: &SocketOptions, read_timeout: bool) -> PeerStream {
+    let read_ms = if read_timeout { options.read_timeout_ms } else { 0 };
+    TimeoutStream::new(stream).with_timeouts(read_ms, options.write_timeout_ms)
+}
+
+/// Whether `addr` is a `host:port` address, the host being an ip or a dns name. Names are
+/// resolved when dialled or bound only, a peer may announce a name its dns doesn't serve yet.
+pub fn is_host_port(addr: &str) -> bool {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    let (host, port) = match addr.rfind(':') {
+        Some(at) => (&addr[..at], &addr[at + 1..]),
+        None => return false,
+    };
+    port.parse::<u16>().is_ok()
+        && !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Resolve a `host:port` address to the first socket address it names, blocking on dns
+pub fn resolve_addr(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("{} resolves to no address", addr))
+    })
+}
+
+static CORRELATION_SEQ: AtomicU64 = AtomicU64::new(0);
 
 /// Generating node id from node's remote address
 pub fn generate_node_id(node_address: &str) -> u64 {
@@ -13,3 +186,475 @@ pub fn generate_node_id(node_address: &str) -> u64 {
 
     id
 }
+
+/// Generating a correlation id for tracing a client request across nodes
+pub fn generate_correlation_id() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let seq = CORRELATION_SEQ.fetch_add(1, Ordering::Relaxed);
+
+    (nanos & !0xffff) | (seq & 0xffff)
+}
+
+/// Exponential backoff with jitter.
+///
+/// Every delay is drawn between half and the full exponential delay, so peers that
+/// start together don't retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base: base,
+            max: max,
+            attempt: 0,
+        }
+    }
+
+    /// Number of delays handed out since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = self.base.as_millis() as u64 * 2u64.saturating_pow(self.attempt.min(32));
+        let delay = exp.min(self.max.as_millis() as u64).max(1);
+        self.attempt += 1;
+
+        let half = delay / 2;
+        Duration::from_millis(half + jitter(delay - half + 1))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Token bucket capping a rate in bytes per second, allowing bursts of up to one second worth
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate.max(1),
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take `amount` tokens, returning how long to wait before they may be used
+    pub fn take(&mut self, amount: u64) -> Duration {
+        let now = Instant::now();
+        let rate = self.rate as f64;
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+
+        // a negative balance is what earlier callers are still waiting for
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate) - amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// Number of buckets of a `LatencyHistogram`, the last one holds everything from ~35 minutes
+const LATENCY_BUCKETS: usize = 32;
+
+/// Histogram of durations in power of two microsecond buckets, cheap enough to record every
+/// call on a hot path
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+    count: u64,
+    sum_us: u64,
+    max_us: u64,
+}
+
+/// Summary of a `LatencyHistogram`, percentiles are the upper bound of their bucket
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean_us: u64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        LatencyHistogram {
+            buckets: [0; LATENCY_BUCKETS],
+            count: 0,
+            sum_us: 0,
+            max_us: 0,
+        }
+    }
+
+    pub fn record(&mut self, elapsed: Duration) {
+        let us = elapsed.as_micros().min(u64::max_value() as u128) as u64;
+        // bucket i holds durations below 2^i microseconds
+        let bucket = (64 - us.leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+        self.max_us = self.max_us.max(us);
+    }
+
+    fn percentile(&self, p: u64) -> u64 {
+        let rank = (self.count * p + 99) / 100;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank && seen > 0 {
+                return (1u64 << bucket).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            mean_us: if self.count == 0 { 0 } else { self.sum_us / self.count },
+            p50_us: self.percentile(50),
+            p99_us: self.percentile(99),
+            max_us: self.max_us,
+        }
+    }
+}
+
+/// A cheap pseudo random number below `range`, good enough to spread out retries
+pub(crate) fn jitter(range: u64) -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    let seq = CORRELATION_SEQ.fetch_add(1, Ordering::Relaxed);
+
+    (nanos ^ seq.wrapping_mul(0x9e37_79b9_7f4a_7c15)) % range.max(1)
+}
+
+/// Receives the metrics of the raft clients, the networks and the storages, so they can be handed
+/// to any backend (StatsD, OpenTelemetry, logs). Durations are in microseconds.
+pub trait MetricsSink: Send + Sync {
+    /// Add `value` to a counter
+    fn counter(&self, name: &str, labels: &[(&str, &str)], value: u64);
+
+    fn gauge(&self, name: &str, labels: &[(&str, &str)], value: f64);
+
+    /// Record an observation, e.g. the duration of a call
+    fn histogram(&self, name: &str, labels: &[(&str, &str)], value: f64);
+}
+
+pub type SharedMetrics = Arc<dyn MetricsSink>;
+
+/// Drops every metric, the default sink
+pub struct NoopMetrics;
+
+impl MetricsSink for NoopMetrics {
+    fn counter(&self, _name: &str, _labels: &[(&str, &str)], _value: u64) {}
+
+    fn gauge(&self, _name: &str, _labels: &[(&str, &str)], _value: f64) {}
+
+    fn histogram(&self, _name: &str, _labels: &[(&str, &str)], _value: f64) {}
+}
+
+/// Histogram buckets of `PrometheusMetrics`, powers of four from 1 to ~16M
+const PROMETHEUS_BUCKETS: usize = 13;
+
+enum Series {
+    Counter(u64),
+    Gauge(f64),
+    Histogram {
+        buckets: [u64; PROMETHEUS_BUCKETS],
+        count: u64,
+        sum: f64,
+    },
+}
+
+/// Keeps the metrics in memory and renders them in the Prometheus text format for a scrape
+/// endpoint, see `render`
+pub struct PrometheusMetrics {
+    /// series by metric name and by rendered labels
+    series: Mutex<BTreeMap<String, BTreeMap<String, Series>>>,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        PrometheusMetrics {
+            series: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Option<Series>)>(&self, name: &str, labels: &[(&str, &str)], f: F) {
+        let mut series = self.series.lock().unwrap();
+        let metric = series.entry(name.to_owned()).or_insert_with(BTreeMap::new);
+        let labels = prometheus_labels(labels);
+        let mut value = metric.remove(&labels);
+        f(&mut value);
+        if let Some(value) = value {
+            metric.insert(labels, value);
+        }
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let mut out = String::new();
+
+        for (name, metric) in series.iter() {
+            let kind = match metric.values().next() {
+                Some(Series::Counter(_)) => "counter",
+                Some(Series::Gauge(_)) => "gauge",
+                Some(Series::Histogram { .. }) => "histogram",
+                None => continue,
+            };
+            out.push_str(&format!("# TYPE {} {}\n", name, kind));
+
+            for (labels, value) in metric.iter() {
+                match value {
+                    Series::Counter(value) => out.push_str(&format!("{}{} {}\n", name, labels, value)),
+                    Series::Gauge(value) => out.push_str(&format!("{}{} {}\n", name, labels, value)),
+                    Series::Histogram { buckets, count, sum } => {
+                        // the cumulative count of every bucket, with `le` next to the other labels
+                        let with_le = |le: &str| match labels.len() {
+                            0 => format!("{{le=\"{}\"}}", le),
+                            _ => format!("{},le=\"{}\"}}", &labels[..labels.len() - 1], le),
+                        };
+                        let mut seen = 0;
+                        for (bucket, bucket_count) in buckets.iter().enumerate() {
+                            seen += bucket_count;
+                            let le = (1u64 << (2 * bucket)).to_string();
+                            out.push_str(&format!("{}_bucket{} {}\n", name, with_le(&le), seen));
+                        }
+                        out.push_str(&format!("{}_bucket{} {}\n", name, with_le("+Inf"), count));
+                        out.push_str(&format!("{}_sum{} {}\n", name, labels, sum));
+                        out.push_str(&format!("{}_count{} {}\n", name, labels, count));
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl MetricsSink for PrometheusMetrics {
+    fn counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        self.update(name, labels, |series| match series {
+            Some(Series::Counter(total)) => *total += value,
+            _ => *series = Some(Series::Counter(value)),
+        });
+    }
+
+    fn gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(name, labels, |series| *series = Some(Series::Gauge(value)));
+    }
+
+    fn histogram(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(name, labels, |series| {
+            let fresh = match series {
+                Some(Series::Histogram { .. }) => false,
+                _ => true,
+            };
+            if fresh {
+                *series = Some(Series::Histogram { buckets: [0; PROMETHEUS_BUCKETS], count: 0, sum: 0.0 });
+            }
+            if let Some(Series::Histogram { buckets, count, sum }) = series {
+                // observations above the last bucket only count towards +Inf
+                if let Some(bucket) = (0..PROMETHEUS_BUCKETS).find(|bucket| value <= (1u64 << (2 * bucket)) as f64) {
+                    buckets[bucket] += 1;
+                }
+                *count += 1;
+                *sum += value;
+            }
+        });
+    }
+}
+
+/// `{name="value",..}`, empty without labels
+fn prometheus_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Source of time and delays, so timing dependent logic can be driven by a `MockClock`
+///
+/// Time has to be monotonic. Timeouts, heartbeats and breakers compare `Instant`s and never the
+/// wall clock, so an NTP step can't make them fire early or hold them back.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Resolves once `duration` passed on this clock
+    fn delay(&self, duration: Duration) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Monotonic time backed by the tokio timer
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, duration: Duration) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(Delay::new(Instant::now() + duration).map_err(|_| ()))
+    }
+}
+
+/// Timeouts measured on a `Clock`, so a `MockClock` fires them once advanced past them
+pub trait ClockTimeout: Future + Sized + 'static {
+    /// Fail with `()` unless the future resolves within `duration` on `clock`, an error of the
+    /// future itself is mapped to `()` as well
+    fn timeout_on(self, clock: &SharedClock, duration: Duration) -> Box<dyn Future<Item = Self::Item, Error = ()>>
+    where
+        Self::Item: 'static,
+    {
+        let expired = clock.delay(duration).then(|_| Err::<Self::Item, ()>(()));
+        Box::new(self.map_err(|_| ()).select(expired).map(|(item, _)| item).map_err(|(err, _)| err))
+    }
+}
+
+impl<F: Future + 'static> ClockTimeout for F {}
+
+/// A clock which only moves when advanced, pending delays resolve once they are due
+pub struct MockClock {
+    state: Mutex<MockClockState>,
+}
+
+struct MockClockState {
+    now: Instant,
+    timers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            state: Mutex::new(MockClockState {
+                now: Instant::now(),
+                timers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Move the clock forward, resolving every delay which became due
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+
+        let now = state.now;
+        let (due, pending): (Vec<_>, Vec<_>) = state.timers.drain(..).partition(|(at, _)| *at <= now);
+        state.timers = pending;
+
+        for (_, tx) in due {
+            let _ = tx.send(());
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    fn delay(&self, duration: Duration) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+
+        if duration == Duration::from_secs(0) {
+            let _ = tx.send(());
+        } else {
+            let at = state.now + duration;
+            state.timers.push((at, tx));
+        }
+
+        Box::new(rx.map_err(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::System;
+
+    #[test]
+    fn host_port_accepts_ips_and_dns_names() {
+        for addr in &["127.0.0.1:8000", "[::1]:8000", "localhost:8000", "raftor-0.raftor.default.svc:9000"] {
+            assert!(is_host_port(addr), "{} was refused", addr);
+        }
+        for addr in &["", "8000", "localhost", ":8000", "localhost:", "localhost:70000", "-bad.host:80", "a..b:80", "a b:80"] {
+            assert!(!is_host_port(addr), "{} was accepted", addr);
+        }
+    }
+
+    #[test]
+    fn resolve_addr_resolves_localhost() {
+        assert_eq!(resolve_addr("127.0.0.1:8000").unwrap(), "127.0.0.1:8000".parse::<SocketAddr>().unwrap());
+        assert_eq!(resolve_addr("localhost:8000").unwrap().port(), 8000);
+    }
+
+    #[test]
+    fn mock_clock_resolves_delays_once_advanced() {
+        let mut sys = System::new("test");
+        let clock = Arc::new(MockClock::new());
+        let shared: SharedClock = clock.clone();
+
+        sys.block_on(futures::future::lazy(move || {
+            let mut delay = shared.delay(Duration::from_secs(10));
+            assert_eq!(delay.poll(), Ok(Async::NotReady));
+            clock.advance(Duration::from_secs(9));
+            assert_eq!(delay.poll(), Ok(Async::NotReady));
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(delay.poll(), Ok(Async::Ready(())));
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn timeout_on_fires_on_the_clock() {
+        let mut sys = System::new("test");
+        let clock = Arc::new(MockClock::new());
+        let shared: SharedClock = clock.clone();
+
+        sys.block_on(futures::future::lazy(move || {
+            let mut pending = futures::future::empty::<(), ()>().timeout_on(&shared, Duration::from_secs(1));
+            assert_eq!(pending.poll(), Ok(Async::NotReady));
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(pending.poll(), Err(()));
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+}